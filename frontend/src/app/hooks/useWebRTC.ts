@@ -51,11 +51,20 @@ export const useWebRTC = () => {
     });
 
     pc.onicecandidate = (event) => {
-      if (event.candidate && sessionId.current) {
+      if (!sessionId.current) return;
+      if (event.candidate) {
         sendSignalingMessage({
           message_type: 'candidate',
           payload: JSON.stringify(event.candidate),
         });
+      } else {
+        // Gathering finished: tell the peer to stop expecting candidates.
+        // The ufrag keeps our marker distinct from the peer's own.
+        const ufrag = pc.localDescription?.sdp.match(/a=ice-ufrag:(\S+)/)?.[1];
+        sendSignalingMessage({
+          message_type: 'candidate',
+          payload: JSON.stringify({ candidate: '', usernameFragment: ufrag ?? null }),
+        });
       }
     };
 
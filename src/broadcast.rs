@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crate::protocol::{Protocol, Transport};
+use crate::source::{ChunkSource, FileSource};
+use crate::transfer::{track_progress, FileTransfer, ProgressConfig, TransferReceipt};
+use crate::Result;
+
+// Sends one file to several peers at once, e.g. a presenter dropping the
+// same slides to everyone who joined a session. Each peer has its own
+// `Protocol` (one per data channel or relay connection). The file is hashed
+// once, then every peer gets an independent `Protocol::send` with its own
+// `FileTransfer`, all driven concurrently: a slow or stalled peer only holds
+// up itself, and a failed one doesn't stop the rest. The peers share one
+// `SharedSource`, so each chunk is normally read from disk once.
+pub struct BroadcastTransfer<T: Transport> {
+    peers: Vec<Protocol<T>>,
+    progress: Option<PeerProgressFn>,
+}
+
+// (peer index, bytes sent to that peer, file size)
+pub type PeerProgressFn = Arc<dyn Fn(usize, u64, u64) + Send + Sync>;
+
+// Chunks `SharedSource` holds for peers that haven't read them yet; a peer
+// lagging further behind than this reads its chunks from disk again
+const MAX_SHARED_CHUNKS: usize = 64;
+
+impl<T: Transport> BroadcastTransfer<T> {
+    pub fn new(peers: Vec<Protocol<T>>) -> Self {
+        Self { peers, progress: None }
+    }
+
+    // Report each peer's progress separately as the broadcast runs, ending
+    // with (peer, size, size) for every peer that completed
+    pub fn with_progress(mut self, progress: impl Fn(usize, u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn peers(&self) -> &[Protocol<T>] {
+        &self.peers
+    }
+
+    pub fn into_peers(self) -> Vec<Protocol<T>> {
+        self.peers
+    }
+
+    // Send `path` to every peer. Fails only if the file itself can't be
+    // prepared; otherwise there is one outcome per peer, in peer order.
+    pub async fn send_file(&mut self, path: PathBuf) -> Result<Vec<Result<TransferReceipt>>> {
+        let metadata = FileTransfer::with_progress_config(path.clone(), &ProgressConfig::hidden())?
+            .prepare_metadata()
+            .await?;
+        let source: Arc<dyn ChunkSource> = Arc::new(SharedSource::new(FileSource::new(path.clone()), self.peers.len()));
+        let sends = self.peers.iter_mut().enumerate().map(|(index, protocol)| {
+            let file = FileTransfer::with_progress_config(path.clone(), &ProgressConfig::hidden())
+                .map(|file| file.with_source(source.clone()).with_prepared_metadata(metadata.clone()));
+            let progress = self.progress.clone();
+            async move {
+                let mut file = file?;
+                let bar = file.progress_bar().clone();
+                let report = progress.as_ref().map(|p| move |done, size: Option<u64>| p(index, done, size.unwrap_or_default()));
+                track_progress(&bar, report, protocol.send(&mut file)).await?;
+                if let Some(progress) = &progress {
+                    progress(index, metadata.size, metadata.size);
+                }
+                Ok(file.receipt())
+            }
+        });
+        Ok(futures::future::join_all(sends).await)
+    }
+}
+
+// The file a broadcast sends, read on behalf of every peer. A chunk read for
+// the first peer to ask is kept until the others have read it too, up to
+// MAX_SHARED_CHUNKS at a time; one asked for again after that (a lagging
+// peer, a retransmit) is read from disk and not kept.
+struct SharedSource {
+    file: FileSource,
+    peers: usize,
+    chunks: Mutex<SharedChunks>,
+}
+
+#[derive(Default)]
+struct SharedChunks {
+    // (offset, length) -> bytes, and how many peers are still to read them
+    held: HashMap<(u64, usize), (Vec<u8>, usize)>,
+    // Keys of `held`, oldest first
+    order: VecDeque<(u64, usize)>,
+    // Every chunk read from disk so far
+    read: HashSet<(u64, usize)>,
+}
+
+impl SharedSource {
+    fn new(file: FileSource, peers: usize) -> Self {
+        Self { file, peers, chunks: Mutex::default() }
+    }
+}
+
+impl ChunkSource for SharedSource {
+    fn len(&self) -> Result<u64> {
+        self.file.len()
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let key = (offset, buffer.len());
+        let mut chunks = self.chunks.lock().unwrap();
+        if let Some((data, waiting)) = chunks.held.get_mut(&key) {
+            let len = data.len();
+            buffer[..len].copy_from_slice(data);
+            *waiting -= 1;
+            if *waiting == 0 {
+                chunks.held.remove(&key);
+                chunks.order.retain(|k| *k != key);
+            }
+            return Ok(len);
+        }
+        let len = self.file.read_at(offset, buffer)?;
+        if chunks.read.insert(key) && self.peers > 1 {
+            if chunks.order.len() == MAX_SHARED_CHUNKS {
+                if let Some(oldest) = chunks.order.pop_front() {
+                    chunks.held.remove(&oldest);
+                }
+            }
+            chunks.held.insert(key, (buffer[..len].to_vec(), self.peers - 1));
+            chunks.order.push_back(key);
+        }
+        Ok(len)
+    }
+
+    fn attributes(&self) -> (Option<u32>, Option<i64>) {
+        self.file.attributes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::protocol::loopback;
+    use crate::TransferProtocol;
+
+    #[tokio::test]
+    async fn test_broadcast_to_two_receivers() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("slides.bin");
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 123u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let (to_slow, slow_end) = loopback();
+        let (to_fast, fast_end) = loopback();
+        let (mut slow, mut fast) = (Protocol::new(slow_end), Protocol::new(fast_end));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = seen.clone();
+        let mut broadcast = BroadcastTransfer::new(vec![Protocol::new(to_slow), Protocol::new(to_fast)])
+            .with_progress(move |peer, done, total| record.lock().unwrap().push((peer, done, total)));
+
+        let (slow_dst, fast_dst) = (dir.path().join("slow.bin"), dir.path().join("fast.bin"));
+        let receivers = async {
+            // The first peer doesn't even start until the second is done, so a
+            // broadcast sending to one peer at a time would never finish
+            fast.receive_file(fast_dst.clone()).await.unwrap();
+            assert_eq!(std::fs::read(&fast_dst).unwrap(), data);
+            slow.receive_file(slow_dst.clone()).await.unwrap();
+        };
+        let (outcomes, ()) = tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::join!(broadcast.send_file(src), receivers)
+        })
+        .await
+        .unwrap();
+
+        let receipts: Vec<TransferReceipt> = outcomes.unwrap().into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts.iter().all(|r| r.size == data.len() as u64 && r.bytes_transferred == data.len() as u64));
+        assert_eq!(std::fs::read(&slow_dst).unwrap(), data);
+        let size = data.len() as u64;
+        let seen = seen.lock().unwrap();
+        for peer in 0..2 {
+            assert_eq!(seen.iter().rfind(|p| p.0 == peer), Some(&(peer, size, size)));
+        }
+    }
+
+    #[test]
+    fn test_shared_source_reads_each_chunk_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slides.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let source = SharedSource::new(FileSource::new(path.clone()), 2);
+        let read = |source: &SharedSource, index: u64| {
+            let mut buffer = vec![0u8; 1000];
+            let len = source.read_at(index * 1000, &mut buffer).unwrap();
+            assert_eq!(&buffer[..len], &data[index as usize * 1000..][..len]);
+        };
+
+        // Both peers in step: every chunk comes off disk once and is let go
+        // once the second peer has it
+        for index in 0..10 {
+            read(&source, index);
+            read(&source, index);
+        }
+        assert_eq!(source.chunks.lock().unwrap().read.len(), 10);
+        assert!(source.chunks.lock().unwrap().held.is_empty());
+
+        // One peer far ahead: only the last MAX_SHARED_CHUNKS are held for the other
+        for index in 10..100 {
+            read(&source, index);
+        }
+        assert_eq!(source.chunks.lock().unwrap().held.len(), MAX_SHARED_CHUNKS);
+        for index in 10..100 {
+            read(&source, index);
+        }
+        assert!(source.chunks.lock().unwrap().held.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+use crate::transfer::MAX_CHUNK_SIZE;
+use crate::{DropError, Result};
+
+// Per-chunk compression applied on the wire. Chunk hashes in `ChunkInfo`
+// always cover the uncompressed bytes: the sender hashes, then compresses;
+// the receiver decompresses, then verifies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+impl Compression {
+    // Every supported algorithm, best first: negotiation picks the earliest
+    // one both peers advertise. Peers may advertise others (e.g. "lz4"),
+    // which are ignored until there is a vetted implementation to add here.
+    pub const PREFERENCE: [Compression; 1] = [Compression::Zstd];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::compress(data, 3)?),
+        }
+    }
+
+    // At most `limit` bytes of output. Ok(None) means `data` isn't valid
+    // compressed data, which the receiver treats like a corrupt chunk; output
+    // beyond the limit is an error, since an honest peer never produces it.
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Option<Vec<u8>>> {
+        match self {
+            Compression::Zstd => {
+                let Ok(mut decoder) = zstd::stream::read::Decoder::new(data) else {
+                    return Ok(None);
+                };
+                // Frames declaring a window bigger than any chunk are refused
+                // before zstd allocates it
+                if decoder.window_log_max(MAX_CHUNK_SIZE.ilog2()).is_err() {
+                    return Ok(None);
+                }
+                let mut out = Vec::new();
+                match decoder.take(limit as u64 + 1).read_to_end(&mut out) {
+                    Ok(_) if out.len() > limit => Err(bomb()),
+                    Ok(_) => Ok(Some(out)),
+                    Err(_) => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+// Room allowed over the chunk size when decompressing a chunk
+pub const DECOMPRESSION_MARGIN: usize = 4096;
+
+fn bomb() -> DropError {
+    DropError::Protocol("decompression bomb".to_string())
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = DropError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(DropError::Protocol(format!("unsupported compression: {}", other))),
+        }
+    }
+}
+
+// Resolve `FileMetadata.compression`; `None` means chunks travel uncompressed
+pub fn from_metadata(name: Option<&str>) -> Result<Option<Compression>> {
+    name.map(str::parse).transpose()
+}
+
+// Name advertised in `Hello` for sending chunks uncompressed, which every peer supports
+pub const NONE: &str = "none";
+
+// Names to advertise in `Hello` for the algorithms in `ours`
+pub fn advertise(ours: &[Compression]) -> Vec<String> {
+    Compression::PREFERENCE
+        .iter()
+        .filter(|c| ours.contains(c))
+        .map(|c| c.to_string())
+        .chain(std::iter::once(NONE.to_string()))
+        .collect()
+}
+
+// The best algorithm in `ours` that the peer also advertised, or None to send
+// uncompressed. Names we don't know are ignored. Both peers prefer in the same
+// order, so they agree whichever side runs this.
+pub fn negotiate(ours: &[Compression], theirs: &[String]) -> Option<Compression> {
+    Compression::PREFERENCE
+        .into_iter()
+        .find(|c| ours.contains(c) && theirs.iter().any(|name| name == c.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_negotiation_prefers_best_common_algorithm() {
+        let all = Compression::PREFERENCE;
+        assert_eq!(negotiate(&all, &names(&["lz4", "zstd", "none"])), Some(Compression::Zstd));
+        // Their order doesn't matter, only ours does
+        assert_eq!(negotiate(&all, &names(&["none", "zstd"])), Some(Compression::Zstd));
+        assert_eq!(advertise(&all), names(&["zstd", "none"]));
+    }
+
+    #[test]
+    fn test_disjoint_compressors_fall_back_to_none() {
+        assert_eq!(negotiate(&[Compression::Zstd], &names(&["lz4", "none"])), None);
+        assert_eq!(negotiate(&Compression::PREFERENCE, &names(&["brotli", "none"])), None);
+        assert_eq!(negotiate(&[], &advertise(&Compression::PREFERENCE)), None);
+    }
+
+    #[test]
+    fn test_decompression_stops_at_limit() {
+        let zeros = vec![0u8; 1 << 20];
+        for compression in Compression::PREFERENCE {
+            let packed = compression.compress(&zeros).unwrap();
+            assert!(packed.len() < 5000, "{} {} bytes", compression, packed.len());
+            assert_eq!(compression.decompress(&packed, zeros.len()).unwrap().map(|d| d.len()), Some(zeros.len()));
+            let err = compression.decompress(&packed, zeros.len() - 1).unwrap_err();
+            assert!(err.to_string().contains("decompression bomb"), "{}", err);
+        }
+    }
+}
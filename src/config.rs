@@ -0,0 +1,208 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Header clients use to address a specific peer within a session.
+pub const PEER_ID_HEADER: &str = "x-drop-peer-id";
+
+// Header a client sets on create_session so a retried request gets the same session
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+pub const DEFAULT_MAX_MESSAGES_PER_POLL: usize = 64;
+// Generous for an SDP offer or answer, which is usually a few KB
+pub const DEFAULT_MAX_SIGNAL_PAYLOAD: usize = 16 * 1024;
+pub const DEFAULT_STUN_SERVERS: &[&str] = &["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+pub const DEFAULT_MAX_SIGNAL_BATCH: usize = 64;
+pub const DEFAULT_MAX_CODE_COLLISION_RETRIES: u32 = 16;
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(10 * 60);
+pub const DEFAULT_SESSION_CODE_ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+pub const DEFAULT_SESSION_CODE_LENGTH: usize = 6;
+pub const DEFAULT_MAX_SESSIONS: usize = 10_000;
+// How long a `?wait_for=` poll is held open before returning empty
+pub const DEFAULT_SIGNAL_WAIT_TIMEOUT: Duration = Duration::from_secs(25);
+// Collision odds among `max_sessions` live codes above which startup complains
+const MAX_CODE_COLLISION_RISK: f64 = 0.5;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // Origins allowed by the global CORS policy
+    pub allowed_origins: Vec<String>,
+    // Request headers allowed by the global CORS policy (checked on preflight)
+    pub allowed_headers: Vec<String>,
+    // Sessions idle for longer than this are reaped
+    pub session_ttl: Duration,
+    // How often the reaper sweeps for idle sessions
+    pub reap_interval: Duration,
+    // Queue a "session_expiring" message this long before an idle session is
+    // reaped; off when unset. Only as precise as `reap_interval`.
+    pub expiry_warning: Option<Duration>,
+    // Keep queued signaling payloads encrypted with a per-process key
+    pub encrypt_signaling_at_rest: bool,
+    // Return a random secret with each new session code, for the peers to
+    // derive a key from; see `crypto::Crypto::from_session_secret`
+    pub session_secrets: bool,
+    // Most messages handed out by one receive_signal call
+    pub max_messages_per_poll: usize,
+    // Longest a receive_signal call with `?wait_for=` waits for a matching message
+    pub signal_wait_timeout: Duration,
+    // Largest signaling payload accepted, separate from the JSON body limit
+    pub max_signal_payload: usize,
+    // Most messages accepted by one send-batch request
+    pub max_signal_batch: usize,
+    // Bearer token for the /api/admin endpoints; they're disabled when unset
+    pub admin_token: Option<String>,
+    // How long an Idempotency-Key keeps returning the session it created
+    pub idempotency_ttl: Duration,
+    // Most signaling and relay bytes one session may pass through; unlimited when unset
+    pub session_byte_cap: Option<u64>,
+    // "host:port" STUN servers used by /api/admin/nat-check
+    pub stun_servers: Vec<String>,
+    // On shutdown, how long open relays get to finish before their peers are told to stop
+    pub shutdown_drain_timeout: Duration,
+    // Fresh session codes tried after a collision before giving up with a 500
+    pub max_code_collision_retries: u32,
+    // Session codes are `session_code_length` characters drawn from this alphabet
+    pub session_code_alphabet: String,
+    pub session_code_length: usize,
+    // Most sessions expected live at once, used to size the code space check
+    pub max_sessions: usize,
+    // "http(s)://host[:port]/path" POSTed a `webhook::WebhookEvent` on every session
+    // lifecycle transition; off when unset
+    pub webhook_url: Option<String>,
+    // Signs webhook bodies, see `webhook::SIGNATURE_HEADER`
+    pub webhook_secret: Option<String>,
+    // Directory for store-and-forward uploads (`/api/spool/*`); off when unset
+    pub spool_dir: Option<PathBuf>,
+    // How long an upload waits to be collected
+    pub spool_ttl: Duration,
+    // Most bytes the spool holds at once; uploads beyond it fail with a 507
+    pub spool_quota: u64,
+    // Refuse to start on configuration that `validate` would only warn about
+    pub strict: bool,
+    // UDP address for the built-in STUN responder, e.g. "0.0.0.0:3478"; off when unset
+    #[cfg(feature = "stun-server")]
+    pub stun_server_addr: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            allowed_origins: vec![
+                "http://localhost:3000".to_string(),
+                "http://127.0.0.1:3000".to_string(),
+            ],
+            allowed_headers: vec![
+                "content-type".to_string(),
+                "authorization".to_string(),
+                PEER_ID_HEADER.to_string(),
+                IDEMPOTENCY_KEY_HEADER.to_string(),
+                crate::spool::FILE_NAME_HEADER.to_string(),
+                "range".to_string(),
+                "if-range".to_string(),
+            ],
+            session_ttl: Duration::from_secs(10 * 60),
+            reap_interval: Duration::from_secs(30),
+            expiry_warning: None,
+            encrypt_signaling_at_rest: false,
+            session_secrets: false,
+            max_messages_per_poll: DEFAULT_MAX_MESSAGES_PER_POLL,
+            signal_wait_timeout: DEFAULT_SIGNAL_WAIT_TIMEOUT,
+            max_signal_payload: DEFAULT_MAX_SIGNAL_PAYLOAD,
+            max_signal_batch: DEFAULT_MAX_SIGNAL_BATCH,
+            admin_token: None,
+            idempotency_ttl: DEFAULT_IDEMPOTENCY_TTL,
+            session_byte_cap: None,
+            stun_servers: DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
+            shutdown_drain_timeout: Duration::from_secs(30),
+            max_code_collision_retries: DEFAULT_MAX_CODE_COLLISION_RETRIES,
+            session_code_alphabet: DEFAULT_SESSION_CODE_ALPHABET.to_string(),
+            session_code_length: DEFAULT_SESSION_CODE_LENGTH,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            webhook_url: None,
+            webhook_secret: None,
+            spool_dir: None,
+            spool_ttl: crate::spool::DEFAULT_SPOOL_TTL,
+            spool_quota: crate::spool::DEFAULT_SPOOL_QUOTA,
+            strict: false,
+            #[cfg(feature = "stun-server")]
+            stun_server_addr: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    fn code_alphabet_size(&self) -> usize {
+        let mut alphabet: Vec<char> = self.session_code_alphabet.chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        alphabet.len()
+    }
+
+    // Distinct session codes the alphabet and length allow
+    pub fn code_keyspace(&self) -> f64 {
+        (self.code_alphabet_size() as f64).powi(self.session_code_length.min(i32::MAX as usize) as i32)
+    }
+
+    // Birthday bound on the chance that two of `max_sessions` random codes
+    // coincide: 1 - e^(-n(n-1) / 2k) for a keyspace of k
+    pub fn code_collision_risk(&self) -> f64 {
+        let n = self.max_sessions as f64;
+        let exponent = n * (n - 1.0) / (2.0 * self.code_keyspace());
+        -(-exponent).exp_m1()
+    }
+
+    // Checked at startup. A code space too small for `max_sessions` is logged
+    // and returned as a warning, or is an error when `strict` is set, since
+    // it ends in session creation failing once codes run out.
+    pub fn validate(&self) -> std::io::Result<Option<String>> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        if self.session_code_length == 0 || self.code_keyspace() < 2.0 {
+            return Err(invalid("session codes need a length and at least two distinct characters".to_string()));
+        }
+        let risk = self.code_collision_risk();
+        if risk <= MAX_CODE_COLLISION_RISK {
+            return Ok(None);
+        }
+        let warning = format!(
+            "session codes of {} characters from a {}-character alphabet allow {} codes; \
+             with up to {} live sessions a collision is {:.0}% likely",
+            self.session_code_length,
+            self.code_alphabet_size(),
+            self.code_keyspace(),
+            self.max_sessions,
+            risk * 100.0
+        );
+        if self.strict {
+            return Err(invalid(warning));
+        }
+        tracing::warn!("{}", warning);
+        Ok(Some(warning))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_code_space_is_flagged() {
+        let tiny = ServerConfig {
+            session_code_alphabet: "0123456789".to_string(),
+            session_code_length: 4,
+            max_sessions: 1_000,
+            ..ServerConfig::default()
+        };
+        assert!(tiny.code_collision_risk() > 0.99);
+        let warning = tiny.validate().unwrap().expect("tiny keyspace should warn");
+        assert!(warning.contains("10000 codes"), "{}", warning);
+        assert!(ServerConfig { strict: true, ..tiny }.validate().is_err());
+
+        let large = ServerConfig::default();
+        assert!(large.code_collision_risk() < 0.05);
+        assert_eq!(large.validate().unwrap(), None);
+        assert!(ServerConfig { strict: true, ..large }.validate().is_ok());
+    }
+}
@@ -1,9 +1,110 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use sha2::{Digest, Sha256};
+use crate::{DropError, Result};
+
+const NONCE_LEN: usize = 12;
+// Random bytes in a session secret
+pub const SESSION_SECRET_LEN: usize = 32;
+
+// AES-256-GCM with a fresh random nonce per message. Sealed output is
+// `nonce || ciphertext || tag`.
 pub struct Crypto {
-    // Crypto implementation will go here
+    cipher: Aes256Gcm,
 }
 
 impl Crypto {
+    // Random key, lives only as long as this value (e.g. one server process)
     pub fn new() -> Self {
-        Self {}
+        Self {
+            cipher: Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng)),
+        }
+    }
+
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    // Key shared by the two peers of a session: the secret returned when it
+    // was created, passed along out of band, salted with the session code so
+    // a secret can't be replayed against another session
+    pub fn from_session_secret(secret: &str, session_id: &str) -> Result<Self> {
+        let secret = URL_SAFE_NO_PAD
+            .decode(secret)
+            .ok()
+            .filter(|bytes| bytes.len() == SESSION_SECRET_LEN)
+            .ok_or_else(|| DropError::Crypto("malformed session secret".to_string()))?;
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(b"drop session key\0")
+            .chain_update(&secret)
+            .chain_update(session_id.as_bytes())
+            .finalize()
+            .into();
+        Ok(Self::from_key(&key))
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| DropError::Crypto(e.to_string()))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(DropError::Crypto("ciphertext too short".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| DropError::Crypto(e.to_string()))
+    }
+
+    // String helpers for storing text (e.g. SDP) encrypted as base64
+    pub fn seal_string(&self, plaintext: &str) -> Result<String> {
+        Ok(STANDARD.encode(self.encrypt(plaintext.as_bytes())?))
     }
-} 
\ No newline at end of file
+
+    pub fn open_string(&self, sealed: &str) -> Result<String> {
+        let bytes = STANDARD
+            .decode(sealed)
+            .map_err(|e| DropError::Crypto(e.to_string()))?;
+        String::from_utf8(self.decrypt(&bytes)?).map_err(|e| DropError::Crypto(e.to_string()))
+    }
+}
+
+// SESSION_SECRET_LEN random bytes as unpadded URL-safe base64
+pub fn generate_session_secret() -> String {
+    let secret: [u8; SESSION_SECRET_LEN] = rand::random();
+    URL_SAFE_NO_PAD.encode(secret)
+}
+
+impl Default for Crypto {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open_roundtrip() {
+        let crypto = Crypto::from_key(&[7u8; 32]);
+        let sealed = crypto.seal_string("v=0\r\no=- 123 IN IP4 0.0.0.0").unwrap();
+        assert!(!sealed.contains("IN IP4"));
+        assert_eq!(crypto.open_string(&sealed).unwrap(), "v=0\r\no=- 123 IN IP4 0.0.0.0");
+        // Nonces are random, so the same plaintext seals differently
+        assert_ne!(sealed, crypto.seal_string("v=0\r\no=- 123 IN IP4 0.0.0.0").unwrap());
+        assert!(Crypto::new().open_string(&sealed).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::hash::{sha256_hex, HashBackend};
+use crate::protocol::{Protocol, Transport};
+use crate::source::{ChunkSource, FileSource};
+use crate::{AbortReason, DropError, Result, TransferCommand};
+
+// rsync-style update of a file the receiver already has an older copy of.
+// The receiver sends `BlockSignatures` of that copy: a rolling weak checksum
+// and a SHA-256 per fixed-size block. The sender slides a window over the new
+// file one byte at a time, looking each position up by weak checksum and
+// confirming with the strong hash, and answers with `Delta` batches of
+// "copy these blocks" and literal bytes, then `DeltaEnd` with the new size and
+// hash. Unlike `Protocol::with_incremental`, this still finds blocks that
+// moved, e.g. after an insertion.
+//
+// The file is rebuilt next to the output and only renamed into place once it
+// matches `DeltaEnd`, so the output (which may also be the basis) is never
+// left half-written.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaTransfer {
+    // Picked from the basis size when unset, see `default_block_size`
+    block_size: Option<usize>,
+}
+
+pub const MIN_BLOCK_SIZE: usize = 512;
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+// Most signatures the receiver sends; bigger bases get bigger blocks
+pub const MAX_SIGNATURE_BLOCKS: u64 = 500_000;
+// Literal bytes per `DeltaOp::Literal`, and roughly per `Delta` message
+const MAX_LITERAL: usize = 256 * 1024;
+const MAX_OPS_PER_MESSAGE: usize = 4096;
+const READ_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DeltaOp {
+    // `count` consecutive basis blocks starting at `block`
+    Copy { block: u32, count: u32 },
+    Literal(Vec<u8>),
+}
+
+// What one delta transfer cost, from either side
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub literal_bytes: u64,
+    pub copied_bytes: u64,
+}
+
+// About sqrt(size), like rsync, so signatures and literals grow alike
+pub fn default_block_size(basis_len: u64) -> usize {
+    let size = (basis_len as f64).sqrt() as u64;
+    size.max(basis_len.div_ceil(MAX_SIGNATURE_BLOCKS))
+        .clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as usize
+}
+
+impl DeltaTransfer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Block size the receiver signs its copy with, clamped to
+    // [MIN_BLOCK_SIZE, MAX_BLOCK_SIZE]
+    pub fn with_block_size(mut self, size: usize) -> Self {
+        self.block_size = Some(size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+        self
+    }
+
+    pub async fn send<T: Transport>(&self, protocol: &mut Protocol<T>, path: &Path) -> Result<DeltaStats> {
+        protocol.handshake().await?;
+        let (block_size, blocks) = match protocol.expect_command().await? {
+            TransferCommand::BlockSignatures { block_size, blocks } => (block_size as usize, blocks),
+            other => return Err(DropError::Protocol(format!("expected BlockSignatures, got {:?}", other))),
+        };
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            let reason = format!("unsupported delta block size {}", block_size);
+            protocol.send_abort(AbortReason::Unsupported, &reason).await?;
+            return Err(DropError::Protocol(reason));
+        }
+
+        let mut encoder = Encoder::new(block_size, &blocks);
+        let mut file = File::open(path)?;
+        let mut hasher = HashBackend::default().hasher();
+        let mut size = 0u64;
+        // Unsent literal bytes start at buffer[literal], the window at buffer[pos]
+        let mut buffer: Vec<u8> = Vec::new();
+        let (mut literal, mut pos) = (0, 0);
+        let mut rolling: Option<Rolling> = None;
+        let mut eof = false;
+        loop {
+            // Keep a byte past the window buffered, for rolling into
+            while !eof && buffer.len() - pos <= block_size {
+                if literal >= READ_SIZE {
+                    buffer.drain(..literal);
+                    pos -= literal;
+                    literal = 0;
+                }
+                let start = buffer.len();
+                buffer.resize(start + READ_SIZE, 0);
+                let read = file.read(&mut buffer[start..])?;
+                buffer.truncate(start + read);
+                hasher.update(&buffer[start..]);
+                size += read as u64;
+                eof = read == 0;
+            }
+            if buffer.len() - pos < block_size {
+                break;
+            }
+            let window = &buffer[pos..pos + block_size];
+            let weak = rolling.get_or_insert_with(|| Rolling::new(window));
+            if let Some(block) = encoder.find(weak.digest(), window) {
+                encoder.literal(&buffer[literal..pos]);
+                encoder.copy(block);
+                pos += block_size;
+                literal = pos;
+                rolling = None;
+            } else {
+                if pos + block_size < buffer.len() {
+                    weak.roll(buffer[pos], buffer[pos + block_size]);
+                } else {
+                    rolling = None;
+                }
+                pos += 1;
+                if pos - literal >= MAX_LITERAL {
+                    encoder.literal(&buffer[literal..pos]);
+                    literal = pos;
+                }
+            }
+            if encoder.batch_full() {
+                protocol.send_command(&TransferCommand::Delta(encoder.take_batch())).await?;
+            }
+        }
+        encoder.literal(&buffer[literal..]);
+        protocol.send_command(&TransferCommand::Delta(encoder.take_batch())).await?;
+        protocol
+            .send_command(&TransferCommand::DeltaEnd { size, hash: hasher.finalize_hex() })
+            .await?;
+
+        match protocol.expect_command().await? {
+            TransferCommand::Complete => {}
+            other => return Err(DropError::Protocol(format!("expected Complete, got {:?}", other))),
+        }
+        protocol.finish_transfer().await?;
+        Ok(encoder.stats)
+    }
+
+    // Rebuild the sender's file at `output` from `basis`, which may be missing
+    // (everything then arrives as literals) or the same path as `output`
+    pub async fn receive<T: Transport>(&self, protocol: &mut Protocol<T>, basis: &Path, output: &Path) -> Result<DeltaStats> {
+        protocol.handshake().await?;
+        let basis_len = std::fs::metadata(basis).ok().filter(|m| m.is_file()).map_or(0, |m| m.len());
+        let block_size = self.block_size.unwrap_or_else(|| default_block_size(basis_len));
+        let blocks = signatures(basis, basis_len, block_size)?;
+        let block_count = blocks.len() as u64;
+        protocol
+            .send_command(&TransferCommand::BlockSignatures { block_size: block_size as u32, blocks })
+            .await?;
+
+        let partial = partial_path(output);
+        let result = self.rebuild(protocol, basis, &partial, block_size, block_count).await;
+        let result = result.and_then(|stats| {
+            std::fs::rename(&partial, output)?;
+            Ok(stats)
+        });
+        match result {
+            Ok(stats) => {
+                protocol.send_command(&TransferCommand::Complete).await?;
+                protocol.finish_transfer().await?;
+                Ok(stats)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                let _ = protocol.send_abort(AbortReason::for_error(&e), &e.to_string()).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn rebuild<T: Transport>(
+        &self,
+        protocol: &mut Protocol<T>,
+        basis: &Path,
+        partial: &Path,
+        block_size: usize,
+        block_count: u64,
+    ) -> Result<DeltaStats> {
+        let source = FileSource::new(basis.to_path_buf());
+        let mut out = BufWriter::new(File::create(partial)?);
+        let mut hasher = HashBackend::default().hasher();
+        let mut stats = DeltaStats::default();
+        let mut block = vec![0u8; block_size];
+        let (size, hash) = loop {
+            match protocol.expect_command().await? {
+                TransferCommand::Delta(ops) => {
+                    for op in ops {
+                        match op {
+                            DeltaOp::Literal(bytes) => {
+                                out.write_all(&bytes)?;
+                                hasher.update(&bytes);
+                                stats.literal_bytes += bytes.len() as u64;
+                            }
+                            DeltaOp::Copy { block: first, count } => {
+                                if first as u64 + count as u64 > block_count {
+                                    return Err(DropError::Protocol(format!("copy of block {} past the basis", first)));
+                                }
+                                for index in first..first + count {
+                                    let read = source.read_at(index as u64 * block_size as u64, &mut block)?;
+                                    if read != block_size {
+                                        return Err(DropError::Protocol("basis changed during delta transfer".to_string()));
+                                    }
+                                    out.write_all(&block)?;
+                                    hasher.update(&block);
+                                    stats.copied_bytes += block_size as u64;
+                                }
+                            }
+                        }
+                    }
+                }
+                TransferCommand::DeltaEnd { size, hash } => break (size, hash),
+                other => return Err(DropError::Protocol(format!("unexpected command during delta: {:?}", other))),
+            }
+        };
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        if stats.literal_bytes + stats.copied_bytes != size || hasher.finalize_hex() != hash {
+            return Err(DropError::VerificationFailed { mismatched_chunks: Vec::new(), file_hash_mismatch: true });
+        }
+        Ok(stats)
+    }
+}
+
+// Signatures of every full block of the basis; a short tail is left out and
+// arrives as literals
+fn signatures(basis: &Path, basis_len: u64, block_size: usize) -> Result<Vec<BlockSignature>> {
+    let source = FileSource::new(basis.to_path_buf());
+    let mut buffer = vec![0u8; block_size];
+    (0..basis_len / block_size as u64)
+        .map(|index| {
+            source.read_at(index * block_size as u64, &mut buffer)?;
+            Ok(BlockSignature { weak: Rolling::new(&buffer).digest(), strong: sha256_hex(&buffer) })
+        })
+        .collect()
+}
+
+fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_os_string();
+    name.push(".delta-partial");
+    PathBuf::from(name)
+}
+
+// Adler-32 style checksum of a window, updated in O(1) as it slides a byte:
+// `a` sums the bytes and `b` weights each by its distance from the window's
+// end, both mod 2^16
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((window.len() - i) as u32 * byte as u32);
+        }
+        Self { a: a & 0xffff, b: b & 0xffff, len: window.len() as u32 }
+    }
+
+    // Slide past `out`, taking in `next`
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+// Sender-side matching against the receiver's signatures, and the pending batch of ops
+struct Encoder<'a> {
+    blocks: &'a [BlockSignature],
+    by_weak: HashMap<u32, Vec<u32>>,
+    ops: Vec<DeltaOp>,
+    batch_literal: usize,
+    stats: DeltaStats,
+    block_size: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(block_size: usize, blocks: &'a [BlockSignature]) -> Self {
+        let mut by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (index, block) in blocks.iter().enumerate() {
+            by_weak.entry(block.weak).or_default().push(index as u32);
+        }
+        Self { blocks, by_weak, ops: Vec::new(), batch_literal: 0, stats: DeltaStats::default(), block_size }
+    }
+
+    // A basis block holding exactly `window`; the strong hash is only
+    // computed once the weak checksum matches
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong = sha256_hex(window);
+        candidates.iter().copied().find(|&index| self.blocks[index as usize].strong == strong)
+    }
+
+    fn copy(&mut self, block: u32) {
+        self.stats.copied_bytes += self.block_size as u64;
+        if let Some(DeltaOp::Copy { block: first, count }) = self.ops.last_mut() {
+            if *first + *count == block {
+                *count += 1;
+                return;
+            }
+        }
+        self.ops.push(DeltaOp::Copy { block, count: 1 });
+    }
+
+    fn literal(&mut self, bytes: &[u8]) {
+        for piece in bytes.chunks(MAX_LITERAL) {
+            self.stats.literal_bytes += piece.len() as u64;
+            self.batch_literal += piece.len();
+            self.ops.push(DeltaOp::Literal(piece.to_vec()));
+        }
+    }
+
+    fn batch_full(&self) -> bool {
+        self.ops.len() >= MAX_OPS_PER_MESSAGE || self.batch_literal >= MAX_LITERAL
+    }
+
+    fn take_batch(&mut self) -> Vec<DeltaOp> {
+        self.batch_literal = 0;
+        std::mem::take(&mut self.ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::loopback;
+
+    // Bytes without repeats at block scale, so only genuine copies match
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rolled_checksum_matches_fresh_one() {
+        let data = noise(5000, 3);
+        let mut rolling = Rolling::new(&data[..700]);
+        for start in 1..=data.len() - 700 {
+            rolling.roll(data[start - 1], data[start + 699]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[start..start + 700]).digest(), "at {}", start);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inserted_block_sends_few_literal_bytes() {
+        const BLOCK: usize = 4096;
+        let dir = tempfile::tempdir().unwrap();
+        let old = noise(50 * BLOCK, 1);
+        let inserted = noise(1000, 2);
+        // A mid-file insertion shifts every later block off its old offset
+        let at = 12 * BLOCK + 848;
+        let new: Vec<u8> = [&old[..at], &inserted[..], &old[at..]].concat();
+        let (src, basis) = (dir.path().join("new.bin"), dir.path().join("old.bin"));
+        std::fs::write(&src, &new).unwrap();
+        std::fs::write(&basis, &old).unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let delta = DeltaTransfer::new().with_block_size(BLOCK);
+        let (sent, received) = tokio::join!(delta.send(&mut sender, &src), delta.receive(&mut receiver, &basis, &basis));
+        let (sent, received) = (sent.unwrap(), received.unwrap());
+
+        assert_eq!(std::fs::read(&basis).unwrap(), new);
+        assert_eq!(sent, received);
+        // The insertion plus the one basis block it split
+        assert_eq!(sent.literal_bytes, (inserted.len() + BLOCK) as u64);
+        assert_eq!(sent.copied_bytes, (49 * BLOCK) as u64);
+        assert!(!partial_path(&basis).exists());
+    }
+}
@@ -0,0 +1,808 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use crate::metadata_cache::file_stamp;
+use crate::protocol::{Protocol, Transport};
+use crate::transfer::{file_attributes, track_progress, FileTransfer, ProgressConfig};
+use crate::{AbortReason, DropError, FileMetadata, Result, TransferCommand};
+
+// Sends or receives everything under `root` over one connection. The sender
+// first announces a `Manifest` of every entry (relative paths with `/`
+// separators), then sends each regular file as an ordinary transfer. Manifest
+// entries carry only name, size and attributes; the hashes come with each
+// file's own `StartTransfer`.
+//
+// Symlinks are never followed. They are sent as entries with
+// `symlink_target` set and recreated as symlinks on the receiving side, which
+// rejects the whole manifest if any target would point outside `root`.
+//
+// Files added after the manifest was sent are not transferred; files removed
+// by then are announced with `Skip`, as are files whose size no longer
+// matches the manifest. Files go out in `send_order`, which both sides derive
+// from the manifest. Each is hashed a few files ahead of its turn and
+// hashed again if it changed before being announced.
+pub struct DirectoryTransfer {
+    root: PathBuf,
+    progress: Option<ProgressFn>,
+    limits: DirectoryLimits,
+    // Manifest name -> priority, for `plan`
+    priorities: Vec<(String, u32)>,
+    // Files hashed ahead of the one being sent
+    hash_concurrency: usize,
+}
+
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+pub const DEFAULT_MAX_FILES: usize = 100_000;
+pub const DEFAULT_MAX_STREAMED_FILES: usize = 100_000;
+pub const DEFAULT_MAX_TOTAL_SIZE: u64 = 1 << 40; // 1 TiB
+pub const DEFAULT_HASH_CONCURRENCY: usize = 4;
+// Each file being hashed holds a descriptor open, so `with_hash_concurrency`
+// is capped well below typical per-process limits
+pub const MAX_HASH_CONCURRENCY: usize = 64;
+// Files a waiting file can be overtaken by before it goes next regardless of priority
+pub const MAX_OVERTAKES: usize = 8;
+
+// Bounds a receiver puts on the peer's manifest before writing anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryLimits {
+    // Path components in an entry name; a file directly under the root is depth 1
+    pub max_depth: usize,
+    // Manifest entries, symlinks included
+    pub max_files: usize,
+    pub max_total_size: u64,
+    // `StartTransfer`s accepted in one session, counted as they arrive rather
+    // than from the manifest, so it holds however the files were announced
+    pub max_streamed_files: usize,
+}
+
+impl Default for DirectoryLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_files: DEFAULT_MAX_FILES,
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_streamed_files: DEFAULT_MAX_STREAMED_FILES,
+        }
+    }
+}
+
+// (manifest index of the current file, bytes done, total bytes) across the
+// whole directory
+pub type ProgressFn = Arc<dyn Fn(usize, u64, u64) + Send + Sync>;
+
+// A file's metadata and its `file_stamp` from just before it was hashed
+type Hashed = (FileMetadata, (u64, u128));
+
+impl DirectoryTransfer {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            progress: None,
+            limits: DirectoryLimits::default(),
+            priorities: Vec::new(),
+            hash_concurrency: DEFAULT_HASH_CONCURRENCY,
+        }
+    }
+
+    // Send the entry `name` (its manifest path, e.g. "docs/index.json") ahead
+    // of entries with a lower priority; everything else has priority 0
+    pub fn with_priority(mut self, name: impl Into<String>, priority: u32) -> Self {
+        self.priorities.push((name.into(), priority));
+        self
+    }
+
+    pub fn with_limits(mut self, limits: DirectoryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Hash up to `concurrency` files ahead of the one being sent, clamped to
+    // 1..=MAX_HASH_CONCURRENCY
+    pub fn with_hash_concurrency(mut self, concurrency: usize) -> Self {
+        self.hash_concurrency = concurrency.clamp(1, MAX_HASH_CONCURRENCY);
+        self
+    }
+
+    // Report overall progress on either side. The total starts as the sum of
+    // the manifest sizes and is corrected as each file's real size is known,
+    // so it only reaches 100% when the last file is done.
+    pub fn with_progress(mut self, progress: impl Fn(usize, u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    // The manifest `send` would announce, in a stable order
+    pub fn plan(&self) -> Result<Vec<FileMetadata>> {
+        let mut entries = Vec::new();
+        walk(&self.root, "", &mut entries)?;
+        for entry in &mut entries {
+            if let Some((_, priority)) = self.priorities.iter().rev().find(|(name, _)| *name == entry.name) {
+                entry.priority = *priority;
+            }
+        }
+        Ok(entries)
+    }
+
+    // The metadata each file in `order` (manifest indices of regular files)
+    // will be announced with, in that order, with the file's stamp from just
+    // before it was hashed; None for files that couldn't be hashed. Hashing
+    // runs on its own tasks, at most `hash_concurrency` files ahead of
+    // whatever is consuming the stream.
+    fn hash_ahead<'a>(
+        &'a self,
+        entries: &'a [FileMetadata],
+        order: Vec<usize>,
+    ) -> impl Stream<Item = (usize, Option<Hashed>)> + 'a {
+        let hashes = order.into_iter().map(move |index| {
+            let path = self.root.join(&entries[index].name);
+            let hashing = tokio::spawn(async move {
+                let stamp = file_stamp(&path)?;
+                let mut file = FileTransfer::with_progress_config(path, &ProgressConfig::hidden())?;
+                Ok::<_, DropError>((file.prepare_metadata().await?, stamp))
+            });
+            async move {
+                match hashing.await {
+                    Ok(Ok(hashed)) => (index, Some(hashed)),
+                    Ok(Err(e)) => {
+                        tracing::debug!(error = %e, "could not hash entry ahead of sending");
+                        (index, None)
+                    }
+                    Err(e) => {
+                        tracing::debug!(error = %e, "hashing task failed");
+                        (index, None)
+                    }
+                }
+            }
+        });
+        futures::stream::iter(hashes).buffered(self.hash_concurrency)
+    }
+
+    pub async fn send<T: Transport>(&self, protocol: &mut Protocol<T>) -> Result<()> {
+        protocol.handshake().await?;
+        let entries = self.plan()?;
+        protocol.send_command(&TransferCommand::Manifest(entries.clone())).await?;
+
+        let keep_alive = protocol.set_keep_alive(true);
+        let result = self.send_entries(protocol, &entries).await;
+        protocol.set_keep_alive(keep_alive);
+        result?;
+        protocol.finish_transfer().await
+    }
+
+    async fn send_entries<T: Transport>(&self, protocol: &mut Protocol<T>, entries: &[FileMetadata]) -> Result<()> {
+        let mut progress = self.progress.clone().map(|callback| Aggregate::new(callback, entries));
+        let hashes = self.hash_ahead(entries, send_order(entries));
+        tokio::pin!(hashes);
+        while let Some((index, hashed)) = hashes.next().await {
+            let entry = &entries[index];
+            let path = self.root.join(&entry.name);
+            let metadata = if !std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+                tracing::warn!(name = %entry.name, "entry vanished before sending, skipping");
+                None
+            } else {
+                let metadata = match hashed {
+                    Some((metadata, stamp)) if file_stamp(&path).is_ok_and(|now| now == stamp) => metadata,
+                    // Changed since it was hashed, or couldn't be hashed then
+                    _ => FileTransfer::with_progress_config(path.clone(), &ProgressConfig::hidden())?
+                        .prepare_metadata()
+                        .await?,
+                };
+                // The receiver rejects an announcement that disagrees with the manifest
+                if metadata.size == entry.size {
+                    Some(metadata)
+                } else {
+                    tracing::warn!(name = %entry.name, "entry changed size since the manifest, skipping");
+                    None
+                }
+            };
+            let Some(metadata) = metadata else {
+                protocol.send_command(&TransferCommand::Skip(index as u32)).await?;
+                if let Some(progress) = &mut progress {
+                    progress.finish(index, 0);
+                }
+                continue;
+            };
+            let mut file = FileTransfer::new(path).with_prepared_metadata(metadata);
+            let bar = file.progress_bar().clone();
+            let report = progress.as_mut().map(|p| move |done, size| p.update(index, done, size));
+            track_progress(&bar, report, protocol.send(&mut file)).await?;
+            if let Some(progress) = &mut progress {
+                progress.finish(index, file.get_metadata().map_or(0, |m| m.size));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn receive<T: Transport>(&self, protocol: &mut Protocol<T>) -> Result<()> {
+        protocol.handshake().await?;
+        let entries = match protocol.expect_command().await? {
+            TransferCommand::Manifest(entries) => entries,
+            other => {
+                return Err(DropError::Protocol(format!("expected Manifest, got {:?}", other)));
+            }
+        };
+        if let Err(e) = self.check_manifest(&entries) {
+            protocol.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+            return Err(e);
+        }
+
+        let keep_alive = protocol.set_keep_alive(true);
+        let result = self.receive_entries(protocol, &entries).await;
+        protocol.set_keep_alive(keep_alive);
+        result?;
+        protocol.finish_transfer().await
+    }
+
+    async fn receive_entries<T: Transport>(&self, protocol: &mut Protocol<T>, entries: &[FileMetadata]) -> Result<()> {
+        let mut progress = self.progress.clone().map(|callback| Aggregate::new(callback, entries));
+        let mut streamed = 0;
+        for index in send_order(entries) {
+            let entry = &entries[index];
+            let metadata = match protocol.expect_command().await? {
+                TransferCommand::StartTransfer(_) if streamed == self.limits.max_streamed_files => {
+                    let reason = format!("directory transfer is limited to {} files", self.limits.max_streamed_files);
+                    protocol.send_abort(AbortReason::Rejected, &reason).await?;
+                    return Err(DropError::Protocol(reason));
+                }
+                TransferCommand::StartTransfer(metadata) => {
+                    if let Err(e) = check_announced(entry, &metadata) {
+                        protocol.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+                        return Err(e);
+                    }
+                    streamed += 1;
+                    metadata
+                }
+                TransferCommand::Skip(skipped) if skipped as usize == index => {
+                    if let Some(progress) = &mut progress {
+                        progress.finish(index, 0);
+                    }
+                    continue;
+                }
+                other => {
+                    return Err(DropError::Protocol(format!("expected {}, got {:?}", entry.name, other)));
+                }
+            };
+            let mut file = FileTransfer::new(self.prepare_entry(&entry.name)?);
+            let bar = file.progress_bar().clone();
+            let report = progress.as_mut().map(|p| move |done, size| p.update(index, done, size));
+            track_progress(&bar, report, protocol.receive_announced(&mut file, metadata)).await?;
+            if let Some(progress) = &mut progress {
+                progress.finish(index, file.get_metadata().map_or(0, |m| m.size));
+            }
+        }
+        // Links last, so no file above is written through one
+        for entry in entries {
+            if let Some(target) = &entry.symlink_target {
+                let path = self.prepare_entry(&entry.name)?;
+                self.check_target_on_disk(&entry.name, target)?;
+                make_symlink(target, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reject the manifest before anything is written
+    fn check_manifest(&self, entries: &[FileMetadata]) -> Result<()> {
+        let limits = &self.limits;
+        if entries.len() > limits.max_files {
+            return Err(DropError::Protocol(format!(
+                "directory has {} entries, limit is {}",
+                entries.len(),
+                limits.max_files
+            )));
+        }
+        let total = entries.iter().try_fold(0u64, |total, e| total.checked_add(e.size));
+        if total.is_none_or(|total| total > limits.max_total_size) {
+            return Err(DropError::Protocol(format!(
+                "directory is larger than the {} byte limit",
+                limits.max_total_size
+            )));
+        }
+        let links: HashSet<&str> =
+            entries.iter().filter(|e| e.symlink_target.is_some()).map(|e| e.name.as_str()).collect();
+        for entry in entries {
+            let depth = relative_path(&entry.name)?.components().count();
+            if depth > limits.max_depth {
+                return Err(DropError::Protocol(format!(
+                    "{} is {} levels deep, limit is {}",
+                    entry.name, depth, limits.max_depth
+                )));
+            }
+            if let Some(target) = &entry.symlink_target {
+                check_link_target(&entry.name, target, |p| p.to_str().is_some_and(|p| links.contains(p)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Destination for `name`, with its parent directories created. Refuses to
+    // place anything beneath an existing symlink under `root`.
+    fn prepare_entry(&self, name: &str) -> Result<PathBuf> {
+        let relative = relative_path(name)?;
+        let mut path = self.root.clone();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            path.push(component);
+            match std::fs::symlink_metadata(&path) {
+                Ok(m) if m.file_type().is_symlink() => {
+                    return Err(DropError::Protocol(format!("{} is beneath a symlink", name)));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&path)?,
+                Err(e) => return Err(DropError::Io(e)),
+            }
+        }
+        Ok(self.root.join(relative))
+    }
+
+    // The manifest check only knows about its own links; one already under
+    // `root` could redirect the target just the same
+    fn check_target_on_disk(&self, name: &str, target: &str) -> Result<()> {
+        check_link_target(name, target, |path| {
+            std::fs::symlink_metadata(self.root.join(path)).is_ok_and(|m| m.file_type().is_symlink())
+        })
+    }
+}
+
+// Sums per-file progress into one figure for `ProgressFn`
+struct Aggregate {
+    callback: ProgressFn,
+    // Per manifest entry: planned size, replaced by the real one once known
+    sizes: Vec<u64>,
+    finished_bytes: u64,
+    last: Option<(u64, u64)>,
+}
+
+impl Aggregate {
+    fn new(callback: ProgressFn, entries: &[FileMetadata]) -> Self {
+        Self {
+            callback,
+            sizes: entries.iter().map(|e| if e.symlink_target.is_some() { 0 } else { e.size }).collect(),
+            finished_bytes: 0,
+            last: None,
+        }
+    }
+
+    // Progress within the file at `index`; `size` once its metadata is known
+    fn update(&mut self, index: usize, done: u64, size: Option<u64>) {
+        if let Some(size) = size {
+            self.sizes[index] = size;
+        }
+        let total: u64 = self.sizes.iter().sum();
+        // Never claim completion before `finish` on the last file
+        let done = (self.finished_bytes + done).min(total.saturating_sub(1));
+        self.emit(index, done, total);
+    }
+
+    fn finish(&mut self, index: usize, size: u64) {
+        self.sizes[index] = size;
+        self.finished_bytes += size;
+        let total: u64 = self.sizes.iter().sum();
+        self.emit(index, self.finished_bytes, total);
+    }
+
+    fn emit(&mut self, index: usize, done: u64, total: u64) {
+        if self.last != Some((done, total)) {
+            self.last = Some((done, total));
+            (self.callback)(index, done, total);
+        }
+    }
+}
+
+// Manifest indices of the regular files in the order they're sent: highest
+// priority first, manifest order among equals. So a long run of high-priority
+// files can't starve the rest, a file that has been overtaken MAX_OVERTAKES
+// times goes next whatever its priority.
+pub fn send_order(entries: &[FileMetadata]) -> Vec<usize> {
+    // (manifest index, priority, times overtaken), in manifest order
+    let mut waiting: Vec<(usize, u32, usize)> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.symlink_target.is_none())
+        .map(|(index, e)| (index, e.priority, 0))
+        .collect();
+    let mut order = Vec::with_capacity(waiting.len());
+    while !waiting.is_empty() {
+        let next = waiting.iter().position(|w| w.2 >= MAX_OVERTAKES).unwrap_or_else(|| {
+            // First of the highest priority
+            let top = waiting.iter().map(|w| w.1).max().unwrap_or_default();
+            waiting.iter().position(|w| w.1 == top).unwrap_or_default()
+        });
+        let (index, _, _) = waiting.remove(next);
+        // Everything that was ahead of it in the manifest got overtaken
+        for w in &mut waiting[..next] {
+            w.2 += 1;
+        }
+        order.push(index);
+    }
+    order
+}
+
+fn walk(dir: &Path, prefix: &str, entries: &mut Vec<FileMetadata>) -> Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    children.sort_by_key(|c| c.file_name());
+    for child in children {
+        let file_name = child.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            tracing::warn!(path = %child.path().display(), "skipping entry with non-UTF-8 name");
+            continue;
+        };
+        let name = if prefix.is_empty() { file_name.to_string() } else { format!("{}/{}", prefix, file_name) };
+        let metadata = std::fs::symlink_metadata(child.path())?;
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(child.path())?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| DropError::Protocol(format!("symlink {} has a non-UTF-8 target", name)))?;
+            entries.push(FileMetadata {
+                name,
+                symlink_target: Some(target.to_string()),
+                ..FileMetadata::default()
+            });
+        } else if file_type.is_dir() {
+            walk(&child.path(), &name, entries)?;
+        } else if file_type.is_file() {
+            let (mode, mtime) = file_attributes(&metadata);
+            entries.push(FileMetadata {
+                name,
+                size: metadata.len(),
+                mode,
+                mtime,
+                ..FileMetadata::default()
+            });
+        }
+    }
+    Ok(())
+}
+
+// A file has to arrive as the manifest described it, or the limits checked
+// against the manifest would mean nothing
+fn check_announced(entry: &FileMetadata, announced: &FileMetadata) -> Result<()> {
+    let name = Path::new(&entry.name).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if announced.name != name || announced.size != entry.size {
+        return Err(DropError::Protocol(format!(
+            "{} ({} bytes in the manifest) was announced as {} ({} bytes)",
+            entry.name, entry.size, announced.name, announced.size
+        )));
+    }
+    Ok(())
+}
+
+// A manifest name as a path that stays below the root
+fn relative_path(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    let valid = !name.is_empty()
+        && !name.contains('\\')
+        && path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        return Err(DropError::Protocol(format!("invalid entry name {:?}", name)));
+    }
+    Ok(path.to_path_buf())
+}
+
+// `target` is resolved from the link's directory; walking it lexically must
+// never climb above the root. Nor may it pass through a path that `is_link`
+// (relative to the root): the walk can't know where that one leads, so
+// `x/up -> ..` plus `esc -> x/up/..` would escape.
+fn check_link_target(name: &str, target: &str, is_link: impl Fn(&Path) -> bool) -> Result<()> {
+    let escapes = || DropError::Protocol(format!("symlink {} points outside the directory: {}", name, target));
+    let mut resolved: PathBuf = Path::new(name).parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut components = Path::new(target).components().peekable();
+    while let Some(component) = components.next() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return Err(escapes());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+        if components.peek().is_some() && is_link(&resolved) {
+            return Err(DropError::Protocol(format!(
+                "symlink {} points through another symlink: {}",
+                name, target
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, path: &Path) -> Result<()> {
+    Err(DropError::Protocol(format!("cannot create symlink {} on this platform", path.display())))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::sync::Mutex;
+    use crate::protocol::loopback;
+
+    #[tokio::test]
+    async fn test_internal_symlinks_are_recreated() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("a.txt"), b"top level").unwrap();
+        std::fs::write(src.path().join("sub/b.txt"), vec![7u8; 200_000]).unwrap();
+        symlink("sub/b.txt", src.path().join("link")).unwrap();
+        symlink("../a.txt", src.path().join("sub/up")).unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        let (s, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        s.unwrap();
+        r.unwrap();
+
+        let link = dst.path().join("link");
+        assert!(std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("sub/b.txt"));
+        assert_eq!(std::fs::read(&link).unwrap(), vec![7u8; 200_000]);
+        assert_eq!(std::fs::read_link(dst.path().join("sub/up")).unwrap(), Path::new("../a.txt"));
+        assert_eq!(std::fs::read(dst.path().join("sub/up")).unwrap(), b"top level");
+        assert!(receiver.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_file_is_sent_first() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("data")).unwrap();
+        std::fs::write(src.path().join("data/a.bin"), vec![1u8; 1_500_000]).unwrap();
+        std::fs::write(src.path().join("data/b.bin"), vec![2u8; 10]).unwrap();
+        std::fs::write(src.path().join("manifest.json"), b"{}").unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf()).with_priority("manifest.json", 10);
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        let (s, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        s.unwrap();
+        r.unwrap();
+
+        let sent: Vec<&str> = sender.history().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(sent, vec!["manifest.json", "a.bin", "b.bin"]);
+        assert_eq!(std::fs::read(dst.path().join("manifest.json")).unwrap(), b"{}");
+        assert_eq!(std::fs::read(dst.path().join("data/a.bin")).unwrap(), vec![1u8; 1_500_000]);
+
+        // One low-priority file ahead of many high-priority ones still gets a turn
+        let mut entries: Vec<FileMetadata> = (0..20)
+            .map(|i| FileMetadata { name: format!("f{}", i), priority: 5, ..FileMetadata::default() })
+            .collect();
+        entries[0].priority = 0;
+        let order = send_order(&entries);
+        assert_eq!(order.iter().position(|&i| i == 0), Some(MAX_OVERTAKES));
+        assert_eq!(order.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_progress_reaches_total_once() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let sizes = [1_500_000usize, 10, 2_300_000];
+        for (i, size) in sizes.iter().enumerate() {
+            std::fs::write(src.path().join(format!("f{}.bin", i)), vec![i as u8 + 1; *size]).unwrap();
+        }
+        let total: u64 = sizes.iter().map(|&s| s as u64).sum();
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorder = reports.clone();
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf())
+            .with_progress(move |index, done, total| recorder.lock().unwrap().push((index, done, total)));
+        let (s, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        s.unwrap();
+        r.unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.iter().filter(|&&(_, done, t)| done == t).count(), 1);
+        assert_eq!(*reports.last().unwrap(), (2, total, total));
+        assert!(reports.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        // A file removed after planning drops out of the total
+        let entries = DirectoryTransfer::new(src.path().to_path_buf()).plan().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let mut progress = Aggregate::new(Arc::new(move |_, done, total| recorder.lock().unwrap().push((done, total))), &entries);
+        progress.finish(0, 1_500_000);
+        progress.finish(1, 0);
+        progress.update(2, 2_300_000, Some(2_300_000));
+        progress.finish(2, 2_300_000);
+        let seen = seen.lock().unwrap();
+        assert_eq!(*seen, vec![(1_500_000, total), (1_500_000, total - 10), (3_799_999, 3_800_000), (3_800_000, 3_800_000)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_hashing_matches_serial() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("nested")).unwrap();
+        for i in 0..50 {
+            let dir = if i % 5 == 0 { src.path().join("nested") } else { src.path().to_path_buf() };
+            std::fs::write(dir.join(format!("f{:02}.txt", i)), format!("file {} ", i).repeat(i * 37 + 1)).unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("f01.txt", src.path().join("link")).unwrap();
+
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf()).with_hash_concurrency(8);
+        let entries = outgoing.plan().unwrap();
+        let order = send_order(&entries);
+        let hashed: Vec<_> = outgoing.hash_ahead(&entries, order.clone()).collect().await;
+        assert_eq!(hashed.iter().map(|h| h.0).collect::<Vec<_>>(), order);
+        for (index, hashed) in &hashed {
+            let entry = &entries[*index];
+            let serial = FileTransfer::new(src.path().join(&entry.name)).prepare_metadata().await.unwrap();
+            let json = |m: &FileMetadata| serde_json::to_value(m).unwrap();
+            assert_eq!(hashed.as_ref().map(|h| json(&h.0)), Some(json(&serial)), "{}", entry.name);
+        }
+        assert_eq!(hashed.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_file_resized_after_the_manifest_is_skipped() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::write(src.path().join("a.txt"), b"unchanged").unwrap();
+        std::fs::write(src.path().join("b.txt"), b"short").unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        let entries = outgoing.plan().unwrap();
+        std::fs::write(src.path().join("b.txt"), b"grown since the manifest was planned").unwrap();
+        let send = async {
+            sender.handshake().await?;
+            sender.send_command(&TransferCommand::Manifest(entries.clone())).await?;
+            sender.set_keep_alive(true);
+            outgoing.send_entries(&mut sender, &entries).await?;
+            sender.set_keep_alive(false);
+            sender.finish_transfer().await
+        };
+        let (s, r) = tokio::join!(send, incoming.receive(&mut receiver));
+        s.unwrap();
+        r.unwrap();
+        assert_eq!(std::fs::read(dst.path().join("a.txt")).unwrap(), b"unchanged");
+        assert!(!dst.path().join("b.txt").exists());
+    }
+
+    // Run a directory transfer, returning the receiver's error
+    async fn receive_error(src: &Path, dst: &Path, limits: DirectoryLimits) -> String {
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.to_path_buf()).with_limits(limits);
+        let (s, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        assert!(s.is_err());
+        r.unwrap_err().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_manifest_limits_are_enforced() {
+        let limits = DirectoryLimits { max_depth: 3, max_files: 5, ..DirectoryLimits::default() };
+
+        let deep = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(deep.path().join("a/b/c")).unwrap();
+        std::fs::write(deep.path().join("a/b/ok.txt"), b"ok").unwrap();
+        std::fs::write(deep.path().join("a/b/c/too_deep.txt"), b"no").unwrap();
+        let err = receive_error(deep.path(), dst.path(), limits).await;
+        assert!(err.contains("levels deep"), "{}", err);
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 0);
+
+        let wide = tempfile::tempdir().unwrap();
+        for i in 0..6 {
+            std::fs::write(wide.path().join(format!("{}.txt", i)), b"x").unwrap();
+        }
+        let err = receive_error(wide.path(), dst.path(), limits).await;
+        assert!(err.contains("6 entries"), "{}", err);
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 0);
+
+        let err = receive_error(wide.path(), dst.path(), DirectoryLimits { max_total_size: 5, ..DirectoryLimits::default() }).await;
+        assert!(err.contains("byte limit"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_file_limit_rejects_extra_files() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        for i in 0..3 {
+            std::fs::write(src.path().join(format!("{}.txt", i)), b"x").unwrap();
+        }
+        let limits = DirectoryLimits { max_streamed_files: 2, ..DirectoryLimits::default() };
+        let err = receive_error(src.path(), dst.path(), limits).await;
+        assert!(err.contains("limited to 2 files"), "{}", err);
+        // Files within the limit were received before the third was refused
+        assert!(std::fs::read(dst.path().join("1.txt")).is_ok());
+        assert!(!dst.path().join("2.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_escaping_symlink_is_rejected() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("sub/ok.txt"), b"fine").unwrap();
+        symlink("../../etc/passwd", src.path().join("sub/evil")).unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        let (s, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        assert!(s.is_err());
+        assert!(r.unwrap_err().to_string().contains("outside the directory"));
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 0);
+
+        assert!(check_link_target("a/b", "../c", |_| false).is_ok());
+        assert!(check_link_target("a", "/etc", |_| false).is_err());
+        assert!(check_link_target("esc", "x/up/..", |p| p == Path::new("x/up")).is_err());
+        // Ending on a link is fine, that link's own target is checked
+        assert!(check_link_target("a", "x/up", |p| p == Path::new("x/up")).is_ok());
+        assert!(relative_path("../x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_announcement_must_match_the_manifest() {
+        let dst = tempfile::tempdir().unwrap();
+        let listed = FileMetadata { name: "docs/small.txt".to_string(), size: 5, ..FileMetadata::default() };
+        for announced in [
+            FileMetadata { name: "small.txt".to_string(), size: 50_000_000, ..FileMetadata::default() },
+            FileMetadata { name: "other.txt".to_string(), size: 5, ..FileMetadata::default() },
+        ] {
+            let (a, b) = loopback();
+            let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+            let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+            let lying = async {
+                sender.handshake().await.unwrap();
+                sender.send_command(&TransferCommand::Manifest(vec![listed.clone()])).await.unwrap();
+                sender.send_command(&TransferCommand::StartTransfer(announced)).await.unwrap();
+            };
+            let ((), r) = tokio::join!(lying, incoming.receive(&mut receiver));
+            assert!(r.unwrap_err().to_string().contains("was announced as"));
+        }
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_symlink_chain_is_rejected() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("x")).unwrap();
+        // Each is harmless read lexically, together `esc` is the parent of the root
+        symlink("..", src.path().join("x/up")).unwrap();
+        symlink("x/up/..", src.path().join("esc")).unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let outgoing = DirectoryTransfer::new(src.path().to_path_buf());
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        // With only links to send, the sender may be done before the abort
+        let (_, r) = tokio::join!(outgoing.send(&mut sender), incoming.receive(&mut receiver));
+        assert!(r.unwrap_err().to_string().contains("through another symlink"));
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_symlink_through_existing_link_is_rejected() {
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dst.path().join("x")).unwrap();
+        symlink("..", dst.path().join("x/up")).unwrap();
+        let incoming = DirectoryTransfer::new(dst.path().to_path_buf());
+        assert!(incoming.check_target_on_disk("esc", "x/up/..").is_err());
+        assert!(incoming.check_target_on_disk("esc", "x/other/..").is_ok());
+    }
+}
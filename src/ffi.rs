@@ -0,0 +1,266 @@
+// C ABI for embedding the transfer core (iOS/Android). The embedder owns the
+// actual channel to the peer, e.g. a native WebRTC data channel: outgoing
+// frames are handed to its `DropSendFn`, and incoming frames are fed back in
+// with `drop_connection_push`. Transfers block the calling thread while they
+// run on an internal tokio runtime.
+//
+// Safety contract for every function here: handles must come from
+// `drop_connection_new` and not be used after `drop_connection_free`; paths
+// are NUL-terminated UTF-8; callbacks and their user data must be safe to call
+// from any thread. Panics are caught and reported as `DROP_ERR_PANIC`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_void, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use async_trait::async_trait;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use crate::protocol::{Protocol, Transport};
+use crate::transfer::{track_progress, FileTransfer, ProgressConfig};
+use crate::{DropError, Result};
+
+pub const DROP_OK: i32 = 0;
+pub const DROP_ERR_INVALID_ARGUMENT: i32 = -1;
+pub const DROP_ERR_TRANSFER: i32 = -2;
+pub const DROP_ERR_CLOSED: i32 = -3;
+pub const DROP_ERR_PANIC: i32 = -4;
+
+// Returns 0 once the frame is queued for the peer, anything else on failure
+pub type DropSendFn = extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize) -> i32;
+// Called periodically during a transfer with bytes done and total bytes
+pub type DropProgressFn = extern "C" fn(user_data: *mut c_void, done: u64, total: u64);
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("failed to start drop runtime"))
+}
+
+// User data pointer the embedder promised is usable from any thread
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    // A method rather than `.0` so closures capture the whole Send wrapper
+    fn ptr(self) -> *mut c_void {
+        self.0
+    }
+}
+
+struct FfiTransport {
+    send: DropSendFn,
+    user_data: UserData,
+    inbox: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+#[async_trait]
+impl Transport for FfiTransport {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        match (self.send)(self.user_data.ptr(), message.as_ptr(), message.len()) {
+            0 => Ok(()),
+            code => Err(DropError::Protocol(format!("embedder send failed with {}", code))),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.inbox.recv().await)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inbox.close();
+        Ok(())
+    }
+}
+
+// Opaque to C
+pub struct DropConnection {
+    incoming: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    protocol: Mutex<Protocol<FfiTransport>>,
+}
+
+fn guard(body: impl FnOnce() -> i32) -> i32 {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or(DROP_ERR_PANIC)
+}
+
+unsafe fn path_arg(path: *const c_char) -> Option<PathBuf> {
+    if path.is_null() {
+        return None;
+    }
+    CStr::from_ptr(path).to_str().ok().map(PathBuf::from)
+}
+
+// Returns null if `send` is missing
+#[no_mangle]
+pub extern "C" fn drop_connection_new(send: Option<DropSendFn>, user_data: *mut c_void) -> *mut DropConnection {
+    let result = catch_unwind(|| {
+        let send = send?;
+        let (tx, inbox) = mpsc::unbounded_channel();
+        let transport = FfiTransport { send, user_data: UserData(user_data), inbox };
+        // The embedder decides when the underlying channel goes away
+        let protocol = Protocol::new(transport).with_keep_alive(true);
+        Some(Box::into_raw(Box::new(DropConnection {
+            incoming: Mutex::new(Some(tx)),
+            protocol: Mutex::new(protocol),
+        })))
+    });
+    result.ok().flatten().unwrap_or(std::ptr::null_mut())
+}
+
+// Hand a frame received from the peer to the connection
+#[no_mangle]
+pub unsafe extern "C" fn drop_connection_push(conn: *mut DropConnection, data: *const u8, len: usize) -> i32 {
+    guard(|| {
+        let Some(conn) = conn.as_ref() else { return DROP_ERR_INVALID_ARGUMENT };
+        if data.is_null() && len > 0 {
+            return DROP_ERR_INVALID_ARGUMENT;
+        }
+        let frame = if len == 0 { Vec::new() } else { std::slice::from_raw_parts(data, len).to_vec() };
+        match conn.incoming.lock().unwrap().as_ref().map(|tx| tx.send(frame)) {
+            Some(Ok(())) => DROP_OK,
+            _ => DROP_ERR_CLOSED,
+        }
+    })
+}
+
+// The peer's channel closed; a transfer waiting on it fails
+#[no_mangle]
+pub unsafe extern "C" fn drop_connection_close(conn: *mut DropConnection) -> i32 {
+    guard(|| {
+        let Some(conn) = conn.as_ref() else { return DROP_ERR_INVALID_ARGUMENT };
+        conn.incoming.lock().unwrap().take();
+        DROP_OK
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drop_connection_free(conn: *mut DropConnection) {
+    if !conn.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(conn))));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drop_send(
+    conn: *mut DropConnection,
+    path: *const c_char,
+    progress: Option<DropProgressFn>,
+    progress_user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        let (Some(conn), Some(path)) = (conn.as_ref(), path_arg(path)) else {
+            return DROP_ERR_INVALID_ARGUMENT;
+        };
+        run_transfer(conn, path, progress, UserData(progress_user_data), true)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drop_receive(
+    conn: *mut DropConnection,
+    path: *const c_char,
+    progress: Option<DropProgressFn>,
+    progress_user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        let (Some(conn), Some(path)) = (conn.as_ref(), path_arg(path)) else {
+            return DROP_ERR_INVALID_ARGUMENT;
+        };
+        run_transfer(conn, path, progress, UserData(progress_user_data), false)
+    })
+}
+
+fn run_transfer(
+    conn: &DropConnection,
+    path: PathBuf,
+    progress: Option<DropProgressFn>,
+    user_data: UserData,
+    sending: bool,
+) -> i32 {
+    let Ok(mut file) = FileTransfer::with_progress_config(path, &ProgressConfig::hidden()) else {
+        return DROP_ERR_TRANSFER;
+    };
+    let mut protocol = conn.protocol.lock().unwrap();
+    let bar = file.progress_bar().clone();
+    let result = runtime().block_on(async {
+        let report = progress.map(|callback| move |done, total: Option<u64>| callback(user_data.ptr(), done, total.unwrap_or(0)));
+        let transfer = async {
+            if sending {
+                protocol.send(&mut file).await
+            } else {
+                protocol.receive(&mut file).await
+            }
+        };
+        track_progress(&bar, report, transfer).await
+    });
+    if let Some(callback) = progress {
+        callback(user_data.ptr(), bar.position(), bar.length().unwrap_or(0));
+    }
+    match result {
+        Ok(()) => DROP_OK,
+        Err(e) => {
+            tracing::warn!(error = %e, "ffi transfer failed");
+            DROP_ERR_TRANSFER
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+    // Forwards frames into whichever connection `user_data` points at
+    extern "C" fn forward(user_data: *mut c_void, data: *const u8, len: usize) -> i32 {
+        let target = unsafe { &*(user_data as *const AtomicPtr<DropConnection>) };
+        unsafe { drop_connection_push(target.load(Ordering::SeqCst), data, len) }
+    }
+
+    extern "C" fn record_progress(user_data: *mut c_void, done: u64, _total: u64) {
+        let last = unsafe { &*(user_data as *const AtomicU64) };
+        last.store(done, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_send_and_receive_through_c_abi() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("in.bin");
+        let dst = dir.path().join("out.bin");
+        let data: Vec<u8> = (0..1_500_000u32).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        // Leaked so the callbacks can reach them from the runtime threads
+        let to_b: &'static AtomicPtr<DropConnection> = Box::leak(Box::new(AtomicPtr::new(std::ptr::null_mut())));
+        let to_a: &'static AtomicPtr<DropConnection> = Box::leak(Box::new(AtomicPtr::new(std::ptr::null_mut())));
+        let a = drop_connection_new(Some(forward), to_b as *const _ as *mut c_void);
+        let b = drop_connection_new(Some(forward), to_a as *const _ as *mut c_void);
+        to_b.store(b, Ordering::SeqCst);
+        to_a.store(a, Ordering::SeqCst);
+
+        let (a_addr, b_addr) = (a as usize, b as usize);
+        let src = CString::new(src.to_str().unwrap()).unwrap();
+        let dst_c = CString::new(dst.to_str().unwrap()).unwrap();
+        let sender = std::thread::spawn(move || unsafe {
+            drop_send(a_addr as *mut DropConnection, src.as_ptr(), None, std::ptr::null_mut())
+        });
+        let received: &'static AtomicU64 = Box::leak(Box::new(AtomicU64::new(0)));
+        let code = unsafe {
+            drop_receive(b_addr as *mut DropConnection, dst_c.as_ptr(), Some(record_progress), received as *const _ as *mut c_void)
+        };
+        assert_eq!(code, DROP_OK);
+        assert_eq!(sender.join().unwrap(), DROP_OK);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert_eq!(received.load(Ordering::SeqCst), data.len() as u64);
+
+        unsafe {
+            assert_eq!(drop_send(std::ptr::null_mut(), std::ptr::null(), None, std::ptr::null_mut()), DROP_ERR_INVALID_ARGUMENT);
+            drop_connection_free(a);
+            drop_connection_free(b);
+        }
+        assert!(drop_connection_new(None, std::ptr::null_mut()).is_null());
+    }
+}
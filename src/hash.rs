@@ -0,0 +1,224 @@
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+
+// SHA-256 implementation used for chunk and whole-file hashes. Every backend
+// produces bit-identical digests; they differ only in speed. `Ring` uses
+// ring's assembly (SHA-NI / ARMv8 crypto extensions) and needs the `hw-sha` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashBackend {
+    #[default]
+    Software,
+    #[cfg(feature = "hw-sha")]
+    Ring,
+}
+
+impl HashBackend {
+    pub fn available() -> Vec<HashBackend> {
+        vec![
+            HashBackend::Software,
+            #[cfg(feature = "hw-sha")]
+            HashBackend::Ring,
+        ]
+    }
+
+    // Time each available backend over `sample_len` bytes and return the fastest
+    pub fn select_fastest(sample_len: usize) -> HashBackend {
+        let sample = vec![0xa5u8; sample_len];
+        Self::available()
+            .into_iter()
+            .map(|backend| (backend.time(&sample), backend))
+            .min_by_key(|(elapsed, _)| *elapsed)
+            .map(|(_, backend)| backend)
+            .unwrap_or_default()
+    }
+
+    pub fn time(self, data: &[u8]) -> Duration {
+        let start = Instant::now();
+        let _ = self.digest_hex(data);
+        start.elapsed()
+    }
+
+    pub fn hasher(self) -> Sha256Hasher {
+        match self {
+            HashBackend::Software => Sha256Hasher::Software(Sha256::new()),
+            #[cfg(feature = "hw-sha")]
+            HashBackend::Ring => Sha256Hasher::Ring(ring::digest::Context::new(&ring::digest::SHA256)),
+        }
+    }
+
+    pub fn digest_hex(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize_hex()
+    }
+}
+
+// Incremental SHA-256 over whichever backend was selected
+pub enum Sha256Hasher {
+    Software(Sha256),
+    #[cfg(feature = "hw-sha")]
+    Ring(ring::digest::Context),
+}
+
+impl Sha256Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Sha256Hasher::Software(hasher) => hasher.update(data),
+            #[cfg(feature = "hw-sha")]
+            Sha256Hasher::Ring(context) => context.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Sha256Hasher::Software(hasher) => format!("{:x}", hasher.finalize()),
+            #[cfg(feature = "hw-sha")]
+            Sha256Hasher::Ring(context) => context
+                .finish()
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    HashBackend::Software.digest_hex(data)
+}
+
+type Node = [u8; 32];
+
+fn sha256_parts(parts: &[&[u8]]) -> Node {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Binary Merkle tree over per-chunk hashes. Leaves are `H(0x00 || chunk_hash)`,
+// inner nodes `H(0x01 || left || right)`; an unpaired node is carried up
+// unchanged. The root of zero chunks is the hash of the empty string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    // levels[0] are the leaves, the last level holds the root
+    levels: Vec<Vec<Node>>,
+}
+
+impl MerkleTree {
+    pub fn build<S: AsRef<str>>(chunk_hashes: &[S]) -> Self {
+        let leaves: Vec<Node> = chunk_hashes
+            .iter()
+            .map(|hash| sha256_parts(&[&[0x00], hash.as_ref().as_bytes()]))
+            .collect();
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => sha256_parts(&[&[0x01], left, right]),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    pub fn root_hex(&self) -> String {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => root.iter().map(|b| format!("{:02x}", b)).collect(),
+            None => sha256_hex(&[]),
+        }
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    // Leaf indices that differ from `other`, found by descending only into
+    // subtrees whose hashes disagree. Trees of different shape differ everywhere.
+    pub fn mismatched_leaves(&self, other: &MerkleTree) -> Vec<u32> {
+        if self.leaf_count() != other.leaf_count() {
+            return (0..self.leaf_count().max(other.leaf_count()) as u32).collect();
+        }
+        let mut mismatched = Vec::new();
+        if self.leaf_count() > 0 {
+            self.descend(other, self.levels.len() - 1, 0, &mut mismatched);
+        }
+        mismatched
+    }
+
+    fn descend(&self, other: &MerkleTree, level: usize, index: usize, out: &mut Vec<u32>) {
+        if self.levels[level][index] == other.levels[level][index] {
+            return;
+        }
+        if level == 0 {
+            out.push(index as u32);
+            return;
+        }
+        for child in [index * 2, index * 2 + 1] {
+            if child < self.levels[level - 1].len() {
+                self.descend(other, level - 1, child, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_produce_identical_digests() {
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i * 31 % 256) as u8).collect();
+        let expected = HashBackend::Software.digest_hex(&data);
+        for backend in HashBackend::available() {
+            assert_eq!(backend.digest_hex(&data), expected, "{:?}", backend);
+            // Incremental updates must match one-shot hashing
+            let mut hasher = backend.hasher();
+            for piece in data.chunks(1000) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize_hex(), expected);
+        }
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_merkle_root_is_stable_and_locates_modified_chunk() {
+        let hashes: Vec<String> = (0..7).map(|i| sha256_hex(&[i as u8])).collect();
+        let tree = MerkleTree::build(&hashes);
+        assert_eq!(tree.root_hex(), MerkleTree::build(&hashes.clone()).root_hex());
+        assert_eq!(tree.root_hex().len(), 64);
+
+        let mut modified = hashes.clone();
+        modified[5] = sha256_hex(b"tampered");
+        let other = MerkleTree::build(&modified);
+        assert_ne!(tree.root_hex(), other.root_hex());
+        assert_eq!(tree.mismatched_leaves(&other), vec![5]);
+
+        let empty: [&str; 0] = [];
+        assert_eq!(MerkleTree::build(&empty).root_hex(), sha256_hex(&[]));
+    }
+
+    // Rough benchmark: `cargo test --release --features hw-sha -- --ignored bench_hash_backends --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_hash_backends() {
+        let sample = vec![0x5au8; 64 * 1024 * 1024];
+        for backend in HashBackend::available() {
+            let elapsed = backend.time(&sample);
+            let mbps = sample.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+            println!("{:?}: {:?} ({:.0} MiB/s)", backend, elapsed, mbps);
+        }
+        println!("fastest: {:?}", HashBackend::select_fastest(8 * 1024 * 1024));
+    }
+}
@@ -0,0 +1,112 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use crate::hash::sha256_hex;
+use crate::{DropError, Result};
+
+// Domain separator for handshake signatures
+const HANDSHAKE_CONTEXT: &[u8] = b"drop-hello-identity-v1";
+
+// The public half of a peer's identity, sent in `Hello`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    // Ed25519 verifying key
+    pub public_key: [u8; 32],
+    pub display_name: String,
+}
+
+impl PeerIdentity {
+    // Short, human-comparable digest of the public key, e.g. "3f2a 9c01 ..."
+    pub fn fingerprint(&self) -> String {
+        let digest = sha256_hex(&self.public_key);
+        digest.as_bytes()[..32]
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Check `signature` proves the holder of this key answered `nonce`
+    pub fn verify_handshake(&self, nonce: &[u8], signature: &[u8]) -> Result<()> {
+        let key = VerifyingKey::from_bytes(&self.public_key)
+            .map_err(|e| DropError::Crypto(format!("invalid peer key: {}", e)))?;
+        let signature = Signature::from_slice(signature)
+            .map_err(|e| DropError::Crypto(format!("invalid identity signature: {}", e)))?;
+        key.verify(&handshake_message(self, nonce), &signature)
+            .map_err(|_| DropError::Crypto("identity signature does not verify".to_string()))
+    }
+}
+
+// Our own identity, including the signing key
+pub struct LocalIdentity {
+    signing_key: SigningKey,
+    display_name: String,
+}
+
+impl LocalIdentity {
+    pub fn generate(display_name: impl Into<String>) -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+            display_name: display_name.into(),
+        }
+    }
+
+    // Restore a persisted identity so peers see the same key across runs
+    pub fn from_secret_key(secret: &[u8; 32], display_name: impl Into<String>) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(secret),
+            display_name: display_name.into(),
+        }
+    }
+
+    pub fn secret_key(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn public(&self) -> PeerIdentity {
+        PeerIdentity {
+            public_key: self.signing_key.verifying_key().to_bytes(),
+            display_name: self.display_name.clone(),
+        }
+    }
+
+    // Sign the peer's handshake nonce together with our identity, so the
+    // signature can't be replayed on another connection or another name
+    pub fn sign_handshake(&self, nonce: &[u8]) -> Vec<u8> {
+        let message = handshake_message(&self.public(), nonce);
+        self.signing_key.sign(&message).to_bytes().to_vec()
+    }
+}
+
+fn handshake_message(identity: &PeerIdentity, nonce: &[u8]) -> Vec<u8> {
+    let mut message = HANDSHAKE_CONTEXT.to_vec();
+    message.extend_from_slice(nonce);
+    message.extend_from_slice(&identity.public_key);
+    message.extend_from_slice(identity.display_name.as_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_signature_binds_key_and_nonce() {
+        let alice = LocalIdentity::generate("alice");
+        let mallory = LocalIdentity::generate("mallory");
+        let nonce = [9u8; 32];
+        let signature = alice.sign_handshake(&nonce);
+        alice.public().verify_handshake(&nonce, &signature).unwrap();
+
+        // Replayed on another connection, or claimed by another key or name
+        assert!(alice.public().verify_handshake(&[1u8; 32], &signature).is_err());
+        assert!(alice.public().verify_handshake(&nonce, &mallory.sign_handshake(&nonce)).is_err());
+        let renamed = PeerIdentity { display_name: "bob".to_string(), ..alice.public() };
+        assert!(renamed.verify_handshake(&nonce, &signature).is_err());
+
+        let restored = LocalIdentity::from_secret_key(&alice.secret_key(), "alice");
+        assert_eq!(restored.public(), alice.public());
+        assert_eq!(alice.public().fingerprint().len(), 39);
+        assert_ne!(alice.public().fingerprint(), mallory.public().fingerprint());
+    }
+}
@@ -4,16 +4,48 @@ pub mod ble;
 pub mod webrtc;
 pub mod crypto;
 pub mod error;
+pub mod retry;
+pub mod config;
+pub mod clock;
+pub mod hash;
+pub mod manager;
+pub mod compression;
+pub mod source;
+pub mod identity;
+pub mod receiver;
+pub mod relay;
+pub mod ws;
+pub mod signaling;
+pub mod directory;
+pub mod delta;
+pub mod metadata_cache;
+pub mod nat;
+pub mod metrics;
+pub mod webhook;
+pub mod spool;
+pub mod broadcast;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "stun-server")]
+pub mod stun_server;
 
+use std::collections::HashSet;
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
-use actix_web::{get, App, HttpResponse, HttpServer, Responder, post, web, middleware::Logger};
+use actix_web::{get, App, HttpRequest, HttpResponse, HttpServer, Responder, post, web, middleware::Logger};
+use actix_web::http::header;
+use actix_web::error::{InternalError, JsonPayloadError};
 use actix_cors::Cors;
 use dashmap::DashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use rand::Rng;
 use async_trait::async_trait;
+pub use tokio_util::sync::CancellationToken;
+use config::ServerConfig;
+use clock::{Clock, SystemClock};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Error)]
 pub enum DropError {
@@ -29,32 +61,306 @@ pub enum DropError {
     WebRTC(String),
     #[error("Crypto error: {0}")]
     Crypto(String),
+    #[error("Transfer cancelled")]
+    Cancelled,
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    // A received file didn't match its metadata. Lists the chunks whose bytes
+    // are wrong (or missing) so only those need fetching again.
+    #[error("Verification failed: bad chunks {mismatched_chunks:?}, file hash mismatch: {file_hash_mismatch}")]
+    VerificationFailed {
+        mismatched_chunks: Vec<u32>,
+        file_hash_mismatch: bool,
+    },
+}
+
+impl DropError {
+    // The receiver ran out of space, see `transfer::DISK_FULL`; retrying with
+    // the same `ReceiveState` after freeing some resumes the transfer
+    pub fn is_disk_full(&self) -> bool {
+        use std::io::ErrorKind;
+        matches!(self, DropError::Io(e) if matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::WriteZero))
+    }
+
+    // Short, stable name for the kind of error, e.g. as a metrics label
+    pub fn category(&self) -> &'static str {
+        match self {
+            DropError::Io(_) => "io",
+            DropError::SerdeJson(_) => "serialization",
+            DropError::Protocol(_) => "protocol",
+            DropError::Ble(_) => "ble",
+            DropError::WebRTC(_) => "webrtc",
+            DropError::Crypto(_) => "crypto",
+            DropError::Cancelled => "cancelled",
+            DropError::Timeout(_) => "timeout",
+            DropError::VerificationFailed { .. } => "verification",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DropError>;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// How `FileMetadata.hash` was computed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntegrityScheme {
+    // Single-pass SHA-256 of the whole file
+    #[default]
+    WholeFile,
+    // Merkle root over the per-chunk hashes, see `hash::MerkleTree`
+    Merkle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct FileMetadata {
     pub name: String,
     pub size: u64,
     pub hash: String,
     pub chunks: Vec<ChunkInfo>,
+    #[serde(default)]
+    pub integrity: IntegrityScheme,
+    // Unix permission bits of the source file
+    #[serde(default)]
+    pub mode: Option<u32>,
+    // Source modification time, seconds since the Unix epoch
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    // Wire compression for chunk payloads, e.g. "zstd"; see `compression::Compression`
+    #[serde(default)]
+    pub compression: Option<String>,
+    // Bytes per chunk (the last may be shorter); absent means `transfer::CHUNK_SIZE`
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    // Set for a symlink in a directory transfer: the link's target, relative
+    // to the link's own directory. Such entries have no data or chunks.
+    #[serde(default)]
+    pub symlink_target: Option<String>,
+    // Directory transfers send higher priorities first, see `DirectoryTransfer::with_priority`
+    #[serde(default)]
+    pub priority: u32,
+}
+
+impl FileMetadata {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.map_or(transfer::CHUNK_SIZE, |size| size as usize)
+    }
+
+    pub fn merkle_tree(&self) -> hash::MerkleTree {
+        let hashes: Vec<&str> = self.chunks.iter().map(|c| c.hash.as_str()).collect();
+        hash::MerkleTree::build(&hashes)
+    }
+
+    pub fn merkle_root(&self) -> String {
+        self.merkle_tree().root_hex()
+    }
+
+    // How `newer` differs from this version of the file, comparing chunk size
+    // and hash at each index. Only meaningful between versions chunked at the
+    // same size; otherwise every chunk both have counts as changed.
+    pub fn diff(&self, newer: &FileMetadata) -> ChunkDiff {
+        let same_layout = self.chunk_size() == newer.chunk_size();
+        let common = self.chunks.len().min(newer.chunks.len());
+        let changed = self.chunks[..common]
+            .iter()
+            .zip(&newer.chunks[..common])
+            .filter(|(old, new)| !same_layout || old.size != new.size || old.hash != new.hash)
+            .map(|(_, new)| new.index)
+            .collect();
+        ChunkDiff {
+            changed,
+            added: newer.chunks[common..].iter().map(|c| c.index).collect(),
+            removed: self.chunks[common..].iter().map(|c| c.index).collect(),
+        }
+    }
+
+    // Reject peer-supplied metadata whose chunk list doesn't describe exactly
+    // `size` bytes in chunks numbered 0, 1, 2, ..., each a full chunk but the last
+    pub fn validate(&self) -> Result<()> {
+        let chunk_size = self.chunk_size.unwrap_or(transfer::CHUNK_SIZE as u64);
+        let allowed = transfer::MIN_CHUNK_SIZE as u64..=transfer::MAX_CHUNK_SIZE as u64;
+        if !allowed.contains(&chunk_size) {
+            return Err(DropError::Protocol(format!("unsupported chunk size {}", chunk_size)));
+        }
+        let mut total: u64 = 0;
+        for (position, chunk) in self.chunks.iter().enumerate() {
+            if chunk.size > chunk_size {
+                return Err(DropError::Protocol(format!("chunk {} larger than chunk size", chunk.index)));
+            }
+            if chunk.size < chunk_size && position + 1 < self.chunks.len() {
+                return Err(DropError::Protocol(format!("chunk {} short but not the last", chunk.index)));
+            }
+            if chunk.index as usize != position {
+                return Err(DropError::Protocol(format!(
+                    "chunk indices not contiguous: expected {}, got {}",
+                    position, chunk.index
+                )));
+            }
+            total = total
+                .checked_add(chunk.size)
+                .ok_or_else(|| DropError::Protocol("chunk sizes overflow".to_string()))?;
+        }
+        if total != self.size {
+            return Err(DropError::Protocol(format!(
+                "chunk sizes sum to {} but file size is {}",
+                total, self.size
+            )));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ChunkInfo {
     pub index: u32,
+    // Size and hash of the chunk as stored on disk, before any wire compression
     pub size: u64,
     pub hash: String,
+    // Chunk is entirely zero bytes; sent as `ZeroChunk` and written as a hole
+    #[serde(default)]
+    pub zero: bool,
+}
+
+// Chunk indices that differ between two versions of a file, see `FileMetadata::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkDiff {
+    // In both versions, with different contents
+    pub changed: Vec<u32>,
+    // Past the end of the older version
+    pub added: Vec<u32>,
+    // Past the end of the newer version
+    pub removed: Vec<u32>,
+}
+
+impl ChunkDiff {
+    // Indices of the chunks whose bytes the newer version needs sent
+    pub fn needed(&self) -> HashSet<u32> {
+        self.changed.iter().chain(&self.added).copied().collect()
+    }
+}
+
+// First message each peer sends on a connection, see `Protocol::handshake`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Hello {
+    pub version: u32,
+    // Random challenge the other peer signs to prove its identity
+    #[serde(default)]
+    pub nonce: Vec<u8>,
+    #[serde(default)]
+    pub identity: Option<identity::PeerIdentity>,
+    // Most unacknowledged chunks this peer allows in flight; the smaller of
+    // the two windows applies, see `Protocol::with_window`. Absent from peers
+    // that predate `Ack`
+    #[serde(default)]
+    pub window: Option<u32>,
+    // Chunk compressors this peer accepts, best first and including "none",
+    // see `compression::negotiate`; absent from peers that predate negotiation
+    #[serde(default)]
+    pub compressors: Option<Vec<String>>,
+    // Accepts `StartTransfer` in the binary form of `protocol::encode_metadata`
+    #[serde(default)]
+    pub binary_metadata: bool,
+    // Accepts `SackRanges` in place of one `Ack` per chunk
+    #[serde(default)]
+    pub sack: bool,
+    // Understands `Abort`; older peers are sent `Error` instead
+    #[serde(default)]
+    pub abort: bool,
+    // Answers `QueryMissing`
+    #[serde(default)]
+    pub query_missing: bool,
+    // Takes `StartStream`, see `Protocol::send_reader`
+    #[serde(default)]
+    pub stream: bool,
+}
+
+// Why a peer gave up on a transfer, so the other side can tell a retry
+// from a lost cause; see `TransferCommand::Abort`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    UserCancelled,
+    // The peer refused what it was sent: bad metadata, a changed file, the wrong identity
+    Rejected,
+    Timeout,
+    // A protocol version, compressor or parameter the peer can't handle
+    Unsupported,
+    Internal,
+}
+
+impl AbortReason {
+    // What should be reported for a transfer that failed with `error`
+    pub fn for_error(error: &DropError) -> Self {
+        match error {
+            DropError::Cancelled => AbortReason::UserCancelled,
+            DropError::Timeout(_) => AbortReason::Timeout,
+            DropError::Crypto(_) => AbortReason::Rejected,
+            _ => AbortReason::Internal,
+        }
+    }
+
+    // The error an `Abort` received from the peer surfaces as
+    pub fn into_error(self, detail: &str) -> DropError {
+        match self {
+            AbortReason::UserCancelled => DropError::Cancelled,
+            AbortReason::Timeout => DropError::Timeout(format!("peer timed out: {}", detail)),
+            AbortReason::Rejected => DropError::Protocol(format!("peer rejected the transfer: {}", detail)),
+            AbortReason::Unsupported => DropError::Protocol(format!("peer does not support this transfer: {}", detail)),
+            AbortReason::Internal => DropError::Protocol(format!("peer error: {}", detail)),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TransferCommand {
+    Hello(Hello),
+    // Signature over the peer's `Hello` nonce, sent when our `Hello` carried an identity
+    Identify(Vec<u8>),
     StartTransfer(FileMetadata),
+    // Entries of a directory transfer, see `directory::DirectoryTransfer`
+    Manifest(Vec<FileMetadata>),
+    // Sent instead of `StartTransfer` for a manifest entry that disappeared
+    // (or stopped being a regular file) before its turn
+    Skip(u32),
     RequestChunk(u32),
     SendChunk(u32, Vec<u8>),
+    // Reply to `RequestChunk` for an all-zero chunk; no payload on the wire
+    ZeroChunk(u32),
+    // Receiver has written this chunk; only sent when both `Hello`s carried a window
+    Ack(u32),
+    // Inclusive ranges of written chunks, acknowledged together; replaces
+    // `Ack` when both `Hello`s set `sack`, see `protocol::sack_ranges`
+    SackRanges(Vec<(u32, u32)>),
+    // Chunk inventory of the sending peer, see `protocol::Bitfield`
+    Bitfield(Vec<u8>),
+    // Sender asking mid-transfer which chunks the receiver still needs; the
+    // receiver answers with its current `Bitfield`
+    QueryMissing,
+    // Receiver continuing an interrupted transfer of the file with this hash
+    ResumeFrom { file_hash: String, have: Vec<u8> },
+    // Receiver's old copy of the file, for a `delta::DeltaTransfer`
+    BlockSignatures { block_size: u32, blocks: Vec<delta::BlockSignature> },
+    // How to rebuild the sender's file from those blocks
+    Delta(Vec<delta::DeltaOp>),
+    // After the last `Delta`: the rebuilt file must have this size and SHA-256
+    DeltaEnd { size: u64, hash: String },
+    // A file of unknown size follows as `SendChunk`s pushed in order, see
+    // `Protocol::send_reader`
+    StartStream { name: String },
+    // After the last pushed chunk: the stream had this size and SHA-256
+    EndStream { size: u64, hash: String },
     Complete,
+    // Free-form failure, from peers that predate `Abort`
     Error(String),
+    // The transfer is over and why; `detail` is for logs
+    Abort {
+        reason: AbortReason,
+        #[serde(default)]
+        detail: String,
+    },
+    // Keepalive sent on an idle connection; answered with `Pong`
+    Ping,
+    Pong,
+    // Text note for the user, at most `protocol::MAX_CHAT_LEN` bytes; may
+    // arrive at any point and never affects the transfer
+    Chat(String),
 }
 
 #[async_trait]
@@ -70,137 +376,1139 @@ pub struct SignalingMessage {
     pub message_type: String,
     // The actual SDP or ICE candidate string
     pub payload: String,
+    // Peer id of the sender, set by the server from `config::PEER_ID_HEADER`
+    // (anything the client put here is replaced). Both peers drain the same
+    // queue, so a receive carrying a peer id leaves that peer's own messages
+    // queued for the other side.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+// Optional body for create_session. Origins here narrow the global CORS
+// policy for this one session's signaling endpoints.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CreateSessionRequest {
+    // When set, only requests from these origins may use the session
+    #[serde(default)]
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_origins: Vec<String>,
+}
+
+// JSON error body: a stable machine-readable code plus a human-readable message
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self { code: code.to_string(), message: message.into() }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreateSessionResponse {
     pub session_id: String,
+    // Only on creation, and only when the server hands out session secrets.
+    // The code is for finding the session; this is for keying it, see
+    // `crypto::Crypto::from_session_secret`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+// A signaling session: the queue of messages waiting for the other peer plus a
+// version that is bumped on every send, so clients can detect racing writers.
+#[derive(Debug)]
+pub struct Session {
+    pub messages: Vec<SignalingMessage>,
+    pub version: u64,
+    // Last time either peer touched the session; drives TTL reaping
+    pub last_activity: Instant,
+    // Signaling payload and relay frame bytes passed through for this session
+    pub bytes_relayed: u64,
+    pub allowed_origins: Option<Vec<String>>,
+    pub denied_origins: Vec<String>,
+    // `last_activity` as of the last expiry warning, so each idle stretch is warned about once
+    pub expiry_warned: Option<Instant>,
+    // The secret handed to the creator, which proves who they are to rotate
+    pub secret: Option<String>,
+    // Woken whenever messages are queued here, so waiting receives can re-check
+    queued: Arc<tokio::sync::Notify>,
+}
+
+impl Session {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            messages: Vec::new(),
+            version: 0,
+            last_activity: now,
+            bytes_relayed: 0,
+            allowed_origins: None,
+            denied_origins: Vec::new(),
+            expiry_warned: None,
+            secret: None,
+            queued: Arc::default(),
+        }
+    }
+
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    pub fn with_origin_policy(mut self, request: CreateSessionRequest) -> Self {
+        self.allowed_origins = request.allowed_origins;
+        self.denied_origins = request.denied_origins;
+        self
+    }
+
+    // A request without an Origin header only passes when no allowlist is set
+    pub fn origin_permitted(&self, origin: Option<&str>) -> bool {
+        if origin.is_some_and(|o| self.denied_origins.iter().any(|d| d == o)) {
+            return false;
+        }
+        match &self.allowed_origins {
+            Some(allowed) => origin.is_some_and(|o| allowed.iter().any(|a| a == o)),
+            None => true,
+        }
+    }
 }
 
 // Shared application state
 // Stores pending signaling messages for each session
-// Key: session_id, Value: Vec<SignalingMessage> (messages waiting for the other peer)
+// Key: session_id, Value: Session (messages waiting for the other peer)
 pub struct AppState {
-    pub sessions: Arc<DashMap<String, Vec<SignalingMessage>>>,
+    pub sessions: Arc<DashMap<String, Session>>,
+    pub clock: Arc<dyn Clock>,
+    // When set, queued payloads are kept encrypted and only decrypted on delivery
+    pub payload_cipher: Option<crypto::Crypto>,
+    // Whether create_session returns a session secret
+    session_secrets: bool,
+    // Upper bound on messages returned by a single receive_signal call
+    pub max_messages_per_poll: usize,
+    // How long a `?wait_for=` receive waits before returning an empty batch
+    signal_wait_timeout: Duration,
+    // How long before reaping idle sessions get a SESSION_EXPIRING message
+    expiry_warning: Option<Duration>,
+    // Largest SignalingMessage payload send_signal will store
+    pub max_signal_payload: usize,
+    // Most messages one send-batch request may carry
+    pub max_signal_batch: usize,
+    // WebSocket relay rooms, keyed by session id
+    pub relays: Arc<DashMap<String, relay::RelayRoom>>,
+    // Set during maintenance so idle sessions aren't reaped under connecting peers
+    reaper_paused: AtomicBool,
+    admin_token: Option<String>,
+    // Idempotency-Key -> the session it created
+    idempotency_keys: DashMap<String, IdempotentCreate>,
+    idempotency_ttl: Duration,
+    // Most bytes a single session may relay; unlimited when unset
+    session_byte_cap: Option<u64>,
+    // "host:port" STUN servers /api/admin/nat-check probes
+    stun_servers: Vec<String>,
+    // Set by `shutdown`: no new sessions or relays are accepted
+    shutting_down: AtomicBool,
+    // Cancelled when the shutdown drain runs out; open relays then notify their peers and close
+    pub(crate) relay_shutdown: CancellationToken,
+    // Relay sockets still being served
+    pub(crate) open_relays: AtomicUsize,
+    pub metrics: metrics::Metrics,
+    pub stats: metrics::AdminStats,
+    // When this state was created, for the uptime in /api/admin/stats
+    started: Instant,
+    // Makes new session codes; swappable so tests can force collisions
+    code_generator: Arc<dyn Fn() -> String + Send + Sync>,
+    // Fresh codes tried after the first one collides
+    max_code_collision_retries: u32,
+    // Told about session lifecycle transitions, when configured
+    webhook: Option<webhook::Webhook>,
+    // Store-and-forward uploads, when configured
+    spool: Option<spool::Spool>,
+}
+
+struct IdempotentCreate {
+    session_id: String,
+    // Returned again to retries, which come from the same creator
+    secret: Option<String>,
+    created: Instant,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let started = clock.now();
+        Self {
+            sessions: Arc::new(DashMap::new()),
+            clock,
+            payload_cipher: None,
+            session_secrets: false,
+            max_messages_per_poll: config::DEFAULT_MAX_MESSAGES_PER_POLL,
+            signal_wait_timeout: config::DEFAULT_SIGNAL_WAIT_TIMEOUT,
+            expiry_warning: None,
+            max_signal_payload: config::DEFAULT_MAX_SIGNAL_PAYLOAD,
+            max_signal_batch: config::DEFAULT_MAX_SIGNAL_BATCH,
+            relays: Arc::new(DashMap::new()),
+            reaper_paused: AtomicBool::new(false),
+            admin_token: None,
+            idempotency_keys: DashMap::new(),
+            idempotency_ttl: config::DEFAULT_IDEMPOTENCY_TTL,
+            session_byte_cap: None,
+            stun_servers: config::DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
+            shutting_down: AtomicBool::new(false),
+            relay_shutdown: CancellationToken::new(),
+            open_relays: AtomicUsize::new(0),
+            metrics: metrics::Metrics::default(),
+            stats: metrics::AdminStats::default(),
+            started,
+            code_generator: Arc::new(|| {
+                generate_session_code(config::DEFAULT_SESSION_CODE_ALPHABET, config::DEFAULT_SESSION_CODE_LENGTH)
+            }),
+            max_code_collision_retries: config::DEFAULT_MAX_CODE_COLLISION_RETRIES,
+            webhook: None,
+            spool: None,
+        }
+    }
+
+    // Enable the /api/admin endpoints for requests bearing `token`
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    pub fn with_idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = ttl;
+        self
+    }
+
+    pub fn with_max_code_collision_retries(mut self, retries: u32) -> Self {
+        self.max_code_collision_retries = retries;
+        self
+    }
+
+    pub fn with_code_generator(mut self, generator: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        self.code_generator = Arc::new(generator);
+        self
+    }
+
+    // Generate codes of `length` characters from `alphabet`; see `ServerConfig::validate`
+    pub fn with_session_code_format(self, alphabet: &str, length: usize) -> Self {
+        let alphabet = alphabet.to_string();
+        self.with_code_generator(move || generate_session_code(&alphabet, length))
+    }
+
+    pub fn with_stun_servers(mut self, servers: Vec<String>) -> Self {
+        self.stun_servers = servers;
+        self
+    }
+
+    pub fn with_session_byte_cap(mut self, cap: u64) -> Self {
+        self.session_byte_cap = Some(cap);
+        self
+    }
+
+    // Count `bytes` against the session's cap. Returns false, leaving the
+    // usage untouched, when they would take it past the cap.
+    pub fn charge_session(&self, session: &mut Session, bytes: u64) -> bool {
+        let total = session.bytes_relayed.saturating_add(bytes);
+        if self.session_byte_cap.is_some_and(|cap| total > cap) {
+            return false;
+        }
+        session.bytes_relayed = total;
+        true
+    }
+
+    // Whether the session has nothing left under its cap
+    pub fn session_exhausted(&self, session: &Session) -> bool {
+        self.session_byte_cap.is_some_and(|cap| session.bytes_relayed >= cap)
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // Stop accepting sessions and relays, give open relays up to `drain` to
+    // finish on their own, then send the peers of any that remain a
+    // `TransferCommand::Error` so they fail cleanly instead of being cut off.
+    pub async fn shutdown(&self, drain: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let relays_closed = async {
+            while self.open_relays.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        if tokio::time::timeout(drain, relays_closed).await.is_ok() {
+            return;
+        }
+        let open = self.open_relays.load(Ordering::SeqCst);
+        tracing::info!(open, "drain timed out, notifying relayed peers");
+        self.relay_shutdown.cancel();
+        let notified = async {
+            while self.open_relays.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let _ = tokio::time::timeout(SHUTDOWN_NOTICE_GRACE, notified).await;
+    }
+
+    pub fn pause_reaper(&self) {
+        self.reaper_paused.store(true, Ordering::SeqCst);
+    }
+
+    // Sessions that went past their TTL while paused are reaped on the next sweep
+    pub fn resume_reaper(&self) {
+        self.reaper_paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn reaper_paused(&self) -> bool {
+        self.reaper_paused.load(Ordering::SeqCst)
+    }
+
+    pub fn with_max_messages_per_poll(mut self, max: usize) -> Self {
+        self.max_messages_per_poll = max.max(1);
+        self
+    }
+
+    pub fn with_signal_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.signal_wait_timeout = timeout;
+        self
+    }
+
+    pub fn with_expiry_warning(mut self, warning: Option<Duration>) -> Self {
+        self.expiry_warning = warning;
+        self
+    }
+
+    pub fn with_max_signal_payload(mut self, max: usize) -> Self {
+        self.max_signal_payload = max;
+        self
+    }
+
+    pub fn with_max_signal_batch(mut self, max: usize) -> Self {
+        self.max_signal_batch = max.max(1);
+        self
+    }
+
+    pub fn with_session_secrets(mut self, enabled: bool) -> Self {
+        self.session_secrets = enabled;
+        self
+    }
+
+    pub fn with_payload_encryption(mut self, cipher: crypto::Crypto) -> Self {
+        self.payload_cipher = Some(cipher);
+        self
+    }
+
+    pub fn with_webhook(mut self, webhook: webhook::Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    pub fn with_spool(mut self, spool: spool::Spool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    // Report a lifecycle transition; delivery never blocks the caller
+    pub(crate) fn emit(&self, event: &str, session_id: &str) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event, session_id);
+        }
+    }
+
+    fn seal_message(&self, mut message: SignalingMessage) -> Result<SignalingMessage> {
+        if let Some(cipher) = &self.payload_cipher {
+            message.payload = cipher.seal_string(&message.payload)?;
+        }
+        Ok(message)
+    }
+
+    fn open_message(&self, mut message: SignalingMessage) -> Result<SignalingMessage> {
+        if let Some(cipher) = &self.payload_cipher {
+            message.payload = cipher.open_string(&message.payload)?;
+        }
+        Ok(message)
+    }
+
+    // `open_message`, dropping a message that won't decrypt rather than the delivery it's in
+    fn open_or_drop(&self, message: SignalingMessage) -> Option<SignalingMessage> {
+        let message_type = message.message_type.clone();
+        self.open_message(message)
+            .inspect_err(|e| tracing::warn!(%message_type, error = %e, "dropping queued message that failed to decrypt"))
+            .ok()
+    }
+
+    // Drop sessions idle for longer than `ttl`, returning how many were removed
+    pub fn reap_expired(&self, ttl: Duration) -> usize {
+        let now = self.clock.now();
+        self.idempotency_keys
+            .retain(|_, create| now.saturating_duration_since(create.created) < self.idempotency_ttl);
+        // Spooled files have their own TTL, which pausing the session reaper doesn't hold
+        if let Some(spool) = &self.spool {
+            let expired = spool.reap(now);
+            if expired > 0 {
+                tracing::info!(expired, "removed expired spool uploads");
+            }
+        }
+        if self.reaper_paused() {
+            return 0;
+        }
+        let mut reaped = 0;
+        self.sessions.retain(|session_id, session| {
+            let live = now.saturating_duration_since(session.last_activity) < ttl;
+            if !live {
+                reaped += 1;
+                self.emit(webhook::REAPED, session_id);
+            }
+            live
+        });
+        if let Some(warning) = self.expiry_warning {
+            self.warn_expiring(now, ttl, warning);
+        }
+        reaped
+    }
+
+    // Queue a SESSION_EXPIRING message, with the seconds left as its payload,
+    // in sessions that will be reaped within `warning`. It doesn't count as
+    // activity; any request from a peer does and restarts the countdown.
+    fn warn_expiring(&self, now: Instant, ttl: Duration, warning: Duration) {
+        for mut session in self.sessions.iter_mut() {
+            let left = ttl.saturating_sub(now.saturating_duration_since(session.last_activity));
+            if left > warning || session.expiry_warned == Some(session.last_activity) {
+                continue;
+            }
+            let notice = SignalingMessage {
+                message_type: SESSION_EXPIRING.to_string(),
+                payload: left.as_secs().to_string(),
+                from: None,
+            };
+            let Ok(notice) = self.seal_message(notice) else { continue };
+            session.messages.push(notice);
+            session.version += 1;
+            session.expiry_warned = Some(session.last_activity);
+            session.queued.notify_waiters();
+        }
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// How long relays get to deliver their shutdown notice
+const SHUTDOWN_NOTICE_GRACE: Duration = Duration::from_secs(2);
+
+// Type of the message queued in a session shortly before it's reaped for
+// inactivity, see `ServerConfig::expiry_warning`
+pub const SESSION_EXPIRING: &str = "session_expiring";
+
+// Set on receive_signal responses: "true" when messages remain queued after this batch
+pub const HAS_MORE_HEADER: &str = "x-drop-has-more";
+
+fn etag(version: u64) -> header::EntityTag {
+    header::EntityTag::new_strong(version.to_string())
+}
+
+// Parse an `If-Match` header into the expected session version.
+// Returns Ok(None) when the header is absent or `*`, Err(()) when it's unparseable.
+fn expected_version(req: &HttpRequest) -> std::result::Result<Option<u64>, ()> {
+    let Some(value) = req.headers().get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| ())?.trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .trim_matches('"')
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|_| ())
+}
+
+// 403 unless the request's Origin may use `session`
+pub(crate) fn reject_origin(req: &HttpRequest, session: &Session) -> Option<HttpResponse> {
+    let origin = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok());
+    if session.origin_permitted(origin) {
+        return None;
+    }
+    Some(HttpResponse::Forbidden().body("Origin not allowed for this session"))
 }
 
-// Generate a user-friendly 6-character code
-fn generate_session_code() -> String {
+// Generate a user-friendly code, by default 6 letters and digits
+fn generate_session_code(alphabet: &str, length: usize) -> String {
     let mut rng = rand::thread_rng();
-    let chars: Vec<char> = "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".chars().collect();
-    (0..6)
+    let chars: Vec<char> = alphabet.chars().collect();
+    (0..length)
         .map(|_| chars[rng.gen_range(0..chars.len())])
         .collect()
 }
 
+// Insert `session` under a fresh, unused code. Gives the session back if
+// every attempt collided, which means the code space is close to full.
+fn insert_session(data: &AppState, session: Session) -> std::result::Result<String, Box<Session>> {
+    for _ in 0..=data.max_code_collision_retries {
+        let session_id = (data.code_generator)();
+        if let dashmap::mapref::entry::Entry::Vacant(entry) = data.sessions.entry(session_id.clone()) {
+            entry.insert(session);
+            return Ok(session_id);
+        }
+        data.metrics.session_code_collisions.fetch_add(1, Ordering::Relaxed);
+    }
+    tracing::warn!(sessions = data.sessions.len(), "no free session code, code space may be exhausted");
+    Err(Box::new(session))
+}
+
+fn codes_exhausted() -> HttpResponse {
+    HttpResponse::InternalServerError().json(ApiError::new("session_codes_exhausted", "no free session code"))
+}
+
+// With an `Idempotency-Key` header, repeats of the same key within the TTL
+// return the session the first request created, as long as it still exists.
 #[post("/api/session/create")]
-async fn create_session(data: web::Data<AppState>) -> impl Responder {
-    let session_id = generate_session_code();
-    // Ensure the session ID is unique
-    while data.sessions.contains_key(&session_id) {
-        let session_id = generate_session_code();
-        if !data.sessions.contains_key(&session_id) {
-            data.sessions.insert(session_id.clone(), Vec::new());
-            return HttpResponse::Ok().json(CreateSessionResponse { session_id });
+async fn create_session(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    if data.shutting_down() {
+        return HttpResponse::ServiceUnavailable().body("Server is shutting down");
+    }
+    let now = data.clock.now();
+    // A bad policy must not quietly become an unrestricted session
+    let policy = match body.is_empty() {
+        true => CreateSessionRequest::default(),
+        false => match serde_json::from_slice(&body) {
+            Ok(policy) => policy,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid session request"),
+        },
+    };
+    let secret = data.session_secrets.then(crypto::generate_session_secret);
+    let session = Session::new(now).with_origin_policy(policy).with_secret(secret.clone());
+    let key = req
+        .headers()
+        .get(config::IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|k| !k.is_empty());
+    let Some(key) = key else {
+        return match insert_session(&data, session) {
+            Ok(session_id) => {
+                data.stats.sessions_created.fetch_add(1, Ordering::Relaxed);
+                data.emit(webhook::SESSION_CREATED, &session_id);
+                HttpResponse::Ok().json(CreateSessionResponse { session_id, secret })
+            }
+            Err(_) => codes_exhausted(),
+        };
+    };
+
+    // Holding the entry serializes concurrent retries of the same key
+    let mut entry = data.idempotency_keys.entry(key.to_string()).or_insert_with(|| IdempotentCreate {
+        session_id: String::new(),
+        secret: None,
+        created: now,
+    });
+    let fresh = now.saturating_duration_since(entry.created) < data.idempotency_ttl;
+    if !(fresh && data.sessions.contains_key(&entry.session_id)) {
+        let Ok(session_id) = insert_session(&data, session) else {
+            return codes_exhausted();
+        };
+        data.stats.sessions_created.fetch_add(1, Ordering::Relaxed);
+        data.emit(webhook::SESSION_CREATED, &session_id);
+        *entry = IdempotentCreate { session_id, secret, created: now };
+    }
+    HttpResponse::Ok().json(CreateSessionResponse {
+        session_id: entry.session_id.clone(),
+        secret: entry.secret.clone(),
+    })
+}
+
+// Move a session's queue to a fresh code, so a code shared with the wrong
+// person stops working before they can pair. Only the creator may, by
+// presenting the session secret as `Authorization: Bearer <secret>`; without
+// session secrets enabled nobody can.
+#[post("/api/session/{session_id}/rotate")]
+async fn rotate_session(req: HttpRequest, data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let session_id = path.into_inner();
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // Checked under the same lock as the removal, so a session can't be taken
+    // by a caller who wasn't allowed to rotate it
+    let mut rejection = None;
+    let removed = data.sessions.remove_if(&session_id, |_, session| {
+        rejection = reject_origin(&req, session).or_else(|| match &session.secret {
+            None => Some(HttpResponse::Forbidden().body("Session has no secret to rotate with")),
+            Some(secret) if presented != Some(secret.as_str()) => {
+                Some(HttpResponse::Unauthorized().body("Invalid session secret"))
+            }
+            Some(_) => None,
+        });
+        rejection.is_none()
+    });
+    let Some((_, mut session)) = removed else {
+        return rejection.unwrap_or_else(|| HttpResponse::NotFound().body("Session not found"));
+    };
+    session.last_activity = data.clock.now();
+    match insert_session(&data, session) {
+        // The secret isn't rotated; peers that already have it keep using it
+        Ok(session_id) => HttpResponse::Ok().json(CreateSessionResponse { session_id, secret: None }),
+        // Keep the old code working rather than losing the session
+        Err(session) => {
+            data.sessions.insert(session_id, *session);
+            codes_exhausted()
         }
     }
-    
-    data.sessions.insert(session_id.clone(), Vec::new());
-    HttpResponse::Ok().json(CreateSessionResponse { session_id })
 }
 
 #[post("/api/session/{session_id}/signal/send")]
 async fn send_signal(
+    req: HttpRequest,
     data: web::Data<AppState>,
     path: web::Path<String>,
     message: web::Json<SignalingMessage>,
 ) -> impl Responder {
-    let session_id = path.into_inner();
-    match data.sessions.get_mut(&session_id) {
-        Some(mut messages) => {
-            messages.push(message.into_inner());
-            HttpResponse::Ok().finish()
+    enqueue_signals(&req, &data, &path.into_inner(), vec![message.into_inner()])
+}
+
+// Queue several messages (e.g. a burst of ICE candidates) in one request.
+// They're stored in order, all or nothing, as a single version bump.
+#[post("/api/session/{session_id}/signal/send-batch")]
+async fn send_signal_batch(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    messages: web::Json<Vec<SignalingMessage>>,
+) -> impl Responder {
+    let messages = messages.into_inner();
+    if messages.is_empty() {
+        return HttpResponse::BadRequest().body("Empty batch");
+    }
+    if messages.len() > data.max_signal_batch {
+        return HttpResponse::PayloadTooLarge().json(ApiError::new(
+            "batch_too_large",
+            format!("batch has {} messages, limit is {}", messages.len(), data.max_signal_batch),
+        ));
+    }
+    enqueue_signals(&req, &data, &path.into_inner(), messages)
+}
+
+// A malformed ICE candidate is refused here rather than failing opaquely in
+// the other peer's `addIceCandidate`; valid ones are relayed exactly as sent
+fn check_candidate(message: SignalingMessage) -> Result<SignalingMessage> {
+    if message.message_type == "candidate" {
+        webrtc::validate_candidate(&message.payload)?;
+    }
+    Ok(message)
+}
+
+// Every check runs before anything is stored, so a rejected request leaves the
+// session untouched
+fn enqueue_signals(req: &HttpRequest, data: &AppState, session_id: &str, messages: Vec<SignalingMessage>) -> HttpResponse {
+    let Ok(expected) = expected_version(req) else {
+        return HttpResponse::BadRequest().body("Invalid If-Match header");
+    };
+    if let Some(message) = messages.iter().find(|m| m.payload.len() > data.max_signal_payload) {
+        return HttpResponse::PayloadTooLarge().json(ApiError::new(
+            "payload_too_large",
+            format!("payload is {} bytes, limit is {}", message.payload.len(), data.max_signal_payload),
+        ));
+    }
+    let messages = match messages.into_iter().map(check_candidate).collect::<Result<Vec<_>>>() {
+        Ok(messages) => messages,
+        Err(e) => return HttpResponse::BadRequest().json(ApiError::new("invalid_candidate", e.to_string())),
+    };
+    let from = peer_id(req);
+    let messages = messages.into_iter().map(|m| SignalingMessage { from: from.clone(), ..m });
+    match data.sessions.get_mut(session_id) {
+        Some(mut session) => {
+            if let Some(rejection) = reject_origin(req, &session) {
+                return rejection;
+            }
+            if expected.is_some_and(|v| v != session.version) {
+                return HttpResponse::PreconditionFailed()
+                    .insert_header(header::ETag(etag(session.version)))
+                    .body("Session version mismatch");
+            }
+            let messages: Vec<SignalingMessage> = messages.collect();
+            let size: u64 = messages
+                .iter()
+                .map(|m| (m.message_type.len() + m.payload.len()) as u64)
+                .sum();
+            let Ok(sealed) = messages.into_iter().map(|m| data.seal_message(m)).collect::<Result<Vec<_>>>() else {
+                return HttpResponse::InternalServerError().body("Failed to store message");
+            };
+            if !data.charge_session(&mut session, size) {
+                return HttpResponse::InsufficientStorage().body("Session byte cap exceeded");
+            }
+            data.stats.messages_relayed.fetch_add(sealed.len() as u64, Ordering::Relaxed);
+            session.messages.extend(sealed);
+            session.version += 1;
+            session.last_activity = data.clock.now();
+            session.queued.notify_waiters();
+            HttpResponse::Ok()
+                .insert_header(header::ETag(etag(session.version)))
+                .finish()
         }
         None => HttpResponse::NotFound().body("Session not found"),
     }
 }
 
+// The caller's `config::PEER_ID_HEADER`, if it sent one
+fn peer_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(config::PEER_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+// Whether `message` should be handed to the peer polling as `peer`: anything
+// but what that peer sent itself
+fn addressed_to(message: &SignalingMessage, peer: Option<&str>) -> bool {
+    peer.is_none() || message.from.as_deref() != peer
+}
+
+#[derive(Deserialize)]
+struct ReceiveQuery {
+    // Only hand out a message of this type, waiting for one if none is queued
+    wait_for: Option<String>,
+}
+
 #[get("/api/session/{session_id}/signal/receive")]
 async fn receive_signal(
+    req: HttpRequest,
     data: web::Data<AppState>,
     path: web::Path<String>,
+    query: web::Query<ReceiveQuery>,
 ) -> impl Responder {
     let session_id = path.into_inner();
+    if let Some(message_type) = &query.wait_for {
+        return wait_for_signal(&req, &data, &session_id, message_type).await;
+    }
     match data.sessions.get_mut(&session_id) {
-        Some(mut messages) => {
-            if messages.is_empty() {
-                HttpResponse::Ok().json(Vec::<SignalingMessage>::new()) // No messages pending
-            } else {
-                let drained_messages = messages.drain(..).collect::<Vec<_>>();
-                HttpResponse::Ok().json(drained_messages)
+        Some(mut session) => {
+            if let Some(rejection) = reject_origin(&req, &session) {
+                return rejection;
+            }
+            session.last_activity = data.clock.now();
+            let tag = header::ETag(etag(session.version));
+            // Hand out at most one batch per poll; clients keep polling while has-more is set
+            let peer = peer_id(&req);
+            let (mut batch, mut kept) = (Vec::new(), Vec::new());
+            for message in std::mem::take(&mut session.messages) {
+                if batch.len() < data.max_messages_per_poll && addressed_to(&message, peer.as_deref()) {
+                    batch.push(message);
+                } else {
+                    kept.push(message);
+                }
             }
+            let has_more = kept.iter().any(|m| addressed_to(m, peer.as_deref()));
+            session.messages = kept;
+            let messages: Vec<SignalingMessage> = batch.into_iter().filter_map(|m| data.open_or_drop(m)).collect();
+            HttpResponse::Ok()
+                .insert_header(tag)
+                .insert_header((HAS_MORE_HEADER, has_more.to_string()))
+                .json(messages)
         }
         None => HttpResponse::NotFound().body("Session not found"),
     }
 }
 
+// Hands out the oldest queued message of `message_type`, leaving any others
+// queued in order. If there is none yet the request is held until one is sent
+// or `signal_wait_timeout` passes, which returns an empty batch.
+async fn wait_for_signal(req: &HttpRequest, data: &AppState, session_id: &str, message_type: &str) -> HttpResponse {
+    let deadline = data.clock.now() + data.signal_wait_timeout;
+    let peer = peer_id(req);
+    loop {
+        let Some(notify) = data.sessions.get(session_id).map(|s| s.queued.clone()) else {
+            return HttpResponse::NotFound().body("Session not found");
+        };
+        // Registered before the queue is checked, so a send in between still wakes us
+        let queued = notify.notified();
+        tokio::pin!(queued);
+        queued.as_mut().enable();
+        {
+            let Some(mut session) = data.sessions.get_mut(session_id) else {
+                return HttpResponse::NotFound().body("Session not found");
+            };
+            if let Some(rejection) = reject_origin(req, &session) {
+                return rejection;
+            }
+            session.last_activity = data.clock.now();
+            let tag = header::ETag(etag(session.version));
+            let wanted = |m: &SignalingMessage| m.message_type == message_type && addressed_to(m, peer.as_deref());
+            let matching = session.messages.iter().position(wanted);
+            let expired = data.clock.now() >= deadline;
+            if matching.is_some() || expired {
+                let message = matching.map(|i| session.messages.remove(i));
+                let has_more = session.messages.iter().any(wanted);
+                let opened = match message.map(|m| data.open_or_drop(m)) {
+                    // Dropped, so look again for the next one of the type
+                    Some(None) => continue,
+                    opened => opened.flatten(),
+                };
+                return HttpResponse::Ok()
+                    .insert_header(tag)
+                    .insert_header((HAS_MORE_HEADER, has_more.to_string()))
+                    .json(Vec::from_iter(opened));
+            }
+        }
+        tokio::select! {
+            _ = queued => {}
+            _ = tokio::time::sleep_until(deadline.into()) => {}
+        }
+    }
+}
+
 #[get("/")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello from drop_backend!")
 }
 
-// Renamed and changed to async, removed FFI parts and explicit runtime.
-pub async fn start_actix_server() -> std::io::Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
-    
-    println!("Starting Actix web server on http://127.0.0.1:8080");
+// The response to send back unless the request carries the configured admin
+// bearer token
+fn reject_non_admin(req: &HttpRequest, data: &AppState) -> Option<HttpResponse> {
+    let Some(token) = &data.admin_token else {
+        return Some(HttpResponse::Forbidden().body("Admin API disabled"));
+    };
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if presented != Some(token.as_str()) {
+        return Some(HttpResponse::Unauthorized().body("Invalid admin token"));
+    }
+    None
+}
 
-    let app_state = web::Data::new(AppState {
-        sessions: Arc::new(DashMap::new()),
-    });
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReaperStatus {
+    pub paused: bool,
+}
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://127.0.0.1:3000")
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
-            .allowed_headers(vec!["content-type", "authorization"])
-            .supports_credentials()
-            .max_age(3600);
+#[post("/api/admin/reaper/pause")]
+async fn pause_reaper(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    data.pause_reaper();
+    HttpResponse::Ok().json(ReaperStatus { paused: true })
+}
 
-        App::new()
-            .wrap(cors)
-            .wrap(Logger::default())
-            .app_data(app_state.clone()) // Add shared state
-            .service(hello) // Keep existing hello route
-            .service(create_session)
-            .service(send_signal)
-            .service(receive_signal)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+#[post("/api/admin/reaper/resume")]
+async fn resume_reaper(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    data.resume_reaper();
+    HttpResponse::Ok().json(ReaperStatus { paused: false })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test, web, App, http::StatusCode};
-    use serde_json::json;
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub queued_messages: usize,
+    pub version: u64,
+    pub bytes_relayed: u64,
+    pub byte_cap: Option<u64>,
+}
 
-    #[actix_web::test]
-    async fn test_hello_route() {
-        let app_state = web::Data::new(AppState {
-            sessions: Arc::new(DashMap::new()),
-        });
-        let app = test::init_service(
-            App::new()
-                .app_data(app_state.clone())
-                .service(hello)
-        ).await;
-        let req = test::TestRequest::get().uri("/").to_request();
-        let resp = test::call_service(&app, req).await;
+#[get("/api/admin/sessions")]
+async fn list_sessions(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    let mut sessions: Vec<SessionSummary> = data
+        .sessions
+        .iter()
+        .map(|entry| SessionSummary {
+            session_id: entry.key().clone(),
+            queued_messages: entry.messages.len(),
+            version: entry.version,
+            bytes_relayed: entry.bytes_relayed,
+            byte_cap: data.session_byte_cap,
+        })
+        .collect();
+    sessions.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+    HttpResponse::Ok().json(sessions)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ServerStats {
+    pub sessions_created: u64,
+    pub active_sessions: usize,
+    pub messages_relayed: u64,
+    // Not affected by a reset
+    pub uptime_secs: u64,
+}
+
+fn server_stats(data: &AppState) -> ServerStats {
+    ServerStats {
+        sessions_created: data.stats.sessions_created.load(Ordering::Relaxed),
+        active_sessions: data.sessions.len(),
+        messages_relayed: data.stats.messages_relayed.load(Ordering::Relaxed),
+        uptime_secs: data.clock.now().saturating_duration_since(data.started).as_secs(),
+    }
+}
+
+#[get("/api/admin/stats")]
+async fn admin_stats(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    HttpResponse::Ok().json(server_stats(&data))
+}
+
+// Zero the counters, e.g. at the start of a load test; returns the stats after the reset
+#[post("/api/admin/stats/reset")]
+async fn reset_admin_stats(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    data.stats.reset();
+    HttpResponse::Ok().json(server_stats(&data))
+}
+
+// Classify the NAT in front of the server host using the configured STUN
+// servers. The probe runs from the server, so it says nothing about a
+// client's own NAT; it's an operator diagnostic for deciding whether the
+// deployment needs the relay, and admin-only so it can't be used to make
+// the server send STUN traffic on demand.
+#[get("/api/admin/nat-check")]
+async fn nat_check(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(rejection) = reject_non_admin(&req, &data) {
+        return rejection;
+    }
+    match nat::probe(&data.stun_servers, nat::DEFAULT_STUN_TIMEOUT).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().json(ApiError::new("nat_check_failed", e.to_string())),
+    }
+}
+
+#[get("/health")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+// Deeper check: build a throwaway peer connection and create an offer, so a
+// build whose WebRTC stack can't initialize reports unhealthy.
+#[get("/health/webrtc")]
+async fn health_webrtc() -> impl Responder {
+    async fn probe() -> Result<()> {
+        let mut transfer = webrtc::WebRTCTransfer::new().await?;
+        let offer = transfer.create_offer().await;
+        transfer.cancel().await?;
+        offer.map(|_| ())
+    }
+    match probe().await {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(format!("WebRTC unavailable: {}", e)),
+    }
+}
+
+// JSON extractor settings for the whole API. A body that doesn't parse gets
+// an `ApiError` saying why, e.g. which field is missing, instead of Actix's
+// bare error; the status stays what Actix would send (413, 415 or 400).
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let response = match &err {
+            JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+                HttpResponse::PayloadTooLarge().json(ApiError::new("payload_too_large", err.to_string()))
+            }
+            // serde's message names the field and position, without Actix's prefix
+            JsonPayloadError::Deserialize(e) => {
+                HttpResponse::BadRequest().json(ApiError::new("invalid_json", e.to_string()))
+            }
+            JsonPayloadError::ContentType => {
+                HttpResponse::UnsupportedMediaType().json(ApiError::new("unsupported_media_type", err.to_string()))
+            }
+            _ => HttpResponse::BadRequest().json(ApiError::new("invalid_json", err.to_string())),
+        };
+        InternalError::from_response(err, response).into()
+    })
+}
+
+// Build the global CORS policy from the server config.
+pub fn build_cors(config: &ServerConfig) -> Cors {
+    let cors = config
+        .allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin));
+    cors.allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .expose_headers(vec!["etag", "content-range", HAS_MORE_HEADER, spool::FILE_NAME_HEADER, spool::SHA256_HEADER])
+        .supports_credentials()
+        .max_age(3600)
+}
+
+// Renamed and changed to async, removed FFI parts and explicit runtime.
+pub async fn start_actix_server() -> std::io::Result<()> {
+    start_actix_server_with_config(ServerConfig::default()).await
+}
+
+pub async fn start_actix_server_with_config(config: ServerConfig) -> std::io::Result<()> {
+    // Initialize logging
+    tracing_subscriber::fmt::init();
+    config.validate()?;
+
+    println!("Starting Actix web server on http://{}:{}", config.host, config.port);
+
+    let mut app_state = AppState::new()
+        .with_max_messages_per_poll(config.max_messages_per_poll)
+        .with_signal_wait_timeout(config.signal_wait_timeout)
+        .with_session_secrets(config.session_secrets)
+        .with_expiry_warning(config.expiry_warning)
+        .with_max_signal_payload(config.max_signal_payload)
+        .with_max_signal_batch(config.max_signal_batch)
+        .with_stun_servers(config.stun_servers.clone())
+        .with_max_code_collision_retries(config.max_code_collision_retries)
+        .with_session_code_format(&config.session_code_alphabet, config.session_code_length)
+        .with_idempotency_ttl(config.idempotency_ttl);
+    if config.encrypt_signaling_at_rest {
+        app_state = app_state.with_payload_encryption(crypto::Crypto::new());
+    }
+    if let Some(token) = &config.admin_token {
+        app_state = app_state.with_admin_token(token.clone());
+    }
+    if let Some(cap) = config.session_byte_cap {
+        app_state = app_state.with_session_byte_cap(cap);
+    }
+    if let Some(url) = &config.webhook_url {
+        let invalid = |e: DropError| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string());
+        let mut webhook = webhook::Webhook::new(url).map_err(invalid)?;
+        if let Some(secret) = &config.webhook_secret {
+            webhook = webhook.with_secret(secret.as_bytes());
+        }
+        app_state = app_state.with_webhook(webhook);
+    }
+    if let Some(dir) = &config.spool_dir {
+        app_state = app_state.with_spool(spool::Spool::open(dir.clone(), config.spool_ttl, config.spool_quota)?);
+    }
+    let app_state = web::Data::new(app_state);
+    let bind_addr = (config.host.clone(), config.port);
+
+    let reaper_state = app_state.clone();
+    let (session_ttl, reap_interval) = (config.session_ttl, config.reap_interval);
+    let drain_timeout = config.shutdown_drain_timeout;
+    actix_web::rt::spawn(async move {
+        let mut ticker = actix_web::rt::time::interval(reap_interval);
+        loop {
+            ticker.tick().await;
+            let reaped = reaper_state.reap_expired(session_ttl);
+            if reaped > 0 {
+                tracing::info!(reaped, "reaped idle sessions");
+            }
+        }
+    });
+
+    #[cfg(feature = "stun-server")]
+    if let Some(addr) = &config.stun_server_addr {
+        let stun = stun_server::StunServer::bind(addr.as_str()).await.map_err(std::io::Error::other)?;
+        println!("STUN responder listening on udp://{}", addr);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = stun.run().await {
+                tracing::warn!(error = %e, "STUN responder stopped");
+            }
+        });
+    }
+
+    let server_state = app_state.clone();
+    let server = HttpServer::new(move || {
+        let cors = build_cors(&config);
+
+        App::new()
+            .wrap(cors)
+            .wrap(Logger::default())
+            .app_data(server_state.clone()) // Add shared state
+            .app_data(json_config())
+            .service(hello) // Keep existing hello route
+            .service(health)
+            .service(health_webrtc)
+            .service(nat_check)
+            .service(metrics::metrics)
+            .service(create_session)
+            .service(rotate_session)
+            .service(pause_reaper)
+            .service(resume_reaper)
+            .service(list_sessions)
+            .service(admin_stats)
+            .service(reset_admin_stats)
+            .service(send_signal)
+            .service(send_signal_batch)
+            .service(receive_signal)
+            .service(relay::relay_socket)
+            .service(spool::upload)
+            .service(spool::download)
+            .service(spool::chunks)
+            .service(spool::discard)
+    })
+    // Signals are handled below so relays can drain before workers stop
+    .disable_signals()
+    .bind(bind_addr)?
+    .run();
+
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        app_state.shutdown(drain_timeout).await;
+        handle.stop(true).await;
+    });
+    server.await
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, http::StatusCode};
+
+    // A distinct, well-formed host candidate line for each `i`
+    fn host_candidate(i: u32) -> String {
+        format!("candidate:{} 1 udp 2130706431 192.168.1.{} 5000 typ host", i, i % 250 + 1)
+    }
+
+    #[actix_web::test]
+    async fn test_hello_route() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(hello)
+        ).await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
         let body = test::read_body(resp).await;
         assert_eq!(body, "Hello from drop_backend!");
@@ -208,9 +1516,7 @@ mod tests {
 
     #[actix_web::test]
     async fn test_create_session() {
-        let app_state = web::Data::new(AppState {
-            sessions: Arc::new(DashMap::new()),
-        });
+        let app_state = web::Data::new(AppState::new());
         let app = test::init_service(
             App::new()
                 .app_data(app_state.clone())
@@ -224,11 +1530,48 @@ mod tests {
         assert!(app_state.sessions.contains_key(&resp.session_id));
     }
 
+    #[actix_web::test]
+    async fn test_session_secret_is_random_per_session() {
+        use base64::Engine;
+        let app_state = web::Data::new(AppState::new().with_session_secrets(true));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+        ).await;
+
+        let mut secrets = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/session/create").to_request();
+            let resp: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+            let secret = resp.secret.expect("secret returned on creation");
+            let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&secret).unwrap();
+            assert_eq!(bytes.len(), crypto::SESSION_SECRET_LEN);
+            // 32 random bytes essentially never repeat this many values
+            let distinct: std::collections::HashSet<_> = bytes.iter().collect();
+            assert!(distinct.len() > 16, "{:?}", bytes);
+            secrets.push((resp.session_id, secret));
+        }
+        assert_ne!(secrets[0].1, secrets[1].1);
+
+        // Both peers derive the same key from the secret and code
+        let (session_id, secret) = &secrets[0];
+        let creator = crypto::Crypto::from_session_secret(secret, session_id).unwrap();
+        let joiner = crypto::Crypto::from_session_secret(secret, session_id).unwrap();
+        assert_eq!(joiner.open_string(&creator.seal_string("sdp").unwrap()).unwrap(), "sdp");
+        let other = crypto::Crypto::from_session_secret(secret, &secrets[1].0).unwrap();
+        assert!(other.open_string(&creator.seal_string("sdp").unwrap()).is_err());
+
+        // Off by default
+        let app = test::init_service(App::new().app_data(web::Data::new(AppState::new())).service(create_session)).await;
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+    }
+
     #[actix_web::test]
     async fn test_send_and_receive_signal() {
-        let app_state = web::Data::new(AppState {
-            sessions: Arc::new(DashMap::new()),
-        });
+        let app_state = web::Data::new(AppState::new());
         let app = test::init_service(
             App::new()
                 .app_data(app_state.clone())
@@ -247,6 +1590,7 @@ mod tests {
         let signal_msg = SignalingMessage {
             message_type: "offer".to_string(),
             payload: "sdp_offer_payload".to_string(),
+            from: None,
         };
         let send_req = test::TestRequest::post()
             .uri(&format!("/api/session/{}/signal/send", session_id))
@@ -256,9 +1600,11 @@ mod tests {
         assert_eq!(send_resp.status(), StatusCode::OK);
 
         // Verify message is stored (indirectly, by receive_signal)
-        let messages_in_session = app_state.sessions.get(&session_id).unwrap();
-        assert_eq!(messages_in_session.len(), 1);
-        assert_eq!(messages_in_session[0].message_type, "offer");
+        {
+            let messages_in_session = app_state.sessions.get(&session_id).unwrap();
+            assert_eq!(messages_in_session.messages.len(), 1);
+            assert_eq!(messages_in_session.messages[0].message_type, "offer");
+        }
 
         // 3. Receive the signal message
         let receive_req = test::TestRequest::get()
@@ -272,14 +1618,12 @@ mod tests {
 
         // Verify messages are drained after receiving
         let messages_after_receive = app_state.sessions.get(&session_id).unwrap();
-        assert!(messages_after_receive.is_empty());
+        assert!(messages_after_receive.messages.is_empty());
     }
 
     #[actix_web::test]
     async fn test_receive_signal_no_messages() {
-        let app_state = web::Data::new(AppState {
-            sessions: Arc::new(DashMap::new()),
-        });
+        let app_state = web::Data::new(AppState::new());
         let app = test::init_service(
             App::new()
                 .app_data(app_state.clone())
@@ -298,11 +1642,48 @@ mod tests {
         assert!(received_msgs.is_empty());
     }
 
+    #[actix_web::test]
+    async fn test_receive_leaves_own_messages_queued() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        let uri = format!("/api/session/{}/signal", session_id);
+        // Naming someone else as the sender doesn't stick
+        let offer = SignalingMessage {
+            message_type: "offer".to_string(),
+            payload: "sdp".to_string(),
+            from: Some("bob".to_string()),
+        };
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/send", uri))
+            .insert_header((config::PEER_ID_HEADER, "alice"))
+            .set_json(&offer)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let poll = |peer: &'static str| {
+            test::TestRequest::get()
+                .uri(&format!("{}/receive", uri))
+                .insert_header((config::PEER_ID_HEADER, peer))
+                .to_request()
+        };
+        let own: Vec<SignalingMessage> = test::call_and_read_body_json(&app, poll("alice")).await;
+        assert!(own.is_empty());
+        let theirs: Vec<SignalingMessage> = test::call_and_read_body_json(&app, poll("bob")).await;
+        assert_eq!(theirs.len(), 1);
+        assert_eq!(theirs[0].from.as_deref(), Some("alice"));
+    }
+
     #[actix_web::test]
     async fn test_signal_to_invalid_session() {
-        let app_state = web::Data::new(AppState {
-            sessions: Arc::new(DashMap::new()),
-        });
+        let app_state = web::Data::new(AppState::new());
         let app = test::init_service(
             App::new()
                 .app_data(app_state.clone())
@@ -314,6 +1695,7 @@ mod tests {
         let signal_msg = SignalingMessage {
             message_type: "offer".to_string(),
             payload: "test".to_string(),
+            from: None,
         };
 
         // Test send_signal to invalid session
@@ -331,4 +1713,931 @@ mod tests {
         let receive_resp = test::call_service(&app, receive_req).await;
         assert_eq!(receive_resp.status(), StatusCode::NOT_FOUND);
     }
+
+    #[actix_web::test]
+    async fn test_send_signal_if_match() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+        ).await;
+
+        let req_create = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_resp: CreateSessionResponse = test::call_and_read_body_json(&app, req_create).await;
+        let session_id = session_resp.session_id;
+        let signal_msg = SignalingMessage {
+            message_type: "offer".to_string(),
+            payload: "sdp_offer_payload".to_string(),
+            from: None,
+        };
+
+        // Matching precondition on a fresh session succeeds and bumps the version
+        let send_req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", session_id))
+            .insert_header((header::IF_MATCH, "\"0\""))
+            .set_json(&signal_msg)
+            .to_request();
+        let send_resp = test::call_service(&app, send_req).await;
+        assert_eq!(send_resp.status(), StatusCode::OK);
+        assert_eq!(send_resp.headers().get(header::ETAG).unwrap(), "\"1\"");
+
+        // A racing client still holding version 0 is rejected
+        let stale_req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", session_id))
+            .insert_header((header::IF_MATCH, "\"0\""))
+            .set_json(&signal_msg)
+            .to_request();
+        let stale_resp = test::call_service(&app, stale_req).await;
+        assert_eq!(stale_resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(stale_resp.headers().get(header::ETAG).unwrap(), "\"1\"");
+        assert_eq!(app_state.sessions.get(&session_id).unwrap().messages.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_preflight_allows_peer_id_header() {
+        let config = ServerConfig::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(build_cors(&config))
+                .app_data(web::Data::new(AppState::new()))
+                .service(send_signal)
+        ).await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/session/ABC123/signal/send")
+            .insert_header((header::ORIGIN, "http://localhost:3000"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type, x-drop-peer-id"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let allowed = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_lowercase();
+        assert!(allowed.contains(config::PEER_ID_HEADER));
+    }
+
+    #[actix_web::test]
+    async fn test_create_session_idempotency_key() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(AppState::with_clock(clock.clone()));
+        let app = test::init_service(App::new().app_data(app_state.clone()).service(create_session)).await;
+        let create = |key: &str| {
+            test::TestRequest::post()
+                .uri("/api/session/create")
+                .insert_header((config::IDEMPOTENCY_KEY_HEADER, key.to_string()))
+                .to_request()
+        };
+
+        let first: CreateSessionResponse = test::call_and_read_body_json(&app, create("retry-1")).await;
+        let retry: CreateSessionResponse = test::call_and_read_body_json(&app, create("retry-1")).await;
+        assert_eq!(first.session_id, retry.session_id);
+        assert_eq!(app_state.sessions.len(), 1);
+
+        let other: CreateSessionResponse = test::call_and_read_body_json(&app, create("retry-2")).await;
+        assert_ne!(other.session_id, first.session_id);
+        assert_eq!(app_state.sessions.len(), 2);
+
+        // Past the TTL the key creates a new session
+        clock.advance(config::DEFAULT_IDEMPOTENCY_TTL);
+        let later: CreateSessionResponse = test::call_and_read_body_json(&app, create("retry-1")).await;
+        assert_ne!(later.session_id, first.session_id);
+    }
+
+    #[actix_web::test]
+    async fn test_reap_expired_with_mock_clock() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(AppState::with_clock(clock.clone()));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(receive_signal)
+        ).await;
+
+        let ttl = Duration::from_secs(600);
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let idle: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let active: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+
+        clock.advance(Duration::from_secs(400));
+        let poll = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", active.session_id))
+            .to_request();
+        assert!(test::call_service(&app, poll).await.status().is_success());
+        assert_eq!(app_state.reap_expired(ttl), 0);
+
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(app_state.reap_expired(ttl), 1);
+        assert!(!app_state.sessions.contains_key(&idle.session_id));
+        assert!(app_state.sessions.contains_key(&active.session_id));
+    }
+
+    #[actix_web::test]
+    async fn test_idle_session_is_warned_before_reaping() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(
+            AppState::with_clock(clock.clone()).with_expiry_warning(Some(Duration::from_secs(60))),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(receive_signal)
+        ).await;
+
+        let ttl = Duration::from_secs(600);
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/session/create").to_request();
+            sessions.push(test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id);
+        }
+        let (kept, idle) = (&sessions[0], &sessions[1]);
+
+        clock.advance(Duration::from_secs(530));
+        assert_eq!(app_state.reap_expired(ttl), 0);
+        assert!(app_state.sessions.get(kept).unwrap().messages.is_empty());
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(app_state.reap_expired(ttl), 0);
+        // Warned once, not on every sweep
+        assert_eq!(app_state.reap_expired(ttl), 0);
+        assert_eq!(app_state.sessions.get(idle).unwrap().messages.len(), 1);
+
+        // Polling picks up the notice and is itself the keepalive
+        let poll = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", kept))
+            .to_request();
+        let notices: Vec<SignalingMessage> = test::call_and_read_body_json(&app, poll).await;
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].message_type, SESSION_EXPIRING);
+        assert_eq!(notices[0].payload, "50");
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(app_state.reap_expired(ttl), 1);
+        assert!(app_state.sessions.contains_key(kept));
+        assert!(!app_state.sessions.contains_key(idle));
+    }
+
+    #[actix_web::test]
+    async fn test_paused_reaper_keeps_expired_sessions() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(AppState::with_clock(clock.clone()).with_admin_token("s3cret"));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(pause_reaper)
+                .service(resume_reaper)
+        ).await;
+
+        let ttl = Duration::from_secs(600);
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::post().uri("/api/admin/reaper/pause").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/api/admin/reaper/pause")
+            .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(app_state.reaper_paused());
+
+        clock.advance(Duration::from_secs(1200));
+        assert_eq!(app_state.reap_expired(ttl), 0);
+        assert!(app_state.sessions.contains_key(&session.session_id));
+
+        let req = test::TestRequest::post()
+            .uri("/api/admin/reaper/resume")
+            .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(app_state.reap_expired(ttl), 1);
+        assert!(!app_state.sessions.contains_key(&session.session_id));
+    }
+
+    #[actix_web::test]
+    async fn test_session_origin_policy() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/session/create")
+            .set_json(CreateSessionRequest {
+                allowed_origins: Some(vec!["https://embed.example".to_string()]),
+                ..Default::default()
+            })
+            .to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let receive = |origin: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!("/api/session/{}/signal/receive", session.session_id));
+            if let Some(origin) = origin {
+                req = req.insert_header((header::ORIGIN, origin));
+            }
+            req.to_request()
+        };
+
+        assert_eq!(test::call_service(&app, receive(Some("https://embed.example"))).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, receive(Some("https://other.example"))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, receive(None)).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/api/session/create")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(r#"{"allowed_origins": "https://embed.example"}"#)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        // Sessions created without a body keep working from anywhere
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let open: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", open.session_id))
+            .insert_header((header::ORIGIN, "https://other.example"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_session_code_collisions_are_bounded() {
+        let app_state = web::Data::new(
+            AppState::new()
+                .with_code_generator(|| "SAME01".to_string())
+                .with_max_code_collision_retries(3),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(metrics::metrics)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert_eq!(error.code, "session_codes_exhausted");
+
+        // The first try plus three retries all collided
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("drop_session_code_collisions_total 4\n"), "{}", body);
+    }
+
+    #[actix_web::test]
+    async fn test_send_signal_batch() {
+        let app_state = web::Data::new(AppState::new().with_max_signal_batch(3));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal_batch)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let batch_uri = format!("/api/session/{}/signal/send-batch", session.session_id);
+        let mut candidates: Vec<SignalingMessage> = (0..3)
+            .map(|i| SignalingMessage { message_type: "candidate".to_string(), payload: host_candidate(i), from: None })
+            .collect();
+        // As a browser sends it, with attributes a re-serialized line would lose
+        candidates[1].payload = r#"{"candidate":"candidate:3 1 tcp 1518280447 203.0.113.7 9 typ srflx raddr 10.0.0.5 rport 9 tcptype active generation 0 ufrag EsAw network-id 1","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"EsAw"}"#.to_string();
+
+        let req = test::TestRequest::post().uri(&batch_uri).set_json(&candidates).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"1\"");
+
+        // One oversized message rejects the whole batch
+        let mut bad = candidates.clone();
+        bad[1].payload = "a".repeat(config::DEFAULT_MAX_SIGNAL_PAYLOAD + 1);
+        let req = test::TestRequest::post().uri(&batch_uri).set_json(&bad).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let mut four = candidates.clone();
+        four.push(candidates[0].clone());
+        let req = test::TestRequest::post().uri(&batch_uri).set_json(&four).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // So does one malformed candidate
+        let mut malformed = candidates.clone();
+        malformed[2].payload = "candidate:2".to_string();
+        let req = test::TestRequest::post().uri(&batch_uri).set_json(&malformed).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert_eq!(error.code, "invalid_candidate");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", session.session_id))
+            .to_request();
+        let received: Vec<SignalingMessage> = test::call_and_read_body_json(&app, req).await;
+        let payloads: Vec<&str> = received.iter().map(|m| m.payload.as_str()).collect();
+        assert_eq!(payloads, candidates.iter().map(|m| m.payload.as_str()).collect::<Vec<_>>());
+    }
+
+    #[actix_web::test]
+    async fn test_send_signal_payload_limit() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let send = |payload: String| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session.session_id))
+                .set_json(SignalingMessage { message_type: "offer".to_string(), payload, from: None })
+                .to_request()
+        };
+
+        let sdp = "v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n".repeat(40);
+        assert_eq!(test::call_service(&app, send(sdp)).await.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, send("a".repeat(config::DEFAULT_MAX_SIGNAL_PAYLOAD + 1))).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert_eq!(error.code, "payload_too_large");
+        assert_eq!(app_state.sessions.get(&session.session_id).unwrap().messages.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_session_byte_cap() {
+        let app_state = web::Data::new(
+            AppState::new().with_admin_token("s3cret").with_session_byte_cap(20),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(list_sessions)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let send = |payload: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session.session_id))
+                .set_json(SignalingMessage { message_type: "offer".to_string(), payload: payload.to_string(), from: None })
+                .to_request()
+        };
+
+        // 5 + 5 bytes each, landing exactly on the cap
+        for _ in 0..2 {
+            assert_eq!(test::call_service(&app, send("sdp-1")).await.status(), StatusCode::OK);
+        }
+        assert_eq!(test::call_service(&app, send("x")).await.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/sessions")
+            .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+            .to_request();
+        let sessions: Vec<SessionSummary> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].bytes_relayed, 20);
+        assert_eq!(sessions[0].queued_messages, 2);
+        assert_eq!(sessions[0].byte_cap, Some(20));
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_signal_names_the_problem() {
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .app_data(json_config())
+                .service(create_session)
+                .service(send_signal)
+        ).await;
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let post = |body: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session.session_id))
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, post(r#"{"payload": "v=0"}"#)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert_eq!(error.code, "invalid_json");
+        assert!(error.message.contains("missing field `message_type`"), "{}", error.message);
+
+        let resp = test::call_service(&app, post(r#"{"message_type": "offer", "payload": 7}"#)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert!(error.message.contains("invalid type"), "{}", error.message);
+
+        // Not JSON at all is still a 415
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", session.session_id))
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload(r#"{"message_type": "offer", "payload": "v=0"}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let error: ApiError = test::read_body_json(resp).await;
+        assert_eq!(error.code, "unsupported_media_type");
+        assert!(app_state.sessions.get(&session.session_id).unwrap().messages.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_admin_stats_and_reset() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(AppState::with_clock(clock.clone()).with_admin_token("s3cret"));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal_batch)
+                .service(admin_stats)
+                .service(reset_admin_stats)
+        ).await;
+        let admin = |req: test::TestRequest| req.insert_header((header::AUTHORIZATION, "Bearer s3cret")).to_request();
+
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/session/create").to_request();
+            let session: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+            sessions.push(session.session_id);
+        }
+        let candidates: Vec<SignalingMessage> = (0..3)
+            .map(|i| SignalingMessage { message_type: "candidate".to_string(), payload: host_candidate(i), from: None })
+            .collect();
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send-batch", sessions[0]))
+            .set_json(&candidates)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        clock.advance(Duration::from_secs(90));
+
+        let stats: ServerStats = test::call_and_read_body_json(&app, admin(test::TestRequest::get().uri("/api/admin/stats"))).await;
+        assert_eq!(stats, ServerStats { sessions_created: 2, active_sessions: 2, messages_relayed: 3, uptime_secs: 90 });
+
+        // Token-guarded like the rest of the admin API
+        let req = test::TestRequest::post().uri("/api/admin/stats/reset").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let stats: ServerStats = test::call_and_read_body_json(&app, admin(test::TestRequest::post().uri("/api/admin/stats/reset"))).await;
+        assert_eq!((stats.sessions_created, stats.messages_relayed), (0, 0));
+
+        // Live sessions and uptime aren't counters, so they survive the reset
+        let stats: ServerStats = test::call_and_read_body_json(&app, admin(test::TestRequest::get().uri("/api/admin/stats"))).await;
+        assert_eq!(stats, ServerStats { sessions_created: 0, active_sessions: 2, messages_relayed: 0, uptime_secs: 90 });
+    }
+
+    #[actix_web::test]
+    async fn test_signaling_payloads_encrypted_at_rest() {
+        let app_state = web::Data::new(AppState::new().with_payload_encryption(crypto::Crypto::new()));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        let signal_msg = SignalingMessage {
+            message_type: "offer".to_string(),
+            payload: "v=0 o=- 4611731400430051336 2 IN IP4 127.0.0.1".to_string(),
+            from: None,
+        };
+        let send_req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", session_id))
+            .set_json(&signal_msg)
+            .to_request();
+        assert_eq!(test::call_service(&app, send_req).await.status(), StatusCode::OK);
+
+        {
+            let stored = app_state.sessions.get(&session_id).unwrap();
+            assert_eq!(stored.messages[0].message_type, "offer");
+            assert_ne!(stored.messages[0].payload, signal_msg.payload);
+            assert!(!stored.messages[0].payload.contains("IN IP4"));
+        }
+
+        let receive_req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", session_id))
+            .to_request();
+        let received: Vec<SignalingMessage> = test::call_and_read_body_json(&app, receive_req).await;
+        assert_eq!(received[0].payload, signal_msg.payload);
+    }
+
+    #[actix_web::test]
+    async fn test_undecryptable_message_is_dropped_alone() {
+        let app_state = web::Data::new(AppState::new().with_payload_encryption(crypto::Crypto::new()));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        let send = |message_type: &str, payload: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session_id))
+                .set_json(SignalingMessage { message_type: message_type.to_string(), payload: payload.to_string(), from: None })
+                .to_request()
+        };
+        let (first, last) = (host_candidate(1), host_candidate(2));
+        for (message_type, payload) in [("candidate", &*first), ("answer", "bad"), ("candidate", &*last), ("answer", "sdp")] {
+            assert_eq!(test::call_service(&app, send(message_type, payload)).await.status(), StatusCode::OK);
+        }
+        // As if sealed under a key the server no longer has
+        app_state.sessions.get_mut(&session_id).unwrap().messages[1].payload = "not sealed".to_string();
+
+        let wait = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive?wait_for=answer", session_id))
+            .to_request();
+        let answered: Vec<SignalingMessage> = test::call_and_read_body_json(&app, wait).await;
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].payload, "sdp");
+
+        app_state.sessions.get_mut(&session_id).unwrap().messages[0].payload = "not sealed".to_string();
+        let poll = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", session_id))
+            .to_request();
+        let rest: Vec<SignalingMessage> = test::call_and_read_body_json(&app, poll).await;
+        assert_eq!(rest.iter().map(|m| &m.payload).collect::<Vec<_>>(), vec![&last]);
+    }
+
+    #[actix_web::test]
+    async fn test_health_webrtc() {
+        let app = test::init_service(App::new().service(health).service(health_webrtc)).await;
+        let req = test::TestRequest::get().uri("/health").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/health/webrtc").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_nat_check_reports_symmetric_nat() {
+        let mapped = ["203.0.113.5:40000", "203.0.113.5:40001"];
+        let mut servers = Vec::new();
+        for addr in mapped {
+            servers.push(nat::tests::mock_stun_server(addr.parse().unwrap()).await.to_string());
+        }
+        let app_state = web::Data::new(AppState::new().with_stun_servers(servers).with_admin_token("t"));
+        let app = test::init_service(App::new().app_data(app_state.clone()).service(nat_check)).await;
+
+        let req = test::TestRequest::get().uri("/api/admin/nat-check").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/nat-check")
+            .insert_header((header::AUTHORIZATION, "Bearer t"))
+            .to_request();
+        let report: nat::NatReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report.nat_type, nat::NatType::Symmetric);
+        assert_eq!(report.candidate_type.as_deref(), Some("srflx"));
+        assert_eq!(report.hint, nat::NatType::Symmetric.hint());
+    }
+
+    #[actix_web::test]
+    async fn test_receive_signal_batches_with_has_more() {
+        let app_state = web::Data::new(AppState::new().with_max_messages_per_poll(20));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        for i in 0..50 {
+            let msg = SignalingMessage {
+                message_type: "candidate".to_string(),
+                payload: host_candidate(i),
+                from: None,
+            };
+            let send_req = test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session_id))
+                .set_json(&msg)
+                .to_request();
+            assert_eq!(test::call_service(&app, send_req).await.status(), StatusCode::OK);
+        }
+
+        let mut received = Vec::new();
+        let mut flags = Vec::new();
+        loop {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/session/{}/signal/receive", session_id))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let has_more = resp.headers().get(HAS_MORE_HEADER).unwrap().to_str().unwrap() == "true";
+            let batch: Vec<SignalingMessage> = test::read_body_json(resp).await;
+            assert!(batch.len() <= 20);
+            received.extend(batch);
+            flags.push(has_more);
+            if !has_more {
+                break;
+            }
+        }
+        assert_eq!(flags, vec![true, true, false]);
+        assert_eq!(received.len(), 50);
+        assert_eq!(received[0].payload, host_candidate(0));
+        assert_eq!(received[49].payload, host_candidate(49));
+    }
+
+    #[actix_web::test]
+    async fn test_wait_for_skips_other_message_types() {
+        let app_state = web::Data::new(AppState::new().with_signal_wait_timeout(Duration::from_secs(5)));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        let send = |message_type: &str, payload: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session_id))
+                .set_json(SignalingMessage { message_type: message_type.to_string(), payload: payload.to_string(), from: None })
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, send("candidate", &host_candidate(1))).await.status(), StatusCode::OK);
+
+        // Held open past the candidate until the answer shows up
+        let poll = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive?wait_for=answer", session_id))
+            .to_request();
+        let (answered, sent) = tokio::join!(test::call_and_read_body_json::<_, _, Vec<SignalingMessage>>(&app, poll), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            test::call_service(&app, send("answer", "sdp")).await.status()
+        });
+        assert_eq!(sent, StatusCode::OK);
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].message_type, "answer");
+
+        // The candidate was left queued for a plain poll
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", session_id))
+            .to_request();
+        let rest: Vec<SignalingMessage> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].payload, host_candidate(1));
+    }
+
+    #[actix_web::test]
+    async fn test_wait_for_times_out_on_the_injected_clock() {
+        let clock = Arc::new(clock::MockClock::new());
+        let app_state = web::Data::new(AppState::with_clock(clock.clone()).with_signal_wait_timeout(Duration::from_secs(60)));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let session_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        let poll = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive?wait_for=answer", session_id))
+            .to_request();
+        let candidate = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", session_id))
+            .set_json(SignalingMessage { message_type: "candidate".to_string(), payload: host_candidate(1), from: None })
+            .to_request();
+        // Past the wait on the mock clock, the next wakeup returns an empty
+        // batch instead of waiting out the minute for real
+        let (answered, sent) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(test::call_and_read_body_json::<_, _, Vec<SignalingMessage>>(&app, poll), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                clock.advance(Duration::from_secs(61));
+                test::call_service(&app, candidate).await.status()
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(sent, StatusCode::OK);
+        assert!(answered.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_send_only_wakes_its_own_session() {
+        use futures::FutureExt;
+        let app_state = web::Data::new(AppState::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(send_signal)
+        ).await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/session/create").to_request();
+            ids.push(test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id);
+        }
+        let send = |session_id: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/session/{}/signal/send", session_id))
+                .set_json(SignalingMessage { message_type: "answer".to_string(), payload: "sdp".to_string(), from: None })
+                .to_request()
+        };
+        let notify = app_state.sessions.get(&ids[0]).unwrap().queued.clone();
+        let waiting = notify.notified();
+        tokio::pin!(waiting);
+        waiting.as_mut().enable();
+
+        assert_eq!(test::call_service(&app, send(&ids[1])).await.status(), StatusCode::OK);
+        assert!(waiting.as_mut().now_or_never().is_none());
+        assert_eq!(test::call_service(&app, send(&ids[0])).await.status(), StatusCode::OK);
+        assert!(waiting.now_or_never().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_rotate_session_moves_queue() {
+        let app_state = web::Data::new(AppState::new().with_session_secrets(true));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(create_session)
+                .service(rotate_session)
+                .service(send_signal)
+                .service(receive_signal)
+        ).await;
+
+        let req = test::TestRequest::post().uri("/api/session/create").to_request();
+        let created: CreateSessionResponse = test::call_and_read_body_json(&app, req).await;
+        let (old_id, secret) = (created.session_id, created.secret.unwrap());
+        let msg = SignalingMessage {
+            message_type: "offer".to_string(),
+            payload: "sdp".to_string(),
+            from: None,
+        };
+        let send_req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/signal/send", old_id))
+            .set_json(&msg)
+            .to_request();
+        assert_eq!(test::call_service(&app, send_req).await.status(), StatusCode::OK);
+
+        // Knowing the code isn't enough, that's what the joiner has too
+        for auth in [None, Some("Bearer wrong")] {
+            let mut req = test::TestRequest::post().uri(&format!("/api/session/{}/rotate", old_id));
+            if let Some(auth) = auth {
+                req = req.insert_header((header::AUTHORIZATION, auth));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/rotate", old_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", secret)))
+            .to_request();
+        let new_id = test::call_and_read_body_json::<_, _, CreateSessionResponse>(&app, req).await.session_id;
+        assert_ne!(new_id, old_id);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", old_id))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/session/{}/rotate", old_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", secret)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        // Without a secret nobody may rotate, and the session stays put
+        app_state.sessions.get_mut(&new_id).unwrap().secret = None;
+        let req = test::TestRequest::post().uri(&format!("/api/session/{}/rotate", new_id)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        app_state.sessions.get_mut(&new_id).unwrap().secret = Some(secret);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/session/{}/signal/receive", new_id))
+            .to_request();
+        let messages: Vec<SignalingMessage> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, "sdp");
+    }
+
+    // Sizes in KiB, chunked at 100 KiB
+    fn metadata_with_chunks(size: u64, chunks: &[(u32, u64)]) -> FileMetadata {
+        FileMetadata {
+            size: size * 1024,
+            chunks: chunks
+                .iter()
+                .map(|&(index, size)| ChunkInfo { index, size: size * 1024, ..ChunkInfo::default() })
+                .collect(),
+            chunk_size: Some(100 * 1024),
+            ..FileMetadata::default()
+        }
+    }
+
+    fn versioned(hashes: &str) -> FileMetadata {
+        let chunks: Vec<ChunkInfo> = hashes
+            .chars()
+            .enumerate()
+            .map(|(index, hash)| ChunkInfo { index: index as u32, size: 100, hash: hash.to_string(), zero: false })
+            .collect();
+        FileMetadata { size: 100 * chunks.len() as u64, chunks, ..FileMetadata::default() }
+    }
+
+    #[actix_web::test]
+    async fn test_abort_reasons_round_trip_and_map_to_errors() {
+        let reasons = [
+            AbortReason::UserCancelled,
+            AbortReason::Rejected,
+            AbortReason::Timeout,
+            AbortReason::Unsupported,
+            AbortReason::Internal,
+        ];
+        for reason in reasons {
+            let frame = serde_json::to_vec(&TransferCommand::Abort { reason, detail: "why".to_string() }).unwrap();
+            match serde_json::from_slice(&frame).unwrap() {
+                TransferCommand::Abort { reason: decoded, detail } => assert_eq!((decoded, detail.as_str()), (reason, "why")),
+                other => panic!("expected Abort, got {:?}", other),
+            }
+        }
+        // `detail` may be left out
+        let bare: TransferCommand = serde_json::from_str(r#"{"Abort":{"reason":"Timeout"}}"#).unwrap();
+        assert!(matches!(bare, TransferCommand::Abort { reason: AbortReason::Timeout, .. }));
+
+        assert!(matches!(AbortReason::UserCancelled.into_error(""), DropError::Cancelled));
+        assert!(matches!(AbortReason::Timeout.into_error("slow"), DropError::Timeout(m) if m.contains("slow")));
+        assert!(matches!(AbortReason::Rejected.into_error("bad"), DropError::Protocol(m) if m.contains("rejected")));
+        assert!(matches!(AbortReason::Unsupported.into_error("v9"), DropError::Protocol(m) if m.contains("support")));
+        assert!(matches!(AbortReason::Internal.into_error("disk"), DropError::Protocol(m) if m == "peer error: disk"));
+
+        // Our own failures pick a reason that maps back to the same kind of error
+        for error in [DropError::Cancelled, DropError::Timeout("t".to_string())] {
+            let mapped = AbortReason::for_error(&error).into_error("");
+            assert_eq!(mapped.category(), error.category());
+        }
+        assert_eq!(AbortReason::for_error(&DropError::Crypto("key".to_string())), AbortReason::Rejected);
+        assert_eq!(AbortReason::for_error(&DropError::Protocol("x".to_string())), AbortReason::Internal);
+    }
+
+    // `test` here is actix_web's, so name the plain attribute in full
+    #[::core::prelude::v1::test]
+    fn test_metadata_diff() {
+        let original = versioned("abcd");
+        assert_eq!(original.diff(&original), ChunkDiff::default());
+
+        let appended = original.diff(&versioned("abcdef"));
+        assert_eq!(appended, ChunkDiff { changed: vec![], added: vec![4, 5], removed: vec![] });
+
+        let edited = original.diff(&versioned("aXcd"));
+        assert_eq!(edited, ChunkDiff { changed: vec![1], added: vec![], removed: vec![] });
+        let needed = edited.needed();
+        assert!(needed.contains(&1) && !needed.contains(&2));
+
+        let truncated = original.diff(&versioned("aY"));
+        assert_eq!(truncated, ChunkDiff { changed: vec![1], added: vec![], removed: vec![2, 3] });
+
+        let rechunked = versioned("ab");
+        let rechunked = FileMetadata { chunk_size: Some(2 * transfer::CHUNK_SIZE as u64), ..rechunked };
+        assert_eq!(original.diff(&rechunked).changed, vec![0, 1]);
+    }
+
+    #[actix_web::test]
+    async fn test_metadata_validation() {
+        assert!(metadata_with_chunks(0, &[]).validate().is_ok());
+        assert!(metadata_with_chunks(250, &[(0, 100), (1, 100), (2, 50)]).validate().is_ok());
+
+        let gap = metadata_with_chunks(200, &[(0, 100), (2, 100)]).validate().unwrap_err();
+        assert!(gap.to_string().contains("not contiguous"), "{}", gap);
+
+        let mismatch = metadata_with_chunks(300, &[(0, 100), (1, 100)]).validate().unwrap_err();
+        assert!(mismatch.to_string().contains(&format!("sum to {}", 200 * 1024)), "{}", mismatch);
+
+        let short = metadata_with_chunks(250, &[(0, 100), (1, 50), (2, 100)]).validate().unwrap_err();
+        assert!(short.to_string().contains("chunk 1 short"), "{}", short);
+    }
 } 
\ No newline at end of file
@@ -1,7 +1,24 @@
+use std::path::{Path, PathBuf};
 use drop_backend::start_actix_server;
+use drop_backend::transfer::{verify_file, VerifyReport};
+use drop_backend::ws::WsTransfer;
+use drop_backend::{FileMetadata, TransferProtocol};
+
+const VERIFY_USAGE: &str = "usage: drop verify <file> --metadata <meta.json>";
+const RECEIVE_USAGE: &str = "usage: drop receive --code <code> [--server <url>] (--stdout | --out <path>)";
+const SEND_USAGE: &str = "usage: drop send --code <code> [--server <url>] (<path> | --name <name>, reading stdin)";
+const DEFAULT_SERVER: &str = "http://127.0.0.1:8080";
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("verify") => std::process::exit(verify(&args[1..])),
+        Some("receive") => std::process::exit(receive(&args[1..]).await),
+        Some("send") => std::process::exit(send(&args[1..]).await),
+        _ => {}
+    }
+
     // You can add any other backend initialization logic here
     println!("Initializing drop_backend...");
 
@@ -13,3 +30,152 @@ async fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+// `drop verify`: exits 0 when the file matches, 1 on a mismatch, 2 on bad
+// arguments or unreadable input
+fn verify(args: &[String]) -> i32 {
+    let (mut file, mut metadata) = (None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metadata" => metadata = args.next(),
+            _ if file.is_none() && !arg.starts_with("--") => file = Some(arg),
+            _ => {
+                eprintln!("{}", VERIFY_USAGE);
+                return 2;
+            }
+        }
+    }
+    let (Some(file), Some(metadata)) = (file, metadata) else {
+        eprintln!("{}", VERIFY_USAGE);
+        return 2;
+    };
+
+    let report = match load_and_verify(Path::new(file), Path::new(metadata)) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("verify failed: {:#}", e);
+            return 2;
+        }
+    };
+    println!("{}: {} chunks checked", file, report.chunks_checked);
+    if report.is_ok() {
+        println!("OK: every chunk and the file hash match");
+        return 0;
+    }
+    if !report.size_matches {
+        println!("size does not match the metadata");
+    }
+    if !report.mismatched_chunks.is_empty() {
+        let indices: Vec<String> = report.mismatched_chunks.iter().map(u32::to_string).collect();
+        println!("mismatched chunks: {}", indices.join(", "));
+    }
+    if !report.file_hash_matches {
+        println!("file hash does not match");
+    }
+    1
+}
+
+fn load_and_verify(file: &Path, metadata: &Path) -> anyhow::Result<VerifyReport> {
+    let metadata: FileMetadata = serde_json::from_slice(&std::fs::read(metadata)?)?;
+    Ok(verify_file(file, &metadata)?)
+}
+
+enum ReceiveTarget {
+    // Chunks are written to stdout in order as they verify; nothing touches disk
+    Stdout,
+    File(PathBuf),
+}
+
+// `drop receive`: fetch a file over the server's relay. Exits 0 on success, 1
+// if the transfer failed and 2 on bad arguments.
+async fn receive(args: &[String]) -> i32 {
+    let (mut code, mut server, mut target) = (None, DEFAULT_SERVER, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--code" => code = args.next(),
+            "--server" if args.len() > 0 => server = args.next().unwrap(),
+            "--stdout" if target.is_none() => target = Some(ReceiveTarget::Stdout),
+            "--out" if target.is_none() => target = args.next().map(|p| ReceiveTarget::File(PathBuf::from(p))),
+            _ => {
+                eprintln!("{}", RECEIVE_USAGE);
+                return 2;
+            }
+        }
+    }
+    let (Some(code), Some(target)) = (code, target) else {
+        eprintln!("{}", RECEIVE_USAGE);
+        return 2;
+    };
+
+    // Only the file's bytes go to stdout, which may be a pipe
+    match receive_into(server, code, target).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("receive failed: {:#}", e);
+            1
+        }
+    }
+}
+
+async fn receive_into(server: &str, code: &str, target: ReceiveTarget) -> anyhow::Result<()> {
+    let mut transfer = WsTransfer::connect(server, code).await?;
+    match target {
+        ReceiveTarget::Stdout => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            transfer.receive_to_writer(&mut out).await?;
+        }
+        ReceiveTarget::File(path) => transfer.receive_file(path).await?,
+    }
+    Ok(())
+}
+
+enum SendSource {
+    // Read to the end and streamed without knowing the size up front
+    Stdin(String),
+    File(PathBuf),
+}
+
+// `drop send`: offer a file, or stdin under `--name`, over the server's relay.
+// Exits 0 on success, 1 if the transfer failed and 2 on bad arguments.
+async fn send(args: &[String]) -> i32 {
+    let (mut code, mut server, mut source) = (None, DEFAULT_SERVER, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--code" => code = args.next(),
+            "--server" if args.len() > 0 => server = args.next().unwrap(),
+            "--name" if source.is_none() => source = args.next().map(|n| SendSource::Stdin(n.clone())),
+            _ if source.is_none() && !arg.starts_with("--") => source = Some(SendSource::File(PathBuf::from(arg))),
+            _ => {
+                eprintln!("{}", SEND_USAGE);
+                return 2;
+            }
+        }
+    }
+    let (Some(code), Some(source)) = (code, source) else {
+        eprintln!("{}", SEND_USAGE);
+        return 2;
+    };
+
+    match send_from(server, code, source).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("send failed: {:#}", e);
+            1
+        }
+    }
+}
+
+async fn send_from(server: &str, code: &str, source: SendSource) -> anyhow::Result<()> {
+    let mut transfer = WsTransfer::connect(server, code).await?;
+    match source {
+        SendSource::Stdin(name) => {
+            transfer.send_reader(&mut tokio::io::stdin(), &name).await?;
+        }
+        SendSource::File(path) => transfer.send_file(path).await?,
+    }
+    Ok(())
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use indicatif::ProgressBar;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::{CancellationToken, DropError, Result};
+
+// Caps how many transfers run at once in this process. Transfers beyond the
+// limit wait their turn, or are rejected once `max_queued` are already waiting.
+#[derive(Clone)]
+pub struct TransferManager {
+    inner: Arc<Inner>,
+    max_queued: Option<usize>,
+}
+
+struct Inner {
+    semaphore: Arc<Semaphore>,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    next_id: AtomicU64,
+    // Every transfer currently queued or running, by id
+    transfers: Mutex<HashMap<u64, Tracked>>,
+}
+
+struct Tracked {
+    cancel: CancellationToken,
+    progress: Option<ProgressBar>,
+    active: bool,
+}
+
+// Snapshot of one managed transfer, as returned by `TransferManager::list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferHandle {
+    pub id: u64,
+    // False while still waiting for a slot
+    pub active: bool,
+    pub bytes_done: u64,
+    // Unknown until the transfer reports a progress bar with a length
+    pub total_bytes: Option<u64>,
+}
+
+// Handed to a transfer started with `run_with`
+pub struct TransferContext {
+    id: u64,
+    cancel: CancellationToken,
+    inner: Arc<Inner>,
+}
+
+impl TransferContext {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Cancelled by `TransferManager::cancel_all`; pass it to the protocol
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    // Report progress through `bar`, e.g. `FileTransfer::progress_bar`
+    pub fn track_progress(&self, bar: &ProgressBar) {
+        if let Some(tracked) = self.inner.transfers.lock().unwrap().get_mut(&self.id) {
+            tracked.progress = Some(bar.clone());
+        }
+    }
+}
+
+// Removes the transfer from the registry however it ends
+struct Registration {
+    id: u64,
+    cancel: CancellationToken,
+    inner: Arc<Inner>,
+}
+
+impl Registration {
+    fn mark_active(&self) {
+        if let Some(tracked) = self.inner.transfers.lock().unwrap().get_mut(&self.id) {
+            tracked.active = true;
+        }
+    }
+
+    fn context(&self) -> TransferContext {
+        TransferContext {
+            id: self.id,
+            cancel: self.cancel.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.inner.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Held for the lifetime of an active transfer
+struct ActiveSlot {
+    _permit: OwnedSemaphorePermit,
+    inner: Arc<Inner>,
+}
+
+impl Drop for ActiveSlot {
+    fn drop(&mut self) {
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct QueuedSlot(Arc<Inner>);
+
+impl Drop for QueuedSlot {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl TransferManager {
+    pub fn new(max_active: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                semaphore: Arc::new(Semaphore::new(max_active)),
+                active: AtomicUsize::new(0),
+                queued: AtomicUsize::new(0),
+                next_id: AtomicU64::new(1),
+                transfers: Mutex::new(HashMap::new()),
+            }),
+            max_queued: None,
+        }
+    }
+
+    // Reject instead of queueing once this many transfers are already waiting
+    pub fn with_max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = Some(max_queued);
+        self
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.inner.queued.load(Ordering::SeqCst)
+    }
+
+    // Queued and running transfers, ordered by id
+    pub fn list(&self) -> Vec<TransferHandle> {
+        let transfers = self.inner.transfers.lock().unwrap();
+        let mut handles: Vec<TransferHandle> = transfers
+            .iter()
+            .map(|(&id, tracked)| TransferHandle {
+                id,
+                active: tracked.active,
+                bytes_done: tracked.progress.as_ref().map_or(0, |bar| bar.position()),
+                total_bytes: tracked.progress.as_ref().and_then(|bar| bar.length()),
+            })
+            .collect();
+        handles.sort_by_key(|h| h.id);
+        handles
+    }
+
+    // Kill switch: cancel every queued and running transfer
+    pub fn cancel_all(&self) {
+        for tracked in self.inner.transfers.lock().unwrap().values() {
+            tracked.cancel.cancel();
+        }
+    }
+
+    // Run `transfer` once a slot is free. It is dropped, returning
+    // `DropError::Cancelled`, if `cancel_all` is called in the meantime.
+    pub async fn run<F, T>(&self, transfer: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        self.run_with(|ctx| async move {
+            tokio::select! {
+                result = transfer => result,
+                _ = ctx.cancel.cancelled() => Err(DropError::Cancelled),
+            }
+        })
+        .await
+    }
+
+    // Like `run`, but the transfer gets a `TransferContext` so it can report
+    // progress and wind down cleanly on its own cancellation token.
+    pub async fn run_with<F, Fut, T>(&self, start: F) -> Result<T>
+    where
+        F: FnOnce(TransferContext) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let registration = self.register();
+        let _slot = tokio::select! {
+            slot = self.acquire() => slot?,
+            _ = registration.cancel.cancelled() => return Err(DropError::Cancelled),
+        };
+        registration.mark_active();
+        start(registration.context()).await
+    }
+
+    fn register(&self) -> Registration {
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        let cancel = CancellationToken::new();
+        self.inner.transfers.lock().unwrap().insert(id, Tracked {
+            cancel: cancel.clone(),
+            progress: None,
+            active: false,
+        });
+        Registration {
+            id,
+            cancel,
+            inner: self.inner.clone(),
+        }
+    }
+
+    async fn acquire(&self) -> Result<ActiveSlot> {
+        let permit = match self.inner.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let max_queued = self.max_queued.unwrap_or(usize::MAX);
+                self.inner
+                    .queued
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                        (queued < max_queued).then_some(queued + 1)
+                    })
+                    .map_err(|_| DropError::Protocol("too many active transfers".to_string()))?;
+                let _queued = QueuedSlot(self.inner.clone());
+                self.inner
+                    .semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| DropError::Protocol("transfer manager closed".to_string()))?
+            }
+        };
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        Ok(ActiveSlot {
+            _permit: permit,
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_third_transfer_queues_until_one_completes() {
+        let manager = TransferManager::new(2);
+        let mut releases = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let (tx, rx) = oneshot::channel::<()>();
+            releases.push(tx);
+            let manager = manager.clone();
+            handles.push(tokio::spawn(async move {
+                manager.run(async move { rx.await.map_err(|_| DropError::Protocol("dropped".into())) }).await
+            }));
+        }
+        settle().await;
+        assert_eq!(manager.active_count(), 2);
+        assert_eq!(manager.queued_count(), 1);
+
+        releases.remove(0).send(()).unwrap();
+        settle().await;
+        assert_eq!(manager.active_count(), 2);
+        assert_eq!(manager.queued_count(), 0);
+
+        for tx in releases {
+            tx.send(()).unwrap();
+        }
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+        assert_eq!(manager.active_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_beyond_queue_limit() {
+        let manager = TransferManager::new(1).with_max_queued(0);
+        let (tx, rx) = oneshot::channel::<()>();
+        let running = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.run(async move { Ok(rx.await.is_ok()) }).await })
+        };
+        settle().await;
+
+        let err = manager.run(async { Ok(()) }).await.unwrap_err();
+        assert!(err.to_string().contains("too many active transfers"));
+
+        tx.send(()).unwrap();
+        assert!(running.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_stops_loopback_transfers() {
+        use crate::protocol::{loopback, Protocol};
+        use crate::transfer::FileTransfer;
+
+        let dir = tempfile::tempdir().unwrap();
+        let manager = TransferManager::new(4);
+        let mut peers = Vec::new();
+        let mut handles = Vec::new();
+        for i in 0..2 {
+            let path = dir.path().join(format!("file{}.bin", i));
+            std::fs::write(&path, vec![i as u8; 4096]).unwrap();
+            // Nobody answers on the other end, so the send waits until cancelled
+            let (a, b) = loopback();
+            peers.push(b);
+            let manager = manager.clone();
+            handles.push(tokio::spawn(async move {
+                manager
+                    .run_with(|ctx| async move {
+                        let mut file = FileTransfer::new(path);
+                        file.prepare_metadata().await?;
+                        ctx.track_progress(file.progress_bar());
+                        Protocol::new(a)
+                            .with_cancellation_token(ctx.cancellation_token())
+                            .send(&mut file)
+                            .await
+                    })
+                    .await
+            }));
+        }
+        settle().await;
+        let listed = manager.list();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|h| h.active && h.total_bytes == Some(4096)));
+        assert_ne!(listed[0].id, listed[1].id);
+
+        manager.cancel_all();
+        for handle in handles {
+            let result = tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+            assert!(matches!(result, Err(DropError::Cancelled)));
+        }
+        assert!(manager.list().is_empty());
+        assert_eq!(manager.active_count(), 0);
+    }
+}
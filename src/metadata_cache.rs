@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use crate::hash::sha256_hex;
+use crate::{FileMetadata, IntegrityScheme, Result};
+
+// On-disk cache of `FileMetadata`, so re-sending an unchanged file skips
+// hashing it. There is one entry per (path, chunk size, integrity scheme); it
+// is only used while the file's size and mtime still match what was hashed,
+// and is overwritten the next time the file is hashed.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    mtime_nanos: u128,
+    metadata: FileMetadata,
+}
+
+impl MetadataCache {
+    // Entries live as JSON files in `dir`, which is created on first store
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    // Cached metadata for `path`, or None if there's no entry or the file
+    // changed since it was written
+    pub fn lookup(&self, path: &Path, chunk_size: usize, integrity: IntegrityScheme) -> Option<FileMetadata> {
+        let path = canonical(path);
+        let (size, mtime_nanos) = file_stamp(&path).ok()?;
+        let bytes = std::fs::read(self.entry_path(&path, chunk_size, integrity)).ok()?;
+        let entry: CacheEntry = serde_json::from_slice(&bytes).ok()?;
+        let valid = entry.path == path
+            && entry.size == size
+            && entry.mtime_nanos == mtime_nanos
+            && entry.metadata.integrity == integrity;
+        valid.then_some(entry.metadata)
+    }
+
+    // Cache `metadata` for `path`, given the file's `file_stamp` from before
+    // it was hashed. Nothing is stored if the file changed since, as the
+    // hashes could then describe a mix of its old and new contents.
+    pub fn store(&self, path: &Path, chunk_size: usize, stamp: (u64, u128), metadata: &FileMetadata) -> Result<()> {
+        let path = canonical(path);
+        let (size, mtime_nanos) = file_stamp(&path)?;
+        if (size, mtime_nanos) != stamp || size != metadata.size {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let target = self.entry_path(&path, chunk_size, metadata.integrity);
+        let entry = CacheEntry { path, size, mtime_nanos, metadata: metadata.clone() };
+        // Write then rename, so a concurrent lookup never sees half an entry
+        let partial = target.with_extension("tmp");
+        std::fs::write(&partial, serde_json::to_vec(&entry)?)?;
+        std::fs::rename(partial, target)?;
+        Ok(())
+    }
+
+    fn entry_path(&self, path: &Path, chunk_size: usize, integrity: IntegrityScheme) -> PathBuf {
+        let key = format!("{}\0{}\0{:?}", path.display(), chunk_size, integrity);
+        self.dir.join(format!("{}.json", sha256_hex(key.as_bytes())))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Size and mtime (nanoseconds since the epoch), enough to notice a file changed
+pub(crate) fn file_stamp(path: &Path) -> Result<(u64, u128)> {
+    let metadata = std::fs::metadata(path)?;
+    let mtime_nanos = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    Ok((metadata.len(), mtime_nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use crate::transfer::{FileTransfer, ProgressConfig, CHUNK_SIZE};
+
+    async fn prepare(path: &Path, cache: &MetadataCache) -> FileMetadata {
+        FileTransfer::with_progress_config(path.to_path_buf(), &ProgressConfig::hidden())
+            .unwrap()
+            .with_metadata_cache(cache.clone())
+            .prepare_metadata()
+            .await
+            .unwrap()
+    }
+
+    fn rewrite_keeping_mtime(path: &Path, data: &[u8], mtime: SystemTime) {
+        std::fs::write(path, data).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_skips_hashing_until_mtime_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache::new(dir.path().join("cache"));
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, vec![1u8; 100_000]).unwrap();
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let first = prepare(&path, &cache).await;
+
+        // Same size and mtime: the stale cached hash proves nothing was re-read
+        rewrite_keeping_mtime(&path, &vec![2u8; 100_000], mtime);
+        let cached = prepare(&path, &cache).await;
+        assert_eq!(cached.hash, first.hash);
+
+        rewrite_keeping_mtime(&path, &vec![2u8; 100_000], mtime + Duration::from_secs(5));
+        let rehashed = prepare(&path, &cache).await;
+        assert_ne!(rehashed.hash, first.hash);
+        assert_eq!(rehashed.hash, prepare(&path, &MetadataCache::new(dir.path().join("empty"))).await.hash);
+    }
+
+    #[tokio::test]
+    async fn test_file_changed_while_hashing_is_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache::new(dir.path().join("cache"));
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, vec![1u8; 100_000]).unwrap();
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let before = file_stamp(&path).unwrap();
+        let metadata = prepare(&path, &MetadataCache::new(dir.path().join("unused"))).await;
+
+        // Same size, new mtime: rewritten after hashing started
+        rewrite_keeping_mtime(&path, &vec![2u8; 100_000], mtime + Duration::from_secs(5));
+        cache.store(&path, CHUNK_SIZE, before, &metadata).unwrap();
+        assert!(cache.lookup(&path, CHUNK_SIZE, metadata.integrity).is_none());
+
+        cache.store(&path, CHUNK_SIZE, file_stamp(&path).unwrap(), &metadata).unwrap();
+        assert!(cache.lookup(&path, CHUNK_SIZE, metadata.integrity).is_some());
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use actix_web::{get, web, HttpResponse, Responder};
+use crate::{AppState, DropError, Result};
+
+// Server counters, exported in the Prometheus text format at /metrics
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Generated session codes that were already taken
+    pub session_code_collisions: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "drop_session_code_collisions_total",
+            "Generated session codes that were already in use",
+            self.session_code_collisions.load(Ordering::Relaxed),
+        );
+        TRANSFERS.render(&mut out);
+        out
+    }
+}
+
+// Counters behind /api/admin/stats. An admin can zero these, so they aren't
+// exported in /metrics, where counters are expected to only go up.
+#[derive(Debug, Default)]
+pub struct AdminStats {
+    pub sessions_created: AtomicU64,
+    // Signaling messages queued for a peer plus relay frames forwarded to one
+    pub messages_relayed: AtomicU64,
+}
+
+impl AdminStats {
+    pub fn reset(&self) {
+        self.sessions_created.store(0, Ordering::Relaxed);
+        self.messages_relayed.store(0, Ordering::Relaxed);
+    }
+}
+
+// `DropError::category` of every error a transfer can fail with, bar `Cancelled`
+const FAILURE_REASONS: [&str; 8] = [
+    "io",
+    "serialization",
+    "protocol",
+    "ble",
+    "webrtc",
+    "crypto",
+    "timeout",
+    "verification",
+];
+
+// Outcomes of every send and receive run by `Protocol` in this process.
+// Process-wide rather than part of `Metrics`, since transfers don't know
+// about any server state.
+#[derive(Debug)]
+pub struct TransferOutcomes {
+    pub completed: AtomicU64,
+    pub cancelled: AtomicU64,
+    // Indexed like FAILURE_REASONS
+    failed: [AtomicU64; FAILURE_REASONS.len()],
+}
+
+pub static TRANSFERS: TransferOutcomes = TransferOutcomes {
+    completed: AtomicU64::new(0),
+    cancelled: AtomicU64::new(0),
+    failed: [const { AtomicU64::new(0) }; FAILURE_REASONS.len()],
+};
+
+impl TransferOutcomes {
+    pub fn record<T>(&self, result: &Result<T>) {
+        let counter = match result {
+            Ok(_) => &self.completed,
+            Err(DropError::Cancelled) => &self.cancelled,
+            Err(e) => {
+                let reason = FAILURE_REASONS.iter().position(|&r| r == e.category()).unwrap_or_default();
+                &self.failed[reason]
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Failures so far with this `DropError::category`
+    pub fn failed(&self, reason: &str) -> u64 {
+        FAILURE_REASONS
+            .iter()
+            .position(|&r| r == reason)
+            .map_or(0, |i| self.failed[i].load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String) {
+        counter(
+            out,
+            "drop_transfers_completed_total",
+            "Sends and receives that finished successfully",
+            self.completed.load(Ordering::Relaxed),
+        );
+        let _ = writeln!(out, "# HELP drop_transfers_failed_total Sends and receives that failed, by error category");
+        let _ = writeln!(out, "# TYPE drop_transfers_failed_total counter");
+        for reason in FAILURE_REASONS {
+            let _ = writeln!(out, "drop_transfers_failed_total{{reason=\"{}\"}} {}", reason, self.failed(reason));
+        }
+        counter(
+            out,
+            "drop_transfers_cancelled_total",
+            "Sends and receives that were cancelled",
+            self.cancelled.load(Ordering::Relaxed),
+        );
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[get("/metrics")]
+pub async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.metrics.render())
+}
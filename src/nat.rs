@@ -0,0 +1,195 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use webrtc::stun::agent::TransactionId;
+use webrtc::stun::message::{Getter, Message, BINDING_REQUEST, BINDING_SUCCESS};
+use webrtc::stun::xoraddr::XorMappedAddress;
+use crate::{DropError, Result};
+
+pub const DEFAULT_STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// What sits between this host and the internet, as seen by STUN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NatType {
+    // The mapped address is our own; host candidates are reachable
+    Open,
+    // Every server saw the same mapping, so a server-reflexive candidate works for any peer
+    Cone,
+    // Each destination got a different mapping
+    Symmetric,
+    // No STUN server answered
+    Blocked,
+}
+
+impl NatType {
+    // The ICE candidate type a peer would most likely connect through
+    pub fn candidate_type(self) -> Option<&'static str> {
+        match self {
+            NatType::Open => Some("host"),
+            NatType::Cone | NatType::Symmetric => Some("srflx"),
+            NatType::Blocked => None,
+        }
+    }
+
+    pub fn hint(self) -> &'static str {
+        match self {
+            NatType::Open => "no NAT — direct connections should work",
+            NatType::Cone => "cone NAT — direct connection likely works",
+            NatType::Symmetric => "symmetric NAT — relay likely required",
+            NatType::Blocked => "UDP blocked — use the WebSocket relay",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatReport {
+    pub nat_type: NatType,
+    pub candidate_type: Option<String>,
+    // Our address as each answering STUN server saw it
+    pub mapped_addresses: Vec<String>,
+    pub hint: String,
+}
+
+// `local` is the address the probe socket sends from. Needs answers from at
+// least two servers to tell cone from symmetric; with one it assumes cone.
+pub fn classify(local: SocketAddr, mapped: &[SocketAddr]) -> NatType {
+    match mapped.first() {
+        None => NatType::Blocked,
+        Some(first) if mapped.iter().any(|m| m != first) => NatType::Symmetric,
+        Some(&first) if first == local => NatType::Open,
+        Some(_) => NatType::Cone,
+    }
+}
+
+// Send a binding request to each of `servers` ("host:port") from one socket
+// and classify the mappings they report. Servers that don't resolve or don't
+// answer within `timeout` are left out.
+pub async fn probe(servers: &[String], timeout: Duration) -> Result<NatReport> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let mut local = None;
+    let mut mapped = Vec::new();
+    for server in servers {
+        let Some(addr) = resolve(server).await else {
+            tracing::debug!(server, "could not resolve STUN server");
+            continue;
+        };
+        if local.is_none() {
+            local = Some(route_address(&socket, addr).await?);
+        }
+        match tokio::time::timeout(timeout, binding(&socket, addr)).await {
+            Ok(Ok(address)) => mapped.push(address),
+            Ok(Err(e)) => tracing::debug!(server, error = %e, "STUN binding failed"),
+            Err(_) => tracing::debug!(server, "STUN server did not answer"),
+        }
+    }
+
+    let nat_type = match local {
+        Some(local) => classify(local, &mapped),
+        None => NatType::Blocked,
+    };
+    Ok(NatReport {
+        nat_type,
+        candidate_type: nat_type.candidate_type().map(str::to_string),
+        mapped_addresses: mapped.iter().map(SocketAddr::to_string).collect(),
+        hint: nat_type.hint().to_string(),
+    })
+}
+
+async fn resolve(server: &str) -> Option<SocketAddr> {
+    tokio::net::lookup_host(server).await.ok()?.find(SocketAddr::is_ipv4)
+}
+
+// The socket is bound to the unspecified address; the address it actually
+// sends from is the one the OS picks for a route to `peer`
+async fn route_address(socket: &UdpSocket, peer: SocketAddr) -> Result<SocketAddr> {
+    let probe = UdpSocket::bind("0.0.0.0:0").await?;
+    probe.connect(peer).await?;
+    Ok(SocketAddr::new(probe.local_addr()?.ip(), socket.local_addr()?.port()))
+}
+
+async fn binding(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+    let stun_error = |e: webrtc::stun::Error| DropError::Protocol(format!("STUN: {}", e));
+    let mut request = Message::new();
+    request
+        .build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])
+        .map_err(stun_error)?;
+    socket.send_to(&request.raw, server).await?;
+
+    let mut buffer = vec![0u8; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await?;
+        let mut response = Message::new();
+        response.raw = buffer[..len].to_vec();
+        // Late answers from a server we already gave up on are skipped
+        if from != server || response.decode().is_err() || response.transaction_id != request.transaction_id {
+            continue;
+        }
+        if response.typ != BINDING_SUCCESS {
+            return Err(DropError::Protocol(format!("STUN server returned {}", response.typ)));
+        }
+        let mut address = XorMappedAddress::default();
+        address.get_from(&response).map_err(stun_error)?;
+        return Ok(SocketAddr::new(address.ip, address.port));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use webrtc::stun::message::Setter;
+
+    // A STUN server on localhost that reports `mapped` as every client's address
+    pub(crate) async fn mock_stun_server(mapped: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                let mut request = Message::new();
+                request.raw = buffer[..len].to_vec();
+                if request.decode().is_err() {
+                    continue;
+                }
+                let response = {
+                    let mut response = Message::new();
+                    let answer: [Box<dyn Setter>; 3] = [
+                        Box::new(request),
+                        Box::new(BINDING_SUCCESS),
+                        Box::new(XorMappedAddress { ip: mapped.ip(), port: mapped.port() }),
+                    ];
+                    response.build(&answer).unwrap();
+                    response.raw
+                };
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probe_classifies_mappings() {
+        let public: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let same = [mock_stun_server(public).await, mock_stun_server(public).await];
+        let report = probe(&same.map(|a| a.to_string()), DEFAULT_STUN_TIMEOUT).await.unwrap();
+        assert_eq!(report.nat_type, NatType::Cone);
+        assert_eq!(report.candidate_type.as_deref(), Some("srflx"));
+        assert_eq!(report.mapped_addresses, vec![public.to_string(); 2]);
+
+        let other: SocketAddr = "203.0.113.5:40001".parse().unwrap();
+        let differing = [mock_stun_server(public).await, mock_stun_server(other).await];
+        let report = probe(&differing.map(|a| a.to_string()), DEFAULT_STUN_TIMEOUT).await.unwrap();
+        assert_eq!(report.nat_type, NatType::Symmetric);
+        assert!(report.hint.contains("relay"));
+
+        // Bound but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let servers = [silent.local_addr().unwrap().to_string()];
+        let report = probe(&servers, Duration::from_millis(200)).await.unwrap();
+        assert_eq!(report.nat_type, NatType::Blocked);
+
+        let local: SocketAddr = "192.168.1.2:5000".parse().unwrap();
+        assert_eq!(classify(local, &[local, local]), NatType::Open);
+    }
+}
@@ -1,9 +1,2791 @@
-pub struct Protocol {
-    // Protocol implementation will go here
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use bincode::Options;
+use futures::{Stream, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+use crate::clock::{Clock, SystemClock};
+use crate::compression::{self, Compression};
+use crate::metrics;
+use crate::hash::{HashBackend, Sha256Hasher};
+use crate::identity::{LocalIdentity, PeerIdentity};
+use crate::relay::MAX_RELAY_FRAME;
+use crate::transfer::{chunk_layout, sanitize_filename, FileTransfer, ReceiveState, CHUNK_SIZE};
+use crate::{AbortReason, CancellationToken, ChunkInfo, DropError, FileMetadata, Hello, IntegrityScheme, Result, TransferCommand, TransferProtocol};
+
+// Bumped on incompatible changes to the command set; peers must match exactly
+pub const PROTOCOL_VERSION: u32 = 1;
+const HANDSHAKE_NONCE_LEN: usize = 32;
+// Unacknowledged chunks allowed in flight unless `Protocol::with_window` says otherwise
+pub const DEFAULT_WINDOW: u32 = 8;
+// Longest `Chat` message sent or surfaced, in bytes
+pub const MAX_CHAT_LEN: usize = 4096;
+// Corrupted copies tolerated per chunk and per file, see `Protocol::with_retransmit_caps`
+pub const DEFAULT_MAX_CHUNK_RETRANSMITS: u32 = 3;
+pub const DEFAULT_MAX_TRANSFER_RETRANSMITS: u32 = 32;
+// A sensible `Protocol::with_handshake_timeout` for a peer that is known to
+// be on the other end already. Off by default: through the relay the second
+// peer may join long after the first has sent its `Hello`.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A reliable, ordered, message-oriented channel between two peers.
+// Implemented by the WebRTC data channel and by the in-memory loopback used in tests.
+#[async_trait]
+pub trait Transport: Send {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()>;
+    // Ok(None) once the peer has closed the channel
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>>;
+    async fn close(&mut self) -> Result<()>;
+}
+
+// In-memory transport; `loopback()` returns both connected ends.
+pub struct LoopbackTransport {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+pub fn loopback() -> (LoopbackTransport, LoopbackTransport) {
+    let (a_tx, b_rx) = mpsc::unbounded_channel();
+    let (b_tx, a_rx) = mpsc::unbounded_channel();
+    (
+        LoopbackTransport { tx: Some(a_tx), rx: a_rx },
+        LoopbackTransport { tx: Some(b_tx), rx: b_rx },
+    )
+}
+
+#[async_trait]
+impl Transport for LoopbackTransport {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        self.tx
+            .as_ref()
+            .and_then(|tx| tx.send(message).ok())
+            .ok_or_else(|| DropError::Protocol("channel closed".to_string()))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.rx.recv().await)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+        Ok(())
+    }
+}
+
+pub fn encode_command(command: &TransferCommand) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(command)?)
+}
+
+pub fn decode_command(message: &[u8]) -> Result<TransferCommand> {
+    if let Some((&BINARY_METADATA_FRAME, metadata)) = message.split_first() {
+        return Ok(TransferCommand::StartTransfer(decode_metadata(metadata)?));
+    }
+    Ok(serde_json::from_slice(message)?)
+}
+
+// Coalesce acknowledged chunk indices, in any order and with repeats, into
+// sorted inclusive ranges
+pub fn sack_ranges(indices: impl IntoIterator<Item = u32>) -> Vec<(u32, u32)> {
+    let mut indices: Vec<u32> = indices.into_iter().collect();
+    indices.sort_unstable();
+    indices.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for index in indices {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    ranges
+}
+
+// First byte of a `StartTransfer` frame carrying `encode_metadata` output
+// instead of JSON, which never starts with it
+const BINARY_METADATA_FRAME: u8 = 0x01;
+
+// `FileMetadata` as bincode, with chunk hashes as raw SHA-256 bytes instead of
+// hex strings: well under half the JSON size for files with many chunks. Fails
+// when a chunk hash isn't SHA-256 hex, so the caller can fall back to JSON.
+pub fn encode_metadata(metadata: &FileMetadata) -> Result<Vec<u8>> {
+    let chunks = metadata
+        .chunks
+        .iter()
+        .map(|chunk| {
+            let hash = hex::decode(&chunk.hash)
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .ok_or_else(|| DropError::Protocol(format!("chunk {} hash is not SHA-256 hex", chunk.index)))?;
+            Ok(CompactChunk { index: chunk.index, size: chunk.size, hash, zero: chunk.zero })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let compact = CompactMetadata {
+        name: metadata.name.clone(),
+        size: metadata.size,
+        hash: metadata.hash.clone(),
+        chunks,
+        integrity: metadata.integrity,
+        mode: metadata.mode,
+        mtime: metadata.mtime,
+        compression: metadata.compression.clone(),
+        chunk_size: metadata.chunk_size,
+        symlink_target: metadata.symlink_target.clone(),
+        priority: metadata.priority,
+    };
+    bincode_options()
+        .serialize(&compact)
+        .map_err(|e| DropError::Protocol(format!("cannot encode metadata: {}", e)))
+}
+
+pub fn decode_metadata(bytes: &[u8]) -> Result<FileMetadata> {
+    let compact: CompactMetadata = bincode_options()
+        .deserialize(bytes)
+        .map_err(|e| DropError::Protocol(format!("malformed metadata: {}", e)))?;
+    Ok(FileMetadata {
+        name: compact.name,
+        size: compact.size,
+        hash: compact.hash,
+        chunks: compact
+            .chunks
+            .into_iter()
+            .map(|c| ChunkInfo { index: c.index, size: c.size, hash: hex::encode(c.hash), zero: c.zero })
+            .collect(),
+        integrity: compact.integrity,
+        mode: compact.mode,
+        mtime: compact.mtime,
+        compression: compact.compression,
+        chunk_size: compact.chunk_size,
+        symlink_target: compact.symlink_target,
+        priority: compact.priority,
+    })
+}
+
+// Varint integers, and a cap so a hostile length prefix can't trigger a huge allocation
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_limit(MAX_RELAY_FRAME as u64)
+}
+
+// Field for field `FileMetadata`, kept separate so the JSON form can keep
+// evolving with `serde(default)` fields, which bincode can't skip
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CompactMetadata {
+    name: String,
+    size: u64,
+    hash: String,
+    chunks: Vec<CompactChunk>,
+    integrity: IntegrityScheme,
+    mode: Option<u32>,
+    mtime: Option<i64>,
+    compression: Option<String>,
+    chunk_size: Option<u64>,
+    symlink_target: Option<String>,
+    priority: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CompactChunk {
+    index: u32,
+    size: u64,
+    hash: [u8; 32],
+    zero: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// One file moved over a connection, in either direction
+#[derive(Debug, Clone)]
+pub struct TransferRecord {
+    pub name: String,
+    pub hash: String,
+    pub direction: Direction,
+}
+
+// Things the peer did that aren't part of a transfer's result, see `Protocol::with_events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    Chat(String),
+    // The receiver's answer to a `missing_query`: chunk indices it hasn't
+    // written yet, in order. Requested chunks in flight count as missing.
+    Missing(Vec<u32>),
+}
+
+// Drives the chunk protocol over a transport. Both peers first exchange
+// `Hello` (once per connection). Then it's receiver-driven: the sender
+// announces `StartTransfer`, the receiver answers with its `Bitfield` and then
+// pulls every missing chunk with `RequestChunk`, finishing with `Complete`.
+pub struct Protocol<T: Transport> {
+    transport: T,
+    keep_alive: bool,
+    closed: bool,
+    history: Vec<TransferRecord>,
+    cancel: CancellationToken,
+    // How long the receiver waits for a requested chunk before asking again
+    chunk_timeout: Duration,
+    // Re-requests allowed per chunk before the transfer fails with `Timeout`
+    chunk_retries: u32,
+    // Re-requests of chunks that arrived corrupted, per chunk and per file
+    max_chunk_retransmits: u32,
+    max_transfer_retransmits: u32,
+    handshake_done: bool,
+    handshake_timeout: Option<Duration>,
+    // Send `Ping` after this long without traffic in either direction
+    ping_interval: Option<Duration>,
+    last_activity: Instant,
+    // Drives keepalive and chunk timeouts
+    clock: Arc<dyn Clock>,
+    identity: Option<LocalIdentity>,
+    // Expected, or learned on the first handshake and required on reconnects
+    peer_identity: Option<PeerIdentity>,
+    // Advertised in our `Hello`
+    window: u32,
+    // Smaller of both windows, once a handshake with a peer that sent one completed
+    negotiated_window: Option<u32>,
+    // Advertised in our `Hello`; empty unless `with_compressors` opted in
+    compressors: Vec<Compression>,
+    // Peer's `Hello` said it decodes binary `StartTransfer` metadata
+    peer_binary_metadata: bool,
+    // Peer takes `SackRanges`; acks then wait in `unsent_acks` until `flush_acks`
+    peer_sack: bool,
+    unsent_acks: Vec<u32>,
+    // Corrupted chunks re-requested in the file being received
+    retransmits: u32,
+    // Peer's `Hello` said it understands `Abort`
+    peer_abort: bool,
+    // Notified to send `QueryMissing` from the send loop, see `missing_query`
+    query_missing: Arc<Notify>,
+    peer_query_missing: bool,
+    // Chat queued through `chat_sender`, sent whenever we next wait on the peer
+    chat_tx: mpsc::UnboundedSender<String>,
+    chat_rx: mpsc::UnboundedReceiver<String>,
+    peer_stream: bool,
+    // Between our `Bitfield` and `Complete` for a file being received. A
+    // query can cross our `Complete`, so outside that it's ignored.
+    receiving: bool,
+    // Receive over an existing copy of the file, see `with_incremental`
+    incremental: bool,
+    // Picked in the handshake, when we opted in and the peer advertised
+    // compressors; None inside means uncompressed. Overrides the
+    // `FileTransfer`'s own setting.
+    negotiated_compression: Option<Option<Compression>>,
+    events: Option<mpsc::UnboundedSender<TransferEvent>>,
+}
+
+// Up to CHUNK_SIZE bytes, short only at the end of the input; empty once it's exhausted
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    reader.take(CHUNK_SIZE as u64).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
+
+async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// A chunk the receiver has requested but not yet received
+struct Outstanding {
+    deadline: Instant,
+    attempts: u32,
+    // Copies that failed verification
+    corrupted: u32,
+}
+
+// Receive-side state behind `Protocol::chunk_stream`
+struct ChunkReader<'a, T: Transport> {
+    protocol: &'a mut Protocol<T>,
+    started: Option<StreamedFile>,
+}
+
+struct StreamedFile {
+    metadata: FileMetadata,
+    compression: Option<Compression>,
+    next: u32,
+    // Chunks before `next`, for late copies and `QueryMissing`
+    completed: Bitfield,
+    // Whole-file hash, when the metadata uses that scheme
+    hasher: Option<Sha256Hasher>,
+}
+
+impl<T: Transport> ChunkReader<'_, T> {
+    async fn next_chunk(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        if self.started.is_none() {
+            self.started = Some(self.start().await?);
+        }
+        let protocol = &mut *self.protocol;
+        let file = self.started.as_mut().unwrap();
+        let chunk_count = file.metadata.chunks.len() as u32;
+        if file.next == chunk_count {
+            return protocol.finish_stream(file).await.map(|()| None);
+        }
+
+        let index = file.next;
+        let mut next = NextChunk { metadata: &file.metadata, chunk: None };
+        let pending = VecDeque::from([index]);
+        protocol
+            .receive_chunks(&mut next, &file.metadata, file.compression, &mut file.completed, pending, 1)
+            .await?;
+        let data = next.chunk.take().expect("receive_chunks returns once the chunk is in");
+        if let Some(hasher) = &mut file.hasher {
+            hasher.update(&data);
+        }
+        file.next += 1;
+        Ok(Some((index, data)))
+    }
+
+    async fn start(&mut self) -> Result<StreamedFile> {
+        let protocol = &mut *self.protocol;
+        protocol.handshake().await?;
+        let metadata = match protocol.expect_command().await? {
+            TransferCommand::StartTransfer(metadata) => metadata,
+            other => {
+                return Err(DropError::Protocol(format!("expected StartTransfer, got {:?}", other)));
+            }
+        };
+        let (metadata, compression) = protocol.accept_announcement(metadata).await?;
+        let chunk_count = metadata.chunks.len() as u32;
+        protocol.send_command(&TransferCommand::Bitfield(Bitfield::new(chunk_count).to_bytes())).await?;
+        protocol.receiving = true;
+        let hasher = (metadata.integrity == IntegrityScheme::WholeFile).then(|| HashBackend::default().hasher());
+        Ok(StreamedFile {
+            metadata,
+            compression,
+            next: 0,
+            completed: Bitfield::new(chunk_count),
+            hasher,
+        })
+    }
+}
+
+impl<T: Transport> Protocol<T> {
+    pub fn new(transport: T) -> Self {
+        let (chat_tx, chat_rx) = mpsc::unbounded_channel();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            transport,
+            keep_alive: false,
+            closed: false,
+            history: Vec::new(),
+            cancel: CancellationToken::new(),
+            chunk_timeout: Duration::from_secs(30),
+            chunk_retries: 3,
+            max_chunk_retransmits: DEFAULT_MAX_CHUNK_RETRANSMITS,
+            max_transfer_retransmits: DEFAULT_MAX_TRANSFER_RETRANSMITS,
+            handshake_done: false,
+            handshake_timeout: None,
+            ping_interval: None,
+            last_activity: Instant::from_std(clock.now()),
+            clock,
+            identity: None,
+            peer_identity: None,
+            window: DEFAULT_WINDOW,
+            negotiated_window: None,
+            compressors: Vec::new(),
+            incremental: false,
+            peer_binary_metadata: false,
+            peer_sack: false,
+            unsent_acks: Vec::new(),
+            retransmits: 0,
+            peer_abort: false,
+            query_missing: Arc::new(Notify::new()),
+            peer_query_missing: false,
+            chat_tx,
+            chat_rx,
+            peer_stream: false,
+            receiving: false,
+            negotiated_compression: None,
+            events: None,
+        }
+    }
+
+    // Keep NAT mappings and proxies from dropping a quiet connection: while
+    // waiting for the peer, ping it whenever nothing has been sent or
+    // received for `interval`. Any traffic resets the timer, so no pings are
+    // sent while data is flowing.
+    pub fn with_ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    // Present `identity` in the handshake, signed over the peer's nonce
+    pub fn with_identity(mut self, identity: LocalIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    // Only talk to a peer proving this identity
+    pub fn with_expected_peer(mut self, peer: PeerIdentity) -> Self {
+        self.peer_identity = Some(peer);
+        self
+    }
+
+    // The verified identity of the peer, once a handshake has completed
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    // Continue on a new transport after the old one dropped (e.g. an ICE
+    // restart). The handshake runs again and, if the peer presented an
+    // identity before, it must present the same one.
+    pub fn reconnect(&mut self, transport: T) {
+        self.transport = transport;
+        self.closed = false;
+        self.handshake_done = false;
+    }
+
+    // Flow control: advertise `window` (at least 1) in `Hello` as the most
+    // chunks this side allows sent and not yet acknowledged. `negotiate` takes
+    // the smaller of the two peers' windows, so this can only lower the
+    // effective window, never raise it past the peer's. The receiver requests
+    // that many chunks ahead, and a sender holds further requests until
+    // `Ack`s free up room. Peers that both set `Hello::sack` batch those into
+    // `SackRanges` instead. Peers that don't advertise a window get one
+    // request at a time and no `Ack`s.
+    pub fn with_window(mut self, window: u32) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    // Opt in to negotiated compression: the best of `compressors` the peer
+    // also supports is used for files sent in either direction, whatever
+    // each `FileTransfer::with_compression` says. By default nothing is
+    // negotiated and each file's own setting applies.
+    pub fn with_compressors(mut self, compressors: &[Compression]) -> Self {
+        self.compressors = compressors.to_vec();
+        self
+    }
+
+    // When the output already holds an older version of the incoming file,
+    // diff the two and only request the chunks that changed or were added;
+    // the rest are kept in place. The old version is overwritten as chunks
+    // arrive, so an interrupted update leaves a mix of both.
+    pub fn with_incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    // A chunk that fails verification is requested again, up to
+    // `per_chunk` times for that chunk and `per_transfer` times across the
+    // file. Past either cap the receive fails with
+    // `DropError::VerificationFailed` naming the chunk.
+    pub fn with_retransmit_caps(mut self, per_chunk: u32, per_transfer: u32) -> Self {
+        self.max_chunk_retransmits = per_chunk;
+        self.max_transfer_retransmits = per_transfer;
+        self
+    }
+
+    pub fn with_chunk_timeout(mut self, timeout: Duration, retries: u32) -> Self {
+        self.chunk_timeout = timeout;
+        self.chunk_retries = retries;
+        self
+    }
+
+    // Give up with `DropError::Timeout` if the peer hasn't completed the
+    // handshake this long after we sent our `Hello`. Separate from the chunk
+    // timeout, so a peer that isn't speaking this protocol fails fast. Only
+    // for transports where the peer is connected before the handshake starts.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    // Abort transfers on this connection when `token` is cancelled, typically from another task
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    // Where keepalive and chunk timeouts get "now" from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_activity = Instant::from_std(clock.now());
+        self.clock = clock;
+        self
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_std(self.clock.now())
+    }
+
+    // Deliver `TransferEvent`s here as they arrive, whichever call is reading
+    // from the peer at the time; without it they are logged and dropped
+    pub fn with_events(mut self, events: mpsc::UnboundedSender<TransferEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Send a text note to the peer's event stream
+    pub async fn send_chat(&mut self, text: &str) -> Result<()> {
+        if text.len() > MAX_CHAT_LEN {
+            return Err(DropError::Protocol(format!("chat message over {} bytes", MAX_CHAT_LEN)));
+        }
+        self.send_command(&TransferCommand::Chat(text.to_string())).await
+    }
+
+    // Queue chat from any task, including while a send or receive holds the
+    // protocol; it goes out the next time that loop waits on the peer.
+    // Messages over MAX_CHAT_LEN are dropped.
+    pub fn chat_sender(&self) -> mpsc::UnboundedSender<String> {
+        self.chat_tx.clone()
+    }
+
+    fn surface_chat(&self, text: String) {
+        if text.len() > MAX_CHAT_LEN {
+            tracing::warn!(len = text.len(), "dropping oversized chat message");
+            return;
+        }
+        self.surface(TransferEvent::Chat(text));
+    }
+
+    fn surface(&self, event: TransferEvent) {
+        match &self.events {
+            // A dropped receiver only means nobody is listening any more
+            Some(events) => {
+                let _ = events.send(event);
+            }
+            None => tracing::debug!(?event, "transfer event with no listener"),
+        }
+    }
+
+    // Call `notify_one` on this, from any task, to have the file being sent
+    // ask the receiver what it still needs. The answer arrives as
+    // `TransferEvent::Missing`, and queued requests for chunks it already
+    // has are dropped. Ignored by peers that don't answer `QueryMissing`.
+    pub fn missing_query(&self) -> Arc<Notify> {
+        self.query_missing.clone()
+    }
+
+    // Leave the channel open after `Complete` so either peer can start another transfer
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Returns the previous setting
+    pub(crate) fn set_keep_alive(&mut self, keep_alive: bool) -> bool {
+        std::mem::replace(&mut self.keep_alive, keep_alive)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn history(&self) -> &[TransferRecord] {
+        &self.history
+    }
+
+    pub async fn send_command(&mut self, command: &TransferCommand) -> Result<()> {
+        self.send_frame(encode_command(command)?).await
+    }
+
+    async fn send_frame(&mut self, message: Vec<u8>) -> Result<()> {
+        if self.closed {
+            return Err(DropError::Protocol("connection closed".to_string()));
+        }
+        self.transport.send(message).await?;
+        self.last_activity = self.now();
+        Ok(())
+    }
+
+    // `StartTransfer`, binary when the peer said it can decode that
+    async fn send_start(&mut self, metadata: &FileMetadata) -> Result<()> {
+        if self.peer_binary_metadata {
+            if let Ok(encoded) = encode_metadata(metadata) {
+                let mut frame = vec![BINARY_METADATA_FRAME];
+                frame.extend(encoded);
+                return self.send_frame(frame).await;
+            }
+        }
+        self.send_command(&TransferCommand::StartTransfer(metadata.clone())).await
+    }
+
+    // Next command from the peer. Keepalive `Ping`/`Pong` are handled here
+    // and never returned.
+    pub async fn recv_command(&mut self) -> Result<Option<TransferCommand>> {
+        loop {
+            let ping_at = self.ping_interval.map(|interval| self.last_activity + interval);
+            let message = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => return Err(DropError::Cancelled),
+                message = self.transport.recv() => message?,
+                Some(text) = self.chat_rx.recv() => {
+                    if text.len() > MAX_CHAT_LEN {
+                        tracing::warn!(len = text.len(), "dropping oversized chat message");
+                    } else {
+                        self.send_command(&TransferCommand::Chat(text)).await?;
+                    }
+                    continue;
+                }
+                _ = sleep_until_some(ping_at) => {
+                    self.send_command(&TransferCommand::Ping).await?;
+                    continue;
+                }
+            };
+            self.last_activity = self.now();
+            let Some(message) = message else { return Ok(None) };
+            match decode_command(&message)? {
+                TransferCommand::Ping => self.send_command(&TransferCommand::Pong).await?,
+                TransferCommand::Pong => {}
+                TransferCommand::Chat(text) => self.surface_chat(text),
+                TransferCommand::QueryMissing if !self.receiving => {
+                    tracing::debug!("ignoring QueryMissing outside a transfer");
+                }
+                command => return Ok(Some(command)),
+            }
+        }
+    }
+
+    pub(crate) async fn expect_command(&mut self) -> Result<TransferCommand> {
+        match self.recv_command().await? {
+            Some(TransferCommand::Error(e)) => Err(DropError::Protocol(format!("peer error: {}", e))),
+            Some(TransferCommand::Abort { reason, detail }) => Err(reason.into_error(&detail)),
+            Some(command) => Ok(command),
+            None => Err(DropError::Protocol("peer closed the connection".to_string())),
+        }
+    }
+
+    pub async fn close(&mut self) -> Result<()> {
+        if !self.closed {
+            self.closed = true;
+            self.transport.close().await?;
+        }
+        Ok(())
+    }
+
+    // Exchange `Hello` with the peer unless this connection already did. When
+    // either side has an identity it also sends `Identify`, signing the other
+    // side's nonce.
+    pub async fn handshake(&mut self) -> Result<()> {
+        if self.handshake_done {
+            return Ok(());
+        }
+        let Some(timeout) = self.handshake_timeout else {
+            return self.negotiate().await;
+        };
+        match tokio::time::timeout(timeout, self.negotiate()).await {
+            Ok(result) => result,
+            Err(_) => {
+                let e = DropError::Timeout(format!("peer did not complete the handshake within {:?}", timeout));
+                // Best effort; the peer may not be listening at all
+                let _ = self.send_abort(AbortReason::Timeout, &e.to_string()).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn negotiate(&mut self) -> Result<()> {
+        let nonce: [u8; HANDSHAKE_NONCE_LEN] = rand::random();
+        self.send_command(&TransferCommand::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            nonce: nonce.to_vec(),
+            identity: self.identity.as_ref().map(LocalIdentity::public),
+            window: Some(self.window),
+            compressors: Some(compression::advertise(&self.compressors)),
+            binary_metadata: true,
+            sack: true,
+            abort: true,
+            query_missing: true,
+            stream: true,
+        }))
+        .await?;
+        let hello = match self.expect_command().await? {
+            TransferCommand::Hello(hello) => hello,
+            other => return Err(DropError::Protocol(format!("expected Hello, got {:?}", other))),
+        };
+        self.peer_abort = hello.abort;
+        if hello.version != PROTOCOL_VERSION {
+            let reason = format!("unsupported protocol version {}", hello.version);
+            self.send_abort(AbortReason::Unsupported, &reason).await?;
+            return Err(DropError::Protocol(reason));
+        }
+        if let Some(identity) = &self.identity {
+            let signature = identity.sign_handshake(&hello.nonce);
+            self.send_command(&TransferCommand::Identify(signature)).await?;
+        }
+        if let Some(presented) = &hello.identity {
+            match self.expect_command().await? {
+                TransferCommand::Identify(signature) => presented.verify_handshake(&nonce, &signature)?,
+                other => return Err(DropError::Protocol(format!("expected Identify, got {:?}", other))),
+            }
+        }
+        let mismatch = match (&self.peer_identity, &hello.identity) {
+            (Some(expected), Some(presented)) => expected.public_key != presented.public_key,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if mismatch {
+            let reason = "peer identity does not match".to_string();
+            self.send_abort(AbortReason::Rejected, &reason).await?;
+            return Err(DropError::Crypto(reason));
+        }
+        if let Some(presented) = hello.identity {
+            self.peer_identity = Some(presented);
+        }
+        self.negotiated_window = hello.window.map(|w| w.clamp(1, self.window));
+        self.peer_binary_metadata = hello.binary_metadata;
+        self.peer_sack = hello.sack;
+        self.peer_query_missing = hello.query_missing;
+        self.peer_stream = hello.stream;
+        self.negotiated_compression = hello
+            .compressors
+            .filter(|_| !self.compressors.is_empty())
+            .map(|theirs| compression::negotiate(&self.compressors, &theirs));
+        self.handshake_done = true;
+        Ok(())
+    }
+
+    // The plaintext of a received chunk payload, or None when it doesn't
+    // decompress. Output bigger than a chunk of `metadata` could be means the
+    // peer is hostile, so that tells it the transfer is over and fails.
+    async fn decode_chunk(
+        &mut self,
+        compression: Option<Compression>,
+        data: Vec<u8>,
+        metadata: &FileMetadata,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(compression) = compression else {
+            return Ok(Some(data));
+        };
+        match compression.decompress(&data, metadata.chunk_size() + compression::DECOMPRESSION_MARGIN) {
+            Err(e) => {
+                self.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+                Err(e)
+            }
+            decoded => decoded,
+        }
+    }
+
+    pub(crate) async fn finish_transfer(&mut self) -> Result<()> {
+        if self.keep_alive {
+            Ok(())
+        } else {
+            self.close().await
+        }
+    }
+
+    // Acknowledge a received chunk, if the peer negotiated a window. With
+    // SACK the ack is only queued; see `make_room` for when it goes out.
+    async fn ack(&mut self, index: u32) -> Result<()> {
+        match (self.negotiated_window, self.peer_sack) {
+            (None, _) => {}
+            (Some(_), true) => self.unsent_acks.push(index),
+            (Some(_), false) => self.send_command(&TransferCommand::Ack(index)).await?,
+        }
+        Ok(())
+    }
+
+    async fn flush_acks(&mut self) -> Result<()> {
+        if self.unsent_acks.is_empty() {
+            return Ok(());
+        }
+        let ranges = sack_ranges(self.unsent_acks.drain(..));
+        self.send_command(&TransferCommand::SackRanges(ranges)).await
+    }
+
+    // Tell the peer why we're giving up: `Abort` if it understands it, else `Error(detail)`
+    pub(crate) async fn send_abort(&mut self, reason: AbortReason, detail: &str) -> Result<()> {
+        let command = match self.peer_abort {
+            true => TransferCommand::Abort { reason, detail: detail.to_string() },
+            false => TransferCommand::Error(detail.to_string()),
+        };
+        self.send_command(&command).await
+    }
+
+    // Call before requesting a new chunk with `in_flight` others outstanding.
+    // Everything the sender counts against its window is outstanding or
+    // queued here, so flushing once that reaches the window guarantees the
+    // request is served rather than held, while batching acks otherwise.
+    async fn make_room(&mut self, in_flight: usize) -> Result<()> {
+        let window = self.negotiated_window.unwrap_or(1) as usize;
+        if in_flight + self.unsent_acks.len() >= window {
+            self.flush_acks().await?;
+        }
+        Ok(())
+    }
+
+    // Answer one `RequestChunk`
+    async fn send_chunk(
+        &mut self,
+        file: &mut FileTransfer,
+        metadata: &FileMetadata,
+        compression: Option<Compression>,
+        index: u32,
+    ) -> Result<()> {
+        if metadata.chunks[index as usize].zero {
+            return self.send_command(&TransferCommand::ZeroChunk(index)).await;
+        }
+        let mut data = file.read_chunk(index).await?;
+        if let Some(compression) = compression {
+            data = compression.compress(&data)?;
+        }
+        self.send_command(&TransferCommand::SendChunk(index, data)).await
+    }
+
+    // Fail the receive once a corrupted chunk has been re-requested too often,
+    // telling the sender so it stops too
+    async fn check_retransmit_caps(&mut self, index: u32, chunk_retransmits: u32, transfer_retransmits: u32) -> Result<()> {
+        if chunk_retransmits <= self.max_chunk_retransmits && transfer_retransmits <= self.max_transfer_retransmits {
+            tracing::debug!(index, chunk_retransmits, "re-requesting corrupted chunk");
+            return Ok(());
+        }
+        let e = DropError::VerificationFailed {
+            mismatched_chunks: vec![index],
+            file_hash_mismatch: false,
+        };
+        self.send_abort(AbortReason::Internal, &e.to_string()).await?;
+        Err(e)
+    }
+
+    // Re-request every outstanding chunk whose deadline passed
+    async fn retransmit_expired(&mut self, outstanding: &mut HashMap<u32, Outstanding>) -> Result<()> {
+        let now = self.now();
+        let mut expired: Vec<u32> = outstanding
+            .iter()
+            .filter(|(_, o)| o.deadline <= now)
+            .map(|(&index, _)| index)
+            .collect();
+        expired.sort_unstable();
+        for index in expired {
+            let entry = outstanding.get_mut(&index).unwrap();
+            if entry.attempts > self.chunk_retries {
+                let e = DropError::Timeout(format!("chunk {} not received after {} requests", index, entry.attempts));
+                let _ = self.send_abort(AbortReason::for_error(&e), &e.to_string()).await;
+                return Err(e);
+            }
+            entry.attempts += 1;
+            entry.deadline = now + self.chunk_timeout;
+            tracing::debug!(index, attempt = entry.attempts, "re-requesting chunk");
+            self.send_command(&TransferCommand::RequestChunk(index)).await?;
+        }
+        Ok(())
+    }
+
+    // Count the outcome in `metrics::TRANSFERS`, and if the transfer was
+    // cancelled tell the peer we're giving up and close
+    async fn conclude(&mut self, result: Result<()>) -> Result<()> {
+        metrics::TRANSFERS.record(&result);
+        self.receiving = false;
+        if matches!(result, Err(DropError::Cancelled)) && !self.closed {
+            let _ = self.send_abort(AbortReason::UserCancelled, "cancelled").await;
+            self.close().await?;
+        }
+        result
+    }
+
+    pub async fn send(&mut self, file: &mut FileTransfer) -> Result<()> {
+        let result = self.send_inner(file).await;
+        self.conclude(result).await
+    }
+
+    async fn send_inner(&mut self, file: &mut FileTransfer) -> Result<()> {
+        self.handshake().await?;
+        file.mark_started();
+        let mut metadata = file.metadata_for_send().await?;
+        if let Some(negotiated) = self.negotiated_compression {
+            metadata.compression = negotiated.map(|c| c.to_string());
+        }
+        let chunk_count = metadata.chunks.len() as u32;
+        let compression = compression::from_metadata(metadata.compression.as_deref())?;
+        self.send_start(&metadata).await?;
+
+        let mut peer_has = Bitfield::new(chunk_count);
+        // Sent and not yet acknowledged, and requests held back until that drops below the window
+        let mut unacked: HashSet<u32> = HashSet::new();
+        let mut waiting: VecDeque<u32> = VecDeque::new();
+        // `QueryMissing`s sent and not yet answered
+        let mut queries = 0u32;
+        let query = self.query_missing.clone();
+        loop {
+            let command = tokio::select! {
+                command = self.expect_command() => command?,
+                _ = query.notified(), if self.peer_query_missing => {
+                    // The receiver may have just finished and closed; then
+                    // its `Complete` is still waiting to be read
+                    if self.send_command(&TransferCommand::QueryMissing).await.is_ok() {
+                        queries += 1;
+                    }
+                    continue;
+                }
+            };
+            match command {
+                TransferCommand::Bitfield(bytes) if queries > 0 => {
+                    queries -= 1;
+                    peer_has = Bitfield::from_bytes(bytes, chunk_count)?;
+                    waiting.retain(|&index| !peer_has.has(index));
+                    self.surface(TransferEvent::Missing(peer_has.missing()));
+                }
+                TransferCommand::Bitfield(bytes) => {
+                    peer_has = Bitfield::from_bytes(bytes, chunk_count)?;
+                    tracing::debug!(missing = peer_has.missing().len(), "peer chunk inventory received");
+                }
+                TransferCommand::ResumeFrom { file_hash, have } => {
+                    if file_hash != metadata.hash {
+                        let reason = "resume requested for a different file".to_string();
+                        self.send_abort(AbortReason::Rejected, &reason).await?;
+                        return Err(DropError::Protocol(reason));
+                    }
+                    peer_has = Bitfield::from_bytes(have, chunk_count)?;
+                    tracing::debug!(missing = peer_has.missing().len(), "peer resuming transfer");
+                }
+                TransferCommand::RequestChunk(index) if index < chunk_count => waiting.push_back(index),
+                TransferCommand::Ack(index) => {
+                    unacked.remove(&index);
+                }
+                TransferCommand::SackRanges(ranges) => {
+                    unacked.retain(|index| !ranges.iter().any(|&(start, end)| (start..=end).contains(index)));
+                }
+                TransferCommand::Complete => break,
+                other => {
+                    return Err(DropError::Protocol(format!("unexpected command while sending: {:?}", other)));
+                }
+            }
+            while let Some(&index) = waiting.front() {
+                // Resending a chunk that's already in flight doesn't take more room
+                let full = self
+                    .negotiated_window
+                    .is_some_and(|window| unacked.len() >= window as usize && !unacked.contains(&index));
+                if full {
+                    break;
+                }
+                waiting.pop_front();
+                self.send_chunk(file, &metadata, compression, index).await?;
+                if self.negotiated_window.is_some() {
+                    unacked.insert(index);
+                }
+                peer_has.set(index);
+            }
+        }
+
+        file.mark_complete(Direction::Sent, self.peer_identity.clone(), false)?;
+        self.history.push(TransferRecord {
+            name: metadata.name,
+            hash: metadata.hash,
+            direction: Direction::Sent,
+        });
+        self.finish_transfer().await
+    }
+
+    // Send everything `reader` yields as a file called `name`, for input whose
+    // size isn't known up front, e.g. stdin. Chunks are pushed in order as
+    // they're read rather than requested, at most the negotiated window ahead
+    // of the receiver's acks, and `EndStream` carries the size and hash once
+    // the reader is exhausted. Returns the bytes sent. The receiver needs no
+    // opt-in: `receive` takes a stream in place of a `StartTransfer`.
+    pub async fn send_reader<R: AsyncRead + Unpin>(&mut self, reader: &mut R, name: &str) -> Result<u64> {
+        let mut size = 0;
+        let result = self.send_reader_inner(reader, name, &mut size).await;
+        self.conclude(result).await.map(|()| size)
+    }
+
+    async fn send_reader_inner<R: AsyncRead + Unpin>(&mut self, reader: &mut R, name: &str, size: &mut u64) -> Result<()> {
+        self.handshake().await?;
+        if !self.peer_stream {
+            return Err(DropError::Protocol("peer does not accept streamed transfers".to_string()));
+        }
+        // Without a window from the peer, keep one chunk in flight
+        let window = self.negotiated_window.unwrap_or(1);
+        self.send_command(&TransferCommand::StartStream { name: name.to_string() }).await?;
+        let mut hasher = HashBackend::default().hasher();
+        let mut unacked: HashSet<u32> = HashSet::new();
+        let mut index = 0;
+        loop {
+            let chunk = match read_chunk(reader).await {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = self.send_abort(AbortReason::Internal, &e.to_string()).await;
+                    return Err(e.into());
+                }
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            while unacked.len() >= window as usize {
+                match self.expect_command().await? {
+                    TransferCommand::Ack(index) => {
+                        unacked.remove(&index);
+                    }
+                    TransferCommand::SackRanges(ranges) => {
+                        unacked.retain(|index| !ranges.iter().any(|&(start, end)| (start..=end).contains(index)));
+                    }
+                    other => return Err(DropError::Protocol(format!("unexpected command while streaming: {:?}", other))),
+                }
+            }
+            hasher.update(&chunk);
+            *size += chunk.len() as u64;
+            self.send_command(&TransferCommand::SendChunk(index, chunk)).await?;
+            unacked.insert(index);
+            index += 1;
+        }
+        let hash = hasher.finalize_hex();
+        self.send_command(&TransferCommand::EndStream { size: *size, hash: hash.clone() }).await?;
+        loop {
+            match self.expect_command().await? {
+                TransferCommand::Complete => break,
+                TransferCommand::Ack(_) | TransferCommand::SackRanges(_) => {}
+                other => return Err(DropError::Protocol(format!("expected Complete, got {:?}", other))),
+            }
+        }
+        self.history.push(TransferRecord {
+            name: name.to_string(),
+            hash,
+            direction: Direction::Sent,
+        });
+        self.finish_transfer().await
+    }
+
+    // The receiving side of `send_reader`: chunks arrive in order, unrequested,
+    // and are appended to the output, which is checked against `EndStream`
+    async fn receive_stream(&mut self, file: &mut FileTransfer, name: String) -> Result<()> {
+        file.mark_started();
+        self.unsent_acks.clear();
+        file.begin_stream()?;
+        let mut hasher = HashBackend::default().hasher();
+        let (mut next, mut received) = (0u32, 0u64);
+        // Only the last chunk may be short
+        let mut short = false;
+        let (size, hash) = loop {
+            match self.expect_command().await? {
+                TransferCommand::SendChunk(index, data) if index == next && !short && data.len() <= CHUNK_SIZE => {
+                    short = data.len() < CHUNK_SIZE;
+                    hasher.update(&data);
+                    received += data.len() as u64;
+                    if let Err(e) = file.append_chunk(&data) {
+                        self.send_abort(AbortReason::Internal, &e.to_string()).await?;
+                        return Err(e);
+                    }
+                    next += 1;
+                    self.ack(index).await?;
+                    self.make_room(0).await?;
+                }
+                TransferCommand::EndStream { size, hash } => break (size, hash),
+                other => {
+                    let reason = format!("unexpected command while receiving a stream: {:?}", other);
+                    self.send_abort(AbortReason::Rejected, &reason).await?;
+                    return Err(DropError::Protocol(reason));
+                }
+            }
+        };
+        if received != size || hasher.finalize_hex() != hash {
+            let e = DropError::VerificationFailed { mismatched_chunks: Vec::new(), file_hash_mismatch: true };
+            self.send_abort(AbortReason::Internal, &e.to_string()).await?;
+            return Err(e);
+        }
+        file.sync_output()?;
+        let name = sanitize_filename(&name);
+        file.end_stream(FileMetadata { name: name.clone(), size, hash: hash.clone(), ..FileMetadata::default() });
+        self.send_command(&TransferCommand::Complete).await?;
+        file.mark_complete(Direction::Received, self.peer_identity.clone(), true)?;
+        self.history.push(TransferRecord {
+            name,
+            hash,
+            direction: Direction::Received,
+        });
+        self.finish_transfer().await
+    }
+
+    pub async fn receive(&mut self, file: &mut FileTransfer) -> Result<()> {
+        let mut state = ReceiveState::new("");
+        self.receive_with_state(file, &mut state).await
+    }
+
+    // Receive while recording progress in `state`. If `state` already describes
+    // the announced file (e.g. restored with `ReceiveState::from_resume_token`),
+    // the partial output is kept and only the missing chunks are requested.
+    pub async fn receive_with_state(&mut self, file: &mut FileTransfer, state: &mut ReceiveState) -> Result<()> {
+        let result = self.receive_inner(file, state).await;
+        self.conclude(result).await
+    }
+
+    async fn receive_inner(&mut self, file: &mut FileTransfer, state: &mut ReceiveState) -> Result<()> {
+        self.handshake().await?;
+        let metadata = match self.expect_command().await? {
+            TransferCommand::StartTransfer(metadata) => metadata,
+            TransferCommand::StartStream { name } => return self.receive_stream(file, name).await,
+            other => {
+                return Err(DropError::Protocol(format!("expected StartTransfer, got {:?}", other)));
+            }
+        };
+        self.receive_started(file, state, metadata).await
+    }
+
+    // Receive a file whose `StartTransfer` the caller already read
+    pub(crate) async fn receive_announced(&mut self, file: &mut FileTransfer, metadata: FileMetadata) -> Result<()> {
+        let mut state = ReceiveState::new("");
+        let result = self.receive_started(file, &mut state, metadata).await;
+        self.conclude(result).await
+    }
+
+    // Receive the next file as a stream of verified chunks, in index order,
+    // instead of writing it to disk. Each chunk is only requested once the
+    // previous one has been taken from the stream, so a slow consumer slows
+    // the sender down rather than buffering the file in memory. The stream
+    // ends after the whole file has verified and `Complete` was sent.
+    pub fn chunk_stream(&mut self) -> impl Stream<Item = Result<(u32, Vec<u8>)>> + '_ {
+        let reader = ChunkReader { protocol: self, started: None };
+        futures::stream::try_unfold(reader, |mut reader| async move {
+            Ok(reader.next_chunk().await?.map(|chunk| (chunk, reader)))
+        })
+    }
+
+    // Receive the next file straight into `writer` (e.g. a locked stdout) in
+    // chunk order, never touching disk. Returns the bytes written. The
+    // whole-file hash is only checked at the end, after the data went out, so
+    // callers must treat an error as "discard what was written".
+    pub async fn receive_to_writer<W: std::io::Write>(&mut self, writer: &mut W) -> Result<u64> {
+        let result = async {
+            let mut stream = std::pin::pin!(self.chunk_stream());
+            let mut written = 0;
+            while let Some((_, chunk)) = stream.try_next().await? {
+                writer.write_all(&chunk)?;
+                written += chunk.len() as u64;
+            }
+            writer.flush()?;
+            Ok(written)
+        }
+        .await;
+        metrics::TRANSFERS.record(&result);
+        result
+    }
+
+    async fn finish_stream(&mut self, file: &mut StreamedFile) -> Result<()> {
+        let hash = match file.hasher.take() {
+            Some(hasher) => hasher.finalize_hex(),
+            None => file.metadata.merkle_root(),
+        };
+        if hash != file.metadata.hash {
+            let reason = "file failed verification".to_string();
+            self.send_abort(AbortReason::Internal, &reason).await?;
+            return Err(DropError::Protocol(reason));
+        }
+        self.receiving = false;
+        self.send_command(&TransferCommand::Complete).await?;
+        self.history.push(TransferRecord {
+            name: file.metadata.name.clone(),
+            hash: file.metadata.hash.clone(),
+            direction: Direction::Received,
+        });
+        self.finish_transfer().await
+    }
+
+    async fn receive_started(
+        &mut self,
+        file: &mut FileTransfer,
+        state: &mut ReceiveState,
+        metadata: FileMetadata,
+    ) -> Result<()> {
+        file.mark_started();
+        let (metadata, compression) = self.accept_announcement(metadata).await?;
+
+        if state.matches(&metadata) {
+            file.resume_receive(metadata.clone(), state).await?;
+            self.send_command(&TransferCommand::ResumeFrom {
+                file_hash: state.file_hash.clone(),
+                have: state.completed.to_bytes(),
+            })
+            .await?;
+        } else {
+            state.reset(&metadata);
+            let existing = match self.incremental {
+                true => chunk_layout(file.path(), metadata.chunk_size())?,
+                false => None,
+            };
+            match existing {
+                Some(existing) => {
+                    let diff = existing.diff(&metadata);
+                    let count = metadata.chunks.len() as u32;
+                    let needed = diff.needed();
+                    state.completed = Bitfield::from_indices(count, (0..count).filter(|i| !needed.contains(i)));
+                    tracing::debug!(changed = diff.changed.len(), added = diff.added.len(), "updating existing file");
+                    file.resume_receive(metadata.clone(), state).await?;
+                }
+                None => file.begin_receive(metadata.clone()).await?,
+            }
+            self.send_command(&TransferCommand::Bitfield(state.completed.to_bytes())).await?;
+        }
+
+        self.receiving = true;
+        let window = self.negotiated_window.unwrap_or(1) as usize;
+        let pending: VecDeque<u32> = state.completed.missing().into();
+        self.receive_chunks(file, &metadata, compression, &mut state.completed, pending, window).await?;
+
+        file.sync_output()?;
+        if let Err(e) = file.verify_complete().await {
+            self.send_abort(AbortReason::Internal, &e.to_string()).await?;
+            return Err(e);
+        }
+        file.apply_attributes();
+        self.receiving = false;
+        self.send_command(&TransferCommand::Complete).await?;
+        file.mark_complete(Direction::Received, self.peer_identity.clone(), true)?;
+
+        self.history.push(TransferRecord {
+            name: metadata.name,
+            hash: metadata.hash,
+            direction: Direction::Received,
+        });
+        self.finish_transfer().await
+    }
+
+    // Check a peer's `StartTransfer` before receiving it, telling the peer why
+    // when it's refused
+    async fn accept_announcement(&mut self, mut metadata: FileMetadata) -> Result<(FileMetadata, Option<Compression>)> {
+        // Acks left from a previous file on this connection would prune the wrong chunks
+        self.unsent_acks.clear();
+        self.retransmits = 0;
+        // The name is peer-controlled and may end up as a path component
+        metadata.name = sanitize_filename(&metadata.name);
+        if let Err(e) = metadata.validate() {
+            self.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+            return Err(e);
+        }
+        match compression::from_metadata(metadata.compression.as_deref()) {
+            Ok(compression) => Ok((metadata, compression)),
+            Err(e) => {
+                self.send_abort(AbortReason::Unsupported, &e.to_string()).await?;
+                Err(e)
+            }
+        }
+    }
+
+    // Request the `pending` chunks, up to `window` at a time, and hand each
+    // to `sink` once it verifies, re-requesting ones that time out or arrive
+    // corrupted. Marks each accepted chunk in `completed` and acks it.
+    async fn receive_chunks(
+        &mut self,
+        sink: &mut (dyn ChunkSink + '_),
+        metadata: &FileMetadata,
+        compression: Option<Compression>,
+        completed: &mut Bitfield,
+        mut pending: VecDeque<u32>,
+        window: usize,
+    ) -> Result<()> {
+        let mut outstanding: HashMap<u32, Outstanding> = HashMap::new();
+        loop {
+            while outstanding.len() < window {
+                let Some(index) = pending.pop_front() else { break };
+                self.make_room(outstanding.len()).await?;
+                self.send_command(&TransferCommand::RequestChunk(index)).await?;
+                outstanding.insert(index, Outstanding {
+                    deadline: self.now() + self.chunk_timeout,
+                    attempts: 1,
+                    corrupted: 0,
+                });
+            }
+            let Some(next_deadline) = outstanding.values().map(|o| o.deadline).min() else {
+                return Ok(());
+            };
+
+            let command = tokio::select! {
+                command = self.expect_command() => command?,
+                _ = tokio::time::sleep_until(next_deadline) => {
+                    // Also unsticks a sender whose window filled with late duplicates
+                    self.flush_acks().await?;
+                    self.retransmit_expired(&mut outstanding).await?;
+                    continue;
+                }
+            };
+            let (index, data) = match command {
+                TransferCommand::SendChunk(index, data) if outstanding.contains_key(&index) => {
+                    // Hashes cover the plaintext, so decompress before verifying. A
+                    // payload that doesn't decompress is treated like a hash mismatch.
+                    let data = self.decode_chunk(compression, data, metadata).await?;
+                    let Some(data) = data.filter(|d| sink.verify(index, d)) else {
+                        let entry = outstanding.get_mut(&index).unwrap();
+                        entry.corrupted += 1;
+                        self.retransmits += 1;
+                        self.check_retransmit_caps(index, entry.corrupted, self.retransmits).await?;
+                        entry.deadline = self.now() + self.chunk_timeout;
+                        self.send_command(&TransferCommand::RequestChunk(index)).await?;
+                        continue;
+                    };
+                    (index, Some(data))
+                }
+                // Only a chunk the metadata announced as all zeros may skip the wire
+                TransferCommand::ZeroChunk(index) if metadata.chunks.get(index as usize).is_some_and(|c| !c.zero) => {
+                    let e = DropError::Protocol(format!("chunk {} is not a zero chunk", index));
+                    self.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+                    return Err(e);
+                }
+                TransferCommand::ZeroChunk(index) if outstanding.contains_key(&index) => (index, None),
+                // A late copy of a chunk that was re-requested and already
+                // arrived; it must match what was accepted
+                TransferCommand::SendChunk(index, data) if completed.has(index) => {
+                    // One that doesn't even decompress is ignored; the good copy is already in
+                    if let Some(data) = self.decode_chunk(compression, data, metadata).await? {
+                        if let Err(e) = sink.accept_again(index, data).await {
+                            self.send_abort(AbortReason::Rejected, &e.to_string()).await?;
+                            return Err(e);
+                        }
+                    }
+                    // Acked again, since the sender counts the copy as in flight
+                    self.ack(index).await?;
+                    continue;
+                }
+                TransferCommand::ZeroChunk(index) if completed.has(index) => {
+                    self.ack(index).await?;
+                    continue;
+                }
+                TransferCommand::QueryMissing => {
+                    self.send_command(&TransferCommand::Bitfield(completed.to_bytes())).await?;
+                    continue;
+                }
+                other => {
+                    return Err(DropError::Protocol(format!("unexpected command while receiving: {:?}", other)));
+                }
+            };
+            if let Err(e) = sink.accept(index, data).await {
+                // `completed` keeps the chunks accepted so far, so after
+                // e.g. freeing disk space a receive with it resumes
+                self.send_abort(AbortReason::Internal, &e.to_string()).await?;
+                return Err(e);
+            }
+            outstanding.remove(&index);
+            completed.set(index);
+            self.ack(index).await?;
+        }
+    }
+}
+
+// Where `Protocol::receive_chunks` puts the chunks it receives
+#[async_trait]
+trait ChunkSink: Send {
+    // Whether `data` is chunk `index` as the metadata describes it
+    fn verify(&self, index: u32, data: &[u8]) -> bool;
+    // Take a verified chunk; None for one announced as all zeros
+    async fn accept(&mut self, index: u32, data: Option<Vec<u8>>) -> Result<()>;
+    // A late copy of a chunk already accepted
+    async fn accept_again(&mut self, index: u32, data: Vec<u8>) -> Result<()>;
+}
+
+#[async_trait]
+impl ChunkSink for FileTransfer {
+    fn verify(&self, index: u32, data: &[u8]) -> bool {
+        self.verify_chunk(index, data).is_ok()
+    }
+
+    async fn accept(&mut self, index: u32, data: Option<Vec<u8>>) -> Result<()> {
+        match data {
+            Some(data) => self.write_chunk(index, data).await,
+            None => self.write_zero_chunk(index).await,
+        }
+    }
+
+    // Written again, which verifies it against the copy already on disk
+    async fn accept_again(&mut self, index: u32, data: Vec<u8>) -> Result<()> {
+        self.write_chunk(index, data).await
+    }
+}
+
+// Holds the one chunk `ChunkReader` asked for
+struct NextChunk<'a> {
+    metadata: &'a FileMetadata,
+    chunk: Option<Vec<u8>>,
+}
+
+#[async_trait]
+impl ChunkSink for NextChunk<'_> {
+    fn verify(&self, index: u32, data: &[u8]) -> bool {
+        let chunk = &self.metadata.chunks[index as usize];
+        chunk.size == data.len() as u64 && chunk.hash == HashBackend::default().digest_hex(data)
+    }
+
+    async fn accept(&mut self, index: u32, data: Option<Vec<u8>>) -> Result<()> {
+        let size = self.metadata.chunks[index as usize].size as usize;
+        self.chunk = Some(data.unwrap_or_else(|| vec![0u8; size]));
+        Ok(())
+    }
+
+    // Already handed out, so there's nothing to compare it with
+    async fn accept_again(&mut self, _index: u32, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Transport> TransferProtocol for Protocol<T> {
+    async fn send_file(&mut self, path: PathBuf) -> Result<()> {
+        let mut file = FileTransfer::new(path);
+        self.send(&mut file).await
+    }
+
+    async fn receive_file(&mut self, path: PathBuf) -> Result<()> {
+        let mut file = FileTransfer::new(path);
+        self.receive(&mut file).await
+    }
+
+    async fn cancel(&mut self) -> Result<()> {
+        if !self.closed {
+            // Best effort: the peer may already be gone
+            let _ = self.send_abort(AbortReason::UserCancelled, "cancelled").await;
+        }
+        self.close().await
+    }
+}
+
+// Compact set of chunk indices a peer already holds, exchanged via
+// `TransferCommand::Bitfield`. Bit `i` lives in byte `i / 8`, most significant
+// bit first (same layout as BitTorrent), so trailing bits in the last byte are 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: u32,
 }
 
-impl Protocol {
-    pub fn new() -> Self {
-        Self {}
+impl Bitfield {
+    pub fn new(len: u32) -> Self {
+        Self {
+            bits: vec![0u8; len.div_ceil(8) as usize],
+            len,
+        }
     }
-} 
\ No newline at end of file
+
+    pub fn from_indices(len: u32, indices: impl IntoIterator<Item = u32>) -> Self {
+        let mut field = Self::new(len);
+        for index in indices {
+            field.set(index);
+        }
+        field
+    }
+
+    // Decode the wire form for a transfer of `len` chunks. Rejects payloads
+    // of the wrong length or with bits set past the end.
+    pub fn from_bytes(bytes: Vec<u8>, len: u32) -> crate::Result<Self> {
+        if bytes.len() != len.div_ceil(8) as usize {
+            return Err(crate::DropError::Protocol(format!(
+                "bitfield is {} bytes, expected {} for {} chunks",
+                bytes.len(),
+                len.div_ceil(8),
+                len
+            )));
+        }
+        let field = Self { bits: bytes, len };
+        let spare = field.bits.len() as u32 * 8 - len;
+        if spare > 0 && field.bits[field.bits.len() - 1] & ((1u8 << spare) - 1) != 0 {
+            return Err(crate::DropError::Protocol("bitfield has bits set past the last chunk".to_string()));
+        }
+        Ok(field)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bits.clone()
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn set(&mut self, index: u32) {
+        if index < self.len {
+            self.bits[(index / 8) as usize] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: u32) {
+        if index < self.len {
+            self.bits[(index / 8) as usize] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        index < self.len && self.bits[(index / 8) as usize] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|b| b.count_ones()).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    // Indices this side doesn't have yet
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.len).filter(|&i| !self.has(i)).collect()
+    }
+
+    // Indices the peer has that this side still lacks
+    pub fn wanted_from(&self, peer: &Bitfield) -> Vec<u32> {
+        (0..self.len.min(peer.len))
+            .filter(|&i| peer.has(i) && !self.has(i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::TransferReceipt;
+
+    fn write_file(dir: &std::path::Path, name: &str, len: usize) -> (PathBuf, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let path = dir.join(name);
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    // What an anonymous peer sends first, for tests that drive one side by hand
+    fn peer_hello() -> Vec<u8> {
+        encode_command(&TransferCommand::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            nonce: vec![0; HANDSHAKE_NONCE_LEN],
+            identity: None,
+            window: None,
+            compressors: None,
+            binary_metadata: false,
+            sack: false,
+            abort: false,
+            query_missing: false,
+            stream: false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_binary_metadata_is_compact() {
+        let chunks: Vec<ChunkInfo> = (0..5000u32)
+            .map(|index| ChunkInfo {
+                index,
+                size: crate::transfer::CHUNK_SIZE as u64,
+                hash: crate::hash::sha256_hex(&index.to_le_bytes()),
+                zero: index % 7 == 0,
+            })
+            .collect();
+        let metadata = FileMetadata {
+            name: "big.iso".to_string(),
+            size: 5000 * crate::transfer::CHUNK_SIZE as u64,
+            hash: crate::hash::sha256_hex(b"whole"),
+            chunks,
+            mode: Some(0o644),
+            compression: Some("zstd".to_string()),
+            priority: 3,
+            ..Default::default()
+        };
+
+        let binary = encode_metadata(&metadata).unwrap();
+        let json = serde_json::to_vec(&metadata).unwrap();
+        assert!(binary.len() * 2 < json.len(), "{} vs {} bytes", binary.len(), json.len());
+        let decoded = decode_metadata(&binary).unwrap();
+        assert_eq!(serde_json::to_vec(&decoded).unwrap(), json);
+
+        // As a frame, it decodes to the same command JSON would
+        let mut frame = vec![BINARY_METADATA_FRAME];
+        frame.extend(binary);
+        assert!(matches!(decode_command(&frame).unwrap(), TransferCommand::StartTransfer(m) if m.chunks.len() == 5000));
+        let unhashed = FileMetadata { chunks: vec![ChunkInfo::default()], ..Default::default() };
+        assert!(encode_metadata(&unhashed).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_loopback_transfer_closes_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "a.bin", 2 * 1024 * 1024 + 17);
+        let dst = dir.path().join("b.bin");
+
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a);
+        let mut receiver = Protocol::new(b);
+        let (sent, received) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        sent.unwrap();
+        received.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert!(sender.is_closed());
+        assert!(receiver.is_closed());
+    }
+
+    // Wraps a transport and records every frame it sends
+    struct Recording<T: Transport> {
+        inner: T,
+        sent: std::sync::Arc<std::sync::Mutex<Vec<TransferCommand>>>,
+    }
+
+    #[async_trait]
+    impl<T: Transport> Transport for Recording<T> {
+        async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+            self.sent.lock().unwrap().push(decode_command(&message)?);
+            self.inner.send(message).await
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+            self.inner.recv().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sparse_file_skips_zero_chunks_on_the_wire() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("sparse.bin");
+        let mut data = vec![0u8; 10 * 1024 * 1024];
+        data[4 * 1024 * 1024 + 10..4 * 1024 * 1024 + 5000].fill(0x42);
+        std::fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("out.bin");
+
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(Recording { inner: a, sent: sent.clone() });
+        let mut receiver = Protocol::new(b);
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let sent = sent.lock().unwrap();
+        let data_chunks: Vec<u32> = sent
+            .iter()
+            .filter_map(|c| match c {
+                TransferCommand::SendChunk(i, _) => Some(*i),
+                _ => None,
+            })
+            .collect();
+        let zero_chunks = sent.iter().filter(|c| matches!(c, TransferCommand::ZeroChunk(_))).count();
+        assert_eq!(data_chunks, vec![4]);
+        assert_eq!(zero_chunks, 9);
+    }
+
+    #[tokio::test]
+    async fn test_resume_requests_only_missing_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "big.bin", 4 * 1024 * 1024 + 99);
+        let dst = dir.path().join("partial.bin");
+
+        // First attempt got chunks 0 and 1 before the connection dropped
+        let metadata = FileTransfer::new(src.clone()).prepare_metadata().await.unwrap();
+        std::fs::write(&dst, &data[..2 * 1024 * 1024]).unwrap();
+        let mut interrupted = ReceiveState::new("ABC123");
+        interrupted.reset(&metadata);
+        interrupted.completed.set(0);
+        interrupted.completed.set(1);
+        let token = interrupted.to_resume_token();
+
+        // Later: restore the state from the token and re-join
+        let mut state = ReceiveState::from_resume_token(&token).unwrap();
+        assert_eq!(state.session_id, "ABC123");
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(a);
+        let mut receiver = Protocol::new(Recording { inner: b, sent: sent.clone() });
+        let mut out = FileTransfer::new(dst.clone());
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_with_state(&mut out, &mut state));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert!(state.completed.is_complete());
+        let requested: Vec<u32> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| match c {
+                TransferCommand::RequestChunk(i) => Some(*i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requested, vec![2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_restarted_sender_resumes_from_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "restart.bin", 3 * 1024 * 1024 + 5);
+        let dst = dir.path().join("restart.out");
+
+        // The first sender process saved its state and got chunk 0 across
+        let metadata = FileTransfer::new(src.clone())
+            .with_send_state_sidecar(true)
+            .prepare_metadata()
+            .await
+            .unwrap();
+        std::fs::write(&dst, &data[..1024 * 1024]).unwrap();
+        let mut state = ReceiveState::new("RST001");
+        state.reset(&metadata);
+        state.completed.set(0);
+
+        // A new process picks it up without preparing the metadata again
+        let mut restarted = FileTransfer::restore_send_state(src.clone()).unwrap();
+        assert_eq!(restarted.get_metadata().unwrap().hash, metadata.hash);
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let mut out = FileTransfer::new(dst.clone());
+        let (s, r) = tokio::join!(sender.send(&mut restarted), receiver.receive_with_state(&mut out, &mut state));
+        s.unwrap();
+        r.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        // Resumed: only chunks 1..=3 were read this time
+        assert_eq!(restarted.receipt().bytes_transferred, data.len() as u64 - 1024 * 1024);
+
+        let file = std::fs::File::options().write(true).open(&src).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert!(FileTransfer::restore_send_state(src).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_file_transfer_creates_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "empty.txt", 0);
+        let dst = dir.path().join("received.txt");
+
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a);
+        let mut receiver = Protocol::new(b);
+        let mut out = FileTransfer::new(dst.clone());
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive(&mut out));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 0);
+        let metadata = out.get_metadata().unwrap();
+        assert!(metadata.chunks.is_empty());
+        assert_eq!(metadata.hash, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        out.verify_complete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_token_aborts_in_flight_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "slow.bin", 3 * 1024 * 1024);
+
+        // The peer accepts the offer and then stalls, leaving the sender waiting
+        let (a, mut b) = loopback();
+        let token = CancellationToken::new();
+        let mut sender = Protocol::new(a).with_cancellation_token(token.clone());
+        let transfer = tokio::spawn(async move { sender.send_file(src).await });
+
+        decode_hello(b.recv().await.unwrap().unwrap());
+        b.send(peer_hello()).await.unwrap();
+        let first = b.recv().await.unwrap().unwrap();
+        assert!(matches!(decode_command(&first).unwrap(), TransferCommand::StartTransfer(_)));
+        tokio::spawn(async move { token.cancel() });
+
+        let result = tokio::time::timeout(std::time::Duration::from_secs(1), transfer)
+            .await
+            .expect("cancelled transfer should stop promptly")
+            .unwrap();
+        assert!(matches!(result, Err(DropError::Cancelled)));
+        let notice = decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(notice, TransferCommand::Error(reason) if reason == "cancelled"));
+        assert!(b.recv().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sender_waits_for_hello_before_offering() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "offer.bin", 1000);
+        let (a, mut b) = loopback();
+        let transfer = tokio::spawn(async move { Protocol::new(a).send_file(src).await });
+
+        let hello = decode_hello(b.recv().await.unwrap().unwrap());
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        let early = tokio::time::timeout(Duration::from_millis(100), b.recv()).await;
+        assert!(early.is_err(), "sent {:?} before the peer's Hello", early);
+        b.send(peer_hello()).await.unwrap();
+        let offer = decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(offer, TransferCommand::StartTransfer(_)));
+        transfer.abort();
+    }
+
+    #[tokio::test]
+    async fn test_abort_from_peer_ends_the_transfer_with_its_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "offer.bin", 1000);
+        let (a, mut b) = loopback();
+        let transfer = tokio::spawn(async move { Protocol::new(a).send_file(src).await });
+
+        let hello = Hello { abort: true, ..decode_hello(peer_hello()) };
+        b.send(encode_command(&TransferCommand::Hello(hello)).unwrap()).await.unwrap();
+        assert!(decode_hello(b.recv().await.unwrap().unwrap()).abort);
+        let start = decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(start, TransferCommand::StartTransfer(_)));
+        let abort = TransferCommand::Abort { reason: AbortReason::Rejected, detail: "no space".to_string() };
+        b.send(encode_command(&abort).unwrap()).await.unwrap();
+
+        let err = transfer.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Protocol error: peer rejected the transfer: no space");
+    }
+
+    // Silently drops the first `SendChunk` for the given index
+    struct DropChunkOnce<T: Transport> {
+        inner: T,
+        index: u32,
+        dropped: bool,
+    }
+
+    #[async_trait]
+    impl<T: Transport> Transport for DropChunkOnce<T> {
+        async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+            if !self.dropped {
+                if let TransferCommand::SendChunk(index, _) = decode_command(&message)? {
+                    if index == self.index {
+                        self.dropped = true;
+                        return Ok(());
+                    }
+                }
+            }
+            self.inner.send(message).await
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+            self.inner.recv().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lost_chunk_is_re_requested() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "lossy.bin", 2 * 1024 * 1024 + 10);
+        let dst = dir.path().join("out.bin");
+
+        let (a, b) = loopback();
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(DropChunkOnce { inner: a, index: 2, dropped: false });
+        let mut receiver = Protocol::new(Recording { inner: b, sent: requests.clone() })
+            .with_chunk_timeout(Duration::from_secs(2), 2);
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let requested: Vec<u32> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| match c {
+                TransferCommand::RequestChunk(i) => Some(*i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requested, vec![0, 1, 2, 2]);
+    }
+
+    // Flips a byte in every copy of one chunk
+    struct CorruptChunk<T: Transport> {
+        inner: T,
+        index: u32,
+    }
+
+    #[async_trait]
+    impl<T: Transport> Transport for CorruptChunk<T> {
+        async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+            match decode_command(&message)? {
+                TransferCommand::SendChunk(index, mut data) if index == self.index => {
+                    data[0] ^= 0xff;
+                    self.inner.send(encode_command(&TransferCommand::SendChunk(index, data))?).await
+                }
+                _ => self.inner.send(message).await,
+            }
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+            self.inner.recv().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    // Sends chunk `index` as a `ZeroChunk` whatever it holds
+    struct ClaimsZero<T: Transport> {
+        inner: T,
+        index: u32,
+    }
+
+    #[async_trait]
+    impl<T: Transport> Transport for ClaimsZero<T> {
+        async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+            match decode_command(&message)? {
+                TransferCommand::SendChunk(index, _) if index == self.index => {
+                    self.inner.send(encode_command(&TransferCommand::ZeroChunk(index))?).await
+                }
+                _ => self.inner.send(message).await,
+            }
+        }
+
+        async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+            self.inner.recv().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_chunk_for_a_chunk_with_data_is_rejected() {
+        use futures::StreamExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "claims-zero.bin", 3 * crate::transfer::CHUNK_SIZE);
+
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(ClaimsZero { inner: a, index: 1 });
+        let mut receiver = Protocol::new(b);
+        let (s, r) = tokio::join!(sender.send_file(src.clone()), receiver.receive_file(dir.path().join("out.bin")));
+        assert!(s.is_err());
+        let err = r.unwrap_err();
+        assert!(matches!(&err, DropError::Protocol(m) if m.contains("not a zero chunk")), "{}", err);
+
+        // Same for a receiver handing chunks out as a stream
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(ClaimsZero { inner: a, index: 1 });
+        let mut receiver = Protocol::new(b);
+        let mut file = FileTransfer::new(src);
+        let consume = async {
+            let mut stream = std::pin::pin!(receiver.chunk_stream());
+            let mut indices = Vec::new();
+            loop {
+                match stream.next().await {
+                    Some(Ok((index, _))) => indices.push(index),
+                    Some(Err(e)) => return (indices, e),
+                    None => panic!("stream ended without an error"),
+                }
+            }
+        };
+        let (s, (indices, err)) = tokio::join!(sender.send(&mut file), consume);
+        assert!(s.is_err());
+        assert_eq!(indices, vec![0]);
+        assert!(matches!(&err, DropError::Protocol(m) if m.contains("not a zero chunk")), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_outcomes_are_counted() {
+        let outcomes = &metrics::TRANSFERS;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "counted.bin", 5000);
+
+        // Other tests run in parallel, so only check that the counters moved
+        let completed = outcomes.completed.load(std::sync::atomic::Ordering::Relaxed);
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let (s, r) = tokio::join!(sender.send_file(src.clone()), receiver.receive_file(dir.path().join("out.bin")));
+        s.unwrap();
+        r.unwrap();
+        assert!(outcomes.completed.load(std::sync::atomic::Ordering::Relaxed) >= completed + 2);
+
+        let failed = outcomes.failed("verification");
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(CorruptChunk { inner: a, index: 0 });
+        let mut receiver = Protocol::new(b).with_retransmit_caps(0, 0);
+        let (_, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dir.path().join("bad.bin")));
+        assert!(matches!(r, Err(DropError::VerificationFailed { .. })));
+        assert!(outcomes.failed("verification") > failed);
+        let rendered = metrics::Metrics::default().render();
+        assert!(rendered.contains("drop_transfers_failed_total{reason=\"verification\"}"), "{}", rendered);
+        assert!(rendered.contains("drop_transfers_cancelled_total"));
+    }
+
+    #[tokio::test]
+    async fn test_persistently_corrupted_chunk_hits_retransmit_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "bad-link.bin", 3 * 1024 * 1024);
+
+        // (per chunk, per transfer) caps and the requests for chunk 1 they allow
+        for (caps, requests_for_bad_chunk) in [((2, 10), 3), ((10, 1), 2)] {
+            let (a, b) = loopback();
+            let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut sender = Protocol::new(CorruptChunk { inner: a, index: 1 });
+            let mut receiver = Protocol::new(Recording { inner: b, sent: requests.clone() })
+                .with_retransmit_caps(caps.0, caps.1);
+            let (s, r) = tokio::join!(sender.send_file(src.clone()), receiver.receive_file(dir.path().join("out.bin")));
+
+            match r.unwrap_err() {
+                DropError::VerificationFailed { mismatched_chunks, file_hash_mismatch } => {
+                    assert_eq!(mismatched_chunks, vec![1]);
+                    assert!(!file_hash_mismatch);
+                }
+                other => panic!("expected VerificationFailed, got {}", other),
+            }
+            assert!(s.is_err());
+            let requested = requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| matches!(c, TransferCommand::RequestChunk(1)))
+                .count();
+            assert_eq!(requested, requests_for_bad_chunk);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_timeout_gives_up_after_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("never.bin");
+        let (mut a, b) = loopback();
+        let mut receiver = Protocol::new(b).with_chunk_timeout(Duration::from_millis(20), 1);
+
+        let metadata = crate::FileMetadata {
+            name: "never.bin".to_string(),
+            size: 1,
+            hash: crate::hash::sha256_hex(&[7]),
+            chunks: vec![crate::ChunkInfo { index: 0, size: 1, hash: crate::hash::sha256_hex(&[7]), zero: false }],
+            ..Default::default()
+        };
+        a.send(peer_hello()).await.unwrap();
+        a.send(encode_command(&TransferCommand::StartTransfer(metadata)).unwrap()).await.unwrap();
+        let err = receiver.receive_file(dst).await.unwrap_err();
+        assert!(matches!(err, DropError::Timeout(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_incremental_receive_requests_only_changed_chunks() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, mut data) = write_file(dir.path(), "v2.bin", 6 * CHUNK_SIZE);
+        let dst = dir.path().join("v1.bin");
+        std::fs::write(&dst, &data).unwrap();
+        // The new version edits chunk 2 and grows by a partial chunk
+        data[2 * CHUNK_SIZE + 10] ^= 0xff;
+        data.extend_from_slice(&[9u8; 1000]);
+        std::fs::write(&src, &data).unwrap();
+
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(a);
+        let mut receiver = Protocol::new(Recording { inner: b, sent: sent.clone() }).with_incremental(true);
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let requested: Vec<u32> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| match c {
+                TransferCommand::RequestChunk(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requested, vec![2, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_over_expanding_chunk_is_refused() {
+        use crate::transfer::MIN_CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (mut a, b) = loopback();
+        let mut receiver = Protocol::new(b);
+
+        let data = vec![1u8; MIN_CHUNK_SIZE];
+        let hash = crate::hash::sha256_hex(&data);
+        let metadata = crate::FileMetadata {
+            name: "bomb.bin".to_string(),
+            size: data.len() as u64,
+            hash: hash.clone(),
+            chunks: vec![crate::ChunkInfo { index: 0, size: data.len() as u64, hash, zero: false }],
+            compression: Some("zstd".to_string()),
+            chunk_size: Some(MIN_CHUNK_SIZE as u64),
+            ..Default::default()
+        };
+        // A few KiB on the wire that would inflate to 64 MiB
+        let bomb = Compression::Zstd.compress(&vec![0u8; 64 * 1024 * 1024]).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+        a.send(peer_hello()).await.unwrap();
+        a.send(encode_command(&TransferCommand::StartTransfer(metadata)).unwrap()).await.unwrap();
+        a.send(encode_command(&TransferCommand::SendChunk(0, bomb)).unwrap()).await.unwrap();
+
+        let err = receiver.receive_file(dir.path().join("bomb.bin")).await.unwrap_err();
+        assert!(matches!(&err, DropError::Protocol(reason) if reason == "decompression bomb"), "{}", err);
+        drop(receiver);
+        let mut told_sender = false;
+        while let Some(message) = a.recv().await.unwrap() {
+            told_sender |= matches!(decode_command(&message).unwrap(), TransferCommand::Error(reason) if reason.contains("decompression bomb"));
+        }
+        assert!(told_sender);
+    }
+
+    #[tokio::test]
+    async fn test_sender_holds_requests_beyond_the_window() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "window.bin", 10 * CHUNK_SIZE);
+        let (a, mut b) = loopback();
+        let transfer = tokio::spawn(async move {
+            let mut sender = Protocol::new(a).with_window(4);
+            sender.send_file(src).await
+        });
+
+        // A greedy receiver advertising a bigger window and asking for everything at once
+        let hello = Hello { window: Some(16), ..decode_hello(peer_hello()) };
+        b.send(encode_command(&TransferCommand::Hello(hello)).unwrap()).await.unwrap();
+        let hello = decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(hello, TransferCommand::Hello(Hello { window: Some(4), .. })));
+        let start = decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(start, TransferCommand::StartTransfer(_)));
+        b.send(encode_command(&TransferCommand::Bitfield(Bitfield::new(10).to_bytes())).unwrap()).await.unwrap();
+        for index in 0..10 {
+            b.send(encode_command(&TransferCommand::RequestChunk(index)).unwrap()).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut in_flight = 0;
+        while received.len() < data.len() {
+            let next = tokio::time::timeout(Duration::from_millis(200), b.recv()).await;
+            let Ok(frame) = next else {
+                // Stalled: only allowed with a full window, which an Ack reopens
+                assert_eq!(in_flight, 4);
+                let acked = (received.len() / CHUNK_SIZE - in_flight) as u32;
+                b.send(encode_command(&TransferCommand::Ack(acked)).unwrap()).await.unwrap();
+                in_flight -= 1;
+                continue;
+            };
+            let TransferCommand::SendChunk(index, chunk) = decode_command(&frame.unwrap().unwrap()).unwrap() else {
+                panic!("expected a chunk");
+            };
+            assert_eq!(index as usize, received.len() / CHUNK_SIZE);
+            received.extend(chunk);
+            in_flight += 1;
+            assert!(in_flight <= 4, "{} chunks in flight", in_flight);
+        }
+        assert_eq!(received, data);
+
+        b.send(encode_command(&TransferCommand::Complete).unwrap()).await.unwrap();
+        transfer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chat_interleaved_with_chunks_reaches_events() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "note.bin", 2 * CHUNK_SIZE + 5);
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+        let dst = dir.path().join("out.bin");
+
+        let (a, mut b) = loopback();
+        let (events, mut inbox) = mpsc::unbounded_channel();
+        let receive = tokio::spawn(async move { Protocol::new(a).with_events(events).receive_file(dst).await });
+
+        // A hand-driven sender that says something before every chunk
+        b.send(peer_hello()).await.unwrap();
+        decode_hello(b.recv().await.unwrap().unwrap());
+        b.send(encode_command(&TransferCommand::StartTransfer(metadata.clone())).unwrap()).await.unwrap();
+        let mut notes = Vec::new();
+        loop {
+            match decode_command(&b.recv().await.unwrap().unwrap()).unwrap() {
+                TransferCommand::Bitfield(_) => {
+                    // Too long to surface, and not written anywhere either
+                    let long = "x".repeat(MAX_CHAT_LEN + 1);
+                    b.send(encode_command(&TransferCommand::Chat(long)).unwrap()).await.unwrap();
+                }
+                TransferCommand::RequestChunk(index) => {
+                    let note = format!("here comes chunk {}", index);
+                    b.send(encode_command(&TransferCommand::Chat(note.clone())).unwrap()).await.unwrap();
+                    notes.push(note);
+                    let start = index as usize * CHUNK_SIZE;
+                    let chunk = data[start..(start + CHUNK_SIZE).min(data.len())].to_vec();
+                    b.send(encode_command(&TransferCommand::SendChunk(index, chunk)).unwrap()).await.unwrap();
+                }
+                TransferCommand::Complete => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        receive.await.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(dir.path().join("out.bin")).unwrap(), data);
+        let mut received = Vec::new();
+        while let Ok(TransferEvent::Chat(text)) = inbox.try_recv() {
+            received.push(text);
+        }
+        assert_eq!(received, notes);
+        assert_eq!(notes.len(), 3);
+
+        let (c, _d) = loopback();
+        assert!(Protocol::new(c).send_chat(&"y".repeat(MAX_CHAT_LEN + 1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chat_queued_during_a_transfer_goes_out_from_its_loops() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "chat.bin", 3 * CHUNK_SIZE + 1);
+        let dst = dir.path().join("out.bin");
+        let (a, b) = loopback();
+        let (sender_events, mut sender_inbox) = mpsc::unbounded_channel();
+        let (receiver_events, mut receiver_inbox) = mpsc::unbounded_channel();
+        let mut sender = Protocol::new(a).with_events(sender_events);
+        let mut receiver = Protocol::new(b).with_events(receiver_events);
+
+        // Neither side calls `send_chat`; the send and receive loops flush these
+        sender.chat_sender().send("from the sender".to_string()).unwrap();
+        receiver.chat_sender().send("x".repeat(MAX_CHAT_LEN + 1)).unwrap();
+        receiver.chat_sender().send("from the receiver".to_string()).unwrap();
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert_eq!(receiver_inbox.try_recv().unwrap(), TransferEvent::Chat("from the sender".to_string()));
+        assert_eq!(sender_inbox.try_recv().unwrap(), TransferEvent::Chat("from the receiver".to_string()));
+        assert!(sender_inbox.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_of_unknown_length_is_received_and_verified() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 777).map(|i| (i % 253) as u8).collect();
+        let dst = dir.path().join("piped.bin");
+
+        // Like a pipe: the bytes trickle in and the length is never announced
+        let (mut writer, mut reader) = tokio::io::duplex(4096);
+        let input = data.clone();
+        let producer = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for piece in input.chunks(10_000) {
+                writer.write_all(piece).await.unwrap();
+            }
+        });
+
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(Recording { inner: a, sent: sent.clone() }).with_window(2);
+        let mut receiver = Protocol::new(b).with_window(2);
+        let out = dst.clone();
+        let receive = tokio::spawn(async move {
+            let mut file = FileTransfer::new(out);
+            receiver.receive(&mut file).await.map(|()| (file.receipt(), receiver.history().to_vec()))
+        });
+        let size = sender.send_reader(&mut reader, "../bigfile").await.unwrap();
+        producer.await.unwrap();
+        let (receipt, history) = receive.await.unwrap().unwrap();
+
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let hash = crate::hash::sha256_hex(&data);
+        assert_eq!(history[0].hash, hash);
+        assert_eq!(history[0].name, "bigfile");
+        assert_eq!((receipt.size, receipt.verified), (data.len() as u64, true));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.iter().filter(|c| matches!(c, TransferCommand::SendChunk(..))).count(), 4);
+        assert!(matches!(sent.last(), Some(TransferCommand::EndStream { size, hash: h }) if *size == data.len() as u64 && *h == hash));
+    }
+
+    #[tokio::test]
+    async fn test_stream_needs_the_peers_capability() {
+        // Windows are older than streams, so having one says nothing
+        let (a, mut b) = loopback();
+        let mut sender = Protocol::new(a);
+        let windowed = Hello { window: Some(4), ..decode_hello(peer_hello()) };
+        b.send(encode_command(&TransferCommand::Hello(windowed)).unwrap()).await.unwrap();
+        let mut input: &[u8] = b"data";
+        let e = sender.send_reader(&mut input, "x").await.unwrap_err();
+        assert!(e.to_string().contains("does not accept streamed"), "{}", e);
+        let hello = decode_hello(b.recv().await.unwrap().unwrap());
+        assert!(hello.stream);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_full_disk_aborts_the_sender() {
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a);
+        let receive = tokio::spawn(async move { Protocol::new(b).receive_file(PathBuf::from("/dev/full")).await });
+        let mut input: &[u8] = &[7u8; 1000];
+        let sent = sender.send_reader(&mut input, "x").await.unwrap_err();
+        let received = receive.await.unwrap().unwrap_err();
+
+        assert!(received.is_disk_full(), "{}", received);
+        assert!(sent.to_string().contains(crate::transfer::DISK_FULL), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_hits_the_handshake_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        // Connected, but never says `Hello`
+        let (a, mut b) = loopback();
+        let mut receiver = Protocol::new(a)
+            .with_handshake_timeout(Some(Duration::from_millis(100)))
+            .with_chunk_timeout(Duration::from_secs(30), 0);
+        let started = Instant::now();
+        let e = receiver.receive_file(dir.path().join("out.bin")).await.unwrap_err();
+
+        assert!(matches!(&e, DropError::Timeout(reason) if reason.contains("handshake")), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        decode_hello(b.recv().await.unwrap().unwrap());
+        assert!(matches!(decode_command(&b.recv().await.unwrap().unwrap()).unwrap(), TransferCommand::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_missing_query_reports_the_receivers_gaps() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "gappy.bin", 6 * CHUNK_SIZE + 5);
+        let metadata = FileTransfer::new(src.clone()).prepare_metadata().await.unwrap();
+        let chunk = |index: u32| {
+            let start = index as usize * CHUNK_SIZE;
+            data[start..(start + CHUNK_SIZE).min(data.len())].to_vec()
+        };
+
+        // A real receiver, asked by a hand-driven sender that answers the
+        // first two requests out of order
+        let dst = dir.path().join("out.bin");
+        let (a, mut b) = loopback();
+        let receive = tokio::spawn(async move { Protocol::new(a).receive_file(dst).await });
+        let windowed = Hello { window: Some(4), ..decode_hello(peer_hello()) };
+        b.send(encode_command(&TransferCommand::Hello(windowed)).unwrap()).await.unwrap();
+        decode_hello(b.recv().await.unwrap().unwrap());
+        b.send(encode_command(&TransferCommand::StartTransfer(metadata.clone())).unwrap()).await.unwrap();
+        let (mut held, mut missing) = (Vec::new(), None);
+        loop {
+            match decode_command(&b.recv().await.unwrap().unwrap()).unwrap() {
+                TransferCommand::RequestChunk(index) if missing.is_none() && (index == 1 || index == 3) => held.push(index),
+                TransferCommand::RequestChunk(index) => {
+                    b.send(encode_command(&TransferCommand::SendChunk(index, chunk(index))).unwrap()).await.unwrap();
+                    if index == 2 {
+                        b.send(encode_command(&TransferCommand::QueryMissing).unwrap()).await.unwrap();
+                    }
+                }
+                TransferCommand::Bitfield(bytes) if held.len() == 2 => {
+                    missing = Some(Bitfield::from_bytes(bytes, 7).unwrap().missing());
+                    for index in held.drain(..) {
+                        b.send(encode_command(&TransferCommand::SendChunk(index, chunk(index))).unwrap()).await.unwrap();
+                    }
+                }
+                TransferCommand::Bitfield(_) | TransferCommand::Ack(_) => {}
+                TransferCommand::Complete => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        receive.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(dir.path().join("out.bin")).unwrap(), data);
+        // 4 and 5 were requested once 0 and 2 arrived, but not yet sent
+        assert_eq!(missing, Some(vec![1, 3, 4, 5, 6]));
+
+        // A real sender asking mid-transfer, answered by a hand-driven receiver
+        let (a, mut b) = loopback();
+        let (events, mut inbox) = mpsc::unbounded_channel();
+        let mut sender = Protocol::new(a).with_events(events);
+        let query = sender.missing_query();
+        let send = tokio::spawn(async move { sender.send(&mut FileTransfer::new(src)).await });
+        let answering = Hello { query_missing: true, ..decode_hello(peer_hello()) };
+        b.send(encode_command(&TransferCommand::Hello(answering)).unwrap()).await.unwrap();
+        decode_hello(b.recv().await.unwrap().unwrap());
+        decode_command(&b.recv().await.unwrap().unwrap()).unwrap();
+        b.send(encode_command(&TransferCommand::Bitfield(Bitfield::new(7).to_bytes())).unwrap()).await.unwrap();
+        b.send(encode_command(&TransferCommand::RequestChunk(0)).unwrap()).await.unwrap();
+        b.recv().await.unwrap().unwrap();
+        query.notify_one();
+        assert!(matches!(decode_command(&b.recv().await.unwrap().unwrap()).unwrap(), TransferCommand::QueryMissing));
+        let have = Bitfield::from_indices(7, [0, 4]);
+        b.send(encode_command(&TransferCommand::Bitfield(have.to_bytes())).unwrap()).await.unwrap();
+        for index in have.missing() {
+            b.send(encode_command(&TransferCommand::RequestChunk(index)).unwrap()).await.unwrap();
+            b.recv().await.unwrap().unwrap();
+        }
+        b.send(encode_command(&TransferCommand::Complete).unwrap()).await.unwrap();
+        send.await.unwrap().unwrap();
+        assert_eq!(inbox.try_recv().unwrap(), TransferEvent::Missing(vec![1, 2, 3, 5, 6]));
+    }
+
+    #[test]
+    fn test_sack_ranges_coalesce_acks() {
+        // 100 acks arriving out of order and twice over, with chunks 30 and 61..=63 missing
+        let mut acks: Vec<u32> = (0..104).filter(|i| *i != 30 && !(61..=63).contains(i)).collect();
+        acks.reverse();
+        acks.extend([5, 70, 0]);
+        assert_eq!(acks.len(), 103);
+        let ranges = sack_ranges(acks);
+        assert_eq!(ranges, vec![(0, 29), (31, 60), (64, 103)]);
+        assert!(sack_ranges([]).is_empty());
+        assert_eq!(sack_ranges([7]), vec![(7, 7)]);
+
+        let frame = encode_command(&TransferCommand::SackRanges(ranges.clone())).unwrap();
+        assert!(matches!(decode_command(&frame).unwrap(), TransferCommand::SackRanges(r) if r == ranges));
+    }
+
+    #[tokio::test]
+    async fn test_windowed_receive_batches_acks() {
+        use crate::transfer::CHUNK_SIZE;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "sack.bin", 32 * CHUNK_SIZE);
+        let dst = dir.path().join("out.bin");
+
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(a).with_window(8);
+        let mut receiver = Protocol::new(Recording { inner: b, sent: sent.clone() }).with_window(8);
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let sent = sent.lock().unwrap();
+        assert!(!sent.iter().any(|c| matches!(c, TransferCommand::Ack(_))));
+        let sacks = sent.iter().filter(|c| matches!(c, TransferCommand::SackRanges(_))).count();
+        assert!(sacks > 0 && sacks < 32, "{} SackRanges frames", sacks);
+    }
+
+    fn decode_hello(message: Vec<u8>) -> Hello {
+        match decode_command(&message).unwrap() {
+            TransferCommand::Hello(hello) => hello,
+            other => panic!("expected Hello, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_permissions_and_mtime_are_restored() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "tool.sh", 300);
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        std::fs::File::options().write(true).open(&src).unwrap().set_modified(mtime).unwrap();
+        let dst = dir.path().join("copy.sh");
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        let restored = std::fs::metadata(&dst).unwrap();
+        assert_eq!(restored.permissions().mode() & 0o777, 0o750);
+        assert_eq!(restored.modified().unwrap(), mtime);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_negotiates_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("notes.txt");
+        let data: Vec<u8> = b"negotiated\n".iter().cycle().take(300_000).copied().collect();
+        std::fs::write(&src, &data).unwrap();
+
+        // (sender's compressors, receiver's compressors, what the file is sent with)
+        let cases = [
+            (&Compression::PREFERENCE[..], &Compression::PREFERENCE[..], Some("zstd")),
+            (&[Compression::Zstd][..], &[][..], None),
+            // The sender didn't opt in, so the file's own setting stands
+            (&[][..], &Compression::PREFERENCE[..], Some("zstd")),
+        ];
+        for (i, (ours, theirs, expected)) in cases.into_iter().enumerate() {
+            let dst = dir.path().join(format!("notes-{}.out", i));
+            let (a, b) = loopback();
+            let mut sender = Protocol::new(a).with_compressors(ours);
+            let mut receiver = Protocol::new(b).with_compressors(theirs);
+            // Negotiation, when the sender opted in, overrides what the file asked for
+            let mut source = FileTransfer::new(src.clone()).with_compression(Some(Compression::Zstd));
+            let mut out = FileTransfer::new(dst.clone());
+            let (s, r) = tokio::join!(sender.send(&mut source), receiver.receive(&mut out));
+            s.unwrap();
+            r.unwrap();
+            assert_eq!(out.get_metadata().unwrap().compression.as_deref(), expected);
+            assert_eq!(std::fs::read(&dst).unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_transfer_verifies_plaintext_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("log.txt");
+        let data: Vec<u8> = b"drop compresses repetitive text well\n"
+            .iter()
+            .cycle()
+            .take(2 * 1024 * 1024 + 123)
+            .copied()
+            .collect();
+        std::fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("log.out");
+
+        let (a, b) = loopback();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sender = Protocol::new(Recording { inner: a, sent: sent.clone() });
+        let mut receiver = Protocol::new(b);
+        let mut source = FileTransfer::new(src).with_compression(Some(compression::Compression::Zstd));
+        let mut out = FileTransfer::new(dst.clone());
+        let (s, r) = tokio::join!(sender.send(&mut source), receiver.receive(&mut out));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let metadata = out.get_metadata().unwrap();
+        assert_eq!(metadata.compression.as_deref(), Some("zstd"));
+        let wire_bytes: usize = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| match c {
+                TransferCommand::SendChunk(_, payload) => Some(payload.len()),
+                _ => None,
+            })
+            .sum();
+        assert!(wire_bytes < data.len() / 10, "{} wire bytes", wire_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_custom_chunk_size_is_used_by_receiver() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("small.bin");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 199) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("small.out");
+
+        let chunk_size = crate::transfer::recommend_chunk_size(data.len() as u64, None);
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a);
+        let mut receiver = Protocol::new(b);
+        let mut source = FileTransfer::new(src).with_chunk_size(chunk_size);
+        let mut out = FileTransfer::new(dst.clone());
+        let (s, r) = tokio::join!(sender.send(&mut source), receiver.receive(&mut out));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        let metadata = out.get_metadata().unwrap();
+        assert_eq!(metadata.chunk_size(), 64 * 1024);
+        assert_eq!(metadata.chunks.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_allows_bidirectional_transfers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a_src, a_data) = write_file(dir.path(), "from_a.bin", 1500);
+        let (b_src, b_data) = write_file(dir.path(), "from_b.bin", 1024 * 1024 + 3);
+        let a_dst = dir.path().join("at_b.bin");
+        let b_dst = dir.path().join("at_a.bin");
+
+        let (a, b) = loopback();
+        let mut peer_a = Protocol::new(a).with_keep_alive(true);
+        let mut peer_b = Protocol::new(b).with_keep_alive(true);
+
+        let (sent, received) = tokio::join!(peer_a.send_file(a_src), peer_b.receive_file(a_dst.clone()));
+        sent.unwrap();
+        received.unwrap();
+        assert!(!peer_a.is_closed());
+
+        let (sent, received) = tokio::join!(peer_b.send_file(b_src), peer_a.receive_file(b_dst.clone()));
+        sent.unwrap();
+        received.unwrap();
+
+        assert_eq!(std::fs::read(&a_dst).unwrap(), a_data);
+        assert_eq!(std::fs::read(&b_dst).unwrap(), b_data);
+        let directions: Vec<Direction> = peer_a.history().iter().map(|r| r.direction).collect();
+        assert_eq!(directions, vec![Direction::Sent, Direction::Received]);
+        assert_eq!(peer_b.history()[0].name, "from_a.bin");
+        assert_eq!(peer_b.history()[1].direction, Direction::Sent);
+    }
+
+    // Frames the raw end has received so far, without waiting for more
+    async fn drain(end: &mut LoopbackTransport) -> Vec<TransferCommand> {
+        let mut commands = Vec::new();
+        while let Ok(Some(Some(frame))) = tokio::time::timeout(Duration::from_millis(1), end.recv()).await.map(Result::ok) {
+            commands.push(decode_command(&frame).unwrap());
+        }
+        commands
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_pings_only_while_idle() {
+        let (a, mut b) = loopback();
+        let mut protocol = Protocol::new(a).with_ping_interval(Some(Duration::from_millis(20)));
+        let listener = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while let Some(command) = protocol.recv_command().await.unwrap() {
+                seen.push(command);
+            }
+            seen
+        });
+
+        // Idle pause: pings go out
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let idle = drain(&mut b).await;
+        assert!(idle.len() >= 3 && idle.iter().all(|c| matches!(c, TransferCommand::Ping)), "{:?}", idle);
+        b.send(encode_command(&TransferCommand::Pong).unwrap()).await.unwrap();
+
+        // Data flowing faster than the interval: no pings
+        for _ in 0..30 {
+            b.send(encode_command(&TransferCommand::Bitfield(Vec::new())).unwrap()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let busy = drain(&mut b).await;
+        assert!(!busy.iter().any(|c| matches!(c, TransferCommand::Ping)), "{:?}", busy);
+
+        b.close().await.unwrap();
+        let seen = listener.await.unwrap();
+        assert_eq!(seen.len(), 30);
+        assert!(seen.iter().all(|c| matches!(c, TransferCommand::Bitfield(_))));
+
+        // The other side answers pings without surfacing them
+        let (mut a, b) = loopback();
+        let mut peer = Protocol::new(b);
+        a.send(encode_command(&TransferCommand::Ping).unwrap()).await.unwrap();
+        a.send(encode_command(&TransferCommand::Complete).unwrap()).await.unwrap();
+        assert!(matches!(peer.recv_command().await.unwrap(), Some(TransferCommand::Complete)));
+        assert!(matches!(decode_command(&a.recv().await.unwrap().unwrap()).unwrap(), TransferCommand::Pong));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_describes_completed_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "report.pdf", 700_000);
+        let alice = LocalIdentity::generate("alice");
+        let bob = LocalIdentity::generate("bob");
+        let (alice_public, bob_public) = (alice.public(), bob.public());
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a).with_identity(alice);
+        let mut receiver = Protocol::new(b).with_identity(bob);
+
+        let mut out = FileTransfer::new(dir.path().join("report.out")).with_receipt_sidecar(true);
+        assert!(!out.receipt().verified);
+        let mut file = FileTransfer::new(src);
+        let (s, r) = tokio::join!(sender.send(&mut file), receiver.receive(&mut out));
+        s.unwrap();
+        r.unwrap();
+
+        let receipt = out.receipt();
+        let metadata = out.get_metadata().unwrap();
+        assert_eq!(receipt.name, "report.pdf");
+        assert_eq!(receipt.direction, Some(Direction::Received));
+        assert_eq!(receipt.size, data.len() as u64);
+        assert_eq!(receipt.bytes_transferred, data.len() as u64);
+        assert!(receipt.verified);
+        assert_eq!(receipt.hash, metadata.hash);
+        assert_eq!(receipt.integrity, metadata.integrity);
+        assert!(receipt.throughput_bytes_per_sec > 0.0);
+        assert_eq!(receipt.peer, Some(alice_public));
+        assert_eq!(file.receipt().direction, Some(Direction::Sent));
+        assert!(!file.receipt().verified);
+        assert_eq!(file.receipt().peer, Some(bob_public));
+        assert_eq!(file.receipt().hash, metadata.hash);
+
+        let sidecar = dir.path().join("report.out.receipt.json");
+        let written: TransferReceipt = serde_json::from_slice(&std::fs::read(sidecar).unwrap()).unwrap();
+        // Floats may not survive JSON bit-for-bit
+        let without_rate = |r: TransferReceipt| TransferReceipt { throughput_bytes_per_sec: 0.0, ..r };
+        assert_eq!(without_rate(written), without_rate(receipt));
+        assert!(!file.receipt_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_chunk_stream_reassembles_file() {
+        use futures::StreamExt;
+        let dir = tempfile::tempdir().unwrap();
+        // Ends in an all-zero chunk, which arrives as `ZeroChunk`
+        let src = dir.path().join("stream.bin");
+        let mut data: Vec<u8> = (0..2_500_000u32).map(|i| (i % 211) as u8).collect();
+        data.resize(4 * crate::transfer::CHUNK_SIZE, 0);
+        std::fs::write(&src, &data).unwrap();
+
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let mut file = FileTransfer::new(src);
+        let consume = async {
+            let mut stream = std::pin::pin!(receiver.chunk_stream());
+            let mut reassembled = Vec::new();
+            let mut expected = 0;
+            while let Some((index, chunk)) = stream.next().await.transpose().unwrap() {
+                assert_eq!(index, expected);
+                expected += 1;
+                reassembled.extend(chunk);
+            }
+            reassembled
+        };
+        let (s, reassembled) = tokio::join!(sender.send(&mut file), consume);
+        s.unwrap();
+        assert_eq!(reassembled, data);
+        assert_eq!(receiver.history()[0].hash, file.get_metadata().unwrap().hash);
+        assert!(receiver.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_receive_to_writer_matches_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, data) = write_file(dir.path(), "piped.bin", 3 * crate::transfer::CHUNK_SIZE + 17);
+        let (a, b) = loopback();
+        let (mut sender, mut receiver) = (Protocol::new(a), Protocol::new(b));
+        let mut file = FileTransfer::new(src);
+        let mut out = Vec::new();
+        let (s, r) = tokio::join!(sender.send(&mut file), receiver.receive_to_writer(&mut out));
+        s.unwrap();
+        assert_eq!(r.unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_different_identity_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, _) = write_file(dir.path(), "id.bin", 500);
+        let dst = dir.path().join("id.out");
+        let alice = LocalIdentity::generate("alice");
+        let alice_public = alice.public();
+
+        let (a, b) = loopback();
+        let mut sender = Protocol::new(a).with_identity(alice);
+        let mut receiver = Protocol::new(b);
+        let (s, r) = tokio::join!(sender.send_file(src.clone()), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+        assert_eq!(receiver.peer_identity(), Some(&alice_public));
+
+        // Same peer after an ICE restart: accepted
+        let (a, b) = loopback();
+        sender.reconnect(a);
+        receiver.reconnect(b);
+        let (s, r) = tokio::join!(sender.send_file(src.clone()), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+
+        // Someone else on the reconnected channel: rejected before any data moves
+        let (a, b) = loopback();
+        let mut impostor = Protocol::new(a).with_identity(LocalIdentity::generate("alice"));
+        receiver.reconnect(b);
+        let (s, r) = tokio::join!(impostor.send_file(src), receiver.receive_file(dst));
+        assert!(matches!(r, Err(DropError::Crypto(_))), "{:?}", r);
+        assert!(s.is_err());
+        assert_eq!(receiver.peer_identity(), Some(&alice_public));
+    }
+
+    #[test]
+    fn test_bitfield_roundtrip_and_missing() {
+        let have: Vec<u32> = (0..1000).filter(|i| i % 3 != 0).collect();
+        let field = Bitfield::from_indices(1000, have.iter().copied());
+        let bytes = field.to_bytes();
+        assert_eq!(bytes.len(), 125);
+
+        let decoded = Bitfield::from_bytes(bytes, 1000).unwrap();
+        assert_eq!(decoded, field);
+        assert_eq!(decoded.count(), have.len() as u32);
+        let expected: Vec<u32> = (0..1000).filter(|i| i % 3 == 0).collect();
+        assert_eq!(decoded.missing(), expected);
+
+        let full = Bitfield::from_indices(1000, 0..1000);
+        assert!(full.is_complete());
+        assert_eq!(decoded.wanted_from(&full), expected);
+        assert!(full.wanted_from(&decoded).is_empty());
+    }
+
+    #[test]
+    fn test_bitfield_rejects_malformed_payloads() {
+        assert!(Bitfield::from_bytes(vec![0u8; 3], 10).is_err());
+        // 10 chunks use 2 bytes; the low 6 bits of the last byte must stay clear
+        assert!(Bitfield::from_bytes(vec![0xff, 0x01], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0], 10).unwrap().is_complete());
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::protocol::{Protocol, Transport};
+use crate::transfer::{FileTransfer, ReceiveState};
+use crate::{DropError, Result};
+
+// Receives into `<name>.part` next to the destination (or in a chosen temp
+// directory) and only moves it into place once the whole file has verified.
+// If the transfer fails, the partial file is kept together with a
+// `<name>.part.state` resume token, and the next `receive` picks it up.
+//
+// In append mode each file received is instead appended to the destination
+// in chunk order, as for a log. The offset up to which the destination holds
+// fully verified transfers is kept in `<name>.offset`; a transfer that fails,
+// or is cut short by a restart, is truncated back to that offset by the next
+// `receive` and starts over from there.
+pub struct Receiver {
+    destination: PathBuf,
+    temp_dir: Option<PathBuf>,
+    append: bool,
+}
+
+impl Receiver {
+    pub fn new(destination: PathBuf) -> Self {
+        Self {
+            destination,
+            temp_dir: None,
+            append: false,
+        }
+    }
+
+    // Keep the partial file and resume state in `dir` instead of beside the
+    // destination, e.g. when that location is read-only or short on space
+    pub fn with_temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = Some(dir);
+        self
+    }
+
+    pub fn with_append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    pub fn part_path(&self) -> PathBuf {
+        self.work_dir().join(format!("{}.part", self.file_name()))
+    }
+
+    pub fn state_path(&self) -> PathBuf {
+        self.work_dir().join(format!("{}.part.state", self.file_name()))
+    }
+
+    pub fn offset_path(&self) -> PathBuf {
+        self.work_dir().join(format!("{}.offset", self.file_name()))
+    }
+
+    // End of the last transfer appended in full. Before the first one it's
+    // the length of whatever the destination already held.
+    pub fn committed_offset(&self) -> Result<u64> {
+        match std::fs::read_to_string(self.offset_path()) {
+            Ok(offset) => offset
+                .trim()
+                .parse()
+                .map_err(|_| DropError::Protocol(format!("invalid offset in {}", self.offset_path().display()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => match std::fs::metadata(&self.destination) {
+                Ok(metadata) => Ok(metadata.len()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn receive<T: Transport>(&self, protocol: &mut Protocol<T>) -> Result<()> {
+        if self.append {
+            return self.receive_appending(protocol).await;
+        }
+        let part_path = self.part_path();
+        let state_path = self.state_path();
+        let mut state = match std::fs::read_to_string(&state_path) {
+            Ok(token) if part_path.exists() => ReceiveState::from_resume_token(&token)?,
+            _ => ReceiveState::new(""),
+        };
+
+        let mut file = FileTransfer::new(part_path.clone());
+        if let Err(e) = protocol.receive_with_state(&mut file, &mut state).await {
+            if let Err(save) = std::fs::write(&state_path, state.to_resume_token()) {
+                tracing::warn!(error = %save, "could not save resume state");
+            }
+            return Err(e);
+        }
+
+        move_file(&part_path, &self.destination)?;
+        let _ = std::fs::remove_file(&state_path);
+        Ok(())
+    }
+
+    async fn receive_appending<T: Transport>(&self, protocol: &mut Protocol<T>) -> Result<()> {
+        let offset = self.committed_offset()?;
+        // Recorded before the first append, or a crash during it would leave
+        // the torn bytes counted as committed by the length fallback
+        if !self.offset_path().exists() {
+            self.write_offset(offset)?;
+        }
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.destination)?;
+        // Anything past the committed offset is from a transfer that never finished
+        out.set_len(offset)?;
+        out.seek(SeekFrom::Start(offset))?;
+        let appended = match protocol.receive_to_writer(&mut out).await {
+            Ok(appended) => appended,
+            Err(e) => {
+                if let Err(truncate) = out.set_len(offset) {
+                    tracing::warn!(error = %truncate, "could not drop partial append");
+                }
+                return Err(e);
+            }
+        };
+        out.sync_data()?;
+        self.write_offset(offset + appended)
+    }
+
+    // Write then rename, so a crash never leaves a half-written offset
+    fn write_offset(&self, offset: u64) -> Result<()> {
+        let offset_path = self.offset_path();
+        let partial = offset_path.with_extension("offset.tmp");
+        std::fs::write(&partial, offset.to_string())?;
+        std::fs::rename(partial, offset_path)?;
+        Ok(())
+    }
+
+    fn work_dir(&self) -> PathBuf {
+        match &self.temp_dir {
+            Some(dir) => dir.clone(),
+            None => self
+                .destination
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        self.destination
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("received_file")
+            .to_string()
+    }
+}
+
+// Rename `from` to `to`, falling back to `copy_into_place` when they're on
+// different filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => copy_into_place(from, to),
+        Err(e) => Err(DropError::Io(e)),
+    }
+}
+
+// Copy `from` to a temporary file beside `to`, sync it and rename it over
+// `to`, so `to` is never seen half-written; only then is `from` removed. The
+// copy keeps the permissions and mtime that were restored on the partial file.
+fn copy_into_place(from: &Path, to: &Path) -> Result<()> {
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    if let Err(e) = copy_synced(from, &partial).and_then(|()| std::fs::rename(&partial, to)) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(DropError::Io(e));
+    }
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+fn copy_synced(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::copy(from, to)?;
+    let file = OpenOptions::new().write(true).open(to)?;
+    file.set_modified(std::fs::metadata(from)?.modified()?)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::loopback;
+
+    #[test]
+    fn test_copy_into_place_keeps_mtime_and_removes_source() {
+        let (from_dir, to_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (from, to) = (from_dir.path().join("photo.jpg.part"), to_dir.path().join("photo.jpg"));
+        std::fs::write(&from, b"finished contents").unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        std::fs::File::options().write(true).open(&from).unwrap().set_modified(mtime).unwrap();
+
+        copy_into_place(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"finished contents");
+        assert_eq!(std::fs::metadata(&to).unwrap().modified().unwrap(), mtime);
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_dir(to_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_partials_go_to_temp_dir() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path().join("photo.jpg");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let destination = dest_dir.path().join("photo.jpg");
+        let receiver = Receiver::new(destination.clone()).with_temp_dir(temp_dir.path().to_path_buf());
+        assert_eq!(receiver.part_path(), temp_dir.path().join("photo.jpg.part"));
+
+        let (a, b) = loopback();
+        let (mut sender, mut incoming) = (Protocol::new(a), Protocol::new(b));
+        let mut file = FileTransfer::new(src);
+        let (s, r) = tokio::join!(sender.send(&mut file), receiver.receive(&mut incoming));
+        s.unwrap();
+        r.unwrap();
+
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(dest_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_append_mode_concatenates_transfers() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("telemetry.log");
+        std::fs::write(&log, b"header\n").unwrap();
+        let receiver = Receiver::new(log.clone()).with_append(true);
+        assert_eq!(receiver.committed_offset().unwrap(), 7);
+
+        let mut expected = b"header\n".to_vec();
+        for (name, len) in [("first.bin", 1_500_000u32), ("second.bin", 12_345)] {
+            let src = dir.path().join(name);
+            let data: Vec<u8> = (0..len).map(|i| (i % 239) as u8).collect();
+            std::fs::write(&src, &data).unwrap();
+            expected.extend(&data);
+
+            let (a, b) = loopback();
+            let (mut sender, mut incoming) = (Protocol::new(a), Protocol::new(b));
+            let mut file = FileTransfer::new(src);
+            let (s, r) = tokio::join!(sender.send(&mut file), receiver.receive(&mut incoming));
+            s.unwrap();
+            r.unwrap();
+            assert_eq!(receiver.committed_offset().unwrap(), expected.len() as u64);
+        }
+        assert_eq!(std::fs::read(&log).unwrap(), expected);
+
+        // Left behind by a transfer that died mid-way; a restarted receiver
+        // appends after the committed offset instead
+        let mut torn = expected.clone();
+        torn.extend_from_slice(b"half a chunk");
+        std::fs::write(&log, &torn).unwrap();
+        let src = dir.path().join("third.bin");
+        std::fs::write(&src, b"third").unwrap();
+        let (a, b) = loopback();
+        let (mut sender, mut incoming) = (Protocol::new(a), Protocol::new(b));
+        let restarted = Receiver::new(log.clone()).with_append(true);
+        let mut file = FileTransfer::new(src);
+        let (s, r) = tokio::join!(sender.send(&mut file), restarted.receive(&mut incoming));
+        s.unwrap();
+        r.unwrap();
+        expected.extend_from_slice(b"third");
+        assert_eq!(std::fs::read(&log).unwrap(), expected);
+        assert_eq!(restarted.committed_offset().unwrap(), expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_first_append_commits_the_starting_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("telemetry.log");
+        std::fs::write(&log, b"header\n").unwrap();
+        let receiver = Receiver::new(log.clone()).with_append(true);
+        // The very first transfer fails, and a crash leaves torn bytes behind
+        let (a, b) = loopback();
+        drop(a);
+        assert!(receiver.receive(&mut Protocol::new(b)).await.is_err());
+        assert_eq!(std::fs::read_to_string(receiver.offset_path()).unwrap(), "7");
+        std::fs::write(&log, b"header\ntorn").unwrap();
+
+        let src = dir.path().join("first.bin");
+        std::fs::write(&src, b"first").unwrap();
+        let (a, b) = loopback();
+        let (mut sender, mut incoming) = (Protocol::new(a), Protocol::new(b));
+        let mut file = FileTransfer::new(src);
+        let (s, r) = tokio::join!(sender.send(&mut file), receiver.receive(&mut incoming));
+        s.unwrap();
+        r.unwrap();
+        assert_eq!(std::fs::read(&log).unwrap(), b"header\nfirst");
+    }
+}
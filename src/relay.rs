@@ -0,0 +1,216 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::AggregatedMessage;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use crate::protocol::encode_command;
+use crate::{AppState, TransferCommand};
+
+// Largest frame the relay accepts; a JSON-encoded chunk at MAX_CHUNK_SIZE fits
+pub const MAX_RELAY_FRAME: usize = 64 * 1024 * 1024;
+
+// Most frames, and bytes, waiting in one side's inbox. A peer that keeps
+// sending while the other isn't reading is disconnected once either is hit,
+// rather than having the server buffer it without limit.
+pub const MAX_RELAY_QUEUED_FRAMES: usize = 1024;
+pub const MAX_RELAY_QUEUED_BYTES: usize = 2 * MAX_RELAY_FRAME;
+
+// Sent to each peer still connected when the server's shutdown drain runs out
+pub const SHUTDOWN_NOTICE: &str = "server shutting down";
+
+// Relay between the two WebSocket peers of one session. Each side has an
+// inbox created up front, so frames sent before the other peer connects are
+// buffered rather than lost.
+pub struct RelayRoom {
+    inboxes: [InboxSender; 2],
+    unclaimed: [Option<Inbox>; 2],
+}
+
+impl RelayRoom {
+    fn new() -> Self {
+        let (a_tx, a_rx) = inbox();
+        let (b_tx, b_rx) = inbox();
+        Self {
+            inboxes: [a_tx, b_tx],
+            unclaimed: [Some(a_rx), Some(b_rx)],
+        }
+    }
+
+    // Take a free side: its inbox and the sender into the other side's inbox
+    fn claim(&mut self) -> Option<(Inbox, InboxSender)> {
+        let side = self.unclaimed.iter().position(Option::is_some)?;
+        let inbox = self.unclaimed[side].take()?;
+        Some((inbox, self.inboxes[1 - side].clone()))
+    }
+}
+
+fn inbox() -> (InboxSender, Inbox) {
+    let (tx, rx) = mpsc::channel(MAX_RELAY_QUEUED_FRAMES);
+    let queued = Arc::new(AtomicUsize::new(0));
+    (InboxSender { tx, queued: queued.clone() }, Inbox { rx, queued })
+}
+
+#[derive(Clone)]
+struct InboxSender {
+    tx: mpsc::Sender<Bytes>,
+    // Bytes sent but not yet taken out by the `Inbox`
+    queued: Arc<AtomicUsize>,
+}
+
+struct Inbox {
+    rx: mpsc::Receiver<Bytes>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl InboxSender {
+    // False if the inbox is full or closed; the frame is dropped either way
+    fn try_send(&self, frame: Bytes) -> bool {
+        let len = frame.len();
+        let fits = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                queued.checked_add(len).filter(|&total| total <= MAX_RELAY_QUEUED_BYTES)
+            })
+            .is_ok();
+        if fits && self.tx.try_send(frame).is_err() {
+            self.queued.fetch_sub(len, Ordering::SeqCst);
+            return false;
+        }
+        fits
+    }
+}
+
+impl Inbox {
+    // Cancel safe, like `mpsc::Receiver::recv`
+    async fn recv(&mut self) -> Option<Bytes> {
+        let frame = self.rx.recv().await?;
+        self.queued.fetch_sub(frame.len(), Ordering::SeqCst);
+        Some(frame)
+    }
+}
+
+// WebSocket fallback for networks that block WebRTC: frames from one peer are
+// forwarded verbatim to the other. When either side disconnects the room is
+// torn down, which also closes the other side. Forwarded frames count against
+// the session's byte cap; a frame that would exceed it, or that doesn't fit
+// in the other side's inbox (see `MAX_RELAY_QUEUED_BYTES`), closes the relay.
+// See `AppState::shutdown` for how open relays are wound down.
+#[get("/api/session/{session_id}/relay")]
+pub async fn relay_socket(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let session_id = path.into_inner();
+    if data.shutting_down() {
+        return Ok(HttpResponse::ServiceUnavailable().body("Server is shutting down"));
+    }
+    match data.sessions.get_mut(&session_id) {
+        Some(mut session) => {
+            if let Some(rejection) = crate::reject_origin(&req, &session) {
+                return Ok(rejection);
+            }
+            if data.session_exhausted(&session) {
+                return Ok(HttpResponse::TooManyRequests().body("Session byte cap exceeded"));
+            }
+            session.last_activity = data.clock.now();
+        }
+        None => return Ok(HttpResponse::NotFound().body("Session not found")),
+    }
+    let claimed = data
+        .relays
+        .entry(session_id.clone())
+        .or_insert_with(RelayRoom::new)
+        .claim();
+    let Some((mut inbox, peer)) = claimed else {
+        return Ok(HttpResponse::Conflict().body("Relay already has two peers"));
+    };
+
+    let (response, mut socket, stream) = actix_ws::handle(&req, body)?;
+    let mut stream = stream
+        .max_frame_size(MAX_RELAY_FRAME)
+        .aggregate_continuations()
+        .max_continuation_size(MAX_RELAY_FRAME);
+    data.open_relays.fetch_add(1, Ordering::SeqCst);
+    data.emit(crate::webhook::PEER_JOINED, &session_id);
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                // Checked first so a peer that just left doesn't hide the shutdown
+                biased;
+                _ = data.relay_shutdown.cancelled() => {
+                    if let Ok(notice) = encode_command(&TransferCommand::Error(SHUTDOWN_NOTICE.to_string())) {
+                        let _ = socket.binary(notice).await;
+                    }
+                    break;
+                }
+                message = stream.next() => match message {
+                    Some(Ok(AggregatedMessage::Binary(frame))) => {
+                        let charged = data
+                            .sessions
+                            .get_mut(&session_id)
+                            .is_some_and(|mut session| data.charge_session(&mut session, frame.len() as u64));
+                        if !charged || !peer.try_send(frame) {
+                            break;
+                        }
+                        data.stats.messages_relayed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => {
+                        if socket.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(AggregatedMessage::Text(_) | AggregatedMessage::Pong(_))) => {}
+                    Some(Ok(AggregatedMessage::Close(_)) | Err(_)) | None => break,
+                },
+                frame = inbox.recv() => match frame {
+                    Some(frame) => {
+                        if socket.binary(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+        // Only the side that tears the room down reports it
+        if data.relays.remove(&session_id).is_some() {
+            data.emit(crate::webhook::COMPLETED, &session_id);
+        }
+        let _ = socket.close(None).await;
+        data.open_relays.fetch_sub(1, Ordering::SeqCst);
+    });
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inbox_is_bounded_by_bytes() {
+        let (tx, mut rx) = inbox();
+        assert!(tx.try_send(Bytes::from(vec![0u8; MAX_RELAY_FRAME])));
+        assert!(tx.try_send(Bytes::from(vec![1u8; MAX_RELAY_FRAME])));
+        // The receiving peer isn't reading; the next frame doesn't fit
+        assert!(!tx.try_send(Bytes::from_static(b"x")));
+        assert_eq!(rx.recv().await.unwrap()[0], 0);
+        assert!(tx.try_send(Bytes::from_static(b"x")));
+        assert_eq!(rx.recv().await.unwrap()[0], 1);
+        assert_eq!(&rx.recv().await.unwrap()[..], b"x");
+        assert_eq!(tx.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_inbox_is_bounded_by_frames() {
+        let (tx, _rx) = inbox();
+        for _ in 0..MAX_RELAY_QUEUED_FRAMES {
+            assert!(tx.try_send(Bytes::from_static(b"f")));
+        }
+        assert!(!tx.try_send(Bytes::from_static(b"f")));
+        assert_eq!(tx.queued.load(Ordering::SeqCst), MAX_RELAY_QUEUED_FRAMES);
+    }
+}
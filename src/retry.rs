@@ -0,0 +1,89 @@
+use std::time::Duration;
+use rand::Rng;
+
+// Exponential backoff with optional jitter, shared by anything that retries
+// (signaling polls, chunk retransmits, ICE restarts).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // Total attempts allowed, including the first one
+    pub max_attempts: u32,
+    // Delay before the first retry; doubled for every attempt after that.
+    pub base_delay: Duration,
+    // Upper bound for any single delay
+    pub max_delay: Duration,
+    // Fraction (0.0..=1.0) of the computed delay that may be randomly shaved off
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Whether another attempt is allowed after `attempt` attempts have failed
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    // The un-jittered delay before retry number `attempt` (0-based)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    // The delay before retry number `attempt` (0-based), with jitter applied.
+    // Never exceeds `max_delay`.
+    pub fn next_delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff(attempt);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let shave = rand::thread_rng().gen_range(0.0..=jitter);
+        delay.mul_f64(1.0 - shave)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_until_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.next_delay(0), Duration::from_millis(100));
+        assert_eq!(policy.next_delay(1), Duration::from_millis(200));
+        assert_eq!(policy.next_delay(2), Duration::from_millis(400));
+        assert_eq!(policy.next_delay(4), Duration::from_secs(1));
+        assert_eq!(policy.next_delay(40), Duration::from_secs(1));
+        assert!(policy.should_retry(9));
+        assert!(!policy.should_retry(10));
+    }
+
+    #[test]
+    fn test_jittered_delay_stays_within_bounds() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for attempt in 0..20 {
+            let delay = policy.next_delay(attempt);
+            let ceiling = policy.backoff(attempt);
+            assert!(delay <= ceiling);
+            assert!(delay >= ceiling.mul_f64(0.5));
+            assert!(delay <= policy.max_delay);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::config::PEER_ID_HEADER;
+use crate::retry::RetryPolicy;
+use crate::{CreateSessionResponse, DropError, Result, SignalingMessage, HAS_MORE_HEADER};
+
+// Carries signaling messages between the two peers being paired, see
+// `WebRTCTransfer::pair_as_offerer`. `SignalingClient` does it over this
+// crate's HTTP API; embedders with a channel of their own (MQTT, XMPP, a
+// game's lobby) implement it to pair peers without the server.
+#[async_trait]
+pub trait SignalingTransport: Send {
+    async fn send(&mut self, message: SignalingMessage) -> Result<()>;
+    // Messages from the other peer, in the order they were sent
+    fn recv(&mut self) -> BoxStream<'_, Result<SignalingMessage>>;
+}
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Client for the signaling endpoints over plain HTTP/1.1. Polling rides out
+// server restarts: connection failures and 5xx responses are retried with the
+// client's `RetryPolicy`, keeping the same session id throughout.
+pub struct SignalingClient {
+    // host:port of the signaling server
+    authority: String,
+    session_id: String,
+    // Random unless set; the server uses it to keep our own messages queued
+    // for the other peer instead of handing them back to us
+    peer_id: String,
+    retry: RetryPolicy,
+    // Wait between polls that came back empty, when used as a `SignalingTransport`
+    poll_interval: Duration,
+    // Polled but not yet handed out by `SignalingTransport::recv`. Kept here
+    // rather than in the stream so dropping it between messages loses none.
+    buffered: VecDeque<SignalingMessage>,
+}
+
+struct Response {
+    status: u16,
+    has_more: bool,
+    body: Vec<u8>,
+}
+
+enum Failure {
+    // Worth retrying: the server is restarting, overloaded or unreachable
+    Transient(String),
+    Fatal(DropError),
+}
+
+impl SignalingClient {
+    // Join an existing session on `server` ("http://host:port")
+    pub fn join(server: &str, session_id: impl Into<String>) -> Result<Self> {
+        let authority = server
+            .trim_end_matches('/')
+            .strip_prefix("http://")
+            .filter(|a| !a.is_empty() && !a.contains('/'))
+            .ok_or_else(|| DropError::Protocol(format!("unsupported signaling server URL: {}", server)))?;
+        Ok(Self {
+            authority: authority.to_string(),
+            session_id: session_id.into(),
+            peer_id: format!("{:016x}", rand::random::<u64>()),
+            retry: RetryPolicy::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            buffered: VecDeque::new(),
+        })
+    }
+
+    // Create a new session on `server` and join it
+    pub async fn create(server: &str) -> Result<Self> {
+        let mut client = Self::join(server, String::new())?;
+        let response = client.request_with_retry("POST", "/api/session/create", None).await?;
+        let created: CreateSessionResponse = serde_json::from_slice(&response.body)?;
+        client.session_id = created.session_id;
+        Ok(client)
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    // Sent as the peer id header on every request
+    pub fn with_peer_id(mut self, peer_id: impl Into<String>) -> Self {
+        self.peer_id = peer_id.into();
+        self
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub async fn send_signal(&self, message: &SignalingMessage) -> Result<()> {
+        let path = format!("/api/session/{}/signal/send", self.session_id);
+        self.request_with_retry("POST", &path, Some(serde_json::to_vec(message)?)).await?;
+        Ok(())
+    }
+
+    // Every message queued for us, following has-more until the queue is
+    // drained. Fails with `DropError::Timeout` once the retry policy runs out.
+    pub async fn poll_signal(&self) -> Result<Vec<SignalingMessage>> {
+        let mut messages = Vec::new();
+        loop {
+            let (batch, has_more) = self.poll_batch().await?;
+            messages.extend(batch);
+            if !has_more {
+                return Ok(messages);
+            }
+        }
+    }
+
+    // One receive request: a batch and whether more are queued
+    async fn poll_batch(&self) -> Result<(Vec<SignalingMessage>, bool)> {
+        let path = format!("/api/session/{}/signal/receive", self.session_id);
+        let response = self.request_with_retry("GET", &path, None).await?;
+        Ok((serde_json::from_slice(&response.body)?, response.has_more))
+    }
+
+    async fn request_with_retry(&self, method: &str, path: &str, body: Option<Vec<u8>>) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let reason = match self.request(method, path, body.as_deref()).await {
+                Ok(response) => return Ok(response),
+                Err(Failure::Fatal(e)) => return Err(e),
+                Err(Failure::Transient(reason)) => reason,
+            };
+            attempt += 1;
+            if !self.retry.should_retry(attempt) {
+                return Err(DropError::Timeout(format!(
+                    "signaling server unavailable after {} attempts: {}",
+                    attempt, reason
+                )));
+            }
+            let delay = self.retry.next_delay(attempt - 1);
+            tracing::debug!(attempt, ?delay, reason, "retrying signaling request");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> std::result::Result<Response, Failure> {
+        let mut stream = TcpStream::connect(&self.authority)
+            .await
+            .map_err(|e| Failure::Transient(e.to_string()))?;
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, self.authority);
+        head.push_str(&format!("{}: {}\r\n", PEER_ID_HEADER, self.peer_id));
+        let body = body.unwrap_or_default();
+        if method == "POST" {
+            head.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+        let mut raw = Vec::new();
+        let exchange = async {
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body).await?;
+            stream.read_to_end(&mut raw).await
+        };
+        exchange.await.map_err(|e| Failure::Transient(e.to_string()))?;
+
+        let response = parse_response(&raw)?;
+        match response.status {
+            200..=299 => Ok(response),
+            500..=599 => Err(Failure::Transient(format!("HTTP {}", response.status))),
+            status => Err(Failure::Fatal(DropError::Protocol(format!(
+                "signaling request failed with HTTP {}: {}",
+                status,
+                String::from_utf8_lossy(&response.body)
+            )))),
+        }
+    }
+}
+
+#[async_trait]
+impl SignalingTransport for SignalingClient {
+    async fn send(&mut self, message: SignalingMessage) -> Result<()> {
+        self.send_signal(&message).await
+    }
+
+    // Polls until something arrives. The server leaves our own messages
+    // queued, so only the other peer's come back. Dropping the stream while a
+    // poll is in flight can still lose what that one request drained.
+    fn recv(&mut self) -> BoxStream<'_, Result<SignalingMessage>> {
+        Box::pin(futures::stream::try_unfold(self, |client| async move {
+            loop {
+                if let Some(message) = client.buffered.pop_front() {
+                    return Ok(Some((message, client)));
+                }
+                let (batch, has_more) = client.poll_batch().await?;
+                client.buffered.extend(batch);
+                if client.buffered.is_empty() && !has_more {
+                    tokio::time::sleep(client.poll_interval).await;
+                }
+            }
+        }))
+    }
+}
+
+fn parse_response(raw: &[u8]) -> std::result::Result<Response, Failure> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let header_len = match parsed.parse(raw) {
+        Ok(httparse::Status::Complete(len)) => len,
+        // Cut off mid-response, e.g. by a restart
+        Ok(httparse::Status::Partial) => return Err(Failure::Transient("truncated response".to_string())),
+        Err(e) => return Err(Failure::Fatal(DropError::Protocol(format!("invalid HTTP response: {}", e)))),
+    };
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+    let mut body = raw[header_len..].to_vec();
+    if let Some(len) = header("content-length").and_then(|v| v.trim().parse::<usize>().ok()) {
+        if body.len() < len {
+            return Err(Failure::Transient("truncated response".to_string()));
+        }
+        body.truncate(len);
+    }
+    Ok(Response {
+        status: parsed.code.unwrap_or_default(),
+        has_more: header(HAS_MORE_HEADER) == Some("true"),
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use futures::TryStreamExt;
+    use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+
+    #[get("/api/session/{session_id}/signal/receive")]
+    async fn flaky_receive(failures: web::Data<AtomicU32>, path: web::Path<String>) -> impl Responder {
+        // Fail the first two polls, then hand out one message
+        if failures.fetch_add(1, Ordering::SeqCst) < 2 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        HttpResponse::Ok().json(vec![SignalingMessage {
+            message_type: "offer".to_string(),
+            payload: path.into_inner(),
+            from: None,
+        }])
+    }
+
+    #[actix_web::test]
+    async fn test_poll_signal_recovers_from_unavailable_server() {
+        let polls = web::Data::new(AtomicU32::new(0));
+        let state = polls.clone();
+        let server = HttpServer::new(move || App::new().app_data(state.clone()).service(flaky_receive))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+        };
+        let client = SignalingClient::join(&base, "FLAKY1").unwrap().with_retry_policy(policy.clone());
+        let messages = client.poll_signal().await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, "FLAKY1");
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        // Never comes back
+        handle.stop(false).await;
+        let client = SignalingClient::join(&base, "FLAKY1").unwrap().with_retry_policy(policy);
+        assert!(matches!(client.poll_signal().await, Err(DropError::Timeout(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_peers_sharing_a_queue_only_see_each_other() {
+        let state = web::Data::new(crate::AppState::new());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .service(crate::create_session)
+                .service(crate::send_signal)
+                .service(crate::receive_signal)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let interval = Duration::from_millis(10);
+        let mut offerer = SignalingClient::create(&base).await.unwrap().with_poll_interval(interval);
+        let mut answerer = SignalingClient::join(&base, offerer.session_id()).unwrap().with_poll_interval(interval);
+        let message = |kind: &str| SignalingMessage { message_type: kind.to_string(), payload: kind.to_string(), from: None };
+
+        SignalingTransport::send(&mut offerer, message("offer")).await.unwrap();
+        // The offerer starts polling first and keeps draining its own offer,
+        // which has to stay queued for the answerer
+        let answered = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let offer = answerer.recv().try_next().await.unwrap().unwrap();
+            assert_eq!(offer.message_type, "offer");
+            SignalingTransport::send(&mut answerer, message("answer")).await.unwrap();
+        };
+        let mut incoming = offerer.recv();
+        let (answer, ()) = tokio::join!(incoming.try_next(), answered);
+        assert_eq!(answer.unwrap().unwrap().message_type, "answer");
+        drop(incoming);
+
+        // Both come back in one poll; the second outlives the stream it was polled by
+        for kind in ["first", "second"] {
+            SignalingTransport::send(&mut answerer, message(kind)).await.unwrap();
+        }
+        assert_eq!(offerer.recv().try_next().await.unwrap().unwrap().message_type, "first");
+        assert_eq!(offerer.recv().try_next().await.unwrap().unwrap().message_type, "second");
+        handle.stop(false).await;
+    }
+}
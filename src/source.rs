@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use crate::Result;
+
+// Random-access byte source a `FileTransfer` reads outgoing chunks from.
+// Lets a transfer serve data that isn't a plain file on disk, such as a
+// member of an archive or the plaintext of an encrypted container.
+pub trait ChunkSource: Send + Sync {
+    // Total number of bytes in the source
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // Fill `buffer` with bytes starting at `offset`, returning how many were
+    // read. Only returns short at the end of the source.
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize>;
+
+    // Unix permission bits and mtime (seconds since the epoch) to advertise, if known
+    fn attributes(&self) -> (Option<u32>, Option<i64>) {
+        (None, None)
+    }
+}
+
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl ChunkSource for FileSource {
+    fn len(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let bytes_read = file.read(&mut buffer[filled..])?;
+            if bytes_read == 0 {
+                break;
+            }
+            filled += bytes_read;
+        }
+        Ok(filled)
+    }
+
+    fn attributes(&self) -> (Option<u32>, Option<i64>) {
+        std::fs::metadata(&self.path)
+            .map(|m| crate::transfer::file_attributes(&m))
+            .unwrap_or((None, None))
+    }
+}
@@ -0,0 +1,635 @@
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use actix_web::http::header::{self, Header};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use crate::hash::{HashBackend, Sha256Hasher};
+use crate::protocol::Bitfield;
+use crate::transfer::sanitize_filename;
+use crate::{ApiError, AppState};
+
+// Optional on upload; returned, sanitized, on download
+pub const FILE_NAME_HEADER: &str = "x-drop-file-name";
+// SHA-256 of the stored file, so the receiver can verify what it downloaded
+pub const SHA256_HEADER: &str = "x-drop-sha256";
+
+pub const DEFAULT_SPOOL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const DEFAULT_SPOOL_QUOTA: u64 = 1 << 30; // 1 GiB
+
+// Bytes read from disk per frame of a download
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Stored files are hashed in pieces of this size too, so a receiver resuming
+// an interrupted download can tell which pieces it already has intact
+pub const SPOOL_CHUNK_SIZE: u64 = 256 * 1024;
+
+// Store-and-forward for "drop it now, pick it up later": a sender uploads a
+// whole file under a fresh code and can disconnect, and a receiver downloads
+// it any time before the TTL runs out. Unlike the relay, the peers never need
+// to be online together. Files live in `dir`; the index is in memory, so
+// anything left there by a previous process is removed on `open`.
+pub struct Spool {
+    dir: PathBuf,
+    ttl: Duration,
+    // Most bytes stored at once, counting uploads still in progress
+    quota: u64,
+    used: AtomicU64,
+    entries: DashMap<String, SpoolEntry>,
+}
+
+struct SpoolEntry {
+    path: PathBuf,
+    name: Option<String>,
+    size: u64,
+    hash: String,
+    chunk_hashes: Vec<String>,
+    stored: Instant,
+    deletion_token: String,
+}
+
+// Returned by an upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpoolReceipt {
+    pub code: String,
+    pub size: u64,
+    pub hash: String,
+    pub expires_in_secs: u64,
+    // Only the uploader gets this; `DELETE` requires it as `Authorization: Bearer <token>`
+    pub deletion_token: String,
+}
+
+// Returned by `GET /api/spool/{code}/chunks`: the strong ETag of the whole
+// file, which resumed range requests send back as `If-Range`, and the
+// SHA-256 of every `chunk_size` piece of it, which act as per-chunk ETags
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpoolManifest {
+    pub size: u64,
+    pub etag: String,
+    pub chunk_size: u64,
+    pub chunk_hashes: Vec<String>,
+}
+
+impl SpoolManifest {
+    // Whether `data` is chunk `index` as stored
+    pub fn verify(&self, index: u32, data: &[u8]) -> bool {
+        self.chunk_hashes
+            .get(index as usize)
+            .is_some_and(|hash| *hash == crate::hash::sha256_hex(data))
+    }
+
+    // Inclusive byte ranges still to fetch once the chunks in `completed` are
+    // verified. Runs of missing chunks are merged so each run is one request.
+    pub fn missing_ranges(&self, completed: &Bitfield) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for index in completed.missing() {
+            let start = index as u64 * self.chunk_size;
+            let end = (start + self.chunk_size).min(self.size) - 1;
+            match ranges.last_mut() {
+                Some(last) if last.1 + 1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+}
+
+// Hashes an upload in `SPOOL_CHUNK_SIZE` pieces as its body arrives
+struct ChunkHasher {
+    current: Sha256Hasher,
+    filled: u64,
+    hashes: Vec<String>,
+}
+
+impl ChunkHasher {
+    fn new() -> Self {
+        Self { current: HashBackend::default().hasher(), filled: 0, hashes: Vec::new() }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = data.len().min((SPOOL_CHUNK_SIZE - self.filled) as usize);
+            self.current.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == SPOOL_CHUNK_SIZE {
+                let full = std::mem::replace(&mut self.current, HashBackend::default().hasher());
+                self.hashes.push(full.finalize_hex());
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.filled > 0 {
+            self.hashes.push(self.current.finalize_hex());
+        }
+        self.hashes
+    }
+}
+
+fn file_etag(hash: &str) -> header::EntityTag {
+    header::EntityTag::new_strong(hash.to_string())
+}
+
+const STORED_EXTENSION: &str = "spool";
+const PARTIAL_EXTENSION: &str = "part";
+
+impl Spool {
+    pub fn open(dir: PathBuf, ttl: Duration, quota: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let ours = path
+                .extension()
+                .is_some_and(|e| e == STORED_EXTENSION || e == PARTIAL_EXTENSION);
+            if ours {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            ttl,
+            quota,
+            used: AtomicU64::new(0),
+            entries: DashMap::new(),
+        })
+    }
+
+    // Bytes held by stored files and uploads in progress
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    // Claim `bytes` of the quota, or nothing if that would exceed it
+    fn reserve(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.quota)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    fn expired(&self, entry: &SpoolEntry, now: Instant) -> bool {
+        now.saturating_duration_since(entry.stored) >= self.ttl
+    }
+
+    fn remove(&self, code: &str) -> bool {
+        let Some((_, entry)) = self.entries.remove(code) else {
+            return false;
+        };
+        if let Err(e) = std::fs::remove_file(&entry.path) {
+            tracing::warn!(error = %e, "could not delete spooled file");
+        }
+        self.release(entry.size);
+        true
+    }
+
+    // Delete files past their TTL, returning how many were removed
+    pub fn reap(&self, now: Instant) -> usize {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| self.expired(entry, now))
+            .map(|entry| entry.key().clone())
+            .collect();
+        expired.iter().filter(|code| self.remove(code)).count()
+    }
+}
+
+// An upload in progress. Owns the file at `path` and the quota reserved for
+// it, and gives both back when dropped unless `keep` is called first, which
+// covers a client that disconnects and gets the handler dropped mid-upload.
+struct PartialUpload<'a> {
+    spool: &'a Spool,
+    path: PathBuf,
+    reserved: u64,
+    kept: bool,
+}
+
+impl<'a> PartialUpload<'a> {
+    fn new(spool: &'a Spool, path: PathBuf) -> Self {
+        Self { spool, path, reserved: 0, kept: false }
+    }
+
+    fn reserve(&mut self, bytes: u64) -> bool {
+        let reserved = self.spool.reserve(bytes);
+        if reserved {
+            self.reserved += bytes;
+        }
+        reserved
+    }
+
+    async fn rename(&mut self, to: PathBuf) -> std::io::Result<()> {
+        tokio::fs::rename(&self.path, &to).await?;
+        self.path = to;
+        Ok(())
+    }
+
+    // The file is now a stored entry's, and so is its share of the quota
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for PartialUpload<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!(error = %e, "could not delete abandoned upload");
+            }
+        }
+        self.spool.release(self.reserved);
+    }
+}
+
+fn spool_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiError::new("spool_disabled", "store-and-forward is not enabled"))
+}
+
+fn spool_full() -> HttpResponse {
+    HttpResponse::InsufficientStorage().json(ApiError::new("spool_full", "spool quota exceeded"))
+}
+
+// Store the request body under a new code. The body is streamed to disk and
+// counted against the quota as it arrives, so an upload that would overflow
+// it fails with a 507 without storing anything.
+#[post("/api/spool/upload")]
+pub async fn upload(req: HttpRequest, mut body: web::Payload, data: web::Data<AppState>) -> HttpResponse {
+    if data.shutting_down() {
+        return HttpResponse::ServiceUnavailable().body("Server is shutting down");
+    }
+    let Some(spool) = &data.spool else {
+        return spool_disabled();
+    };
+    let name = req
+        .headers()
+        .get(FILE_NAME_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(sanitize_filename);
+    let id = uuid::Uuid::new_v4();
+    // Declared before the file so the file is closed by the time it is deleted
+    let mut partial = PartialUpload::new(spool, spool.dir.join(format!("{}.{}", id, PARTIAL_EXTENSION)));
+    let Ok(mut file) = tokio::fs::File::create(&partial.path).await else {
+        return HttpResponse::InternalServerError().body("Failed to store upload");
+    };
+
+    let mut size = 0u64;
+    let mut hasher = HashBackend::default().hasher();
+    let mut chunk_hasher = ChunkHasher::new();
+    loop {
+        let chunk = match body.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(_)) => return HttpResponse::BadRequest().body("Upload interrupted"),
+            None => break,
+        };
+        if !partial.reserve(chunk.len() as u64) {
+            return spool_full();
+        }
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+        chunk_hasher.update(&chunk);
+        if file.write_all(&chunk).await.is_err() {
+            return HttpResponse::InternalServerError().body("Failed to store upload");
+        }
+    }
+    let stored = spool.dir.join(format!("{}.{}", id, STORED_EXTENSION));
+    if file.sync_all().await.is_err() || partial.rename(stored.clone()).await.is_err() {
+        return HttpResponse::InternalServerError().body("Failed to store upload");
+    }
+    drop(file);
+
+    let hash = hasher.finalize_hex();
+    let deletion_token = uuid::Uuid::new_v4().simple().to_string();
+    let entry = SpoolEntry {
+        path: stored,
+        name,
+        size,
+        hash: hash.clone(),
+        chunk_hashes: chunk_hasher.finish(),
+        stored: data.clock.now(),
+        deletion_token: deletion_token.clone(),
+    };
+    for _ in 0..=data.max_code_collision_retries {
+        let code = (data.code_generator)();
+        if let dashmap::mapref::entry::Entry::Vacant(vacant) = spool.entries.entry(code.clone()) {
+            vacant.insert(entry);
+            partial.keep();
+            let expires_in_secs = spool.ttl.as_secs();
+            return HttpResponse::Ok().json(SpoolReceipt { code, size, hash, expires_in_secs, deletion_token });
+        }
+    }
+    crate::codes_exhausted()
+}
+
+// Stream a stored file back. It stays stored until it expires or is deleted,
+// so an interrupted download can be resumed: the stored SHA-256 is the
+// file's strong ETag, and a request for a single byte range gets just that
+// range back as a 206. With `If-Range` the range is only honored while the
+// ETag still matches; otherwise the whole file comes back with a 200.
+#[get("/api/spool/{code}")]
+pub async fn download(req: HttpRequest, data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let Some(spool) = &data.spool else {
+        return spool_disabled();
+    };
+    let code = path.into_inner();
+    let (stored, name, size, hash) = match spool.entries.get(&code) {
+        Some(entry) if !spool.expired(&entry, data.clock.now()) => {
+            (entry.path.clone(), entry.name.clone(), entry.size, entry.hash.clone())
+        }
+        _ => return HttpResponse::NotFound().body("Nothing stored under this code"),
+    };
+    let etag = file_etag(&hash);
+    let Ok(range) = requested_range(&req, &etag, size) else {
+        return HttpResponse::RangeNotSatisfiable()
+            .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(size),
+            }))
+            .finish();
+    };
+    let Ok(mut file) = tokio::fs::File::open(&stored).await else {
+        return HttpResponse::NotFound().body("Nothing stored under this code");
+    };
+    let (start, len) = match range {
+        Some((first, last)) => (first, last - first + 1),
+        None => (0, size),
+    };
+    if file.seek(SeekFrom::Start(start)).await.is_err() {
+        return HttpResponse::InternalServerError().body("Failed to read stored file");
+    }
+    let frames = futures::stream::try_unfold(file.take(len), |mut file| async move {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        let n = file.read(&mut buffer).await?;
+        buffer.truncate(n);
+        Ok::<_, std::io::Error>((n > 0).then(|| (web::Bytes::from(buffer), file)))
+    });
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    response
+        .content_type("application/octet-stream")
+        .insert_header(header::ETag(etag))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((SHA256_HEADER, hash));
+    if let Some(range) = range {
+        response.insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+            range: Some(range),
+            instance_length: Some(size),
+        }));
+    }
+    if let Some(name) = name {
+        response.insert_header((FILE_NAME_HEADER, name));
+    }
+    response.no_chunking(len).streaming(frames)
+}
+
+// The inclusive byte range a download should answer with, or Ok(None) for the
+// whole file: no `Range`, one we don't serve (several ranges, another unit),
+// or an `If-Range` that no longer matches `etag`. Err(()) when the range lies
+// entirely past the end of the file.
+fn requested_range(
+    req: &HttpRequest,
+    etag: &header::EntityTag,
+    size: u64,
+) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let Ok(header::Range::Bytes(specs)) = header::Range::parse(req) else {
+        return Ok(None);
+    };
+    let [spec] = specs.as_slice() else {
+        return Ok(None);
+    };
+    if req.headers().contains_key(header::IF_RANGE) {
+        match header::IfRange::parse(req) {
+            Ok(header::IfRange::EntityTag(tag)) if tag.strong_eq(etag) => {}
+            _ => return Ok(None),
+        }
+    }
+    spec.to_satisfiable_range(size).map(Some).ok_or(())
+}
+
+// What a receiver needs to resume an interrupted download: check the bytes it
+// has against `chunk_hashes`, then ask only for the chunks that failed.
+#[get("/api/spool/{code}/chunks")]
+pub async fn chunks(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let Some(spool) = &data.spool else {
+        return spool_disabled();
+    };
+    match spool.entries.get(&path.into_inner()) {
+        Some(entry) if !spool.expired(&entry, data.clock.now()) => HttpResponse::Ok().json(SpoolManifest {
+            size: entry.size,
+            etag: file_etag(&entry.hash).to_string(),
+            chunk_size: SPOOL_CHUNK_SIZE,
+            chunk_hashes: entry.chunk_hashes.clone(),
+        }),
+        _ => HttpResponse::NotFound().body("Nothing stored under this code"),
+    }
+}
+
+// Give up a stored file before its TTL, e.g. once the receiver has verified
+// it. Needs the receipt's deletion token: the code alone is what every
+// receiver has.
+#[delete("/api/spool/{code}")]
+pub async fn discard(req: HttpRequest, data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let Some(spool) = &data.spool else {
+        return spool_disabled();
+    };
+    let code = path.into_inner();
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match spool.entries.get(&code) {
+        Some(entry) if presented == Some(entry.deletion_token.as_str()) => {}
+        Some(_) => return HttpResponse::Unauthorized().body("Invalid deletion token"),
+        None => return HttpResponse::NotFound().body("Nothing stored under this code"),
+    }
+    match spool.remove(&code) {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().body("Nothing stored under this code"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use actix_web::{test, App};
+    use crate::clock::MockClock;
+
+    #[actix_web::test]
+    async fn test_upload_then_download_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path().join("spool"), Duration::from_secs(60), 3_000_000).unwrap();
+        let clock = Arc::new(MockClock::new());
+        let state = web::Data::new(AppState::with_clock(clock.clone()).with_spool(spool));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(upload)
+                .service(download)
+                .service(chunks)
+                .service(discard),
+        )
+        .await;
+
+        // The sender uploads everything, then its request (and connection) is done
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let req = test::TestRequest::post()
+            .uri("/api/spool/upload")
+            .insert_header((FILE_NAME_HEADER, "../report.pdf"))
+            .set_payload(data.clone())
+            .to_request();
+        let receipt: SpoolReceipt = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(receipt.size, data.len() as u64);
+        assert_eq!(receipt.hash, crate::hash::sha256_hex(&data));
+
+        // Some time later a receiver picks it up and verifies it
+        clock.advance(Duration::from_secs(30));
+        let req = test::TestRequest::get().uri(&format!("/api/spool/{}", receipt.code)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get(FILE_NAME_HEADER).unwrap(), "report.pdf");
+        let hash = resp.headers().get(SHA256_HEADER).unwrap().to_str().unwrap().to_string();
+        let body = test::read_body(resp).await;
+        assert_eq!(body, data);
+        assert_eq!(crate::hash::sha256_hex(&body), hash);
+
+        // Receivers know the code, but only the uploader can delete
+        let uri = format!("/api/spool/{}", receipt.code);
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header((header::AUTHORIZATION, "Bearer guess"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+        let req = test::TestRequest::post().uri("/api/spool/upload").set_payload(vec![1u8; 10]).to_request();
+        let other: SpoolReceipt = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/spool/{}", other.code))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", other.deletion_token)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        // A second file doesn't fit in the quota alongside the first
+        let req = test::TestRequest::post()
+            .uri("/api/spool/upload")
+            .set_payload(vec![0u8; 2_500_000])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 507);
+        assert_eq!(state.spool.as_ref().unwrap().used(), data.len() as u64);
+
+        // Gone once the TTL runs out
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(state.reap_expired(Duration::from_secs(600)), 0);
+        let req = test::TestRequest::get().uri(&format!("/api/spool/{}", receipt.code)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+        assert_eq!(state.spool.as_ref().unwrap().used(), 0);
+        assert_eq!(std::fs::read_dir(dir.path().join("spool")).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_interrupted_download_resumes_with_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path().to_path_buf(), Duration::from_secs(60), 3_000_000).unwrap();
+        let state = web::Data::new(AppState::default().with_spool(spool));
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .service(upload)
+                .service(download)
+                .service(chunks),
+        )
+        .await;
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let req = test::TestRequest::post().uri("/api/spool/upload").set_payload(data.clone()).to_request();
+        let receipt: SpoolReceipt = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/api/spool/{}", receipt.code);
+        let req = test::TestRequest::get().uri(&format!("{}/chunks", uri)).to_request();
+        let manifest: SpoolManifest = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(manifest.chunk_hashes.len(), 4);
+        assert_eq!(manifest.etag, format!("\"{}\"", receipt.hash));
+
+        let fetch = |range: String, if_range: String| {
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::RANGE, range))
+                .insert_header((header::IF_RANGE, if_range))
+                .to_request()
+        };
+
+        // Earlier attempts got chunk 0 and part of chunk 1, then chunk 2,
+        // before the connection dropped
+        let mut partial = vec![0u8; data.len()];
+        let resp = test::call_service(&app, fetch("bytes=0-299999".to_string(), manifest.etag.clone())).await;
+        assert_eq!(resp.status(), 206);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 0-299999/1000000");
+        partial[..300_000].copy_from_slice(&test::read_body(resp).await);
+        let resp = test::call_service(&app, fetch("bytes=524288-786431".to_string(), manifest.etag.clone())).await;
+        partial[524_288..786_432].copy_from_slice(&test::read_body(resp).await);
+
+        // Resuming asks only for what didn't verify
+        let chunk_size = manifest.chunk_size as usize;
+        let completed = Bitfield::from_indices(
+            4,
+            (0..4u32).filter(|&i| {
+                let start = i as usize * chunk_size;
+                manifest.verify(i, &partial[start..(start + chunk_size).min(data.len())])
+            }),
+        );
+        let ranges = manifest.missing_ranges(&completed);
+        assert_eq!(ranges, vec![(262_144, 524_287), (786_432, 999_999)]);
+        for (first, last) in ranges {
+            let resp = test::call_service(&app, fetch(format!("bytes={}-{}", first, last), manifest.etag.clone())).await;
+            assert_eq!(resp.status(), 206);
+            partial[first as usize..=last as usize].copy_from_slice(&test::read_body(resp).await);
+        }
+        assert_eq!(partial, data);
+
+        // A stale If-Range gets the whole file, and a range past the end a 416
+        let resp = test::call_service(&app, fetch("bytes=0-9".to_string(), "\"other\"".to_string())).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await.len(), data.len());
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::RANGE, "bytes=1000000-"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 416);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */1000000");
+    }
+
+    #[actix_web::test]
+    async fn test_abandoned_upload_gives_back_file_and_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path().to_path_buf(), Duration::from_secs(60), 100).unwrap();
+        {
+            let mut partial = PartialUpload::new(&spool, dir.path().join("x.part"));
+            std::fs::write(&partial.path, b"half").unwrap();
+            assert!(partial.reserve(60));
+            assert!(!spool.reserve(60));
+            // Dropped here, as when the client disconnects mid-body
+        }
+        assert_eq!(spool.used(), 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let mut partial = PartialUpload::new(&spool, dir.path().join("y.part"));
+        std::fs::write(&partial.path, b"whole").unwrap();
+        assert!(partial.reserve(5));
+        partial.keep();
+        assert_eq!(spool.used(), 5);
+        assert!(dir.path().join("y.part").exists());
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use webrtc::stun::message::{Message, Setter, BINDING_REQUEST, BINDING_SUCCESS};
+use webrtc::stun::xoraddr::XorMappedAddress;
+use crate::Result;
+
+// Minimal RFC 5389 responder for running without an external STUN server,
+// e.g. on a LAN or in tests: every binding request is answered with the
+// address it came from as XOR-MAPPED-ADDRESS. Anything else is ignored.
+pub struct StunServer {
+    socket: UdpSocket,
+}
+
+impl StunServer {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Answer requests until the socket fails
+    pub async fn run(self) -> Result<()> {
+        let mut buffer = vec![0u8; 1500];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buffer).await?;
+            let Some(response) = binding_response(&buffer[..len], from) else {
+                continue;
+            };
+            if let Err(e) = self.socket.send_to(&response, from).await {
+                tracing::debug!(%from, error = %e, "could not send STUN response");
+            }
+        }
+    }
+}
+
+// Success response to a binding request from `from`, or None if `request`
+// isn't one
+fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    message.raw = request.to_vec();
+    if message.decode().is_err() || message.typ != BINDING_REQUEST {
+        return None;
+    }
+    let mut response = Message::new();
+    // The request sets the transaction id the client matches answers on
+    let attributes: [Box<dyn Setter>; 3] = [
+        Box::new(message),
+        Box::new(BINDING_SUCCESS),
+        Box::new(XorMappedAddress { ip: from.ip(), port: from.port() }),
+    ];
+    response.build(&attributes).ok()?;
+    Some(response.raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::stun::agent::TransactionId;
+    use webrtc::stun::message::Getter;
+
+    #[tokio::test]
+    async fn test_binding_request_gets_reflexive_address() {
+        let server = StunServer::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = Message::new();
+        request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)]).unwrap();
+        // Not STUN at all; dropped without an answer
+        client.send_to(b"hello", server_addr).await.unwrap();
+        client.send_to(&request.raw, server_addr).await.unwrap();
+
+        let mut buffer = vec![0u8; 1500];
+        let (len, from) = tokio::time::timeout(std::time::Duration::from_secs(3), client.recv_from(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, server_addr);
+        let mut response = Message::new();
+        response.raw = buffer[..len].to_vec();
+        response.decode().unwrap();
+        assert_eq!(response.typ, BINDING_SUCCESS);
+        assert_eq!(response.transaction_id, request.transaction_id);
+        let mut mapped = XorMappedAddress::default();
+        mapped.get_from(&response).unwrap();
+        assert_eq!(SocketAddr::new(mapped.ip, mapped.port), client.local_addr().unwrap());
+    }
+}
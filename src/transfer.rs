@@ -1,112 +1,2134 @@
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::{Read, Write, Seek, SeekFrom};
-use sha2::{Sha256, Digest};
-use indicatif::{ProgressBar, ProgressStyle};
-use crate::{Result, FileMetadata, ChunkInfo};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::clock::{Clock, SystemClock};
+use crate::compression::Compression;
+use crate::hash::{sha256_hex, HashBackend};
+use crate::identity::PeerIdentity;
+use crate::metadata_cache::{file_stamp, MetadataCache};
+use crate::protocol::{Bitfield, Direction};
+use crate::source::{ChunkSource, FileSource};
+use crate::{CancellationToken, Result, DropError, FileMetadata, ChunkInfo, IntegrityScheme};
 
-const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
+pub const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
+// Bounds accepted by `FileTransfer::with_chunk_size` and in received metadata
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+// `FileTransfer::eta` averages throughput over the progress made in this
+// window, long enough to smooth out a bursty link
+pub const ETA_WINDOW: Duration = Duration::from_secs(10);
+// Progress samples needed before `FileTransfer::eta` gives an estimate
+const ETA_MIN_SAMPLES: usize = 3;
+// Most samples kept, however many chunks fit in the window
+const ETA_MAX_SAMPLES: usize = 1024;
+
+// Chunks hashed between two writes of the hash checkpoint, see
+// `FileTransfer::with_hash_checkpoint`
+pub const HASH_CHECKPOINT_CHUNKS: u32 = 64;
+
+// Chunks worth of data a file should be split into at minimum, so resume and
+// retransmission have something to work with
+const TARGET_CHUNKS_PER_FILE: u64 = 64;
+// Upper bound on how long one chunk should occupy the link
+const TARGET_CHUNK_MILLIS: u64 = 250;
+const MIN_RECOMMENDED_CHUNK_SIZE: usize = 64 * 1024;
+
+// Assumed filesystem block size when the platform can't report one
+const FALLBACK_BLOCK_SIZE: usize = 4096;
+// Received chunks are buffered and reach the disk in writes of this many
+// bytes, rounded down to a multiple of the block size
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+// Suggest a chunk size for `with_chunk_size`. Starts from file_size / 64
+// rounded up to a power of two, so small files get small chunks and large ones
+// grow to MAX_CHUNK_SIZE. If the link speed (bits/s) is known, the result is
+// also capped to what the link moves in about 250ms, rounded down to a power
+// of two. Result is always a power of two in [64 KiB, MAX_CHUNK_SIZE] and never
+// decreases as either argument grows.
+pub fn recommend_chunk_size(file_size: u64, link_bps: Option<u64>) -> usize {
+    let clamp = |size: u64| size.clamp(MIN_RECOMMENDED_CHUNK_SIZE as u64, MAX_CHUNK_SIZE as u64) as usize;
+    let by_size = clamp((file_size / TARGET_CHUNKS_PER_FILE).max(1).next_power_of_two());
+    match link_bps {
+        Some(bps) => {
+            let per_window = (bps / 8).saturating_mul(TARGET_CHUNK_MILLIS) / 1000;
+            let by_link = match per_window {
+                0 => 1,
+                n => 1u64 << (63 - n.leading_zeros()),
+            };
+            by_size.min(clamp(by_link))
+        }
+        None => by_size,
+    }
+}
+
+// When a receiver forces written chunks to disk with `sync_all`, see
+// `FileTransfer::with_write_durability`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteDurability {
+    // Leave it to the OS; fastest, but a crash can lose chunks already acknowledged
+    Buffered,
+    // After every chunk, so a resume after a crash only repeats the chunk in flight
+    FsyncPerChunk,
+    // Once, before the output is verified and reported complete
+    #[default]
+    FsyncOnComplete,
+}
 
 pub struct FileTransfer {
     path: PathBuf,
+    // Where outgoing chunks are read from; the file at `path` unless overridden
+    source: Arc<dyn ChunkSource>,
     metadata: Option<FileMetadata>,
     progress_bar: ProgressBar,
+    hash_backend: HashBackend,
+    integrity: IntegrityScheme,
+    compression: Option<Compression>,
+    // Bytes per chunk; comes from the metadata when receiving
+    chunk_size: usize,
+    // Output was truncated by `begin_receive`, so unwritten regions read as zero
+    fresh_output: bool,
+    // Check each chunk against its advertised hash before writing it
+    verify_on_write: bool,
+    // Chunks already written by this receive; writing one again must repeat its bytes
+    written: Bitfield,
+    // Also write `receipt()` to `receipt_path()` once a transfer completes
+    receipt_sidecar: bool,
+    // Set by the protocol as the transfer runs, for `receipt`
+    started: Option<Instant>,
+    completion: Option<Completion>,
+    bytes_transferred: u64,
+    metadata_cache: Option<MetadataCache>,
+    // False once `with_source` replaced the file at `path`, which the cache is keyed on
+    reads_path: bool,
+    // Write `send_state_path()` once the metadata is prepared
+    send_state_sidecar: bool,
+    // Metadata came from `restore_send_state` or `with_prepared_metadata`; the
+    // next send uses it as-is
+    restored: bool,
+    // Block size of the filesystem holding the output, found when receiving starts
+    block_size: usize,
+    // The output between `write_at` calls, and the offset its next byte lands
+    // at. Consecutive chunks collect in the buffer and reach the disk in
+    // block-aligned writes; anything that reads the output flushes it first.
+    output: Option<(BufWriter<File>, u64)>,
+    write_durability: WriteDurability,
+    // Reused by every `read_chunk`; taking `&mut self` keeps those reads sequential
+    read_buffer: Vec<u8>,
+    // Output of a streamed transfer, open from `begin_stream` to `end_stream`
+    stream_output: Option<File>,
+    // Save hashing progress to `hash_checkpoint_path()` as `prepare_metadata` goes
+    hash_checkpoint: bool,
+    // Stops a checkpointed `prepare_metadata` at its next checkpoint
+    cancel: Option<CancellationToken>,
+    // (when, progress bar position) after each chunk within ETA_WINDOW, for `eta`
+    progress_samples: VecDeque<(Instant, u64)>,
+    // Times the transfer for `receipt` and `eta`
+    clock: Arc<dyn Clock>,
+}
+
+// Persisted by a sender so it can pick a transfer back up after restarting,
+// see `FileTransfer::restore_send_state`
+#[derive(Serialize, Deserialize)]
+struct SendState {
+    size: u64,
+    mtime_nanos: u128,
+    metadata: FileMetadata,
+}
+
+// First line of a hash checkpoint, see `FileTransfer::with_hash_checkpoint`.
+// The chunks hashed so far follow, one `ChunkInfo` per line, appended a
+// batch at a time.
+#[derive(Serialize, Deserialize)]
+struct HashCheckpoint {
+    size: u64,
+    mtime_nanos: u128,
+    chunk_size: usize,
+    integrity: IntegrityScheme,
+}
+
+struct Completion {
+    direction: Direction,
+    elapsed: Duration,
+    peer: Option<PeerIdentity>,
+    verified: bool,
+}
+
+// Summary of a transfer for tools that log or audit them, see `FileTransfer::receipt`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferReceipt {
+    pub name: String,
+    // None until the transfer completes
+    pub direction: Option<Direction>,
+    pub size: u64,
+    // Chunk bytes read or written by this transfer; less than `size` after a
+    // resume or when zero chunks were skipped
+    pub bytes_transferred: u64,
+    pub duration_ms: u64,
+    pub throughput_bytes_per_sec: f64,
+    // This side checked the whole file against `hash`; only a receiver does
+    pub verified: bool,
+    pub integrity: IntegrityScheme,
+    pub hash: String,
+    pub peer: Option<PeerIdentity>,
+}
+
+pub const DEFAULT_PROGRESS_TEMPLATE: &str =
+    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})";
+
+// How a `FileTransfer` renders its progress bar
+#[derive(Debug, Clone)]
+pub struct ProgressConfig {
+    // indicatif template string
+    pub template: String,
+    // Draw to stderr (the default) rather than stdout
+    pub draw_to_stderr: bool,
+    // Don't draw at all, e.g. when embedded in a GUI or a service
+    pub hidden: bool,
+}
+
+impl Default for ProgressConfig {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_PROGRESS_TEMPLATE.to_string(),
+            draw_to_stderr: true,
+            hidden: false,
+        }
+    }
+}
+
+impl ProgressConfig {
+    pub fn hidden() -> Self {
+        Self { hidden: true, ..Self::default() }
+    }
+
+    fn build(&self) -> Result<ProgressBar> {
+        let style = ProgressStyle::default_spinner()
+            .template(&self.template)
+            .map_err(|e| DropError::Protocol(format!("invalid progress template: {}", e)))?
+            .progress_chars("#>-");
+        let target = if self.hidden {
+            ProgressDrawTarget::hidden()
+        } else if self.draw_to_stderr {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::stdout()
+        };
+        let progress_bar = ProgressBar::with_draw_target(None, target);
+        progress_bar.set_style(style);
+        Ok(progress_bar)
+    }
+}
+
+// How often `track_progress` reports a running transfer's progress
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Run `transfer`, passing `bar`'s position and length to `report` every
+// PROGRESS_INTERVAL until it finishes; just runs it without a `report`
+pub(crate) async fn track_progress<T>(
+    bar: &ProgressBar,
+    report: Option<impl FnMut(u64, Option<u64>)>,
+    transfer: impl std::future::Future<Output = T>,
+) -> T {
+    let Some(mut report) = report else { return transfer.await };
+    tokio::pin!(transfer);
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut transfer => return result,
+            _ = ticker.tick() => report(bar.position(), bar.length()),
+        }
+    }
+}
+
+const RESUME_TOKEN_VERSION: u8 = 1;
+
+// What a receiver has written so far, enough to pick an interrupted transfer back up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveState {
+    pub session_id: String,
+    pub file_hash: String,
+    pub completed: Bitfield,
+}
+
+impl ReceiveState {
+    pub fn new(session_id: impl Into<String>) -> Self {
+        Self {
+            session_id: session_id.into(),
+            file_hash: String::new(),
+            completed: Bitfield::new(0),
+        }
+    }
+
+    // Start tracking a new file, discarding progress on any previous one
+    pub fn reset(&mut self, metadata: &FileMetadata) {
+        self.file_hash = metadata.hash.clone();
+        self.completed = Bitfield::new(metadata.chunks.len() as u32);
+    }
+
+    pub fn matches(&self, metadata: &FileMetadata) -> bool {
+        self.file_hash == metadata.hash && self.completed.len() == metadata.chunks.len() as u32
+    }
+
+    // Add the chunks `other` completed, e.g. from a partial download of the
+    // same file on another machine. Fails, leaving `self` alone, if `other`
+    // tracks a different file. The bytes themselves are combined with
+    // `stitch_partial_outputs`.
+    pub fn merge(&mut self, other: &ReceiveState) -> Result<()> {
+        if self.file_hash != other.file_hash || self.completed.len() != other.completed.len() {
+            return Err(DropError::Protocol("cannot merge resume state of a different file".to_string()));
+        }
+        for index in 0..other.completed.len() {
+            if other.completed.has(index) {
+                self.completed.set(index);
+            }
+        }
+        Ok(())
+    }
+
+    // Compact, URL-safe token: version, session id, file hash, chunk count and
+    // bitmap, followed by a 4-byte SHA-256 checksum of everything before it.
+    pub fn to_resume_token(&self) -> String {
+        let mut bytes = vec![RESUME_TOKEN_VERSION];
+        for field in [self.session_id.as_bytes(), self.file_hash.as_bytes()] {
+            bytes.push(field.len().min(u8::MAX as usize) as u8);
+            bytes.extend_from_slice(&field[..field.len().min(u8::MAX as usize)]);
+        }
+        bytes.extend_from_slice(&self.completed.len().to_be_bytes());
+        bytes.extend_from_slice(&self.completed.to_bytes());
+        let checksum = token_checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn from_resume_token(token: &str) -> Result<Self> {
+        let invalid = || DropError::Protocol("invalid resume token".to_string());
+        let bytes = URL_SAFE_NO_PAD.decode(token.trim()).map_err(|_| invalid())?;
+        if bytes.len() < 5 {
+            return Err(invalid());
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if token_checksum(body) != checksum || body[0] != RESUME_TOKEN_VERSION {
+            return Err(invalid());
+        }
+
+        let mut rest = &body[1..];
+        let mut fields = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let (&len, tail) = rest.split_first().ok_or_else(invalid)?;
+            if tail.len() < len as usize {
+                return Err(invalid());
+            }
+            let (field, tail) = tail.split_at(len as usize);
+            fields.push(String::from_utf8(field.to_vec()).map_err(|_| invalid())?);
+            rest = tail;
+        }
+        if rest.len() < 4 {
+            return Err(invalid());
+        }
+        let (count, bits) = rest.split_at(4);
+        let count = u32::from_be_bytes(count.try_into().unwrap());
+        let completed = Bitfield::from_bytes(bits.to_vec(), count)?;
+        let file_hash = fields.pop().unwrap();
+        let session_id = fields.pop().unwrap();
+        Ok(Self { session_id, file_hash, completed })
+    }
+}
+
+fn token_checksum(bytes: &[u8]) -> [u8; 4] {
+    let digest = sha256_hex(bytes);
+    let mut checksum = [0u8; 4];
+    for (i, byte) in checksum.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    checksum
+}
+
+// Permissions and mtime worth carrying to the receiver, where the platform exposes them
+pub(crate) fn file_attributes(metadata: &std::fs::Metadata) -> (Option<u32>, Option<i64>) {
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    (mode, mtime)
+}
+
+// Prefix of the error a receive fails with when the output's disk or quota is full
+pub const DISK_FULL: &str = "destination disk full";
+
+// A failed write to the output. Running out of space is the one failure the
+// user can fix and then resume from, so it's spelled out, keeping the kind.
+fn write_error(e: std::io::Error) -> DropError {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::WriteZero => {
+            DropError::Io(std::io::Error::new(e.kind(), format!("{} ({})", DISK_FULL, e)))
+        }
+        _ => DropError::Io(e),
+    }
+}
+
+const FALLBACK_FILE_NAME: &str = "received_file";
+const MAX_FILE_NAME_BYTES: usize = 255;
+
+// Make a peer-supplied file name safe to join onto a local directory: path
+// separators, NULs and other control characters are removed, as are leading
+// dots (so no `..` or hidden files). Falls back to a fixed name if nothing is left.
+pub fn sanitize_filename(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|&c| c != '/' && c != '\\' && !c.is_control())
+        .collect();
+    let mut cleaned = cleaned.trim().trim_start_matches('.').trim_start().to_string();
+    if cleaned.len() > MAX_FILE_NAME_BYTES {
+        let mut end = MAX_FILE_NAME_BYTES;
+        while !cleaned.is_char_boundary(end) {
+            end -= 1;
+        }
+        cleaned.truncate(end);
+    }
+    if cleaned.is_empty() {
+        FALLBACK_FILE_NAME.to_string()
+    } else {
+        cleaned
+    }
+}
+
+// Preferred I/O block size of the filesystem holding `path`. Anything that
+// isn't a sane power of two (or a failed query) falls back to 4 KiB.
+fn filesystem_block_size(path: &Path) -> usize {
+    #[cfg(unix)]
+    let reported = {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).ok().map(|m| m.blksize() as usize)
+    };
+    #[cfg(not(unix))]
+    let reported = {
+        let _ = path;
+        None
+    };
+    reported
+        .filter(|&size| size.is_power_of_two() && (512..=WRITE_BUFFER_SIZE).contains(&size))
+        .unwrap_or(FALLBACK_BLOCK_SIZE)
+}
+
+// Split a write of `len` bytes at `offset` into pieces that end on multiples
+// of `buffer_len` (itself a multiple of the block size). Only the first piece
+// can start unaligned and only the last can end unaligned, whatever the chunk
+// size is.
+fn aligned_writes(offset: u64, len: usize, buffer_len: usize) -> impl Iterator<Item = (u64, usize)> {
+    let end = offset + len as u64;
+    let mut at = offset;
+    std::iter::from_fn(move || {
+        if at >= end {
+            return None;
+        }
+        let boundary = (at / buffer_len as u64 + 1) * buffer_len as u64;
+        let piece = (boundary.min(end) - at) as usize;
+        let item = (at, piece);
+        at += piece as u64;
+        Some(item)
+    })
+}
+
+// Combine partial outputs of the file described by `metadata` into
+// `output`. Each part is a path and the resume state that was written to it;
+// every chunk is copied from the first part that claims it and still hashes
+// correctly. Returns the state of `output`, which is only complete if the
+// parts covered every chunk between them.
+pub fn stitch_partial_outputs(metadata: &FileMetadata, parts: &[(&Path, &ReceiveState)], output: &Path) -> Result<ReceiveState> {
+    if parts.iter().any(|(_, state)| !state.matches(metadata)) {
+        return Err(DropError::Protocol("cannot merge resume state of a different file".to_string()));
+    }
+    let mut stitched = ReceiveState::new("");
+    stitched.reset(metadata);
+
+    let backend = HashBackend::default();
+    let sources: Vec<FileSource> = parts.iter().map(|(path, _)| FileSource::new(path.to_path_buf())).collect();
+    let mut out = File::create(output)?;
+    out.set_len(metadata.size)?;
+    let mut buffer = vec![0u8; metadata.chunk_size()];
+    for chunk in &metadata.chunks {
+        for (source, (_, state)) in sources.iter().zip(parts) {
+            if !state.completed.has(chunk.index) {
+                continue;
+            }
+            // `set_len` already left zero chunks as holes
+            if chunk.zero {
+                stitched.completed.set(chunk.index);
+                break;
+            }
+            let bytes_read = read_chunk_at(source, chunk.index, &mut buffer)
+                .unwrap_or(0)
+                .min(chunk.size as usize);
+            if bytes_read as u64 != chunk.size || backend.digest_hex(&buffer[..bytes_read]) != chunk.hash {
+                tracing::debug!(index = chunk.index, "skipping damaged chunk in partial output");
+                continue;
+            }
+            out.seek(SeekFrom::Start(chunk.index as u64 * metadata.chunk_size() as u64))?;
+            out.write_all(&buffer[..bytes_read])?;
+            stitched.completed.set(chunk.index);
+            break;
+        }
+    }
+    Ok(stitched)
+}
+
+fn is_all_zero(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
+
+// `buffer` is one chunk long, so chunk `i` starts at `i * buffer.len()`
+fn read_chunk_at(source: &dyn ChunkSource, chunk_index: u32, buffer: &mut [u8]) -> Result<usize> {
+    source.read_at(chunk_index as u64 * buffer.len() as u64, buffer)
+}
+
+// Hash every chunk of the file on the rayon pool
+fn hash_chunks_parallel(
+    source: &dyn ChunkSource,
+    size: u64,
+    chunk_size: usize,
+    backend: HashBackend,
+) -> Result<Vec<ChunkInfo>> {
+    let count = size.div_ceil(chunk_size as u64) as u32;
+    hash_chunk_range(source, 0..count, chunk_size, backend)
+}
+
+// Sees each batch of chunks `FileTransfer::hash_source` hashes
+type BatchFn<'a> = dyn FnMut(&[ChunkInfo]) -> Result<()> + 'a;
+
+// One JSON line per chunk, as the hash checkpoint stores them
+fn checkpoint_lines(chunks: &[ChunkInfo]) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for chunk in chunks {
+        serde_json::to_writer(&mut lines, chunk)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+fn hash_chunk_range(
+    source: &dyn ChunkSource,
+    indices: std::ops::Range<u32>,
+    chunk_size: usize,
+    backend: HashBackend,
+) -> Result<Vec<ChunkInfo>> {
+    indices
+        .into_par_iter()
+        .map(|index| {
+            let mut buffer = vec![0u8; chunk_size];
+            let bytes_read = read_chunk_at(source, index, &mut buffer)?;
+            Ok(ChunkInfo {
+                index,
+                size: bytes_read as u64,
+                hash: backend.digest_hex(&buffer[..bytes_read]),
+                zero: is_all_zero(&buffer[..bytes_read]),
+            })
+        })
+        .collect()
+}
+
+// Outcome of `verify_file`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub chunks_checked: usize,
+    // Chunks whose bytes on disk don't match the metadata, including ones
+    // missing from (or extra in) a file of the wrong size
+    pub mismatched_chunks: Vec<u32>,
+    pub size_matches: bool,
+    pub file_hash_matches: bool,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched_chunks.is_empty() && self.size_matches && self.file_hash_matches
+    }
+}
+
+// Chunk hashes of whatever is at `path` now, at `chunk_size`, for diffing
+// against an incoming version of the file; None when there's no file there.
+// Only `size`, `chunks` and `chunk_size` are filled in.
+pub fn chunk_layout(path: &Path, chunk_size: usize) -> Result<Option<FileMetadata>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let source = FileSource::new(path.to_path_buf());
+    let size = source.len()?;
+    let chunks = hash_chunks_parallel(&source, size, chunk_size, HashBackend::default())?;
+    Ok(Some(FileMetadata {
+        size,
+        chunks,
+        chunk_size: Some(chunk_size as u64),
+        ..Default::default()
+    }))
+}
+
+// Independently re-check a file on disk against the metadata it was sent
+// with: re-chunk it at the metadata's chunk size and compare every chunk hash
+// and the file hash. Metadata that doesn't hold together, e.g. from a
+// hand-edited sidecar, is an error rather than a report.
+pub fn verify_file(path: &Path, metadata: &FileMetadata) -> Result<VerifyReport> {
+    metadata.validate()?;
+    let backend = HashBackend::default();
+    let source = FileSource::new(path.to_path_buf());
+    let size = source.len()?;
+    let chunks = hash_chunks_parallel(&source, size, metadata.chunk_size(), backend)?;
+    let count = chunks.len().max(metadata.chunks.len());
+    let mismatched_chunks = (0..count)
+        .filter(|&i| match (chunks.get(i), metadata.chunks.get(i)) {
+            (Some(actual), Some(expected)) => actual.size != expected.size || actual.hash != expected.hash,
+            _ => true,
+        })
+        .map(|i| i as u32)
+        .collect();
+
+    let file_hash = match metadata.integrity {
+        IntegrityScheme::Merkle => {
+            let hashes: Vec<&str> = chunks.iter().map(|c| c.hash.as_str()).collect();
+            crate::hash::MerkleTree::build(&hashes).root_hex()
+        }
+        IntegrityScheme::WholeFile => {
+            let mut file = File::open(path)?;
+            let mut hasher = backend.hasher();
+            let mut buffer = vec![0u8; metadata.chunk_size()];
+            loop {
+                let bytes_read = file.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+            hasher.finalize_hex()
+        }
+    };
+    Ok(VerifyReport {
+        chunks_checked: count,
+        mismatched_chunks,
+        size_matches: size == metadata.size,
+        file_hash_matches: file_hash == metadata.hash,
+    })
 }
 
 impl FileTransfer {
     pub fn new(path: PathBuf) -> Self {
-        let progress_bar = ProgressBar::new_spinner();
-        progress_bar.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+        Self::with_progress_config(path, &ProgressConfig::default())
+            .expect("default progress template is valid")
+    }
 
-        Self {
+    // Like `new`, with a custom or hidden progress bar
+    pub fn with_progress_config(path: PathBuf, progress: &ProgressConfig) -> Result<Self> {
+        let progress_bar = progress.build()?;
+        Ok(Self {
+            source: Arc::new(FileSource::new(path.clone())),
             path,
             metadata: None,
             progress_bar,
+            hash_backend: HashBackend::default(),
+            integrity: IntegrityScheme::default(),
+            compression: None,
+            chunk_size: CHUNK_SIZE,
+            fresh_output: false,
+            verify_on_write: false,
+            written: Bitfield::new(0),
+            receipt_sidecar: false,
+            started: None,
+            completion: None,
+            bytes_transferred: 0,
+            metadata_cache: None,
+            reads_path: true,
+            send_state_sidecar: false,
+            restored: false,
+            block_size: FALLBACK_BLOCK_SIZE,
+            output: None,
+            write_durability: WriteDurability::default(),
+            read_buffer: Vec::new(),
+            stream_output: None,
+            hash_checkpoint: false,
+            cancel: None,
+            progress_samples: VecDeque::new(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    // Reopen a partially written output to continue receiving it. Every chunk
+    // `state` claims is re-hashed from disk; chunks that don't match (torn
+    // writes, corruption) are dropped from `state` so they get requested again.
+    pub async fn open_existing_for_resume(path: PathBuf, metadata: FileMetadata, state: &mut ReceiveState) -> Result<Self> {
+        if !state.matches(&metadata) {
+            return Err(DropError::Protocol("resume state belongs to a different file".to_string()));
+        }
+        let output = FileSource::new(path.clone());
+        let mut transfer = Self::new(path);
+        let mut buffer = vec![0u8; metadata.chunk_size()];
+        for chunk in &metadata.chunks {
+            if !state.completed.has(chunk.index) {
+                continue;
+            }
+            let bytes_read = read_chunk_at(&output, chunk.index, &mut buffer)
+                .unwrap_or(0)
+                .min(chunk.size as usize);
+            let intact = bytes_read as u64 == chunk.size
+                && transfer.hash_backend.digest_hex(&buffer[..bytes_read]) == chunk.hash;
+            if !intact {
+                tracing::debug!(index = chunk.index, "discarding damaged chunk from resume state");
+                state.completed.clear(chunk.index);
+            }
         }
+        transfer.resume_receive(metadata, state).await?;
+        Ok(transfer)
     }
 
+    // Read outgoing chunks from `source` instead of the file at `path`; the
+    // path is then only used for the advertised name.
+    pub fn with_source(mut self, source: Arc<dyn ChunkSource>) -> Self {
+        self.source = source;
+        self.reads_path = false;
+        self
+    }
+
+    // Split outgoing files into chunks of `size` bytes, clamped to
+    // [MIN_CHUNK_SIZE, MAX_CHUNK_SIZE]; see `recommend_chunk_size`
+    pub fn with_chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        self
+    }
+
+    // Read each chunk back after `write_chunk` writes it and check what the
+    // filesystem returns against the chunk's hash, so a write that didn't land
+    // as sent fails there instead of at `verify_complete`. Costs a read and a
+    // hash per chunk.
+    pub fn with_verify_on_write(mut self, verify_on_write: bool) -> Self {
+        self.verify_on_write = verify_on_write;
+        self
+    }
+
+    // Write `receipt()` to `receipt_path()` each time a transfer of this file completes
+    pub fn with_receipt_sidecar(mut self, receipt_sidecar: bool) -> Self {
+        self.receipt_sidecar = receipt_sidecar;
+        self
+    }
+
+    // Compress chunk payloads on the wire, unless the `Protocol` opted in to
+    // negotiating compression with `with_compressors`, which then decides
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Use a Merkle root over chunk hashes as the file hash; chunks are then hashed in parallel
+    pub fn with_integrity(mut self, integrity: IntegrityScheme) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    // Also write the prepared metadata to `send_state_path()`, so a sender
+    // that restarts can answer the receiver's resume without re-hashing
+    pub fn with_send_state_sidecar(mut self, send_state_sidecar: bool) -> Self {
+        self.send_state_sidecar = send_state_sidecar;
+        self
+    }
+
+    // Save the chunk hashes to `hash_checkpoint_path()` every
+    // HASH_CHECKPOINT_CHUNKS chunks while preparing metadata, so hashing a huge
+    // file that gets interrupted picks up from the last checkpoint next time
+    // instead of starting over. The checkpoint is only used if the file's size
+    // and mtime haven't changed since, and is removed once hashing completes.
+    // Under `IntegrityScheme::WholeFile` the checkpointed part is still read
+    // once more for the file hash, just not hashed per chunk again.
+    pub fn with_hash_checkpoint(mut self, hash_checkpoint: bool) -> Self {
+        self.hash_checkpoint = hash_checkpoint;
+        self
+    }
+
+    // Make a checkpointed `prepare_metadata` stop with `DropError::Cancelled`
+    // at the first checkpoint after `token` is cancelled
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    // Where `eta` and the receipt's elapsed time get "now" from
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_metadata_cache(mut self, cache: MetadataCache) -> Self {
+        self.metadata_cache = Some(cache);
+        self
+    }
+
+    pub fn with_hash_backend(mut self, backend: HashBackend) -> Self {
+        self.hash_backend = backend;
+        self
+    }
+
+    pub fn with_write_durability(mut self, durability: WriteDurability) -> Self {
+        self.write_durability = durability;
+        self
+    }
+
+    // With a metadata cache, a file whose size and mtime are unchanged since it
+    // was last hashed reuses that metadata instead of being hashed again
     pub async fn prepare_metadata(&mut self) -> Result<FileMetadata> {
-        let file = File::open(&self.path)?;
-        let size = file.metadata()?.len();
-        
-        let mut chunks = Vec::new();
-        let mut file = file;
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let mut index = 0;
+        let cache = self.metadata_cache.as_ref().filter(|_| self.reads_path);
+        // Taken before hashing: neither the cache entry nor the send state is
+        // written unless the file still has it afterwards
+        let stamp = match self.reads_path && (cache.is_some() || self.send_state_sidecar) {
+            true => file_stamp(&self.path).ok(),
+            false => None,
+        };
+        let metadata = match cache.and_then(|c| c.lookup(&self.path, self.chunk_size, self.integrity)) {
+            Some(mut cached) => {
+                (cached.mode, cached.mtime) = self.source.attributes();
+                cached.name = self.file_name();
+                cached.compression = self.compression.map(|c| c.to_string());
+                cached
+            }
+            None => {
+                let metadata = self.compute_metadata()?;
+                if let (Some(cache), Some(stamp)) = (cache, stamp) {
+                    if let Err(e) = cache.store(&self.path, self.chunk_size, stamp, &metadata) {
+                        tracing::debug!(error = %e, "failed to cache file metadata");
+                    }
+                }
+                metadata
+            }
+        };
+        self.metadata = Some(metadata.clone());
+        self.progress_bar.set_length(metadata.size);
+        if let Some(stamp) = stamp.filter(|_| self.send_state_sidecar) {
+            self.write_send_state(stamp, &metadata)?;
+        }
+        Ok(metadata)
+    }
 
-        loop {
-            let bytes_read = file.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+    // Announce `metadata`, computed ahead of time for this same file, on the
+    // next send instead of hashing the file again
+    pub(crate) fn with_prepared_metadata(mut self, metadata: FileMetadata) -> Self {
+        self.progress_bar.set_length(metadata.size);
+        self.metadata = Some(metadata);
+        self.restored = true;
+        self
+    }
+
+    // The metadata to announce: what `restore_send_state` loaded, once, or freshly prepared
+    pub(crate) async fn metadata_for_send(&mut self) -> Result<FileMetadata> {
+        if std::mem::take(&mut self.restored) {
+            return self.expect_metadata().cloned();
+        }
+        self.prepare_metadata().await
+    }
+
+    pub fn hash_checkpoint_path(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".hash-checkpoint.json");
+        PathBuf::from(name)
+    }
+
+    // Chunks a still-valid checkpoint already covers; none if there's no
+    // checkpoint or it was taken of a different file or with other settings
+    fn load_hash_checkpoint(&self, stamp: (u64, u128), count: u32) -> Vec<ChunkInfo> {
+        let Ok(raw) = std::fs::read(self.hash_checkpoint_path()) else {
+            return Vec::new();
+        };
+        let mut lines = raw.split(|&b| b == b'\n');
+        match lines.next().map(serde_json::from_slice::<HashCheckpoint>) {
+            Some(Ok(saved))
+                if (saved.size, saved.mtime_nanos) == stamp
+                    && saved.chunk_size == self.chunk_size
+                    && saved.integrity == self.integrity => {}
+            _ => {
+                tracing::debug!(path = %self.path.display(), "ignoring stale hash checkpoint");
+                return Vec::new();
             }
+        }
+        // An interrupted append leaves a torn last line; everything before it stands
+        lines
+            .map_while(|line| serde_json::from_slice::<ChunkInfo>(line).ok())
+            .enumerate()
+            .map_while(|(i, chunk)| (chunk.index as usize == i).then_some(chunk))
+            .take(count as usize)
+            .collect()
+    }
 
-            let mut hasher = Sha256::new();
-            hasher.update(&buffer[..bytes_read]);
-            let hash = format!("{:x}", hasher.finalize());
+    // Start the checkpoint over with `chunks`, written aside and renamed so an
+    // interruption mid-write leaves the previous one
+    fn start_hash_checkpoint(&self, stamp: (u64, u128), chunks: &[ChunkInfo]) -> Result<()> {
+        let header = HashCheckpoint {
+            size: stamp.0,
+            mtime_nanos: stamp.1,
+            chunk_size: self.chunk_size,
+            integrity: self.integrity,
+        };
+        let mut contents = serde_json::to_vec(&header)?;
+        contents.push(b'\n');
+        contents.extend(checkpoint_lines(chunks)?);
+        let path = self.hash_checkpoint_path();
+        let mut partial = path.clone().into_os_string();
+        partial.push(".tmp");
+        std::fs::write(&partial, contents)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(())
+    }
 
-            chunks.push(ChunkInfo {
-                index,
-                size: bytes_read as u64,
-                hash,
-            });
+    fn append_hash_checkpoint(&self, chunks: &[ChunkInfo]) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new().append(true).open(self.hash_checkpoint_path())?;
+        file.write_all(&checkpoint_lines(chunks)?)?;
+        Ok(())
+    }
 
-            index += 1;
+    pub fn send_state_path(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".send-state.json");
+        PathBuf::from(name)
+    }
+
+    // `stamp` is the file's from before `metadata` was hashed; if it changed
+    // since, the hashes may match neither version and nothing is saved
+    fn write_send_state(&self, stamp: (u64, u128), metadata: &FileMetadata) -> Result<()> {
+        let (size, mtime_nanos) = file_stamp(&self.path)?;
+        if (size, mtime_nanos) != stamp {
+            tracing::warn!(path = %self.path.display(), "file changed while it was hashed, not saving send state");
+            return Ok(());
         }
+        let state = SendState { size, mtime_nanos, metadata: metadata.clone() };
+        std::fs::write(self.send_state_path(), serde_json::to_vec(&state)?)?;
+        Ok(())
+    }
+
+    // Rebuild a sender from the state `with_send_state_sidecar` saved, after
+    // the process that first sent `path` went away. Fails if the file's size
+    // or mtime changed since, as the saved hashes would no longer match.
+    pub fn restore_send_state(path: PathBuf) -> Result<Self> {
+        let mut transfer = Self::new(path);
+        let saved: SendState = serde_json::from_slice(&std::fs::read(transfer.send_state_path())?)?;
+        if file_stamp(&transfer.path)? != (saved.size, saved.mtime_nanos) {
+            return Err(DropError::Protocol("file changed since its send state was saved".to_string()));
+        }
+        let metadata = saved.metadata;
+        transfer.chunk_size = metadata.chunk_size();
+        transfer.integrity = metadata.integrity;
+        transfer.compression = crate::compression::from_metadata(metadata.compression.as_deref())?;
+        transfer.progress_bar.set_length(metadata.size);
+        transfer.metadata = Some(metadata);
+        transfer.restored = true;
+        Ok(transfer)
+    }
+
+    // Hashing a file that is still being written would describe neither its
+    // old nor its new contents, so a size change while reading is an error
+    fn compute_metadata(&self) -> Result<FileMetadata> {
+        let size = self.source.len()?;
+        let metadata = match self.hash_checkpoint && self.reads_path && size > 0 {
+            true => self.hash_with_checkpoints(size)?,
+            false => self.hash_source(size, Vec::new(), None)?,
+        };
+        let read: u64 = metadata.chunks.iter().map(|c| c.size).sum();
+        if read != size || self.source.len()? != size {
+            return Err(DropError::Io(std::io::Error::other("file changed during read")));
+        }
+        Ok(metadata)
+    }
 
-        let mut hasher = Sha256::new();
-        file.seek(SeekFrom::Start(0))?;
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer)?;
-        hasher.update(&buffer);
-        let file_hash = format!("{:x}", hasher.finalize());
+    // Hash the source, carrying on after `chunks`, the file's first chunks
+    // already hashed (say by an earlier, interrupted run); under WholeFile
+    // those are read once more for the file hash, just not hashed per chunk
+    // again. With `on_batch`, hashing goes HASH_CHECKPOINT_CHUNKS chunks at a
+    // time and each batch of new chunks is passed to it; an error from it
+    // stops hashing.
+    fn hash_source(
+        &self,
+        size: u64,
+        mut chunks: Vec<ChunkInfo>,
+        mut on_batch: Option<&mut BatchFn>,
+    ) -> Result<FileMetadata> {
+        let (mode, mtime) = self.source.attributes();
+        let count = size.div_ceil(self.chunk_size as u64) as u32;
+        let batch = if on_batch.is_some() { HASH_CHECKPOINT_CHUNKS } else { count };
+        let mut buffer = vec![0u8; self.chunk_size];
+        // A streaming file hash can't be resumed, so it's caught up on the chunks already hashed
+        let mut file_hasher = (self.integrity == IntegrityScheme::WholeFile).then(|| self.hash_backend.hasher());
+        if let Some(hasher) = &mut file_hasher {
+            for chunk in &chunks {
+                let bytes_read = read_chunk_at(self.source.as_ref(), chunk.index, &mut buffer)?;
+                hasher.update(&buffer[..bytes_read]);
+            }
+        }
+
+        while (chunks.len() as u32) < count {
+            let start = chunks.len() as u32;
+            let end = start.saturating_add(batch).min(count);
+            match &mut file_hasher {
+                Some(hasher) => {
+                    for index in start..end {
+                        let bytes_read = read_chunk_at(self.source.as_ref(), index, &mut buffer)?;
+                        hasher.update(&buffer[..bytes_read]);
+                        chunks.push(ChunkInfo {
+                            index,
+                            size: bytes_read as u64,
+                            hash: self.hash_backend.digest_hex(&buffer[..bytes_read]),
+                            zero: is_all_zero(&buffer[..bytes_read]),
+                        });
+                    }
+                }
+                None => chunks.extend(hash_chunk_range(
+                    self.source.as_ref(),
+                    start..end,
+                    self.chunk_size,
+                    self.hash_backend,
+                )?),
+            }
+            if let Some(on_batch) = &mut on_batch {
+                on_batch(&chunks[start as usize..])?;
+            }
+        }
 
-        let metadata = FileMetadata {
-            name: self.path.file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unknown")
-                .to_string(),
+        let mut metadata = FileMetadata {
+            name: self.file_name(),
             size,
-            hash: file_hash,
+            hash: String::new(),
             chunks,
+            integrity: self.integrity,
+            mode,
+            mtime,
+            compression: self.compression.map(|c| c.to_string()),
+            chunk_size: self.advertised_chunk_size(),
+            symlink_target: None,
+            priority: 0,
+        };
+        metadata.hash = match file_hasher {
+            // A zero-byte file has no chunks; its hash is the digest of the
+            // empty input under either scheme, and the receiver still creates the file.
+            _ if size == 0 => self.hash_backend.digest_hex(&[]),
+            Some(hasher) => hasher.finalize_hex(),
+            None => metadata.merkle_root(),
         };
+        Ok(metadata)
+    }
 
-        self.metadata = Some(metadata.clone());
-        self.progress_bar.set_length(size);
+    // `hash_source` starting after whatever a checkpoint already covers and
+    // saving each batch to it. Hashing doesn't depend on the checkpoint, so
+    // once saving fails it carries on without one.
+    fn hash_with_checkpoints(&self, size: u64) -> Result<FileMetadata> {
+        let stamp = file_stamp(&self.path)?;
+        let count = size.div_ceil(self.chunk_size as u64) as u32;
+        let chunks = self.load_hash_checkpoint(stamp, count);
+        // Rewritten once up front so a torn record left by an interruption
+        // isn't appended after; from then on batches are only appended
+        let mut saving = match self.start_hash_checkpoint(stamp, &chunks) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to write hash checkpoint, hashing without one");
+                false
+            }
+        };
+        let mut on_batch = |batch: &[ChunkInfo]| {
+            if saving {
+                if let Err(e) = self.append_hash_checkpoint(batch) {
+                    tracing::warn!(error = %e, "failed to write hash checkpoint, hashing without one");
+                    saving = false;
+                }
+            }
+            let more = batch.last().is_some_and(|c| c.index + 1 < count);
+            if more && self.cancel.as_ref().is_some_and(|t| t.is_cancelled()) {
+                return Err(DropError::Cancelled);
+            }
+            Ok(())
+        };
+        let metadata = self.hash_source(size, chunks, Some(&mut on_batch))?;
+        if let Err(e) = std::fs::remove_file(self.hash_checkpoint_path()) {
+            tracing::debug!(error = %e, "failed to remove hash checkpoint");
+        }
         Ok(metadata)
     }
 
+    // Reads into the transfer's own buffer, so the only allocation per chunk
+    // is the returned copy of the bytes actually read
     pub async fn read_chunk(&mut self, chunk_index: u32) -> Result<Vec<u8>> {
-        let file = File::open(&self.path)?;
-        let mut file = file;
-        
-        let offset = (chunk_index as u64) * (CHUNK_SIZE as u64);
-        file.seek(SeekFrom::Start(offset))?;
+        self.read_buffer.resize(self.chunk_size, 0);
+        let bytes_read = read_chunk_at(self.source.as_ref(), chunk_index, &mut self.read_buffer)?;
 
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let bytes_read = file.read(&mut buffer)?;
-        
-        self.progress_bar.inc(bytes_read as u64);
-        Ok(buffer[..bytes_read].to_vec())
+        self.advance(bytes_read as u64);
+        self.bytes_transferred += bytes_read as u64;
+        Ok(self.read_buffer[..bytes_read].to_vec())
     }
 
+    // Set up the output file for an incoming transfer described by `metadata`,
+    // truncating anything already at the destination.
+    pub async fn begin_receive(&mut self, metadata: FileMetadata) -> Result<()> {
+        self.output = None;
+        // Sizing up front leaves zero chunks as holes on filesystems that support them
+        File::create(&self.path)?.set_len(metadata.size).map_err(write_error)?;
+        self.block_size = filesystem_block_size(&self.path);
+        self.progress_bar.set_length(metadata.size);
+        self.chunk_size = metadata.chunk_size();
+        self.written = Bitfield::new(metadata.chunks.len() as u32);
+        self.metadata = Some(metadata);
+        self.fresh_output = true;
+        Ok(())
+    }
+
+    // Set up the output for a streamed transfer (`Protocol::send_reader`),
+    // whose size isn't known until it ends: truncated, nothing preallocated
+    pub(crate) fn begin_stream(&mut self) -> Result<()> {
+        self.stream_output = Some(File::create(&self.path)?);
+        self.chunk_size = CHUNK_SIZE;
+        self.metadata = None;
+        self.fresh_output = true;
+        Ok(())
+    }
+
+    // Next chunk of a streamed transfer, written after the previous ones
+    pub(crate) fn append_chunk(&mut self, data: &[u8]) -> Result<()> {
+        let Some(file) = self.stream_output.as_mut() else {
+            return Err(DropError::Protocol("no stream in progress".to_string()));
+        };
+        file.write_all(data).map_err(write_error)?;
+        if self.write_durability == WriteDurability::FsyncPerChunk {
+            file.sync_all().map_err(write_error)?;
+        }
+        self.advance(data.len() as u64);
+        self.bytes_transferred += data.len() as u64;
+        Ok(())
+    }
+
+    // What a finished stream turned out to be. It was never chunk-hashed, so
+    // `metadata.chunks` is empty and only the whole-file hash is known.
+    pub(crate) fn end_stream(&mut self, metadata: FileMetadata) {
+        self.stream_output = None;
+        self.progress_bar.set_length(metadata.size);
+        self.metadata = Some(metadata);
+    }
+
+    // Reopen a partially received output without discarding what's already there
+    pub async fn resume_receive(&mut self, metadata: FileMetadata, state: &ReceiveState) -> Result<()> {
+        self.output = None;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?
+            .set_len(metadata.size)
+            .map_err(write_error)?;
+        self.block_size = filesystem_block_size(&self.path);
+        let done: u64 = metadata
+            .chunks
+            .iter()
+            .filter(|c| state.completed.has(c.index))
+            .map(|c| c.size)
+            .sum();
+        self.progress_bar.set_length(metadata.size);
+        self.progress_bar.set_position(done);
+        self.chunk_size = metadata.chunk_size();
+        self.written = state.completed.clone();
+        self.metadata = Some(metadata);
+        self.fresh_output = false;
+        Ok(())
+    }
+
+    // Writing a chunk that was already written is a no-op when the bytes are
+    // identical and an error when they differ, so a misbehaving sender can't
+    // replace data that already verified.
     pub async fn write_chunk(&mut self, chunk_index: u32, data: Vec<u8>) -> Result<()> {
-        let file = File::create(&self.path)?;
-        let mut file = file;
-        
-        let offset = (chunk_index as u64) * (CHUNK_SIZE as u64);
-        file.seek(SeekFrom::Start(offset))?;
-        
-        file.write_all(&data)?;
-        self.progress_bar.inc(data.len() as u64);
+        if self.written.has(chunk_index) {
+            self.flush_output()?;
+            let mut existing = vec![0u8; self.chunk_size];
+            let bytes_read = read_chunk_at(&FileSource::new(self.path.clone()), chunk_index, &mut existing)?;
+            if existing[..bytes_read] != data[..] {
+                return Err(DropError::Protocol(format!("chunk {} rewritten with different data", chunk_index)));
+            }
+            return Ok(());
+        }
+        self.write_at(chunk_index, &data)?;
+        if self.verify_on_write {
+            self.flush_output()?;
+            let mut stored = vec![0u8; self.chunk_size];
+            let bytes_read = read_chunk_at(&FileSource::new(self.path.clone()), chunk_index, &mut stored)?;
+            if let Err(e) = self.verify_chunk(chunk_index, &stored[..bytes_read]) {
+                self.written.clear(chunk_index);
+                return Err(e);
+            }
+        }
+        self.bytes_transferred += data.len() as u64;
         Ok(())
     }
 
+    fn write_at(&mut self, chunk_index: u32, data: &[u8]) -> Result<()> {
+        let offset = (chunk_index as u64) * (self.chunk_size as u64);
+        if self.output.as_ref().is_some_and(|(_, next)| *next != offset) {
+            self.flush_output()?;
+        }
+        // Chunk offsets needn't fall on block boundaries, so the buffer is
+        // flushed at multiples of its block-aligned size instead of per chunk
+        let buffer_len = (WRITE_BUFFER_SIZE / self.block_size).max(1) * self.block_size;
+        let fsync = self.write_durability == WriteDurability::FsyncPerChunk;
+        let output = match self.output.take() {
+            Some(output) => Ok(output),
+            None => OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)
+                .and_then(|mut file| file.seek(SeekFrom::Start(offset)).map(|_| file))
+                .map(|file| (BufWriter::with_capacity(buffer_len, file), offset)),
+        };
+        let write = |(writer, next): &mut (BufWriter<File>, u64)| -> std::io::Result<()> {
+            for (at, len) in aligned_writes(offset, data.len(), buffer_len) {
+                let start = (at - offset) as usize;
+                writer.write_all(&data[start..start + len])?;
+                if (at + len as u64).is_multiple_of(buffer_len as u64) {
+                    writer.flush()?;
+                }
+            }
+            *next = offset + data.len() as u64;
+            if fsync {
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+            }
+            Ok(())
+        };
+        // A writer that failed is dropped, so the next write starts afresh
+        let mut output = output.map_err(write_error)?;
+        write(&mut output).map_err(write_error)?;
+        self.output = Some(output);
+        self.advance(data.len() as u64);
+        self.written.set(chunk_index);
+        Ok(())
+    }
+
+    // Hand whatever `write_at` buffered to the file and close it
+    fn flush_output(&mut self) -> Result<()> {
+        if let Some((mut writer, _)) = self.output.take() {
+            writer.flush().map_err(write_error)?;
+        }
+        Ok(())
+    }
+
+    // Left out of the metadata at the default so older peers still agree on offsets
+    fn advertised_chunk_size(&self) -> Option<u64> {
+        (self.chunk_size != CHUNK_SIZE).then_some(self.chunk_size as u64)
+    }
+
+    fn file_name(&self) -> String {
+        self.path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string()
+    }
+
+    // Accept an all-zero chunk. On a fresh output the region is already a hole
+    // because `begin_receive` sized a truncated file; a resumed output may hold
+    // stale bytes there, so it gets explicit zeros.
+    pub async fn write_zero_chunk(&mut self, chunk_index: u32) -> Result<()> {
+        let chunk = self
+            .expect_metadata()?
+            .chunks
+            .get(chunk_index as usize)
+            .ok_or_else(|| DropError::Protocol(format!("chunk {} out of range", chunk_index)))?;
+        if !chunk.zero {
+            return Err(DropError::Protocol(format!("chunk {} is not a zero chunk", chunk_index)));
+        }
+        let size = chunk.size;
+        if self.fresh_output {
+            self.advance(size);
+            self.written.set(chunk_index);
+            Ok(())
+        } else {
+            self.write_at(chunk_index, &vec![0u8; size as usize])
+        }
+    }
+
+    pub fn progress_bar(&self) -> &ProgressBar {
+        &self.progress_bar
+    }
+
+    // Draw the bar one last time, full, and stop it so it doesn't linger as
+    // if still running. Chunks skipped on resume or by an incremental receive
+    // never `inc` it, so the position is filled up to the length first. A
+    // hidden bar stays hidden.
+    pub fn finish(&self) {
+        if let Some(length) = self.progress_bar.length() {
+            self.progress_bar.set_position(length);
+        }
+        self.progress_bar.finish_with_message("done");
+    }
+
     pub fn get_metadata(&self) -> Option<&FileMetadata> {
         self.metadata.as_ref()
     }
-} 
\ No newline at end of file
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn mark_started(&mut self) {
+        self.started = Some(self.clock.now());
+        self.completion = None;
+        self.bytes_transferred = 0;
+        self.progress_samples.clear();
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.progress_bar.inc(bytes);
+        self.sample_progress(self.clock.now());
+    }
+
+    fn sample_progress(&mut self, at: Instant) {
+        self.progress_samples.push_back((at, self.progress_bar.position()));
+        while self.progress_samples.len() > ETA_MAX_SAMPLES
+            || self.progress_samples.front().is_some_and(|&(t, _)| at.saturating_duration_since(t) > ETA_WINDOW)
+        {
+            self.progress_samples.pop_front();
+        }
+    }
+
+    // Time left at the throughput averaged over the last ETA_WINDOW, as of the
+    // latest chunk. None until a few chunks have gone through, while the size
+    // isn't known, if nothing moved within the window, or if no chunk has
+    // gone through for a whole window.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.progress_samples.len() < ETA_MIN_SAMPLES {
+            return None;
+        }
+        let (&(first_at, first), &(last_at, last)) = (self.progress_samples.front()?, self.progress_samples.back()?);
+        if self.clock.now().saturating_duration_since(last_at) > ETA_WINDOW {
+            return None;
+        }
+        let elapsed = last_at.saturating_duration_since(first_at).as_secs_f64();
+        let moved = last.saturating_sub(first) as f64;
+        if elapsed <= 0.0 || moved <= 0.0 {
+            return None;
+        }
+        let remaining = self.progress_bar.length()?.saturating_sub(last);
+        Some(Duration::from_secs_f64(remaining as f64 * elapsed / moved))
+    }
+
+    // `verified` once the received data was checked against the file hash
+    pub(crate) fn mark_complete(&mut self, direction: Direction, peer: Option<PeerIdentity>, verified: bool) -> Result<()> {
+        let elapsed = self.started.map(|t| self.clock.now().saturating_duration_since(t)).unwrap_or_default();
+        self.completion = Some(Completion { direction, elapsed, peer, verified });
+        self.finish();
+        if self.receipt_sidecar {
+            self.write_receipt()?;
+        }
+        Ok(())
+    }
+
+    // What happened in the last transfer of this file. Before it completes,
+    // `verified` is false and the duration is the time so far.
+    pub fn receipt(&self) -> TransferReceipt {
+        let elapsed = match &self.completion {
+            Some(completion) => completion.elapsed,
+            None => self.started.map(|t| self.clock.now().saturating_duration_since(t)).unwrap_or_default(),
+        };
+        let secs = elapsed.as_secs_f64();
+        let metadata = self.metadata.as_ref();
+        TransferReceipt {
+            name: metadata.map(|m| m.name.clone()).unwrap_or_else(|| self.file_name()),
+            direction: self.completion.as_ref().map(|c| c.direction),
+            size: metadata.map_or(0, |m| m.size),
+            bytes_transferred: self.bytes_transferred,
+            duration_ms: elapsed.as_millis() as u64,
+            throughput_bytes_per_sec: if secs > 0.0 { self.bytes_transferred as f64 / secs } else { 0.0 },
+            verified: self.completion.as_ref().is_some_and(|c| c.verified),
+            integrity: metadata.map_or(self.integrity, |m| m.integrity),
+            hash: metadata.map(|m| m.hash.clone()).unwrap_or_default(),
+            peer: self.completion.as_ref().and_then(|c| c.peer.clone()),
+        }
+    }
+
+    // `<path>.receipt.json`
+    pub fn receipt_path(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".receipt.json");
+        PathBuf::from(name)
+    }
+
+    pub fn write_receipt(&self) -> Result<PathBuf> {
+        let path = self.receipt_path();
+        std::fs::write(&path, serde_json::to_vec_pretty(&self.receipt())?)?;
+        Ok(path)
+    }
+
+    fn expect_metadata(&self) -> Result<&FileMetadata> {
+        self.metadata
+            .as_ref()
+            .ok_or_else(|| DropError::Protocol("no metadata for transfer".to_string()))
+    }
+
+    // Check a chunk's bytes against the hash advertised in the metadata
+    pub fn verify_chunk(&self, chunk_index: u32, data: &[u8]) -> Result<()> {
+        let metadata = self.expect_metadata()?;
+        let chunk = metadata
+            .chunks
+            .get(chunk_index as usize)
+            .ok_or_else(|| DropError::Protocol(format!("chunk {} out of range", chunk_index)))?;
+        if chunk.size != data.len() as u64 || chunk.hash != self.hash_backend.digest_hex(data) {
+            return Err(DropError::Protocol(format!("chunk {} failed verification", chunk_index)));
+        }
+        Ok(())
+    }
+
+    // Best-effort: restore the sender's permission bits and mtime. Call after
+    // `verify_complete`; failures are logged rather than failing the transfer.
+    pub fn apply_attributes(&self) {
+        let Some(metadata) = &self.metadata else { return };
+        #[cfg(unix)]
+        if let Some(mode) = metadata.mode {
+            use std::os::unix::fs::PermissionsExt;
+            if let Err(e) = std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(mode)) {
+                tracing::warn!(error = %e, "could not restore file permissions");
+            }
+        }
+        if let Some(mtime) = metadata.mtime.filter(|&t| t >= 0) {
+            let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64);
+            let result = OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|f| f.set_modified(modified));
+            if let Err(e) = result {
+                tracing::warn!(error = %e, "could not restore modification time");
+            }
+        }
+    }
+
+    // Flush the received output to disk if the durability policy asks for it
+    // at the end; call once every chunk is written, before `verify_complete`
+    pub fn sync_output(&mut self) -> Result<()> {
+        self.flush_output()?;
+        if self.write_durability == WriteDurability::FsyncOnComplete {
+            // Write access, since Windows won't flush a read-only handle
+            OpenOptions::new().write(true).open(&self.path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Re-hash the file on disk and compare it with the advertised file hash
+    pub async fn verify_complete(&mut self) -> Result<()> {
+        self.flush_output()?;
+        let metadata = self.expect_metadata()?;
+        if self.file_hash_matches(metadata)? {
+            return Ok(());
+        }
+        // Rare, so it's fine to hash every chunk again to say which are bad
+        let report = verify_file(&self.path, metadata)?;
+        Err(DropError::VerificationFailed {
+            mismatched_chunks: report.mismatched_chunks,
+            file_hash_mismatch: !report.file_hash_matches,
+        })
+    }
+
+    fn file_hash_matches(&self, metadata: &FileMetadata) -> Result<bool> {
+        if metadata.integrity == IntegrityScheme::Merkle {
+            let output = FileSource::new(self.path.clone());
+            let size = output.len()?;
+            let chunks = hash_chunks_parallel(&output, size, self.chunk_size, self.hash_backend)?;
+            let hashes: Vec<&str> = chunks.iter().map(|c| c.hash.as_str()).collect();
+            let tree = crate::hash::MerkleTree::build(&hashes);
+            return Ok(size == metadata.size && tree.root_hex() == metadata.hash);
+        }
+        let mut file = File::open(&self.path)?;
+        let mut hasher = self.hash_backend.hasher();
+        let mut buffer = vec![0u8; self.chunk_size];
+        let mut size = 0u64;
+        loop {
+            let bytes_read = file.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            size += bytes_read as u64;
+            hasher.update(&buffer[..bytes_read]);
+        }
+        Ok(size == metadata.size && hasher.finalize_hex() == metadata.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_resume_token_roundtrip() {
+        let state = ReceiveState {
+            session_id: "ABC123".to_string(),
+            file_hash: sha256_hex(b"file"),
+            completed: Bitfield::from_indices(37, [0, 1, 2, 10, 36]),
+        };
+        let token = state.to_resume_token();
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(ReceiveState::from_resume_token(&token).unwrap(), state);
+    }
+
+    #[tokio::test]
+    async fn test_stitch_complementary_partial_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..4 * CHUNK_SIZE + 123).map(|i| (i % 101) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+
+        // Each machine got a different half; the rest of its file is zeros
+        let mut parts = Vec::new();
+        for (name, indices) in [("first.bin", 0..2u32), ("second.bin", 2..5u32)] {
+            let mut partial = vec![0u8; data.len()];
+            let mut state = ReceiveState::new(name);
+            state.reset(&metadata);
+            for index in indices {
+                let range = index as usize * CHUNK_SIZE..((index as usize + 1) * CHUNK_SIZE).min(data.len());
+                partial[range.clone()].copy_from_slice(&data[range]);
+                state.completed.set(index);
+            }
+            let path = dir.path().join(name);
+            std::fs::write(&path, &partial).unwrap();
+            parts.push((path, state));
+        }
+
+        let mut merged = parts[0].1.clone();
+        merged.merge(&parts[1].1).unwrap();
+        assert!(merged.completed.is_complete());
+        assert_eq!(merged.session_id, "first.bin");
+
+        let out = dir.path().join("stitched.bin");
+        let inputs: Vec<(&Path, &ReceiveState)> = parts.iter().map(|(p, s)| (p.as_path(), s)).collect();
+        let stitched = stitch_partial_outputs(&metadata, &inputs, &out).unwrap();
+        assert!(stitched.completed.is_complete());
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+        assert!(verify_file(&out, &metadata).unwrap().is_ok());
+
+        let mut other = ReceiveState::new("other");
+        other.file_hash = sha256_hex(b"another file");
+        other.completed = Bitfield::new(5);
+        assert!(merged.merge(&other).is_err());
+        assert!(stitch_partial_outputs(&metadata, &[(inputs[0].0, &other)], &out).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_existing_for_resume_discards_corrupted_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..4 * CHUNK_SIZE).map(|i| (i % 113) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+
+        // Chunks 0-2 were written before the interruption, then chunk 1 got damaged
+        let partial_path = dir.path().join("partial.bin");
+        let mut partial = data[..3 * CHUNK_SIZE].to_vec();
+        partial[CHUNK_SIZE + 42] ^= 0x01;
+        std::fs::write(&partial_path, &partial).unwrap();
+        let mut state = ReceiveState::new("ABC123");
+        state.reset(&metadata);
+        for index in 0..3 {
+            state.completed.set(index);
+        }
+
+        let resumed = FileTransfer::open_existing_for_resume(partial_path, metadata.clone(), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(state.completed.missing(), vec![1, 3]);
+        assert_eq!(resumed.get_metadata().unwrap().hash, metadata.hash);
+
+        let mut other = ReceiveState::new("ABC123");
+        assert!(FileTransfer::open_existing_for_resume(dir.path().join("x"), metadata, &mut other).await.is_err());
+    }
+
+    #[test]
+    fn test_corrupted_resume_token_is_rejected() {
+        let state = ReceiveState {
+            session_id: "ABC123".to_string(),
+            file_hash: sha256_hex(b"file"),
+            completed: Bitfield::from_indices(16, [3, 4]),
+        };
+        let token = state.to_resume_token();
+        let mut chars: Vec<char> = token.chars().collect();
+        chars[10] = if chars[10] == 'A' { 'B' } else { 'A' };
+        let corrupted: String = chars.into_iter().collect();
+        assert!(ReceiveState::from_resume_token(&corrupted).is_err());
+        assert!(ReceiveState::from_resume_token(&token[..token.len() - 3]).is_err());
+        assert!(ReceiveState::from_resume_token("not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merkle_metadata_matches_sequential_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 97) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let sequential = FileTransfer::new(path.clone()).prepare_metadata().await.unwrap();
+        let mut merkle = FileTransfer::new(path.clone()).with_integrity(IntegrityScheme::Merkle);
+        let metadata = merkle.prepare_metadata().await.unwrap();
+
+        assert_eq!(metadata.integrity, IntegrityScheme::Merkle);
+        assert_eq!(metadata.hash, sequential.merkle_root());
+        let hashes: Vec<_> = metadata.chunks.iter().map(|c| &c.hash).collect();
+        let expected: Vec<_> = sequential.chunks.iter().map(|c| &c.hash).collect();
+        assert_eq!(hashes, expected);
+        merkle.verify_complete().await.unwrap();
+
+        // Corrupt one byte in chunk 2: the tree pinpoints it
+        let mut corrupted = data.clone();
+        corrupted[2 * CHUNK_SIZE + 5] ^= 0xff;
+        std::fs::write(&path, &corrupted).unwrap();
+        let err = merkle.verify_complete().await.unwrap_err();
+        assert!(err.to_string().contains("[2]"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_verification_failure_lists_bad_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whole.bin");
+        let data: Vec<u8> = (0..4 * CHUNK_SIZE + 10).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut transfer = FileTransfer::new(path.clone());
+        transfer.prepare_metadata().await.unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[CHUNK_SIZE + 1] ^= 0xff;
+        corrupted[4 * CHUNK_SIZE + 3] ^= 0xff;
+        std::fs::write(&path, &corrupted).unwrap();
+        match transfer.verify_complete().await {
+            Err(DropError::VerificationFailed { mismatched_chunks, file_hash_mismatch }) => {
+                assert_eq!(mismatched_chunks, vec![1, 4]);
+                assert!(file_hash_mismatch);
+            }
+            other => panic!("expected VerificationFailed, got {:?}", other),
+        }
+    }
+
+    struct MemorySource(Vec<u8>);
+
+    impl ChunkSource for MemorySource {
+        fn len(&self) -> Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+            let start = (offset as usize).min(self.0.len());
+            let n = buffer.len().min(self.0.len() - start);
+            buffer[..n].copy_from_slice(&self.0[start..start + n]);
+            Ok(n)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_source_matches_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 300).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut from_file = FileTransfer::new(path.clone());
+        let mut from_memory = FileTransfer::new(path).with_source(Arc::new(MemorySource(data)));
+        let file_metadata = from_file.prepare_metadata().await.unwrap();
+        let memory_metadata = from_memory.prepare_metadata().await.unwrap();
+
+        assert_eq!(memory_metadata.hash, file_metadata.hash);
+        assert_eq!(memory_metadata.chunks.len(), file_metadata.chunks.len());
+        for chunk in &file_metadata.chunks {
+            assert_eq!(memory_metadata.chunks[chunk.index as usize].hash, chunk.hash);
+            assert_eq!(
+                from_memory.read_chunk(chunk.index).await.unwrap(),
+                from_file.read_chunk(chunk.index).await.unwrap()
+            );
+        }
+    }
+
+    // Appends to the file it reads on the first read, like a writer racing the sender
+    struct GrowingSource {
+        file: FileSource,
+        path: PathBuf,
+        grown: std::sync::atomic::AtomicBool,
+    }
+
+    impl ChunkSource for GrowingSource {
+        fn len(&self) -> Result<u64> {
+            self.file.len()
+        }
+
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+            if !self.grown.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                OpenOptions::new().append(true).open(&self.path)?.write_all(&[7u8; 1000])?;
+            }
+            self.file.read_at(offset, buffer)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_growing_during_prepare_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.bin");
+        for integrity in [IntegrityScheme::WholeFile, IntegrityScheme::Merkle] {
+            std::fs::write(&path, vec![1u8; 2 * CHUNK_SIZE + 10]).unwrap();
+            let source = GrowingSource {
+                file: FileSource::new(path.clone()),
+                path: path.clone(),
+                grown: Default::default(),
+            };
+            let mut transfer = FileTransfer::new(path.clone()).with_integrity(integrity).with_source(Arc::new(source));
+            match transfer.prepare_metadata().await {
+                Err(DropError::Io(e)) => assert_eq!(e.to_string(), "file changed during read"),
+                other => panic!("expected an io error, got {:?}", other.map(|m| m.size)),
+            }
+            assert!(transfer.get_metadata().is_none());
+        }
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "etcpasswd");
+        assert_eq!(sanitize_filename("..\\windows\\system.ini"), "windowssystem.ini");
+        assert_eq!(sanitize_filename("evil\0name.txt"), "evilname.txt");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("/"), FALLBACK_FILE_NAME);
+        assert_eq!(sanitize_filename("\0\0"), FALLBACK_FILE_NAME);
+        assert_eq!(sanitize_filename(&"é".repeat(200)).len(), 254);
+    }
+
+    #[tokio::test]
+    async fn test_progress_config() {
+        let bad = ProgressConfig {
+            template: "{bar:wide}".to_string(),
+            ..ProgressConfig::default()
+        };
+        assert!(matches!(
+            FileTransfer::with_progress_config(PathBuf::from("x"), &bad),
+            Err(DropError::Protocol(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, vec![7u8; 1000]).unwrap();
+        let mut transfer = FileTransfer::with_progress_config(path, &ProgressConfig::hidden()).unwrap();
+        assert!(transfer.progress_bar.is_hidden());
+        transfer.prepare_metadata().await.unwrap();
+        transfer.read_chunk(0).await.unwrap();
+        assert_eq!(transfer.progress_bar.position(), 1000);
+        assert!(transfer.progress_bar.is_hidden());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_reads_reuse_one_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        // 40 full chunks and a short one
+        let data: Vec<u8> = (0..40 * MIN_CHUNK_SIZE as u32 + 1000).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut transfer = FileTransfer::with_progress_config(path, &ProgressConfig::hidden())
+            .unwrap()
+            .with_chunk_size(MIN_CHUNK_SIZE);
+        let metadata = transfer.prepare_metadata().await.unwrap();
+        assert_eq!(metadata.chunks.len(), 41);
+
+        let mut buffer = None;
+        for round in 0..25 {
+            let mut read = Vec::with_capacity(data.len());
+            for chunk in &metadata.chunks {
+                let bytes = transfer.read_chunk(chunk.index).await.unwrap();
+                assert_eq!(bytes.len() as u64, chunk.size, "round {} chunk {}", round, chunk.index);
+                read.extend_from_slice(&bytes);
+                // Always the same allocation underneath
+                let current = transfer.read_buffer.as_ptr();
+                assert_eq!(*buffer.get_or_insert(current), current);
+            }
+            assert!(read == data, "round {} read back different bytes", round);
+        }
+        assert_eq!(transfer.bytes_transferred, 25 * data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_hashing_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("huge.bin");
+        // Two full checkpoints' worth and a bit
+        let data: Vec<u8> = (0..(2 * HASH_CHECKPOINT_CHUNKS + 5) * MIN_CHUNK_SIZE as u32 + 77)
+            .map(|i| (i % 239) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+        let transfer = |integrity| {
+            FileTransfer::with_progress_config(path.clone(), &ProgressConfig::hidden())
+                .unwrap()
+                .with_chunk_size(MIN_CHUNK_SIZE)
+                .with_integrity(integrity)
+        };
+
+        for integrity in [IntegrityScheme::WholeFile, IntegrityScheme::Merkle] {
+            let expected = transfer(integrity).prepare_metadata().await.unwrap();
+
+            // Cancelled before it starts, it still gets as far as the first checkpoint
+            let token = CancellationToken::new();
+            token.cancel();
+            let mut interrupted = transfer(integrity).with_hash_checkpoint(true).with_cancellation_token(token);
+            assert!(matches!(interrupted.prepare_metadata().await, Err(DropError::Cancelled)));
+            let stamp = file_stamp(&path).unwrap();
+            assert_eq!(interrupted.load_hash_checkpoint(stamp, u32::MAX).len(), HASH_CHECKPOINT_CHUNKS as usize);
+
+            // A record torn by the interruption is dropped, the ones before it kept
+            let mut checkpoint = std::fs::OpenOptions::new().append(true).open(interrupted.hash_checkpoint_path()).unwrap();
+            checkpoint.write_all(b"{\"index\":64,\"si").unwrap();
+            assert_eq!(interrupted.load_hash_checkpoint(stamp, u32::MAX).len(), HASH_CHECKPOINT_CHUNKS as usize);
+
+            // A restart picks up from there
+            let mut resumed = transfer(integrity).with_hash_checkpoint(true);
+            let metadata = resumed.prepare_metadata().await.unwrap();
+            assert_eq!(serde_json::to_value(&metadata).unwrap(), serde_json::to_value(&expected).unwrap());
+            assert!(!resumed.hash_checkpoint_path().exists());
+        }
+
+        // A checkpoint of the file before it changed isn't trusted
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut interrupted = transfer(IntegrityScheme::Merkle).with_hash_checkpoint(true).with_cancellation_token(token);
+        assert!(interrupted.prepare_metadata().await.is_err());
+        let mut changed = data.clone();
+        changed[0] ^= 0xff;
+        changed.push(1);
+        std::fs::write(&path, &changed).unwrap();
+        let expected = transfer(IntegrityScheme::Merkle).prepare_metadata().await.unwrap();
+        let metadata = transfer(IntegrityScheme::Merkle).with_hash_checkpoint(true).prepare_metadata().await.unwrap();
+        assert_eq!(metadata.hash, expected.hash);
+        assert_eq!(metadata.chunks[0].hash, expected.chunks[0].hash);
+
+        // Nowhere to save a checkpoint doesn't stop the hashing
+        let mut checkpointed = transfer(IntegrityScheme::Merkle).with_hash_checkpoint(true);
+        std::fs::create_dir(checkpointed.hash_checkpoint_path()).unwrap();
+        let metadata = checkpointed.prepare_metadata().await.unwrap();
+        assert_eq!(metadata.hash, expected.hash);
+    }
+
+    #[tokio::test]
+    async fn test_send_state_not_saved_for_a_file_changed_while_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, vec![1u8; 100_000]).unwrap();
+        let before = file_stamp(&path).unwrap();
+        let mut transfer = FileTransfer::with_progress_config(path.clone(), &ProgressConfig::hidden()).unwrap();
+        let metadata = transfer.prepare_metadata().await.unwrap();
+
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(mtime + Duration::from_secs(5)).unwrap();
+        transfer.write_send_state(before, &metadata).unwrap();
+        assert!(!transfer.send_state_path().exists());
+
+        transfer.write_send_state(file_stamp(&path).unwrap(), &metadata).unwrap();
+        assert!(FileTransfer::restore_send_state(path).is_ok());
+    }
+
+    #[test]
+    fn test_eta_from_moving_average() {
+        const MB: u64 = 1_000_000;
+        let clock = Arc::new(MockClock::new());
+        let mut transfer = FileTransfer::with_progress_config(PathBuf::from("x"), &ProgressConfig::hidden())
+            .unwrap()
+            .with_clock(clock.clone());
+        transfer.progress_bar.set_length(200 * MB);
+        let start = clock.now();
+        let sample = |transfer: &mut FileTransfer, millis: u64, position: u64| {
+            transfer.progress_bar.set_position(position);
+            clock.advance((start + Duration::from_millis(millis)).saturating_duration_since(clock.now()));
+            transfer.sample_progress(clock.now());
+        };
+
+        sample(&mut transfer, 0, 0);
+        sample(&mut transfer, 100, MB);
+        assert_eq!(transfer.eta(), None);
+
+        // 10 MB/s for 3 seconds, with one 9 MB burst at the 2 second mark
+        for step in 2..=30 {
+            let burst = if step >= 20 { 9 * MB } else { 0 };
+            sample(&mut transfer, step * 100, step * MB + burst);
+        }
+        // The burst is averaged in: 39 MB over 3 s, 161 MB left
+        let eta = transfer.eta().unwrap().as_secs_f64();
+        assert!((eta - 161.0 / 13.0).abs() < 0.01, "{}", eta);
+
+        // Samples older than the window drop out, so a link that slows down
+        // is reflected within ETA_WINDOW
+        for step in 1..=12 {
+            sample(&mut transfer, 3000 + step * 1000, 39 * MB + step * MB);
+        }
+        let eta = transfer.eta().unwrap().as_secs_f64();
+        assert!((eta - 149.0).abs() < 0.01, "{}", eta);
+
+        // A stall gives no estimate rather than an infinite one
+        for step in 1..=12 {
+            sample(&mut transfer, 15000 + step * 1000, 51 * MB);
+        }
+        assert_eq!(transfer.eta(), None);
+
+        // Nor does a link that went quiet once its last sample ages out of the window
+        for step in 1..=3 {
+            sample(&mut transfer, 27000 + step * 100, (51 + step) * MB);
+        }
+        assert!(transfer.eta().is_some());
+        clock.advance(ETA_WINDOW + Duration::from_secs(1));
+        assert_eq!(transfer.eta(), None);
+    }
+
+    #[test]
+    fn test_recommend_chunk_size_monotonic_and_bounded() {
+        let sizes: Vec<u64> = (0..44).map(|shift| 1u64 << shift).chain([0, 3, 999_999]).collect();
+        let links = [None, Some(1_000_000), Some(100_000_000), Some(10_000_000_000)];
+        for link in links {
+            let mut sorted = sizes.clone();
+            sorted.sort();
+            let mut previous = 0;
+            for &size in &sorted {
+                let recommended = recommend_chunk_size(size, link);
+                assert!(recommended.is_power_of_two());
+                assert!((MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&recommended));
+                assert!(recommended >= previous, "{} shrank at {} bytes", recommended, size);
+                previous = recommended;
+            }
+        }
+        assert_eq!(recommend_chunk_size(1024, None), 64 * 1024);
+        assert_eq!(recommend_chunk_size(1 << 40, None), MAX_CHUNK_SIZE);
+        // A slow link caps what a large file would otherwise get
+        assert!(recommend_chunk_size(1 << 40, Some(1_000_000)) < recommend_chunk_size(1 << 40, Some(1_000_000_000)));
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_write_rejects_corrupted_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE + 500).map(|i| (i % 89) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+
+        let dst = dir.path().join("dst.bin");
+        let mut out = FileTransfer::new(dst.clone()).with_verify_on_write(true);
+        out.begin_receive(metadata).await.unwrap();
+        out.write_chunk(0, data[..CHUNK_SIZE].to_vec()).await.unwrap();
+
+        let mut tail = data[CHUNK_SIZE..].to_vec();
+        tail[3] ^= 0x80;
+        let err = out.write_chunk(1, tail).await.unwrap_err();
+        assert!(err.to_string().contains("chunk 1 failed verification"), "{}", err);
+        // Checked from disk, and not counted as written
+        assert!(out.written.has(0));
+        assert!(!out.written.has(1));
+    }
+
+    #[tokio::test]
+    async fn test_block_aligned_writes_with_unaligned_chunks() {
+        // Every piece but the first starts on a block boundary
+        let pieces: Vec<_> = aligned_writes(20_000, 9_000, 4096).collect();
+        assert_eq!(pieces, vec![(20_000, 480), (20_480, 4096), (24_576, 4096), (28_672, 328)]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        // Neither the chunk size nor the total is a multiple of any block size
+        let data: Vec<u8> = (0..250_003u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let mut sender = FileTransfer::new(src).with_chunk_size(20_001);
+        let metadata = sender.prepare_metadata().await.unwrap();
+
+        let dst = dir.path().join("dst.bin");
+        let mut out = FileTransfer::new(dst.clone());
+        out.begin_receive(metadata.clone()).await.unwrap();
+        assert!(out.block_size.is_power_of_two());
+        // Back to front, so each write lands past unwritten data
+        for chunk in metadata.chunks.iter().rev() {
+            let bytes = sender.read_chunk(chunk.index).await.unwrap();
+            out.write_chunk(chunk.index, bytes).await.unwrap();
+        }
+        out.verify_complete().await.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        // In order, chunks smaller than the buffer wait in it until it fills
+        let mut out = FileTransfer::new(dst.clone());
+        out.begin_receive(metadata.clone()).await.unwrap();
+        for chunk in &metadata.chunks[..2] {
+            out.write_chunk(chunk.index, sender.read_chunk(chunk.index).await.unwrap()).await.unwrap();
+        }
+        assert!(std::fs::read(&dst).unwrap().iter().all(|&b| b == 0));
+        for chunk in &metadata.chunks[2..] {
+            out.write_chunk(chunk.index, sender.read_chunk(chunk.index).await.unwrap()).await.unwrap();
+        }
+        out.sync_output().unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        out.verify_complete().await.unwrap();
+    }
+
+    // Takes nothing, like a file on a full disk
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_disk_is_reported_as_such() {
+        let e = write_error(FullDisk.write_all(b"chunk").unwrap_err());
+        assert!(e.is_disk_full());
+        assert!(e.to_string().contains(DISK_FULL), "{}", e);
+        assert!(!write_error(std::io::ErrorKind::PermissionDenied.into()).is_disk_full());
+
+        // The real thing, where the platform has one
+        if cfg!(target_os = "linux") {
+            let mut out = FileTransfer::new(PathBuf::from("/dev/full"));
+            out.begin_stream().unwrap();
+            let e = out.append_chunk(&[1u8; 4096]).unwrap_err();
+            assert!(e.is_disk_full(), "{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_write_durability_produces_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 77).map(|i| (i % 199) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let mut sender = FileTransfer::new(src);
+        let metadata = sender.prepare_metadata().await.unwrap();
+
+        for durability in [WriteDurability::Buffered, WriteDurability::FsyncPerChunk, WriteDurability::FsyncOnComplete] {
+            let dst = dir.path().join(format!("{:?}.bin", durability));
+            let mut out = FileTransfer::new(dst.clone()).with_write_durability(durability);
+            out.begin_receive(metadata.clone()).await.unwrap();
+            for chunk in &metadata.chunks {
+                let bytes = sender.read_chunk(chunk.index).await.unwrap();
+                out.write_chunk(chunk.index, bytes).await.unwrap();
+            }
+            out.sync_output().unwrap();
+            out.verify_complete().await.unwrap();
+            assert_eq!(std::fs::read(&dst).unwrap(), data, "{:?}", durability);
+        }
+    }
+
+    // Terminal that keeps every line drawn to it
+    #[derive(Debug, Clone, Default)]
+    struct RecordingTerm(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl indicatif::TermLike for RecordingTerm {
+        fn width(&self) -> u16 {
+            80
+        }
+        fn move_cursor_up(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_down(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_right(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_left(&self, _: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn write_line(&self, s: &str) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(s.to_string());
+            Ok(())
+        }
+        fn write_str(&self, s: &str) -> std::io::Result<()> {
+            self.write_line(s)
+        }
+        fn clear_line(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_progress_bar_finishes_full_on_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 9).map(|i| (i % 13) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+
+        let config = ProgressConfig { template: "{pos}/{len} {msg}".to_string(), ..ProgressConfig::default() };
+        let mut out = FileTransfer::with_progress_config(dir.path().join("dst.bin"), &config).unwrap();
+        let term = RecordingTerm::default();
+        out.progress_bar().set_draw_target(ProgressDrawTarget::term_like(Box::new(term.clone())));
+
+        // Chunk 1 is skipped, as a resumed or incremental receive would, so it never counts
+        out.begin_receive(metadata.clone()).await.unwrap();
+        out.write_chunk(0, data[..CHUNK_SIZE].to_vec()).await.unwrap();
+        out.write_chunk(2, data[2 * CHUNK_SIZE..].to_vec()).await.unwrap();
+        assert!(out.progress_bar().position() < metadata.size);
+
+        out.mark_complete(Direction::Received, None, false).unwrap();
+        assert!(out.progress_bar().is_finished());
+        assert_eq!(out.progress_bar().position(), metadata.size);
+        let lines = term.0.lock().unwrap();
+        let last = lines.iter().rev().find(|l| !l.trim().is_empty()).expect("bar was drawn");
+        assert_eq!(last.trim_end(), format!("{0}/{0} done", metadata.size));
+    }
+
+    #[tokio::test]
+    async fn test_rewriting_a_chunk_must_repeat_its_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE + 500).map(|i| (i % 97) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let metadata = FileTransfer::new(src).prepare_metadata().await.unwrap();
+
+        let dst = dir.path().join("dst.bin");
+        let mut out = FileTransfer::new(dst.clone());
+        out.begin_receive(metadata).await.unwrap();
+        out.write_chunk(1, data[CHUNK_SIZE..].to_vec()).await.unwrap();
+        // A benign duplicate, e.g. a retransmission, is accepted
+        out.write_chunk(1, data[CHUNK_SIZE..].to_vec()).await.unwrap();
+
+        let mut conflicting = data[CHUNK_SIZE..].to_vec();
+        conflicting[0] ^= 1;
+        let err = out.write_chunk(1, conflicting).await.unwrap_err();
+        assert!(err.to_string().contains("chunk 1 rewritten"), "{}", err);
+        assert!(out.write_chunk(1, data[CHUNK_SIZE..CHUNK_SIZE + 10].to_vec()).await.is_err());
+
+        out.write_chunk(0, data[..CHUNK_SIZE].to_vec()).await.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        out.verify_complete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_file_reports_corrupted_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("received.bin");
+        let mut data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 83) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let metadata = FileTransfer::new(path.clone()).prepare_metadata().await.unwrap();
+
+        let report = verify_file(&path, &metadata).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.chunks_checked, 4);
+
+        data[2 * CHUNK_SIZE + 7] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let report = verify_file(&path, &metadata).unwrap();
+        assert_eq!(report.mismatched_chunks, vec![2]);
+        assert!(report.size_matches);
+        assert!(!report.file_hash_matches);
+        assert!(!report.is_ok());
+
+        let broken = FileMetadata { chunk_size: Some(0), ..metadata };
+        assert!(matches!(verify_file(&path, &broken), Err(DropError::Protocol(_))));
+    }
+}
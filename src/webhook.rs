@@ -0,0 +1,400 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::retry::RetryPolicy;
+use crate::{DropError, Result};
+
+// Carries "sha256=<hex HMAC-SHA256 of the body>" when the webhook has a secret
+pub const SIGNATURE_HEADER: &str = "x-drop-signature";
+
+// Lifecycle transitions reported in `WebhookEvent::event`
+pub const SESSION_CREATED: &str = "session_created";
+// A peer connected to the session's relay. Relay only: peers that pair over
+// signaling and then talk directly never tell the server they've joined.
+pub const PEER_JOINED: &str = "peer_joined";
+// The session's relay closed, normally because the transfer finished. Relay
+// only, like PEER_JOINED: a direct transfer finishes out of the server's sight.
+pub const COMPLETED: &str = "completed";
+pub const REAPED: &str = "reaped";
+
+// Body of every webhook POST
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub event: String,
+    pub session_id: String,
+    // Milliseconds since the Unix epoch. Deliveries run concurrently and are
+    // retried, so they can arrive out of order; sort by this instead.
+    pub timestamp_ms: u64,
+}
+
+// Bound on connecting, and on each read and write, of one delivery attempt
+pub const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where CA bundles usually live; the first one found is trusted for https
+const CA_BUNDLES: [&str; 4] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+    "/etc/ssl/ca-bundle.pem",
+];
+
+// POSTs `WebhookEvent`s to an integrator's endpoint over HTTP/1.1, or over
+// TLS for https:// URLs. Delivery happens on a spawned task with the
+// webhook's `RetryPolicy`, so a slow or failing endpoint never holds up the
+// request that caused the event, and every attempt is bounded by a timeout.
+#[derive(Clone)]
+pub struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+    // Set for https
+    tls: Option<Arc<rustls::ClientConfig>>,
+    secret: Option<Vec<u8>>,
+    retry: RetryPolicy,
+    timeout: Duration,
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("path", &self.path)
+            .field("tls", &self.tls.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Webhook {
+    // `url` is "http://host[:port]/path" or "https://host[:port]/path". For
+    // https the system's CA bundle is trusted, see `with_root_certificates`.
+    pub fn new(url: &str) -> Result<Self> {
+        let unsupported = || DropError::Protocol(format!("unsupported webhook URL: {}", url));
+        let (rest, tls, default_port) = match url.split_once("://") {
+            Some(("http", rest)) => (rest, false, 80),
+            Some(("https", rest)) => (rest, true, 443),
+            _ => return Err(unsupported()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| unsupported())?),
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(unsupported());
+        }
+        let tls = tls.then(|| client_config(system_roots()));
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            tls,
+            secret: None,
+            retry: RetryPolicy::default(),
+            timeout: DEFAULT_WEBHOOK_TIMEOUT,
+        })
+    }
+
+    // Sign every body with `secret`, see SIGNATURE_HEADER
+    pub fn with_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Trust only the certificates in `pem` for https, instead of the system's
+    pub fn with_root_certificates(mut self, pem: &[u8]) -> Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        let certificates = pem::parse_many(pem).map_err(|e| DropError::Protocol(format!("invalid PEM: {}", e)))?;
+        let ders: Vec<Vec<u8>> = certificates.into_iter().map(pem::Pem::into_contents).collect();
+        let (added, _) = roots.add_parsable_certificates(&ders);
+        if added == 0 {
+            return Err(DropError::Protocol("no usable root certificates".to_string()));
+        }
+        if self.tls.is_some() {
+            self.tls = Some(client_config(roots));
+        }
+        Ok(self)
+    }
+
+    // Queue delivery of `event` for `session_id` and return immediately.
+    // Outside a Tokio runtime there is nothing to deliver on, so the event is dropped.
+    pub fn notify(&self, event: &str, session_id: &str) {
+        let event = WebhookEvent {
+            event: event.to_string(),
+            session_id: session_id.to_string(),
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_millis() as u64),
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!(event = %event.event, "no runtime to deliver webhook on");
+            return;
+        };
+        let webhook = self.clone();
+        runtime.spawn(async move {
+            if let Err(e) = webhook.deliver(&event).await {
+                tracing::warn!(error = %e, event = %event.event, session_id = %event.session_id, "webhook delivery failed");
+            }
+        });
+    }
+
+    // Connection failures, timeouts and 5xx responses are retried; any other
+    // non-2xx response means the endpoint rejected the event, so it's given up on
+    pub async fn deliver(&self, event: &WebhookEvent) -> Result<()> {
+        let body = serde_json::to_vec(event)?;
+        let mut attempt = 0;
+        loop {
+            let reason = match self.post(&body).await {
+                Ok(200..=299) => return Ok(()),
+                Ok(status @ 500..=599) => format!("HTTP {}", status),
+                Ok(status) => return Err(DropError::Protocol(format!("webhook rejected with HTTP {}", status))),
+                Err(e) => e.to_string(),
+            };
+            attempt += 1;
+            if !self.retry.should_retry(attempt) {
+                return Err(DropError::Timeout(format!(
+                    "webhook unavailable after {} attempts: {}",
+                    attempt, reason
+                )));
+            }
+            tokio::time::sleep(self.retry.next_delay(attempt - 1)).await;
+        }
+    }
+
+    // The response status. The exchange is blocking I/O with socket
+    // timeouts, which rustls drives directly, so it runs off the runtime.
+    async fn post(&self, body: &[u8]) -> Result<u16> {
+        let webhook = self.clone();
+        let body = body.to_vec();
+        tokio::task::spawn_blocking(move || webhook.post_blocking(&body))
+            .await
+            .map_err(|e| DropError::Protocol(format!("webhook task failed: {}", e)))?
+    }
+
+    fn post_blocking(&self, body: &[u8]) -> Result<u16> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| DropError::Protocol(format!("webhook host {} did not resolve", self.host)))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        if let Some(secret) = &self.secret {
+            head.push_str(&format!("{}: {}\r\n", SIGNATURE_HEADER, sign(secret, body)));
+        }
+        head.push_str("\r\n");
+        let raw = match &self.tls {
+            None => exchange(stream, head.as_bytes(), body)?,
+            Some(config) => {
+                let name = rustls::ServerName::try_from(self.host.as_str())
+                    .map_err(|_| DropError::Protocol(format!("invalid webhook host {}", self.host)))?;
+                let connection = rustls::ClientConnection::new(config.clone(), name)
+                    .map_err(|e| DropError::Protocol(format!("webhook TLS setup failed: {}", e)))?;
+                exchange(rustls::StreamOwned::new(connection, stream), head.as_bytes(), body)?
+            }
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&raw) {
+            Ok(_) => response
+                .code
+                .ok_or_else(|| DropError::Protocol("truncated webhook response".to_string())),
+            Err(e) => Err(DropError::Protocol(format!("invalid webhook response: {}", e))),
+        }
+    }
+}
+
+// Send the request and read the response until the server closes
+fn exchange(mut stream: impl Read + Write, head: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut raw = Vec::new();
+    match stream.read_to_end(&mut raw) {
+        Ok(_) => Ok(raw),
+        // Servers often close a TLS connection without close_notify once the response is out
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !raw.is_empty() => Ok(raw),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn client_config(roots: rustls::RootCertStore) -> Arc<rustls::ClientConfig> {
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+// The first CA bundle found in CA_BUNDLES, or no roots at all, in which case
+// https deliveries fail until `with_root_certificates` is used
+fn system_roots() -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    let bundle = CA_BUNDLES.iter().find_map(|path| std::fs::read(path).ok());
+    match bundle.map(pem::parse_many) {
+        Some(Ok(certificates)) => {
+            let ders: Vec<Vec<u8>> = certificates.into_iter().map(pem::Pem::into_contents).collect();
+            roots.add_parsable_certificates(&ders);
+        }
+        Some(Err(e)) => tracing::warn!(error = %e, "could not parse the system CA bundle"),
+        None => tracing::warn!("no system CA bundle found for https webhooks"),
+    }
+    roots
+}
+
+// Value of SIGNATURE_HEADER for `body`; receivers recompute it with the shared secret
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use actix_web::{post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+    use tokio::sync::mpsc;
+
+    // Signature header and body of each delivery
+    type Deliveries = mpsc::UnboundedSender<(String, Vec<u8>)>;
+
+    #[post("/hooks/drop")]
+    async fn receive_hook(req: HttpRequest, body: web::Bytes, seen: web::Data<Deliveries>) -> impl Responder {
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let _ = seen.send((signature, body.to_vec()));
+        HttpResponse::NoContent().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_session_created_event_is_delivered() {
+        let (seen, mut hooks) = mpsc::unbounded_channel();
+        let seen: web::Data<Deliveries> = web::Data::new(seen);
+        let hook_server = HttpServer::new(move || App::new().app_data(seen.clone()).service(receive_hook))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}/hooks/drop", hook_server.addrs()[0]);
+        actix_web::rt::spawn(hook_server.run());
+
+        let webhook = Webhook::new(&url).unwrap().with_secret("hush");
+        let state = web::Data::new(crate::AppState::new().with_webhook(webhook));
+        let app = actix_web::test::init_service(App::new().app_data(state).service(crate::create_session)).await;
+        let req = actix_web::test::TestRequest::post().uri("/api/session/create").to_request();
+        let created: crate::CreateSessionResponse = actix_web::test::call_and_read_body_json(&app, req).await;
+
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), hooks.recv()).await.unwrap().unwrap();
+        let event: WebhookEvent = serde_json::from_slice(&body).unwrap();
+        assert_eq!(event.event, SESSION_CREATED);
+        assert_eq!(event.session_id, created.session_id);
+        assert_eq!(signature, sign(b"hush", &body));
+        assert_ne!(signature, sign(b"wrong", &body));
+
+        assert!(Webhook::new("https://example.com/hook").is_ok());
+        assert!(Webhook::new("ftp://example.com/hook").is_err());
+        assert!(Webhook::new("http:///hook").is_err());
+    }
+
+    fn event() -> WebhookEvent {
+        WebhookEvent { event: SESSION_CREATED.to_string(), session_id: "ABC123".to_string(), timestamp_ms: 0 }
+    }
+
+    fn once() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, base_delay: Duration::ZERO, max_delay: Duration::ZERO, jitter: 0.0 }
+    }
+
+    #[tokio::test]
+    async fn test_silent_endpoint_times_out() {
+        // Accepts the connection, then never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let webhook = Webhook::new(&url).unwrap().with_timeout(Duration::from_millis(200)).with_retry_policy(once());
+        let started = std::time::Instant::now();
+        let e = webhook.deliver(&event()).await.unwrap_err();
+        assert!(matches!(e, DropError::Timeout(_)), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_delivers_over_https() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let connection = rustls::ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut stream = rustls::StreamOwned::new(connection, tcp);
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            // Headers, then the body the client said it would send
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let head = String::from_utf8(request).unwrap();
+            let length: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).unwrap();
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            body
+        });
+
+        let url = format!("https://localhost:{}/hook", port);
+        let webhook = Webhook::new(&url)
+            .unwrap()
+            .with_root_certificates(cert.serialize_pem().unwrap().as_bytes())
+            .unwrap()
+            .with_retry_policy(once());
+        webhook.deliver(&event()).await.unwrap();
+        let delivered: WebhookEvent = serde_json::from_slice(&server.join().unwrap()).unwrap();
+        assert_eq!(delivered, event());
+    }
+}
@@ -1,12 +1,285 @@
+use std::future::Future;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use tokio::sync::{mpsc, watch};
 use webrtc::api::APIBuilder;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::ice::candidate::candidate_base::unmarshal_candidate;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use crate::{Result, TransferProtocol};
+use crate::identity::LocalIdentity;
+use crate::protocol::{Protocol, Transport};
+use crate::relay::MAX_RELAY_FRAME;
+use crate::signaling::SignalingTransport;
+use crate::{CancellationToken, DropError, Result, SignalingMessage, TransferProtocol};
 
+pub const DEFAULT_CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+// Max message size to assume of a peer whose description carries no
+// `a=max-message-size` (RFC 8841 section 6.1); webrtc-rs sends no attribute
+// and accepts exactly this much
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// Received messages held for the protocol before the data channel stops
+// reading, so at most this times DEFAULT_MAX_MESSAGE_SIZE waits in memory
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 64;
+
+// Parse the payload of a "candidate" signaling message: the JSON of a
+// browser's `RTCIceCandidate`, or a bare candidate line with or without its
+// "a=" and "candidate:" prefixes. The line is only parsed to check it: it
+// comes back as sent, just without an "a=" and with a "candidate:" prefix,
+// since re-serializing it would drop attributes the parser doesn't keep
+// (generation, ufrag, network-id, ...). An empty line (end of candidates) is
+// passed through.
+pub fn validate_candidate(s: &str) -> Result<RTCIceCandidateInit> {
+    let s = s.trim();
+    let mut init = match s.starts_with('{') {
+        true => serde_json::from_str::<RTCIceCandidateInit>(s)
+            .map_err(|e| DropError::WebRTC(format!("malformed ICE candidate JSON: {}", e)))?,
+        false => RTCIceCandidateInit { candidate: s.to_string(), ..RTCIceCandidateInit::default() },
+    };
+    let line = init.candidate.trim();
+    let line = line.strip_prefix("a=").unwrap_or(line);
+    let Some(line) = line.strip_prefix("candidate:").or((!line.is_empty()).then_some(line)) else {
+        init.candidate = String::new();
+        return Ok(init);
+    };
+    unmarshal_candidate(line)
+        .map_err(|e| DropError::WebRTC(format!("malformed ICE candidate {:?}: {}", init.candidate, e)))?;
+    init.candidate = format!("candidate:{}", line);
+    Ok(init)
+}
+
+// The end-of-candidates marker sent once gathering has finished: a
+// "candidate" message with an empty candidate line, which a browser's
+// `addIceCandidate` also understands. It names the sender's ICE username
+// fragment, so the two peers' markers aren't identical messages.
+pub fn end_of_candidates(ufrag: Option<String>) -> SignalingMessage {
+    let init = RTCIceCandidateInit { username_fragment: ufrag, ..RTCIceCandidateInit::default() };
+    SignalingMessage {
+        message_type: "candidate".to_string(),
+        payload: serde_json::to_string(&init).expect("candidate init serializes"),
+        from: None,
+    }
+}
+
+pub fn is_end_of_candidates(message: &SignalingMessage) -> bool {
+    message.message_type == "candidate"
+        && validate_candidate(&message.payload).is_ok_and(|c| c.candidate.is_empty())
+}
+
+fn ice_ufrag(sdp: &str) -> Option<String> {
+    sdp.lines().find_map(|line| line.trim().strip_prefix("a=ice-ufrag:")).map(str::to_string)
+}
+
+// The `a=max-message-size` a description advertises; 0 there means no limit
+fn advertised_max_message_size(sdp: &str) -> Option<usize> {
+    let size = sdp.lines().find_map(|line| line.trim().strip_prefix("a=max-message-size:"))?;
+    match size.parse::<usize>().ok()? {
+        0 => Some(usize::MAX),
+        size => Some(size),
+    }
+}
+
+// Frames up to the max message size go out as-is. Larger ones are split into
+// fragments of `[FRAGMENT_MARKER, FRAGMENT_MORE | FRAGMENT_LAST, payload...]`.
+// A protocol frame starts with `{` (JSON) or 0x01 (binary metadata, see
+// `protocol::BINARY_METADATA_FRAME`), never with 0xFF, so the two can't be
+// confused.
+const FRAGMENT_MARKER: u8 = 0xFF;
+const FRAGMENT_MORE: u8 = 0;
+const FRAGMENT_LAST: u8 = 1;
+const FRAGMENT_HEADER_LEN: usize = 2;
+
+fn fragment(frame: Vec<u8>, max_message_size: usize) -> Vec<Vec<u8>> {
+    if frame.len() <= max_message_size {
+        return vec![frame];
+    }
+    let payload = max_message_size.saturating_sub(FRAGMENT_HEADER_LEN).max(1);
+    let count = frame.len().div_ceil(payload);
+    frame
+        .chunks(payload)
+        .enumerate()
+        .map(|(i, piece)| {
+            let flag = if i + 1 == count { FRAGMENT_LAST } else { FRAGMENT_MORE };
+            let mut message = Vec::with_capacity(FRAGMENT_HEADER_LEN + piece.len());
+            message.extend_from_slice(&[FRAGMENT_MARKER, flag]);
+            message.extend_from_slice(piece);
+            message
+        })
+        .collect()
+}
+
+// Collects fragments back into whole frames
+#[derive(Default)]
+struct Reassembler {
+    partial: Vec<u8>,
+}
+
+impl Reassembler {
+    fn push(&mut self, message: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let (flag, payload) = match message.as_slice() {
+            [FRAGMENT_MARKER, flag, payload @ ..] => (*flag, payload),
+            _ if self.partial.is_empty() => return Ok(Some(message)),
+            _ => return Err(DropError::Protocol("unfragmented message inside a fragmented frame".to_string())),
+        };
+        if self.partial.len() + payload.len() > MAX_RELAY_FRAME {
+            return Err(DropError::Protocol("fragmented frame too large".to_string()));
+        }
+        self.partial.extend_from_slice(payload);
+        match flag {
+            FRAGMENT_MORE => Ok(None),
+            FRAGMENT_LAST => Ok(Some(std::mem::take(&mut self.partial))),
+            _ => Err(DropError::Protocol(format!("invalid fragment flag {}", flag))),
+        }
+    }
+}
+
+// Adapts a data channel to the `Transport` the chunk protocol runs over.
+// Incoming messages are queued from the `on_message` callback; the queue
+// ends when the channel closes. It's bounded: once it's full the callback
+// waits, which stops webrtc-rs reading the channel, so the SCTP receive
+// window fills and the sender's flow control holds it back. Sends wait for
+// the channel to open, since the offering side can have it before the
+// connection is up. Frames longer than the max message size are fragmented
+// and reassembled transparently.
+pub struct DataChannelTransport {
+    channel: Arc<RTCDataChannel>,
+    inbox: mpsc::Receiver<Vec<u8>>,
+    // Flips to true from `on_open`
+    open: watch::Receiver<bool>,
+    open_timeout: Duration,
+    max_message_size: usize,
+    reassembler: Reassembler,
+}
+
+impl DataChannelTransport {
+    pub fn new(channel: Arc<RTCDataChannel>) -> Self {
+        Self::bounded(channel, DEFAULT_MAX_QUEUED_MESSAGES)
+    }
+
+    // Queue at most `max_queued` received messages (at least one)
+    pub fn bounded(channel: Arc<RTCDataChannel>, max_queued: usize) -> Self {
+        let (open_tx, open) = watch::channel(channel.ready_state() == RTCDataChannelState::Open);
+        channel.on_open(Box::new(move || {
+            open_tx.send_replace(true);
+            Box::pin(async {})
+        }));
+
+        let (tx, inbox) = mpsc::channel(max_queued.max(1));
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        let message_tx = tx.clone();
+        channel.on_message(Box::new(move |msg: DataChannelMessage| {
+            let tx = message_tx.lock().unwrap().clone();
+            Box::pin(async move {
+                // webrtc-rs awaits this before reading the next message
+                if let Some(tx) = tx {
+                    let _ = tx.send(msg.data.to_vec()).await;
+                }
+            })
+        }));
+        channel.on_close(Box::new(move || {
+            tx.lock().unwrap().take();
+            Box::pin(async {})
+        }));
+
+        Self {
+            channel,
+            inbox,
+            open,
+            open_timeout: DEFAULT_CHANNEL_OPEN_TIMEOUT,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            reassembler: Reassembler::default(),
+        }
+    }
+
+    // Largest single data channel message to send; both peers must be able to
+    // receive at least this much
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size.max(FRAGMENT_HEADER_LEN + 1);
+        self
+    }
+
+    // How long `send` waits for the channel to open
+    pub fn with_open_timeout(mut self, timeout: Duration) -> Self {
+        self.open_timeout = timeout;
+        self
+    }
+
+    // Resolves once the channel is open, immediately if it already is
+    pub async fn await_channel_open(&self, timeout: Duration) -> Result<()> {
+        if self.channel.ready_state() == RTCDataChannelState::Open {
+            return Ok(());
+        }
+        let mut open = self.open.clone();
+        let opened = tokio::time::timeout(timeout, open.wait_for(|open| *open)).await.map(|r| r.map(|_| ()));
+        match opened {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(crate::DropError::WebRTC("data channel went away before opening".to_string())),
+            Err(_) => Err(crate::DropError::Timeout(format!("data channel did not open within {:?}", timeout))),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for DataChannelTransport {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        self.await_channel_open(self.open_timeout).await?;
+        for piece in fragment(message, self.max_message_size) {
+            self.channel
+                .send(&Bytes::from(piece))
+                .await
+                .map_err(|e| crate::DropError::WebRTC(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some(message) = self.inbox.recv().await {
+            if let Some(frame) = self.reassembler.push(message)? {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.channel
+            .close()
+            .await
+            .map_err(|e| crate::DropError::WebRTC(e.to_string()))
+    }
+}
+
+// Dropping a transfer without calling `cancel` still tears the peer connection
+// down, on a detached task of the current tokio runtime; await `closed()` to
+// know when that has finished.
 pub struct WebRTCTransfer {
-    peer_connection: webrtc::peer_connection::RTCPeerConnection,
-    data_channel: Option<std::sync::Arc<webrtc::data_channel::RTCDataChannel>>,
+    peer_connection: Arc<RTCPeerConnection>,
+    data_channel: Option<Arc<RTCDataChannel>>,
+    // Transport for a channel we created or the remote peer opened, waiting to be used
+    pending: Arc<Mutex<Option<DataChannelTransport>>>,
+    protocol: Option<Protocol<DataChannelTransport>>,
+    keep_alive: bool,
+    cancel: CancellationToken,
+    ping_interval: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    channel_open_timeout: Duration,
+    max_message_size: usize,
+    // See `DataChannelTransport::bounded`; shared with the `on_data_channel` handler
+    max_queued_messages: Arc<AtomicUsize>,
+    // Handed to the protocol when it's created
+    identity: Option<LocalIdentity>,
+    // Flips to true once the peer connection reports `Closed`
+    closed: watch::Receiver<bool>,
 }
 
 impl WebRTCTransfer {
@@ -18,18 +291,144 @@ impl WebRTCTransfer {
             .await
             .map_err(|e| crate::DropError::WebRTC(e.to_string()))?;
 
+        // The answering side learns about the channel from the remote peer
+        let pending = Arc::new(Mutex::new(None));
+        let incoming = pending.clone();
+        let max_queued_messages = Arc::new(AtomicUsize::new(DEFAULT_MAX_QUEUED_MESSAGES));
+        let max_queued = max_queued_messages.clone();
+        peer_connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            let transport = DataChannelTransport::bounded(channel, max_queued.load(Ordering::Relaxed));
+            *incoming.lock().unwrap() = Some(transport);
+            Box::pin(async {})
+        }));
+
+        let (closed_tx, closed) = watch::channel(false);
+        peer_connection.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            if state == RTCPeerConnectionState::Closed {
+                closed_tx.send_replace(true);
+            }
+            Box::pin(async {})
+        }));
+
         Ok(Self {
-            peer_connection,
+            peer_connection: Arc::new(peer_connection),
             data_channel: None,
+            pending,
+            protocol: None,
+            keep_alive: false,
+            cancel: CancellationToken::new(),
+            ping_interval: None,
+            handshake_timeout: None,
+            channel_open_timeout: DEFAULT_CHANNEL_OPEN_TIMEOUT,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_queued_messages,
+            identity: None,
+            closed,
         })
     }
 
+    // Resolves once the peer connection has closed, whether through `cancel` or drop
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.clone();
+        async move {
+            // An Err means the connection (and its state handler) is already gone
+            let _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
+
+    // Keep the data channel open after a transfer completes so either peer can send again
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Abort the running send/receive when `token` is cancelled
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    // Ping the peer when the data channel has been quiet this long, see `Protocol::with_ping_interval`
+    pub fn with_ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    // See `Protocol::with_handshake_timeout`. Off by default: even with the
+    // channel open, the other app may not start its side until its user accepts.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    // Give up with `DropError::Timeout` if the data channel hasn't opened
+    // this long after the first send
+    pub fn with_channel_open_timeout(mut self, timeout: Duration) -> Self {
+        self.channel_open_timeout = timeout;
+        self
+    }
+
+    // See `DataChannelTransport::with_max_message_size`
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    // Received messages to hold before the data channel stops reading, see
+    // `DataChannelTransport::bounded`. Applies to channels opened after this.
+    pub fn with_max_queued_messages(self, max_queued: usize) -> Self {
+        self.max_queued_messages.store(max_queued, Ordering::Relaxed);
+        self
+    }
+
+    // Prove this identity to the remote peer in the protocol handshake
+    pub fn with_identity(mut self, identity: LocalIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    fn protocol(&mut self) -> Result<&mut Protocol<DataChannelTransport>> {
+        if self.protocol.is_none() {
+            let transport = self
+                .pending
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| crate::DropError::WebRTC("Data channel not established".to_string()))?
+                .with_open_timeout(self.channel_open_timeout)
+                .with_max_message_size(self.max_message_size);
+            let mut protocol = Protocol::new(transport)
+                .with_keep_alive(self.keep_alive)
+                .with_cancellation_token(self.cancel.clone())
+                .with_ping_interval(self.ping_interval)
+                .with_handshake_timeout(self.handshake_timeout);
+            if let Some(identity) = self.identity.take() {
+                protocol = protocol.with_identity(identity);
+            }
+            self.protocol = Some(protocol);
+        }
+        Ok(self.protocol.as_mut().unwrap())
+    }
+
+    // Wait for the data channel to open; sends already do this on their own
+    pub async fn await_channel_open(&mut self, timeout: Duration) -> Result<()> {
+        self.protocol()?.transport().await_channel_open(timeout).await
+    }
+
+    // Receive the next file as verified chunks instead of writing it to disk,
+    // see `Protocol::chunk_stream`
+    pub fn chunk_stream(&mut self) -> Result<impl Stream<Item = Result<(u32, Vec<u8>)>> + '_> {
+        Ok(self.protocol()?.chunk_stream())
+    }
+
     pub async fn create_offer(&mut self) -> Result<String> {
         let data_channel = self.peer_connection
             .create_data_channel("file-transfer", None)
             .await
             .map_err(|e| crate::DropError::WebRTC(e.to_string()))?;
 
+        let max_queued = self.max_queued_messages.load(Ordering::Relaxed);
+        *self.pending.lock().unwrap() = Some(DataChannelTransport::bounded(data_channel.clone(), max_queued));
         self.data_channel = Some(data_channel);
 
         let offer = self.peer_connection
@@ -50,8 +449,11 @@ impl WebRTCTransfer {
         Ok(serde_json::to_string(&sdp)?)
     }
 
+    // Also lowers the max message size to what the peer advertises, if less
     pub async fn set_remote_description(&mut self, sdp: &str) -> Result<()> {
         let desc: RTCSessionDescription = serde_json::from_str(sdp)?;
+        let advertised = advertised_max_message_size(&desc.sdp).unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        self.max_message_size = self.max_message_size.min(advertised);
         self.peer_connection
             .set_remote_description(desc)
             .await
@@ -77,22 +479,143 @@ impl WebRTCTransfer {
 
         Ok(serde_json::to_string(&sdp)?)
     }
+
+    // Local description once ICE gathering has finished, so it carries the candidates
+    async fn gathered_description(&self) -> Result<String> {
+        let _ = self.peer_connection.gathering_complete_promise().await.recv().await;
+        let sdp = self
+            .peer_connection
+            .local_description()
+            .await
+            .ok_or_else(|| DropError::WebRTC("No local description".to_string()))?;
+        Ok(serde_json::to_string(&sdp)?)
+    }
+
+    // Pair with the peer on the other end of `signaling` by sending it an
+    // offer and applying its answer. Descriptions are only sent once ICE
+    // gathering is done, so no separate candidate messages are exchanged.
+    pub async fn pair_as_offerer(&mut self, signaling: &mut dyn SignalingTransport) -> Result<()> {
+        self.create_offer().await?;
+        let offer = self.gathered_description().await?;
+        signaling.send(SignalingMessage { message_type: "offer".to_string(), payload: offer, from: None }).await?;
+        let answer = next_signal(signaling, "answer").await?;
+        self.set_remote_description(&answer).await
+    }
+
+    // The other half of `pair_as_offerer`: wait for the offer and answer it
+    pub async fn pair_as_answerer(&mut self, signaling: &mut dyn SignalingTransport) -> Result<()> {
+        let offer = next_signal(signaling, "offer").await?;
+        self.set_remote_description(&offer).await?;
+        self.create_answer().await?;
+        let answer = self.gathered_description().await?;
+        signaling.send(SignalingMessage { message_type: "answer".to_string(), payload: answer, from: None }).await
+    }
+
+    // Trickle ICE variant of `pair_as_offerer`: the offer goes out straight
+    // away and our candidates follow as "candidate" messages while they're
+    // gathered, ending with `end_of_candidates`. Each side still sends all of
+    // its own candidates before reading the answer or the peer's candidates,
+    // so pairing takes at least as long as the slower side's gathering; what
+    // it saves is the peer having to wait for a fully gathered description
+    // before it can start on its own.
+    pub async fn pair_trickle_as_offerer(&mut self, signaling: &mut dyn SignalingTransport) -> Result<()> {
+        let gathered = self.local_candidates();
+        let offer = self.create_offer().await?;
+        signaling.send(SignalingMessage { message_type: "offer".to_string(), payload: offer, from: None }).await?;
+        self.send_candidates(signaling, gathered).await?;
+        let answer = next_signal(signaling, "answer").await?;
+        self.set_remote_description(&answer).await?;
+        self.apply_candidates(signaling).await
+    }
+
+    // The other half of `pair_trickle_as_offerer`
+    pub async fn pair_trickle_as_answerer(&mut self, signaling: &mut dyn SignalingTransport) -> Result<()> {
+        let offer = next_signal(signaling, "offer").await?;
+        self.set_remote_description(&offer).await?;
+        let gathered = self.local_candidates();
+        let answer = self.create_answer().await?;
+        signaling.send(SignalingMessage { message_type: "answer".to_string(), payload: answer, from: None }).await?;
+        self.send_candidates(signaling, gathered).await?;
+        self.apply_candidates(signaling).await
+    }
+
+    // Candidates as they're gathered, then None once gathering is done. Has
+    // to be called before the local description is set, which starts gathering.
+    fn local_candidates(&self) -> mpsc::UnboundedReceiver<Option<RTCIceCandidateInit>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            match candidate.map(|c| c.to_json()).transpose() {
+                Ok(init) => {
+                    let _ = tx.send(init);
+                }
+                Err(e) => tracing::warn!(error = %e, "could not serialize local ICE candidate"),
+            }
+            Box::pin(async {})
+        }));
+        rx
+    }
+
+    async fn send_candidates(
+        &self,
+        signaling: &mut dyn SignalingTransport,
+        mut gathered: mpsc::UnboundedReceiver<Option<RTCIceCandidateInit>>,
+    ) -> Result<()> {
+        // The channel closes without a None if the connection goes away mid-gathering
+        while let Some(Some(candidate)) = gathered.recv().await {
+            let payload = serde_json::to_string(&candidate)?;
+            signaling.send(SignalingMessage { message_type: "candidate".to_string(), payload, from: None }).await?;
+        }
+        let ufrag = self.peer_connection.local_description().await.and_then(|d| ice_ufrag(&d.sdp));
+        signaling.send(end_of_candidates(ufrag)).await
+    }
+
+    // Add the peer's candidates until its end-of-candidates marker
+    async fn apply_candidates(&self, signaling: &mut dyn SignalingTransport) -> Result<()> {
+        let mut incoming = signaling.recv();
+        while let Some(message) = incoming.try_next().await? {
+            if message.message_type != "candidate" {
+                tracing::debug!(message_type = %message.message_type, "ignoring signaling message while trickling");
+                continue;
+            }
+            let candidate = validate_candidate(&message.payload)?;
+            if candidate.candidate.is_empty() {
+                return Ok(());
+            }
+            self.peer_connection
+                .add_ice_candidate(candidate)
+                .await
+                .map_err(|e| DropError::WebRTC(e.to_string()))?;
+        }
+        Err(DropError::WebRTC("signaling closed before the end of candidates".to_string()))
+    }
+}
+
+// Payload of the next `message_type` message, skipping any others
+async fn next_signal(signaling: &mut dyn SignalingTransport, message_type: &str) -> Result<String> {
+    let mut incoming = signaling.recv();
+    while let Some(message) = incoming.try_next().await? {
+        if message.message_type == message_type {
+            return Ok(message.payload);
+        }
+        tracing::debug!(message_type = %message.message_type, "ignoring signaling message while pairing");
+    }
+    Err(DropError::WebRTC(format!("signaling closed before the {} arrived", message_type)))
 }
 
 #[async_trait::async_trait]
 impl TransferProtocol for WebRTCTransfer {
-    async fn send_file(&mut self, _path: PathBuf) -> Result<()> {
-        // Implementation for sending file over WebRTC
-        Ok(())
+    async fn send_file(&mut self, path: PathBuf) -> Result<()> {
+        self.protocol()?.send_file(path).await
     }
 
-    async fn receive_file(&mut self, _path: PathBuf) -> Result<()> {
-        // Implementation for receiving file over WebRTC
-        Ok(())
+    async fn receive_file(&mut self, path: PathBuf) -> Result<()> {
+        self.protocol()?.receive_file(path).await
     }
 
     async fn cancel(&mut self) -> Result<()> {
-        if let Some(dc) = &self.data_channel {
+        if let Some(protocol) = &mut self.protocol {
+            protocol.cancel().await?;
+        } else if let Some(dc) = &self.data_channel {
             dc.close().await
                 .map_err(|e| crate::DropError::WebRTC(e.to_string()))?;
         }
@@ -100,4 +623,283 @@ impl TransferProtocol for WebRTCTransfer {
             .map_err(|e| crate::DropError::WebRTC(e.to_string()))?;
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+impl Drop for WebRTCTransfer {
+    fn drop(&mut self) {
+        if *self.closed.borrow() {
+            return;
+        }
+        // Outside a runtime there's nothing to drive the close on; the caller
+        // should have used `cancel` in that case.
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let peer_connection = self.peer_connection.clone();
+            handle.spawn(async move {
+                if let Err(e) = peer_connection.close().await {
+                    tracing::warn!("Failed to close dropped peer connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_validate_candidate() {
+        let host = validate_candidate("1 1 UDP 2130706431 192.168.1.20 54400 typ host").unwrap();
+        assert_eq!(host.candidate, "candidate:1 1 UDP 2130706431 192.168.1.20 54400 typ host");
+
+        // Attributes the parser doesn't keep are still there
+        let line = "candidate:3 1 tcp 1518280447 203.0.113.7 9 typ srflx raddr 10.0.0.5 rport 9 tcptype active generation 0 ufrag EsAw network-id 1";
+        assert_eq!(validate_candidate(line).unwrap().candidate, line);
+
+        // As a browser sends it, with the SDP fields kept
+        let json = r#"{"candidate":"a=candidate:842163049 1 udp 1677729535 203.0.113.7 61665 typ srflx raddr 10.0.0.5 rport 61665","sdpMid":"0","sdpMLineIndex":0}"#;
+        let srflx = validate_candidate(json).unwrap();
+        assert_eq!(
+            srflx.candidate,
+            "candidate:842163049 1 udp 1677729535 203.0.113.7 61665 typ srflx raddr 10.0.0.5 rport 61665"
+        );
+        assert_eq!((srflx.sdp_mid.as_deref(), srflx.sdp_mline_index), (Some("0"), Some(0)));
+
+        for garbage in ["garbage", "candidate:1 1 udp x 192.168.1.20 54400 typ host", "candidate:1 1 udp 1 10.0.0.1 9 typ bogus", "{\"candidate\":"] {
+            let e = validate_candidate(garbage).unwrap_err();
+            assert!(matches!(&e, DropError::WebRTC(m) if m.starts_with("malformed ICE candidate")), "{}", e);
+        }
+        assert_eq!(validate_candidate("").unwrap().candidate, "");
+    }
+
+    #[tokio::test]
+    async fn test_drop_closes_peer_connection() {
+        let mut transfer = WebRTCTransfer::new().await.unwrap();
+        transfer.create_offer().await.unwrap();
+        let peer_connection = transfer.peer_connection.clone();
+        let closed = transfer.closed();
+
+        drop(transfer);
+        tokio::time::timeout(Duration::from_secs(5), closed).await.unwrap();
+        assert_eq!(peer_connection.connection_state(), RTCPeerConnectionState::Closed);
+    }
+
+    async fn gathered_description(transfer: &WebRTCTransfer) -> String {
+        transfer.gathered_description().await.unwrap()
+    }
+
+    // One end of an in-memory signaling channel; records what it sent
+    struct MemorySignaling {
+        tx: mpsc::UnboundedSender<SignalingMessage>,
+        rx: mpsc::UnboundedReceiver<SignalingMessage>,
+        sent: Vec<SignalingMessage>,
+    }
+
+    impl MemorySignaling {
+        fn sent_types(&self) -> Vec<&str> {
+            self.sent.iter().map(|m| m.message_type.as_str()).collect()
+        }
+    }
+
+    fn memory_signaling() -> (MemorySignaling, MemorySignaling) {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        (
+            MemorySignaling { tx: a_tx, rx: a_rx, sent: Vec::new() },
+            MemorySignaling { tx: b_tx, rx: b_rx, sent: Vec::new() },
+        )
+    }
+
+    #[async_trait::async_trait]
+    impl SignalingTransport for MemorySignaling {
+        async fn send(&mut self, message: SignalingMessage) -> Result<()> {
+            self.sent.push(message.clone());
+            self.tx.send(message).map_err(|_| DropError::WebRTC("peer gone".to_string()))
+        }
+
+        fn recv(&mut self) -> futures::stream::BoxStream<'_, Result<SignalingMessage>> {
+            Box::pin(futures::stream::unfold(&mut self.rx, |rx| async move {
+                rx.recv().await.map(|message| (Ok(message), rx))
+            }))
+        }
+    }
+
+    // Offer from `a`, answer from `b`; returns before the channel is open
+    async fn negotiate(a: &mut WebRTCTransfer, b: &mut WebRTCTransfer) -> (MemorySignaling, MemorySignaling) {
+        let (mut a_signaling, mut b_signaling) = memory_signaling();
+        let (offered, answered) = tokio::join!(
+            a.pair_as_offerer(&mut a_signaling),
+            b.pair_as_answerer(&mut b_signaling)
+        );
+        offered.unwrap();
+        answered.unwrap();
+        (a_signaling, b_signaling)
+    }
+
+    // Send `src` from `a` to `dst` on `b`, starting right after negotiation
+    async fn transfer(a: &mut WebRTCTransfer, b: &mut WebRTCTransfer, src: PathBuf, dst: PathBuf) {
+        let pending = b.pending.clone();
+        let receive = async {
+            while pending.lock().unwrap().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            b.receive_file(dst).await
+        };
+        let (s, r) = tokio::time::timeout(Duration::from_secs(30), async { tokio::join!(a.send_file(src), receive) })
+            .await
+            .unwrap();
+        s.unwrap();
+        r.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_channel_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("early.bin");
+        let dst = dir.path().join("early.out");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 199) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let mut a = WebRTCTransfer::new().await.unwrap();
+        let mut b = WebRTCTransfer::new().await.unwrap();
+        a.create_offer().await.unwrap();
+        let offer = gathered_description(&a).await;
+        // Nobody has answered yet, so nothing can go out
+        let err = a.await_channel_open(Duration::from_millis(50)).await.unwrap_err();
+        assert!(matches!(err, crate::DropError::Timeout(_)), "{}", err);
+
+        b.set_remote_description(&offer).await.unwrap();
+        b.create_answer().await.unwrap();
+        let answer = gathered_description(&b).await;
+        a.set_remote_description(&answer).await.unwrap();
+
+        // The channel is still connecting here. Sending straight away would
+        // fail on a closed pipe if the first frames weren't held back.
+        transfer(&mut a, &mut b, src, dst.clone()).await;
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_pairing_over_custom_signaling() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("paired.bin");
+        let dst = dir.path().join("paired.out");
+        std::fs::write(&src, b"no signaling server involved").unwrap();
+
+        let mut a = WebRTCTransfer::new().await.unwrap();
+        let mut b = WebRTCTransfer::new().await.unwrap();
+        let (a_signaling, b_signaling) = negotiate(&mut a, &mut b).await;
+        assert_eq!(a_signaling.sent_types(), vec!["offer"]);
+        assert_eq!(b_signaling.sent_types(), vec!["answer"]);
+        transfer(&mut a, &mut b, src, dst.clone()).await;
+        assert_eq!(std::fs::read(&dst).unwrap(), b"no signaling server involved");
+    }
+
+    #[tokio::test]
+    async fn test_trickle_pairing_reaches_connected() {
+        let mut a = WebRTCTransfer::new().await.unwrap();
+        let mut b = WebRTCTransfer::new().await.unwrap();
+        let (mut a_signaling, mut b_signaling) = memory_signaling();
+        let (offered, answered) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(
+                a.pair_trickle_as_offerer(&mut a_signaling),
+                b.pair_trickle_as_answerer(&mut b_signaling)
+            )
+        })
+        .await
+        .unwrap();
+        offered.unwrap();
+        answered.unwrap();
+
+        // Description first, then at least one candidate, then the marker
+        for (signaling, description) in [(&a_signaling, "offer"), (&b_signaling, "answer")] {
+            let types = signaling.sent_types();
+            assert_eq!(types[0], description);
+            assert!(types[1..].iter().all(|t| *t == "candidate"), "{:?}", types);
+            assert!(types.len() > 2, "{:?}", types);
+            let (last, candidates) = signaling.sent[1..].split_last().unwrap();
+            assert!(is_end_of_candidates(last));
+            assert!(candidates.iter().all(|c| !is_end_of_candidates(c)));
+        }
+        assert_ne!(a_signaling.sent.last().unwrap().payload, b_signaling.sent.last().unwrap().payload);
+
+        let connected = async {
+            for transfer in [&a, &b] {
+                while transfer.peer_connection.connection_state() != RTCPeerConnectionState::Connected {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), connected).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader_keeps_the_queue_bounded() {
+        let mut a = WebRTCTransfer::new().await.unwrap();
+        let mut b = WebRTCTransfer::new().await.unwrap().with_max_queued_messages(8);
+        negotiate(&mut a, &mut b).await;
+        let mut sender = a.pending.lock().unwrap().take().unwrap();
+        let pending = b.pending.clone();
+        let mut receiver = loop {
+            if let Some(transport) = pending.lock().unwrap().take() {
+                break transport;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        let frames: Vec<Vec<u8>> = (0..300u32).map(|i| [i.to_be_bytes().to_vec(), vec![i as u8; 1000]].concat()).collect();
+        let send = async {
+            for frame in &frames {
+                sender.send(frame.clone()).await.unwrap();
+            }
+        };
+        let receive = async {
+            let (mut most_queued, mut received) = (0, Vec::new());
+            for _ in &frames {
+                // A slow consumer, e.g. a receiver writing to a busy disk
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                most_queued = most_queued.max(receiver.inbox.len());
+                received.push(receiver.recv().await.unwrap().unwrap());
+            }
+            (most_queued, received)
+        };
+        let ((), (most_queued, received)) =
+            tokio::time::timeout(Duration::from_secs(30), async { tokio::join!(send, receive) }).await.unwrap();
+        // However far the reader fell behind, the queue never grew past the
+        // bound, and nothing was dropped or reordered to keep it there
+        assert!(most_queued <= 8, "{}", most_queued);
+        assert_eq!(received, frames);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_are_fragmented() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("big.bin");
+        let dst = dir.path().join("big.out");
+        // One full 1 MiB chunk, several MB once JSON-encoded
+        let data: Vec<u8> = (0..crate::transfer::CHUNK_SIZE).map(|i| (i % 233) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let mut a = WebRTCTransfer::new().await.unwrap().with_max_message_size(16 * 1024);
+        let mut b = WebRTCTransfer::new().await.unwrap().with_max_message_size(16 * 1024);
+        negotiate(&mut a, &mut b).await;
+        transfer(&mut a, &mut b, src, dst.clone()).await;
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        let mut reassembler = Reassembler::default();
+        let pieces = fragment(b"{\"Complete\"}".repeat(10), 8);
+        assert!(pieces.iter().all(|p| p.len() <= 8));
+        let frames: Vec<_> = pieces.into_iter().filter_map(|p| reassembler.push(p).unwrap()).collect();
+        assert_eq!(frames, vec![b"{\"Complete\"}".repeat(10)]);
+        assert!(reassembler.push(vec![FRAGMENT_MARKER, 7, 1]).is_err());
+    }
+
+    #[test]
+    fn test_advertised_max_message_size() {
+        let browser = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=sctp-port:5000\r\na=max-message-size:262144\r\n";
+        assert_eq!(advertised_max_message_size(browser), Some(262144));
+        assert_eq!(advertised_max_message_size("a=max-message-size:0\r\n"), Some(usize::MAX));
+        assert_eq!(advertised_max_message_size("a=sctp-port:5000\r\n"), None);
+    }
+}
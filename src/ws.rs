@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use crate::protocol::{Protocol, Transport};
+use crate::relay::MAX_RELAY_FRAME;
+use crate::{CancellationToken, DropError, Result, TransferProtocol};
+
+// Carries protocol frames as binary WebSocket messages through the server's
+// relay (`/api/session/{id}/relay`).
+pub struct WsTransport {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WsTransport {
+    // `server` is the signaling server's base URL, http(s):// or ws(s)://
+    pub async fn connect(server: &str, session_id: &str) -> Result<Self> {
+        let base = server.trim_end_matches('/');
+        let base = if let Some(rest) = base.strip_prefix("http://") {
+            format!("ws://{}", rest)
+        } else if let Some(rest) = base.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else {
+            base.to_string()
+        };
+        let url = format!("{}/api/session/{}/relay", base, session_id);
+        let config = WebSocketConfig {
+            max_message_size: Some(MAX_RELAY_FRAME),
+            max_frame_size: Some(MAX_RELAY_FRAME),
+            ..WebSocketConfig::default()
+        };
+        let (stream, _) = tokio_tungstenite::connect_async_with_config(url, Some(config), true)
+            .await
+            .map_err(|e| DropError::Protocol(format!("relay connect failed: {}", e)))?;
+        Ok(Self { stream })
+    }
+}
+
+#[async_trait]
+impl Transport for WsTransport {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        self.stream
+            .send(Message::Binary(message))
+            .await
+            .map_err(|e| DropError::Protocol(format!("relay send failed: {}", e)))
+    }
+
+    async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some(message) = self.stream.next().await {
+            match message {
+                Ok(Message::Binary(frame)) => return Ok(Some(frame)),
+                Ok(Message::Close(_)) => return Ok(None),
+                // Pings are answered by tungstenite itself
+                Ok(_) => continue,
+                Err(e) => return Err(DropError::Protocol(format!("relay receive failed: {}", e))),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // The relay may already have torn the socket down
+        let _ = self.stream.close(None).await;
+        Ok(())
+    }
+}
+
+// `TransferProtocol` over the WebSocket relay, for networks where WebRTC is blocked
+pub struct WsTransfer {
+    protocol: Protocol<WsTransport>,
+}
+
+impl WsTransfer {
+    pub async fn connect(server: &str, session_id: &str) -> Result<Self> {
+        let transport = WsTransport::connect(server, session_id).await?;
+        Ok(Self {
+            protocol: Protocol::new(transport),
+        })
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.protocol = self.protocol.with_keep_alive(keep_alive);
+        self
+    }
+
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.protocol = self.protocol.with_cancellation_token(token);
+        self
+    }
+
+    // See `Protocol::send_reader`
+    pub async fn send_reader<R: tokio::io::AsyncRead + Unpin>(&mut self, reader: &mut R, name: &str) -> Result<u64> {
+        self.protocol.send_reader(reader, name).await
+    }
+
+    // See `Protocol::receive_to_writer`
+    pub async fn receive_to_writer<W: std::io::Write>(&mut self, writer: &mut W) -> Result<u64> {
+        self.protocol.receive_to_writer(writer).await
+    }
+}
+
+#[async_trait]
+impl TransferProtocol for WsTransfer {
+    async fn send_file(&mut self, path: PathBuf) -> Result<()> {
+        self.protocol.send_file(path).await
+    }
+
+    async fn receive_file(&mut self, path: PathBuf) -> Result<()> {
+        self.protocol.receive_file(path).await
+    }
+
+    async fn cancel(&mut self) -> Result<()> {
+        self.protocol.cancel().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpServer};
+    use std::time::Duration;
+    use crate::{AppState, Session, TransferCommand};
+
+    #[actix_web::test]
+    async fn test_transfer_over_websocket_relay() {
+        let app_state = web::Data::new(AppState::new());
+        app_state.sessions.insert("RELAY1".to_string(), Session::new(app_state.clock.now()));
+        let state = app_state.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(state.clone()).service(crate::relay::relay_socket)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("note.txt");
+        let dst = dir.path().join("note.out");
+        let data: Vec<u8> = (0..1_200_000u32).map(|i| (i % 181) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let mut sender = WsTransfer::connect(&base, "RELAY1").await.unwrap();
+        let mut receiver = WsTransfer::connect(&base, "RELAY1").await.unwrap();
+        // A third peer can't join a full room
+        assert!(WsTransfer::connect(&base, "RELAY1").await.is_err());
+        assert!(WsTransfer::connect(&base, "NOSUCH").await.is_err());
+
+        let (s, r) = tokio::join!(sender.send_file(src), receiver.receive_file(dst.clone()));
+        s.unwrap();
+        r.unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_notifies_relayed_peers() {
+        let app_state = web::Data::new(AppState::new());
+        app_state.sessions.insert("DRAIN1".to_string(), Session::new(app_state.clock.now()));
+        let state = app_state.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(state.clone()).service(crate::relay::relay_socket)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut sender = Protocol::new(WsTransport::connect(&base, "DRAIN1").await.unwrap());
+        let mut receiver = Protocol::new(WsTransport::connect(&base, "DRAIN1").await.unwrap());
+        let hello = TransferCommand::StartTransfer(crate::FileMetadata::default());
+        sender.send_command(&hello).await.unwrap();
+        assert!(matches!(receiver.recv_command().await.unwrap(), Some(TransferCommand::StartTransfer(_))));
+
+        // Neither peer finishes, so the drain runs out and both are told why
+        app_state.shutdown(Duration::from_millis(200)).await;
+        for peer in [&mut sender, &mut receiver] {
+            let notice = peer.recv_command().await.unwrap();
+            assert!(matches!(notice, Some(TransferCommand::Error(reason)) if reason == crate::relay::SHUTDOWN_NOTICE));
+        }
+        assert_eq!(app_state.open_relays.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert!(WsTransport::connect(&base, "DRAIN1").await.is_err());
+
+        handle.stop(false).await;
+    }
+}